@@ -98,6 +98,21 @@ export const rootRouterConfig: Routes = [
       }
     ]
   },
+  {
+    path: '',
+    component: MainLayoutComponent,
+    canActivate: [AuthGuard, UserRoleGuard],
+    data:
+    {
+      roles: ['Viewer', 'Engineer', 'Admin' ]
+    },
+    children: [
+      {
+        path: '',
+        loadChildren: () => import('./profile/profile.module').then(m => m.ProfileModule)
+      }
+    ]
+  },
   {
     path: '',    
     canActivate: [AuthGuard, UserRoleGuard],
@@ -8,6 +8,7 @@ import { interval, Observable, Observer, Subject, SubscriptionLike } from 'rxjs'
 import { WebSocketConfig, WebsocketService, WsMessage } from '../models/webSocket';
 import { config } from 'src/app/web-socket/web-socket.config';
 import { distinctUntilChanged, map, share, takeWhile } from 'rxjs/operators';
+import { JwtAuthService } from 'src/app/shared/services/auth/jwt-auth.service';
 
 
 @Injectable({
@@ -44,7 +45,10 @@ export class WebSocketService implements WebsocketService, OnDestroy {
   // Connection status
   public status: Observable<boolean>;
 
-  constructor(@Inject(config) private wsConfig: WebSocketConfig) {
+  // Session of the current connection, reused when reconnecting
+  private sessionId: string;
+
+  constructor(@Inject(config) private wsConfig: WebSocketConfig, private jwtAuth: JwtAuthService) {
     this.wsMessages$ = new Subject<WsMessage<any>>();
     this.wsConnection$ = new Subject<boolean>();
 
@@ -85,7 +89,9 @@ export class WebSocketService implements WebsocketService, OnDestroy {
 
   // Makes WebSocket connection
   public connect(sessionId: string) {
-    this.config.url = this.wsConfig.url + sessionId;
+    // Browsers can't set an authorization header on websockets, the token goes in the query
+    this.sessionId = sessionId;
+    this.config.url = this.wsConfig.url + sessionId + '?token=' + encodeURIComponent(this.jwtAuth.getJwtToken());
     this.websocket$ = new WebSocketSubject(this.config);    
     this.websocket$.subscribe(
       (message) => {        
@@ -104,7 +110,7 @@ export class WebSocketService implements WebsocketService, OnDestroy {
       .pipe(takeWhile((v, index) => !this.websocket$));
 
     this.reconnection$.subscribe(
-      () => this.connect(this.config.url),
+      () => this.connect(this.sessionId),
       null,
       () => {
         this.reconnection$ = null;
@@ -116,6 +122,11 @@ export class WebSocketService implements WebsocketService, OnDestroy {
       });
   }
 
+  // Session of the open connection, null when not connected
+  get connectedSessionId(): string {
+    return this.isConnected ? this.sessionId : null;
+  }
+
   // Sends WebSocket message
   sendWsData(data: any = {}): void {
     if (this.isConnected) {      
@@ -135,7 +135,7 @@ export class DataConnectComponent implements OnInit {
   onDiagramChanged(id: string) {
     console.log("data-connect:: on diagram changed: " + id);
     this.selectedDiagramId = id;    
-    this.service.get(id).subscribe(data => {
+    this.service.get(id, true).subscribe(data => {
       this.diagram = data;
 
       if (!this.diagram) {
@@ -441,7 +441,12 @@ export class DataConnectComponent implements OnInit {
                 //console.log("Updated diagram:: " + response),
                 this.snack.open('Diagram is updated.', 'OK', { duration: 2000 });        
               },
-              err => console.log(err)
+              err => {
+                console.log(err);
+                if (this.service.isVersionConflict(err)) {
+                  this.snack.open('Diagram was changed by someone else, reload it before connecting data.', 'OK', { duration: 4000 });
+                }
+              }
             );            
             break;
           }
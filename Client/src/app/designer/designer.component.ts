@@ -31,8 +31,15 @@ import { DiagramData } from '../shared/models/userobject.model'
 import { MatSnackBar } from '@angular/material/snack-bar';
 import { Router } from '@angular/router';
 import { Diagram } from '../shared/models/diagram.model';
+import { DiagramProblem, DiagramValidationReport } from '../shared/models/diagram-validation.model';
 import { Helpers, Hmi, Symbol } from '../shared/hmi.constants'
 import { JwtAuthService } from '../shared/services/auth/jwt-auth.service';
+import { AppConfirmService } from '../shared/services/app-confirm/app-confirm.service';
+import { DiagramCollaborationService } from '../shared/services/diagram-collaboration.service';
+import { EditEvent, ElementLock, ElementOperation, Participant } from '../shared/models/collaboration.model';
+
+// Colors of the selections of other designers
+const PARTICIPANT_COLORS = ['#ff9800', '#e91e63', '#8bc34a', '#9c27b0', '#00bcd4', '#ffeb3b'];
 
 const {
   mxGraph,
@@ -54,7 +61,8 @@ const {
   mxUndoManager,
   mxClient,
   mxCell,
-  mxGeometry
+  mxGeometry,
+  mxCellHighlight
 } = mxgraphFactory({
   mxLoadResources: false,
   mxLoadStylesheets: false,
@@ -91,6 +99,17 @@ export class DesignerComponent implements OnInit, AfterViewInit, OnDestroy {
   currentDiagram: Diagram;
   undoManager: mxgraph.mxUndoManager;
 
+  // Edit session shared with the other designers of the diagram
+  collaborating = false;
+  participants: Participant[] = [];
+  locks: { [elementId: string]: ElementLock } = {};
+  private wsConnected = false;
+  private applyingRemote = false;
+  private requestedLocks = new Set<string>();
+  private pendingOperations: { [elementId: string]: number } = {};
+  private remoteHighlights: any[] = [];
+  private problemHighlights: any[] = [];
+
   private destroy$ = new Subject();
 
   constructor(
@@ -103,7 +122,9 @@ export class DesignerComponent implements OnInit, AfterViewInit, OnDestroy {
     private diagramService: DiagramsService,
     private snack: MatSnackBar,
     private naviator: Router,
-    private jwtAuth: JwtAuthService
+    private jwtAuth: JwtAuthService,
+    private confirmService: AppConfirmService,
+    private collaboration: DiagramCollaborationService
   ) {
     // Check Auth Token is valid
     this.jwtAuth.checkTokenIsValid().subscribe();
@@ -146,6 +167,7 @@ export class DesignerComponent implements OnInit, AfterViewInit, OnDestroy {
     // load graph
     if (this.diagramId) { 
       this.loadGraphFromServer(this.diagramId);       
+      this.connectEditSession();
     }
     else {
       this.naviator.navigateByUrl("**");
@@ -169,11 +191,16 @@ export class DesignerComponent implements OnInit, AfterViewInit, OnDestroy {
     // undo manager
     this.undoManager = new mxUndoManager(20);
     
+    // changes of other designers are not undone
     this.graph.getModel().addListener(mxEvent.UNDO, (sender, evt) => {
-      this.undoManager?.undoableEditHappened(evt.getProperty('edit'));
+      if (!this.applyingRemote) {
+        this.undoManager?.undoableEditHappened(evt.getProperty('edit'));
+      }
     });
     this.graph.getView().addListener(mxEvent.UNDO, (sender, evt) => {
-      this.undoManager?.undoableEditHappened(evt.getProperty('edit'));
+      if (!this.applyingRemote) {
+        this.undoManager?.undoableEditHappened(evt.getProperty('edit'));
+      }
     });
 
     // Open popup in double click.
@@ -208,10 +235,25 @@ export class DesignerComponent implements OnInit, AfterViewInit, OnDestroy {
       mxEvent.consume(evt);
     });
 
-    this.graph.getModel().addListener(mxEvent.CHANGE, (evt: Event) => {
+    this.graph.getModel().addListener(mxEvent.CHANGE, (sender, evt) => {
       //console.log("Graph model has changed!");  
+      this.sendOperations(evt.getProperty('edit').changes);
     });
 
+    this.graph.getSelectionModel().addListener(mxEvent.CHANGE, () => {
+      this.selectionChanged();
+    });
+
+    // elements locked by other designers can't be moved, resized or deleted
+    const isCellLocked = this.graph.isCellLocked;
+    this.graph.isCellLocked = (cell) => {
+      return this.isLockedByOthers(cell) || isCellLocked.apply(this.graph, [cell]);
+    };
+    const isCellDeletable = this.graph.isCellDeletable;
+    this.graph.isCellDeletable = (cell) => {
+      return !this.isLockedByOthers(cell) && isCellDeletable.apply(this.graph, [cell]);
+    };
+
     const rubberband = new mxRubberband(this.graph);
 
     // disable cell connection.
@@ -995,6 +1037,12 @@ export class DesignerComponent implements OnInit, AfterViewInit, OnDestroy {
   }
 
   saveGraphToServer() {
+    // the server saves the diagram merged from the changes of all designers
+    if (this.collaborating) {
+      this.collaboration.save(this.diagramId);
+      return;
+    }
+
     var encoder = new mxCodec();
     var node = encoder.encode(this.graph.getModel());
     var xml = mxUtils.getXml(node);   
@@ -1008,10 +1056,71 @@ export class DesignerComponent implements OnInit, AfterViewInit, OnDestroy {
       },
       error => {
         console.error(error);
-        this.snack.open(error, 'OK', { duration: 4000 });
+        if (this.diagramService.isVersionConflict(error)) {
+          this.resolveSaveConflict(error.error);
+        } else if (this.diagramService.isInvalidDiagram(error)) {
+          this.showProblems(error.error.problems || []);
+        } else {
+          this.snack.open(error, 'OK', { duration: 4000 });
+        }
       }
     );    
   }
+
+  // Checks the diagram as drawn, without saving it
+  validateGraph() {
+    var encoder = new mxCodec();
+    var node = encoder.encode(this.graph.getModel());
+    const diagram: Diagram = { ...this.currentDiagram, data: mxUtils.getXml(node) };
+
+    this.diagramService.validate(diagram).subscribe(
+      (report: DiagramValidationReport) => this.showProblems(report.problems),
+      error => {
+        console.error(error);
+        this.snack.open(error, 'OK', { duration: 4000 });
+      }
+    );
+  }
+
+  // Outlines the elements with problems, errors in red and warnings in orange, until the
+  // next validation
+  private showProblems(problems: DiagramProblem[]) {
+    this.problemHighlights.forEach(highlight => highlight.destroy());
+    this.problemHighlights = [];
+    if (problems.length == 0) {
+      this.snack.open('No problems found.', 'OK', { duration: 2000 });
+      return;
+    }
+
+    for (const problem of problems) {
+      console.warn('Diagram ' + problem.severity + ' (' + problem.code + '): ' + problem.message);
+      const cell = problem.element_id ? this.graph.getModel().getCell(problem.element_id) : null;
+      const state = cell ? this.graph.getView().getState(cell) : null;
+      if (state) {
+        const highlight = new mxCellHighlight(this.graph, problem.severity === 'error' ? '#ff0000' : '#ff9900', 3);
+        highlight.highlight(state);
+        this.problemHighlights.push(highlight);
+      }
+    }
+    const errors = problems.filter(p => p.severity === 'error').length;
+    this.snack.open(errors + ' error(s), ' + (problems.length - errors) + ' warning(s): ' + problems[0].message, 'OK', { duration: 8000 });
+  }
+
+  // Someone else saved the diagram since it was loaded, either save this version on
+  // top of theirs or leave it to be reloaded
+  resolveSaveConflict(conflict: any) {
+    this.confirmService.confirm({
+      title: 'Diagram changed',
+      message: 'The diagram was changed by someone else since it was loaded. Overwrite their changes?'
+    }).subscribe(overwrite => {
+      if (overwrite) {
+        this.currentDiagram.version = conflict.version;
+        this.saveGraphToServer();
+      } else {
+        this.snack.open('Diagram is not saved. Reload it to get the latest version.', 'OK', { duration: 4000 });
+      }
+    });
+  }
   
   sendWsData(data: any) {    
     this.wsService.sendWsData(data);
@@ -1045,9 +1154,10 @@ export class DesignerComponent implements OnInit, AfterViewInit, OnDestroy {
 
   loadGraphFromServer(id: string) {
 
-    this.diagramService.get(id).subscribe(
+    this.diagramService.get(id, true).subscribe(
       data => {
         this.currentDiagram = data;
+        this.joinEditSession();
 
         try {
           if (this.currentDiagram.data && this.currentDiagram.data != "") {
@@ -1103,7 +1213,313 @@ export class DesignerComponent implements OnInit, AfterViewInit, OnDestroy {
     this.repaintGrid(new ElementRef(this.canvas), this.graph, this.gridData.scale, this.gridData.gridSize, this.gridData.translationPoint, this.gridData.width, this.gridData.height);
   }
 
+  // Connects to the data websocket to edit the diagram together with other designers
+  private connectEditSession() {
+    this.collaboration.events(this.diagramId).pipe(takeUntil(this.destroy$)).subscribe(event => {
+      this.onEditEvent(event);
+    });
+    this.wsService.wsConnection$.pipe(takeUntil(this.destroy$)).subscribe(connected => {
+      this.wsConnected = connected;
+      if (connected) {
+        this.joinEditSession();
+      } else {
+        this.endEditSession();
+      }
+    });
+    // the connection of another page is reused, the server knows the designer by its session
+    const sessionId = this.wsService.connectedSessionId;
+    if (sessionId) {
+      this.sessionId = sessionId;
+      this.wsConnected = true;
+      this.joinEditSession();
+    } else {
+      this.wsService.connect(this.sessionId);
+    }
+  }
+
+  private joinEditSession() {
+    if (this.wsConnected && this.currentDiagram && !this.collaborating) {
+      this.collaboration.join(this.diagramId);
+    }
+  }
+
+  private endEditSession() {
+    this.collaborating = false;
+    this.participants = [];
+    this.locks = {};
+    this.requestedLocks.clear();
+    this.pendingOperations = {};
+    this.refreshHighlights();
+  }
+
+  onEditEvent(event: EditEvent) {
+    switch (event.type) {
+      case 'session_state':
+        this.collaborating = true;
+        this.participants = event.participants;
+        this.locks = {};
+        event.locks.forEach(lock => this.locks[lock.element_id] = lock);
+        this.currentDiagram.version = event.version;
+        // others may have changed the diagram since it was loaded
+        if (event.data !== this.currentDiagram.data) {
+          this.currentDiagram.data = event.data;
+          this.applyRemote(() => {
+            const xml = mxUtils.parseXml(event.data);
+            this.graph.getModel().clear();
+            new mxCodec(xml).decode(xml.documentElement, this.graph.getModel());
+          });
+          this.undoManager.clear();
+        }
+        this.selectionChanged();
+        break;
+      case 'participant_joined':
+        this.participants = [...this.participants, event.participant];
+        break;
+      case 'participant_left':
+        this.participants = this.participants.filter(p => p.client_id !== event.client_id);
+        break;
+      case 'selection':
+        this.participants.filter(p => p.client_id === event.client_id).forEach(p => p.selection = event.element_ids);
+        break;
+      case 'locked':
+        this.locks[event.lock.element_id] = event.lock;
+        break;
+      case 'unlocked':
+        delete this.locks[event.element_id];
+        this.requestedLocks.delete(event.element_id);
+        break;
+      case 'lock_denied':
+        this.requestedLocks.delete(event.lock.element_id);
+        this.snack.open(event.lock.name + ' is editing this element.', 'OK', { duration: 4000 });
+        break;
+      case 'operation':
+        this.receiveOperation(event.operation, event.client_id === this.sessionId);
+        break;
+      case 'operation_rejected':
+        this.completeOperation(event.operation.element_id);
+        this.applyRemote(() => this.applyOperation(event.current
+          ? { kind: 'update', element_id: event.operation.element_id, xml: event.current }
+          : { kind: 'remove', element_id: event.operation.element_id }));
+        this.snack.open('Change not saved: ' + event.reason, 'OK', { duration: 4000 });
+        break;
+      case 'saved':
+        this.currentDiagram.version = event.version;
+        this.snack.open('Diagram is saved by ' + event.user_name + '.', 'OK', { duration: 2000 });
+        break;
+      case 'edit_error':
+        if (event.code === 'version_conflict') {
+          this.confirmService.confirm({
+            title: 'Diagram changed',
+            message: 'The diagram was saved by someone else outside of this session. Overwrite their changes?'
+          }).subscribe(overwrite => {
+            if (overwrite) {
+              this.collaboration.save(this.diagramId, true);
+            }
+          });
+        } else if (event.code === 'invalid_diagram') {
+          this.validateGraph();
+        } else {
+          this.snack.open(event.message, 'OK', { duration: 4000 });
+        }
+        break;
+    }
+    this.refreshHighlights();
+  }
+
+  // Sends the cells changed by this designer to the other designers
+  private sendOperations(changes: any[]) {
+    if (!this.collaborating || this.applyingRemote) {
+      return;
+    }
+    const model = this.graph.getModel();
+    const kinds = new Map<string, 'add' | 'update' | 'remove'>();
+    const note = (cell: mxgraph.mxCell, kind: 'add' | 'update' | 'remove') => {
+      const id = cell.getId();
+      const previous = kinds.get(id);
+      if (previous === 'add' && kind === 'update') {
+        return;
+      }
+      if (previous === 'add' && kind === 'remove') {
+        kinds.delete(id);
+        return;
+      }
+      kinds.set(id, kind);
+    };
+
+    for (const change of changes) {
+      if (change.child !== undefined) {
+        // the parent and previous parent of the child after the change
+        if (change.parent == null) {
+          note(change.child, 'remove');
+        } else if (change.previous == null) {
+          model.getDescendants(change.child).forEach(cell => note(cell, 'add'));
+        } else {
+          note(change.child, 'update');
+        }
+      } else if (change.cell) {
+        note(change.cell, 'update');
+      }
+    }
+
+    kinds.forEach((kind, id) => {
+      const operation: ElementOperation = { kind, element_id: id };
+      if (kind !== 'remove') {
+        const cell = model.getCell(id);
+        if (!cell) {
+          return;
+        }
+        operation.xml = this.encodeCell(cell);
+      }
+      this.pendingOperations[id] = (this.pendingOperations[id] || 0) + 1;
+      this.collaboration.sendOperation(this.diagramId, operation);
+    });
+  }
+
+  // Operations come back to their sender too. They are applied in the order of the server,
+  // so that all designers end up with the same diagram.
+  private receiveOperation(operation: ElementOperation, own: boolean) {
+    if (own) {
+      // later changes of the element are on their way
+      if (this.completeOperation(operation.element_id) > 0) {
+        return;
+      }
+    }
+    this.applyRemote(() => this.applyOperation(operation));
+  }
+
+  // Resolves to the operations of the element still waiting for the server
+  private completeOperation(elementId: string): number {
+    const pending = Math.max((this.pendingOperations[elementId] || 0) - 1, 0);
+    if (pending > 0) {
+      this.pendingOperations[elementId] = pending;
+    } else {
+      delete this.pendingOperations[elementId];
+    }
+    return pending;
+  }
+
+  // Makes the element match the operation, without sending it again
+  private applyRemote(apply: () => void) {
+    this.applyingRemote = true;
+    try {
+      apply();
+    }
+    catch (e) {
+      console.error(e);
+    }
+    finally {
+      this.applyingRemote = false;
+    }
+  }
+
+  private applyOperation(operation: ElementOperation) {
+    const model = this.graph.getModel();
+    const existing = model.getCell(operation.element_id);
+    if (operation.kind === 'remove') {
+      if (existing) {
+        model.remove(existing);
+      }
+      return;
+    }
+    if (existing && this.encodeCell(existing) === operation.xml) {
+      return;
+    }
+
+    const cell = this.decodeCell(operation.xml);
+    const parent = cell.getParent();
+    model.beginUpdate();
+    try {
+      if (!existing) {
+        cell.parent = null;
+        if (parent) {
+          model.add(parent, cell);
+        }
+      } else {
+        model.setValue(existing, cell.getValue());
+        model.setStyle(existing, cell.getStyle());
+        model.setGeometry(existing, cell.getGeometry());
+        model.setVisible(existing, cell.isVisible());
+        model.setTerminal(existing, cell.getTerminal(true), true);
+        model.setTerminal(existing, cell.getTerminal(false), false);
+        if (parent && parent !== existing.getParent()) {
+          model.add(parent, existing);
+        }
+      }
+    }
+    finally {
+      model.endUpdate();
+    }
+  }
+
+  private encodeCell(cell: mxgraph.mxCell): string {
+    return mxUtils.getXml(new mxCodec().encode(cell));
+  }
+
+  // Decodes a cell, references to other cells resolve to the cells of the model
+  private decodeCell(xml: string): mxgraph.mxCell {
+    const doc = mxUtils.parseXml(xml);
+    const codec = new mxCodec(doc);
+    const model = this.graph.getModel();
+    codec.lookup = (id) => model.getCell(id);
+    return codec.decode(doc.documentElement);
+  }
+
+  // Shows the selection to the other designers and locks the selected elements
+  private selectionChanged() {
+    if (!this.collaborating) {
+      return;
+    }
+    const ids = this.graph.getSelectionCells().map(cell => cell.getId());
+    this.collaboration.select(this.diagramId, ids);
+    ids.filter(id => !this.requestedLocks.has(id) && !this.isLockedByOthers(this.graph.getModel().getCell(id)))
+      .forEach(id => {
+        this.requestedLocks.add(id);
+        this.collaboration.lock(this.diagramId, id);
+      });
+    Array.from(this.requestedLocks).filter(id => ids.indexOf(id) < 0).forEach(id => {
+      this.requestedLocks.delete(id);
+      this.collaboration.unlock(this.diagramId, id);
+    });
+  }
+
+  isLockedByOthers(cell: mxgraph.mxCell): boolean {
+    const lock = cell ? this.locks[cell.getId()] : null;
+    return !!lock && lock.client_id !== this.sessionId;
+  }
+
+  otherParticipants(): Participant[] {
+    return this.participants.filter(p => p.client_id !== this.sessionId);
+  }
+
+  participantColor(clientId: string): string {
+    let hash = 0;
+    for (let i = 0; i < clientId.length; ++i) {
+      hash = (hash * 31 + clientId.charCodeAt(i)) | 0;
+    }
+    return PARTICIPANT_COLORS[Math.abs(hash) % PARTICIPANT_COLORS.length];
+  }
+
+  // Outlines the elements selected by other designers in their color
+  private refreshHighlights() {
+    this.remoteHighlights.forEach(highlight => highlight.destroy());
+    this.remoteHighlights = [];
+    for (const participant of this.otherParticipants()) {
+      for (const id of participant.selection || []) {
+        const state = this.graph.getView().getState(this.graph.getModel().getCell(id));
+        if (state) {
+          const highlight = new mxCellHighlight(this.graph, this.participantColor(participant.client_id), 2);
+          highlight.highlight(state);
+          this.remoteHighlights.push(highlight);
+        }
+      }
+    }
+  }
+
   ngOnDestroy() {
+    if (this.collaborating) {
+      this.collaboration.leave(this.diagramId);
+    }
+    this.endEditSession();
     this.destroy$.next();
     this.destroy$.complete();
   }
@@ -26,6 +26,7 @@ export class HeaderToolComponent implements OnInit {
   @Output() exportGraph = new EventEmitter();
   @Output() loadGraph = new EventEmitter();
   @Output() runGraph = new EventEmitter();
+  @Output() validateGraph = new EventEmitter();
   readonly DesignerConstant = DesignerConstant;
   selectedMode$: Observable<number>;
   selectedConnectColor$: Observable<string>;
@@ -111,4 +112,8 @@ export class HeaderToolComponent implements OnInit {
     this.runGraph.emit();
   }
 
+  onValidateGraph() {
+    this.validateGraph.emit();
+  }
+
 }
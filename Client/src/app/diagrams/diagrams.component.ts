@@ -9,11 +9,13 @@ import { Router } from '@angular/router';
 import { MatDialogRef, MatDialog } from '@angular/material/dialog';
 import { MatSnackBar } from '@angular/material/snack-bar';
 import { DialogsComponent } from './dialogs/dialogs.component';
-import { Subscription } from 'rxjs';
+import { HistoryComponent } from './history/history.component';
+import { TemplatesComponent } from './templates/templates.component';
+import { Subject, Subscription } from 'rxjs';
+import { debounceTime } from 'rxjs/operators';
 import { AppLoaderService } from '../shared/services/app-loader/app-loader.service';
-import { Diagram } from '../shared/models/diagram.model';
+import { Diagram, DiagramFilter, DiagramSummary } from '../shared/models/diagram.model';
 import { JwtAuthService } from "../shared/services/auth/jwt-auth.service";
-import { v4 as uuidv4 } from 'uuid';
 import { Authorization } from '../shared/models/user.model';
 
 
@@ -23,10 +25,16 @@ import { Authorization } from '../shared/models/user.model';
   styleUrls: ['./diagrams.component.scss']
 })
 export class DiagramsComponent implements OnInit, OnDestroy {
-  public rows = [];
-  temp = [];
+  public rows: DiagramSummary[] = [];
+  public total = 0;
+  public page = 0;
+  public pageSize = 10;
+  public filter: DiagramFilter = {};
   canEditDiagram: boolean = false;
+  canPublishDiagram: boolean = false;
   public getItemSub: Subscription;
+  private filterChanged = new Subject<void>();
+  private filterSub: Subscription;
 
   constructor(
     private renderer: Renderer2,
@@ -39,6 +47,13 @@ export class DiagramsComponent implements OnInit, OnDestroy {
 
   ngOnInit() {   
     this.canEditDiagram = Authorization.canEditDiagram( this.jwtAuth.getUserRole());
+    this.canPublishDiagram = Authorization.canPublishDiagram( this.jwtAuth.getUserRole());
+    this.filterSub = this.filterChanged
+      .pipe(debounceTime(300))
+      .subscribe(() => {
+        this.page = 0;
+        this.getData();
+      });
     this.getData();
   }
 
@@ -46,38 +61,41 @@ export class DiagramsComponent implements OnInit, OnDestroy {
     if (this.getItemSub) {
       this.getItemSub.unsubscribe()
     }
+    this.filterSub.unsubscribe();
   }
+
   getData() {
-    this.getItemSub = this.service.getAll()
+    if (this.getItemSub) {
+      this.getItemSub.unsubscribe()
+    }
+    this.getItemSub = this.service.query(this.filter, this.page, this.pageSize)
       .subscribe(data => {
-        this.rows = this.temp = data;
+        this.rows = data.diagrams;
+        this.total = data.total;
       },
       error => {
         console.error(error);
-        this.rows = this.temp = [];
+        this.rows = [];
+        this.total = 0;
         this.snack.open(error, 'OK', { duration: 4000 });
       });
   }
 
-  updateFilter(event) {
-    const val = event.target.value.toLowerCase();
-    var columns = Object.keys(this.temp[0]);
-    // Removes last "$$index" from "column"
-    columns.splice(columns.length - 1);
-    
-    if (!columns.length)
-      return;
+  updateFilter(key: string, event) {
+    this.filter[key] = event.target.value.trim();
+    this.filterChanged.next();
+  }
 
-    const rows = this.temp.filter(function(d) {
-      for (let i = 0; i <= columns.length; i++) {
-        let column = columns[i];       
-        if (d[column] && d[column].toString().toLowerCase().indexOf(val) > -1) {
-          return true;
-        }
-      }
-    });
+  setPage(pageInfo: any) {
+    this.page = pageInfo.offset;
+    this.getData();
+  }
 
-    this.rows = rows;
+  setSort(event: any) {
+    const sort = event.sorts[0];
+    this.filter.sort = sort ? (sort.dir === 'desc' ? '-' : '') + sort.prop : undefined;
+    this.page = 0;
+    this.getData();
   }
 
   open(id: string) {         
@@ -90,7 +108,7 @@ export class DiagramsComponent implements OnInit, OnDestroy {
   }
 
   export(id: string) {
-    this.service.get(id).subscribe(
+    this.service.get(id, this.canEditDiagram).subscribe(
       data => {               
         try {
           const blob = new Blob([JSON.stringify(data)], { type: 'text/json;charset=utf-8' });
@@ -113,6 +131,24 @@ export class DiagramsComponent implements OnInit, OnDestroy {
     ); 
   }
 
+  // The diagram as shown right now, e.g. for an incident report
+  snapshot(row: DiagramSummary) {
+    this.service.render(row.diagramId, 'svg', true).subscribe(
+      blob => {
+        const link = URL.createObjectURL(blob);
+        const downloadLink = this.renderer.createElement('a');
+        this.renderer.setStyle(downloadLink, 'display', 'none');
+        this.renderer.setAttribute(downloadLink, 'download', row.diagramId + '.svg');
+        this.renderer.setAttribute(downloadLink, 'href', link);
+        downloadLink.click();
+      },
+      error => {
+        console.error(error);
+        this.snack.open(error, 'OK', { duration: 4000 });
+      }
+    );
+  }
+
   delete(row: any) {
     console.log("delete diagram: " + row.diagramId);
 
@@ -129,11 +165,86 @@ export class DiagramsComponent implements OnInit, OnDestroy {
     }
   }
 
+  status(row: Diagram): string {
+    if (!row.published_version) {
+      return 'Draft';
+    }
+    if (row.version && row.version !== row.published_version) {
+      return 'Published (rev ' + row.published_version + '), unpublished changes';
+    }
+    return 'Published (rev ' + row.published_version + ')';
+  }
+
+  publish(row: Diagram) {
+    this.service.publish(row.diagramId)
+      .subscribe(result => {
+        this.snack.open('Diagram published (revision ' + result.published_version + ')!', 'OK', { duration: 4000 });
+        this.getData();
+      }, error => {
+        console.error(error);
+        const problem = error.error?.problems?.find(p => p.severity === 'error');
+        const message = error.error?.message || 'Unable to publish the diagram';
+        this.snack.open(problem ? message + ': ' + problem.message : message, 'OK', { duration: 8000 });
+      });
+  }
+
+  history(row: any) {
+    this.dialog.open(HistoryComponent, {
+      width: '720px',
+      data: { diagram: row, canEdit: this.canEditDiagram }
+    }).afterClosed()
+      .subscribe(restored => {
+        if (restored) {
+          this.getData();
+        }
+      });
+  }
+
+  // The mRIDs of the diagram become placeholders, filled in with other equipment when a
+  // diagram is made from the template
+  saveAsTemplate(row: DiagramSummary) {
+    const name = prompt("Template name", row.name);
+    if (!name || !name.trim()) {
+      return;
+    }
+    this.service.saveTemplate({ diagram_id: row.diagramId, name: name.trim(), description: row.description })
+      .subscribe(template => {
+        this.snack.open('Template saved with ' + template.placeholders.length + ' placeholders!', 'OK', { duration: 4000 });
+      }, error => {
+        console.error(error);
+        this.snack.open(error.error?.message || 'Unable to save the template', 'OK', { duration: 4000 });
+      });
+  }
+
+  fromTemplate() {
+    this.dialog.open(TemplatesComponent, {
+      width: '720px',
+      disableClose: true
+    }).afterClosed()
+      .subscribe(diagram => {
+        if (diagram) {
+          this.snack.open('Diagram ' + diagram.name + ' created!', 'OK', { duration: 4000 });
+          this.getData();
+        }
+      });
+  }
+
   connect(id: string) {
     console.log("data connect: " + id);
     this.router.navigateByUrl('/data-connect?id=' + id);
   }
 
+  // The list has no graph data, the dialog edits the latest draft
+  edit(row: DiagramSummary) {
+    this.service.get(row.diagramId, true).subscribe(
+      diagram => this.addOrEdit(diagram),
+      error => {
+        console.error(error);
+        this.snack.open(error, 'OK', { duration: 4000 });
+      }
+    );
+  }
+
   addOrEdit(data: any = {}, isNew?) {
     let title = isNew ? 'Add new diagram' : 'Update diagram';
     let dialogRef: MatDialogRef<any> = this.dialog.open(DialogsComponent, {
@@ -149,14 +260,16 @@ export class DiagramsComponent implements OnInit, OnDestroy {
         }        
 
         const diagram: Diagram = {
-          diagramId: res.diagramId ? res.diagramId : uuidv4(),
+          // New diagrams get their id from the server
+          diagramId: res.diagramId || '',
           name: res.name,
           description: res.description || '',
           location: res.location || '',
           backgroundColor: res.backgroundColor && res.backgroundColor.hex ? '#' + res.backgroundColor.hex : '',
           data: data.data || '', // graph data
           createdBy: res.createdBy || '',
-          createdDate: res.createdDate? res.createdDate : new Date().toLocaleDateString()
+          createdDate: res.createdDate? res.createdDate : new Date().toLocaleDateString(),
+          version: data.version
         }
         console.log(diagram);
         this.loader.open();
@@ -177,7 +290,12 @@ export class DiagramsComponent implements OnInit, OnDestroy {
                 this.getData();
               }, error => {
                 console.error(error);
-                this.snack.open(error, 'OK', { duration: 4000 });
+                if (this.service.isVersionConflict(error)) {
+                  this.snack.open('Diagram was changed by someone else, reloaded the latest version!', 'OK', { duration: 4000 });
+                  this.getData();
+                } else {
+                  this.snack.open(error, 'OK', { duration: 4000 });
+                }
               });
           }
         }
@@ -9,12 +9,14 @@ import { DiagramsComponent } from './diagrams.component';
 import { SharedMaterialModule } from '../shared/shared-material.module';
 import { NgxDatatableModule } from '@swimlane/ngx-datatable';
 import { DialogsComponent } from './dialogs/dialogs.component';
+import { HistoryComponent } from './history/history.component';
+import { TemplatesComponent } from './templates/templates.component';
 import { SharedComponentsModule } from '../shared/components/shared-components.module';
 import { SharedModule } from '../shared/shared.module';
 import { NgxMatColorPickerModule, MAT_COLOR_FORMATS, NGX_MAT_COLOR_FORMATS } from '@angular-material-components/color-picker'
 
 @NgModule({
-  declarations: [DiagramsComponent, DialogsComponent],
+  declarations: [DiagramsComponent, DialogsComponent, HistoryComponent, TemplatesComponent],
   imports: [
     CommonModule,
     DiagramsRoutingModule,
@@ -0,0 +1,86 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Component, OnInit, Inject } from '@angular/core';
+import { MatDialogRef, MAT_DIALOG_DATA } from '@angular/material/dialog';
+import { MatSnackBar } from '@angular/material/snack-bar';
+import { DiagramsService } from '../../shared/services/diagrams.service';
+import { DataBinding, DiagramDiff, DiagramRevision } from '../../shared/models/diagram-revision.model';
+
+@Component({
+  selector: 'app-history',
+  templateUrl: './history.component.html',
+  styleUrls: ['./history.component.scss']
+})
+export class HistoryComponent implements OnInit {
+  revisions: DiagramRevision[] = [];
+  diff: DiagramDiff = null;
+  restored = false;
+
+  constructor(
+    @Inject(MAT_DIALOG_DATA) public data: any,
+    public dialogRef: MatDialogRef<HistoryComponent>,
+    private service: DiagramsService,
+    private snack: MatSnackBar
+  ) {}
+
+  ngOnInit() {
+    this.getRevisions();
+  }
+
+  getRevisions() {
+    this.service.getRevisions(this.data.diagram.diagramId)
+      .subscribe(data => {
+        this.revisions = data;
+      },
+      error => {
+        console.error(error);
+        this.snack.open(error, 'OK', { duration: 4000 });
+      });
+  }
+
+  get latest(): number {
+    return this.revisions.length > 0 ? this.revisions[0].revision : 0;
+  }
+
+  // Shows what changed from the revision to the latest one
+  compare(revision: DiagramRevision) {
+    this.service.diff(revision.diagram_id, revision.revision)
+      .subscribe(data => {
+        this.diff = data;
+      },
+      error => {
+        console.error(error);
+        this.snack.open(error, 'OK', { duration: 4000 });
+      });
+  }
+
+  restore(revision: DiagramRevision) {
+    if (!confirm("Restore revision " + revision.revision + " of " + this.data.diagram.name + "?")) {
+      return;
+    }
+    const version = this.revisions[0]?.deleted ? undefined : this.latest;
+    this.service.revert(revision.diagram_id, revision.revision, version)
+      .subscribe(data => {
+        this.restored = true;
+        this.diff = null;
+        this.snack.open('Revision ' + revision.revision + ' restored as revision ' + data.revision, 'OK', { duration: 4000 });
+        this.getRevisions();
+      },
+      error => {
+        console.error(error);
+        this.snack.open(error, 'OK', { duration: 4000 });
+        // The diagram may have been saved meanwhile
+        this.getRevisions();
+      });
+  }
+
+  describe(binding: DataBinding): string {
+    return binding.kind + ': ' + (binding.path || Object.values(binding.attributes).join(', '));
+  }
+
+  close() {
+    this.dialogRef.close(this.restored);
+  }
+}
@@ -0,0 +1,110 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Component, OnInit } from '@angular/core';
+import { MatDialogRef } from '@angular/material/dialog';
+import { MatSnackBar } from '@angular/material/snack-bar';
+import { DiagramsService } from '../../shared/services/diagrams.service';
+import { DiagramTemplate, TemplatePlaceholder } from '../../shared/models/diagram-template.model';
+
+@Component({
+  selector: 'app-templates',
+  templateUrl: './templates.component.html',
+  styleUrls: ['./templates.component.scss']
+})
+export class TemplatesComponent implements OnInit {
+  templates: DiagramTemplate[] = [];
+  equipment: any[] = [];
+  selected: DiagramTemplate = null;
+  name = '';
+  location = '';
+  // equipment mRID by placeholder name
+  mapping: { [placeholder: string]: string } = {};
+  problems: string[] = [];
+
+  constructor(
+    public dialogRef: MatDialogRef<TemplatesComponent>,
+    private service: DiagramsService,
+    private snack: MatSnackBar
+  ) {}
+
+  ngOnInit() {
+    this.getTemplates();
+    this.service.getEquipmentList()
+      .subscribe(data => {
+        this.equipment = data;
+      },
+      error => {
+        console.error(error);
+        this.snack.open(error, 'OK', { duration: 4000 });
+      });
+  }
+
+  getTemplates() {
+    this.service.getTemplates()
+      .subscribe(data => {
+        this.templates = data;
+      },
+      error => {
+        console.error(error);
+        this.snack.open(error, 'OK', { duration: 4000 });
+      });
+  }
+
+  select(template: DiagramTemplate) {
+    this.selected = template;
+    this.mapping = {};
+    this.problems = [];
+  }
+
+  // Equipment that can fill in the placeholder
+  candidates(placeholder: TemplatePlaceholder): any[] {
+    if (!placeholder.device_type) {
+      return this.equipment;
+    }
+    return this.equipment.filter(eq => eq.deviceType === placeholder.device_type);
+  }
+
+  get complete(): boolean {
+    return this.selected && this.name.trim() !== ''
+      && this.selected.placeholders.every(p => this.mapping[p.name]);
+  }
+
+  delete(template: DiagramTemplate) {
+    if (!confirm("Are you sure to delete template " + template.name + "?")) {
+      return;
+    }
+    this.service.deleteTemplate(template.id)
+      .subscribe(() => {
+        if (this.selected && this.selected.id === template.id) {
+          this.selected = null;
+        }
+        this.getTemplates();
+      },
+      error => {
+        console.error(error);
+        this.snack.open(error.error?.message || 'Unable to delete the template', 'OK', { duration: 4000 });
+      });
+  }
+
+  create() {
+    this.problems = [];
+    this.service.instantiateTemplate({
+      template_id: this.selected.id,
+      name: this.name.trim(),
+      location: this.location.trim() || undefined,
+      mapping: this.mapping
+    }).subscribe(diagram => {
+      this.dialogRef.close(diagram);
+    },
+    error => {
+      console.error(error);
+      if (this.service.isInvalidDiagram(error)) {
+        this.problems = error.error.problems.map(p => p.message);
+      } else {
+        this.snack.open(error.error?.message || 'Unable to create the diagram', 'OK', { duration: 4000 });
+      }
+    });
+  }
+}
@@ -36,6 +36,7 @@ export class ControlDialogComponent implements OnInit {
   hasDataMapped: boolean = false;
   lastUpdate: string;
   hasLastUpdate: boolean = false;
+  reason: string = '';
   commands: any[] = [];
 
   constructor(
@@ -163,6 +164,7 @@ export class ControlDialogComponent implements OnInit {
 
       this.dialogRef.close({
         proceed: true,
+        reason: this.reason,
         //action: CommandAction.SETVALUE,
         action: this.diagramData.verb ? this.diagramData.verb : CommandAction.SETVALUE,
         value: this.setpointValue,
@@ -171,7 +173,8 @@ export class ControlDialogComponent implements OnInit {
     }    
     else if (this.isSetBoolean) {        
       this.dialogRef.close({
-        proceed: true,        
+        proceed: true,
+        reason: this.reason,
         action: this.diagramData.verb ? this.diagramData.verb : CommandAction.SETVALUE,
         value: this.onOffCommand ? 1.0 : 0.0,
         index: this.ggioIndex,  
@@ -181,6 +184,7 @@ export class ControlDialogComponent implements OnInit {
     {
       this.dialogRef.close({
         proceed: true,
+        reason: this.reason,
         action: CommandAction.VERB,
         value: this.controlValue  
       });
@@ -188,6 +192,7 @@ export class ControlDialogComponent implements OnInit {
     else {  // status indicator
       this.dialogRef.close({
         proceed: true,
+        reason: this.reason,
         action: CommandAction.PRECONFIGURED,
         value: this.controlValue  
       });
@@ -43,6 +43,7 @@ export class RegulatorDialogComponent implements OnInit {
 
   lastUpdate: string;
   hasLastUpdate: boolean = false;    
+  reason: string = '';
 
   constructor(
     public dialogRef: MatDialogRef<RegulatorDialogComponent>,
@@ -117,6 +118,7 @@ export class RegulatorDialogComponent implements OnInit {
     if (this.has3PhaseLowerMapped) {
       this.dialogRef.close({
         proceed: true,
+        reason: this.reason,
         action: CommandAction.TAP_LOWER_PHS3,
         path: this.phase3LowerPath
       });
@@ -129,6 +131,7 @@ export class RegulatorDialogComponent implements OnInit {
     if (this.has3PhaseRaiseMapped) {
       this.dialogRef.close({
         proceed: true,
+        reason: this.reason,
         action: CommandAction.TAP_RAISE_PHS3,
         path: this.phase3RaisePath
       });
@@ -141,6 +144,7 @@ export class RegulatorDialogComponent implements OnInit {
     if (this.hasPhaseALowerMapped) {
       this.dialogRef.close({
         proceed: true,
+        reason: this.reason,
         action: CommandAction.TAP_LOWER_PHSA,
         path: this.phaseALowerPath
       });
@@ -153,6 +157,7 @@ export class RegulatorDialogComponent implements OnInit {
     if (this.hasPhaseARaiseMapped) {
       this.dialogRef.close({
         proceed: true,
+        reason: this.reason,
         action: CommandAction.TAP_RAISE_PHSA,
         path: this.phaseARaisePath
       });
@@ -165,6 +170,7 @@ export class RegulatorDialogComponent implements OnInit {
     if (this.hasPhaseBLowerMapped) {
       this.dialogRef.close({
         proceed: true,
+        reason: this.reason,
         action: CommandAction.TAP_LOWER_PHSB,
         path: this.phaseBLowerPath
       });
@@ -177,6 +183,7 @@ export class RegulatorDialogComponent implements OnInit {
     if (this.hasPhaseBRaiseMapped) {
       this.dialogRef.close({
         proceed: true,
+        reason: this.reason,
         action: CommandAction.TAP_RAISE_PHSB,
         path: this.phaseBRaisePath
       });
@@ -189,6 +196,7 @@ export class RegulatorDialogComponent implements OnInit {
     if (this.hasPhaseCLowerMapped) {
       this.dialogRef.close({
         proceed: true,
+        reason: this.reason,
         action: CommandAction.TAP_LOWER_PHSC,
         path: this.phaseCLowerPath
       });
@@ -201,6 +209,7 @@ export class RegulatorDialogComponent implements OnInit {
     if (this.hasPhaseCRaiseMapped) {
       this.dialogRef.close({
         proceed: true,
+        reason: this.reason,
         action: CommandAction.TAP_RAISE_PHSC,
         path: this.phaseCRaisePath
       });
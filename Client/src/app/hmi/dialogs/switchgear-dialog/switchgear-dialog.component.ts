@@ -29,6 +29,7 @@ export class SwitchgearDialogComponent implements OnInit {
   hasDataMapped: boolean = false;
   lastUpdate: string;
   hasLastUpdate: boolean = false; 
+  reason: string = '';
   isCoordinatorActive: boolean = false;   
 
   constructor(
@@ -118,6 +119,7 @@ export class SwitchgearDialogComponent implements OnInit {
   onAction() : void {
     this.dialogRef.close({
       proceed: true,
+      reason: this.reason,
       action: this.actionText
     });
   }
@@ -771,7 +771,7 @@ export class HmiComponent implements OnInit, AfterViewInit, OnDestroy {
 
     dialogRef.afterClosed().pipe(takeUntil(this.destroy$)).subscribe(result => {
       if (result && result.proceed) {                      
-        this.sendCommand(currentCellData, result.action);
+        this.sendCommand(currentCellData, result.action, undefined, undefined, result.reason);
       }
     });
   } 
@@ -796,7 +796,7 @@ export class HmiComponent implements OnInit, AfterViewInit, OnDestroy {
 
     dialogRef.afterClosed().pipe(takeUntil(this.destroy$)).subscribe(result => {
       if (result && result.proceed) {                
-        this.sendTapChangerCommand(currentCellData, result.action, result.path);
+        this.sendTapChangerCommand(currentCellData, result.action, result.path, undefined, result.reason);
       }
     });
   }
@@ -821,7 +821,7 @@ export class HmiComponent implements OnInit, AfterViewInit, OnDestroy {
 
     dialogRef.afterClosed().pipe(takeUntil(this.destroy$)).subscribe(result => {
       if (result && result.proceed) {                
-        this.sendCommand(currentCellData, result.action, result.value, result.index);
+        this.sendCommand(currentCellData, result.action, result.value, result.index, result.reason);
       }
     });
   }
@@ -886,7 +886,8 @@ export class HmiComponent implements OnInit, AfterViewInit, OnDestroy {
     const domElement = document.querySelectorAll('span[mrid]');    
     const ts = Helpers.currentTimestamp();
 
-    if (domElement.length > 0) {
+    // messages of designers editing diagrams have no updates
+    if (domElement.length > 0 && message.updates) {
       for(let update of message.updates) {  
         // special coordinator flag
         if (update.topic?.name === InternalTopic.isCoordinatorActive) {          
@@ -1156,7 +1157,7 @@ export class HmiComponent implements OnInit, AfterViewInit, OnDestroy {
     this.wsService.sendWsData(data);
   }
 
-  sendTapChangerCommand(userObject: DiagramData, action: string, path: string, value?: number) {
+  sendTapChangerCommand(userObject: DiagramData, action: string, path: string, value?: number, reason?: string) {
     if (!path) {
       this.snack.open('Unable to send command.  No data is mapped for this control.', 'OK', { duration: 2000 });
     }
@@ -1169,7 +1170,8 @@ export class HmiComponent implements OnInit, AfterViewInit, OnDestroy {
       };
 
       const data: UpdateData = {
-        topic: t
+        topic: t,
+        reason: reason
       };
 
       this.diagramService.updateData( data)
@@ -1182,7 +1184,7 @@ export class HmiComponent implements OnInit, AfterViewInit, OnDestroy {
     }
   }
 
-  sendCommand(userObject: DiagramData, action: string, args?: any, args2?: any) {
+  sendCommand(userObject: DiagramData, action: string, args?: any, args2?: any, reason?: string) {
     console.log("Sending command with action " + action + " with args = " + args + " and args2 = " + args2);         
     
     if (action == CommandAction.VERB)
@@ -1193,7 +1195,8 @@ export class HmiComponent implements OnInit, AfterViewInit, OnDestroy {
       };
 
       const data: UpdateData = {
-        topic: t
+        topic: t,
+        reason: reason
       };
 
       this.diagramService.updateData(data)
@@ -1223,7 +1226,8 @@ export class HmiComponent implements OnInit, AfterViewInit, OnDestroy {
       };
 
       const data: UpdateData = {
-        topic: t
+        topic: t,
+        reason: reason
       };
 
       this.diagramService.updateData( data)
@@ -0,0 +1,24 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { NgModule } from '@angular/core';
+import { Routes, RouterModule } from '@angular/router';
+import { ProfileComponent } from './profile.component'
+
+const routes: Routes = [
+  {
+    path: '',
+    children: [{
+      path: 'profile',
+      component: ProfileComponent,
+      data: { title: 'PROFILE' }
+    }]
+  }
+];
+
+@NgModule({
+  imports: [RouterModule.forChild(routes)],
+  exports: [RouterModule]
+})
+export class ProfileRoutingModule { }
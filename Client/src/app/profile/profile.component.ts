@@ -0,0 +1,114 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Component, OnInit } from '@angular/core';
+import { Router } from '@angular/router';
+import { Validators, UntypedFormGroup, UntypedFormControl, AbstractControl, ValidationErrors } from '@angular/forms';
+import { MatSnackBar } from '@angular/material/snack-bar';
+import { JwtAuthService } from '../shared/services/auth/jwt-auth.service';
+import { DiagramsService } from '../shared/services/diagrams.service';
+import { User } from '../shared/models/user.model';
+
+@Component({
+  selector: 'app-profile',
+  templateUrl: './profile.component.html',
+  styleUrls: ['./profile.component.scss']
+})
+export class ProfileComponent implements OnInit {
+  user: User = {};
+  diagrams = [];
+  profileForm: UntypedFormGroup;
+  passwordForm: UntypedFormGroup;
+  preferencesForm: UntypedFormGroup;
+  landingPages = [
+    { value: 'home', name: 'Home' },
+    { value: 'diagrams', name: 'Diagrams' },
+    { value: 'default_diagram', name: 'Default diagram' }
+  ];
+
+  constructor(
+    private jwtAuth: JwtAuthService,
+    private diagramsService: DiagramsService,
+    private snack: MatSnackBar,
+    private router: Router
+  ) { }
+
+  ngOnInit(): void {
+    this.profileForm = new UntypedFormGroup({
+      displayname: new UntypedFormControl('', Validators.required),
+      currentPassword: new UntypedFormControl('', Validators.required)
+    });
+    this.passwordForm = new UntypedFormGroup({
+      currentPassword: new UntypedFormControl('', Validators.required),
+      newPassword: new UntypedFormControl('', Validators.required),
+      confirmPassword: new UntypedFormControl('', Validators.required)
+    }, { validators: this.passwordsMatch });
+    this.preferencesForm = new UntypedFormGroup({
+      landing_page: new UntypedFormControl('home'),
+      default_diagram: new UntypedFormControl(null)
+    });
+
+    this.jwtAuth.checkTokenIsValid().subscribe((user: User) => this.setUser(user));
+    this.diagramsService.getAll().subscribe(data => this.diagrams = data);
+  }
+
+  setUser(user: User) {
+    this.user = user || {};
+    this.profileForm.reset({ displayname: this.user.displayname, currentPassword: '' });
+    this.preferencesForm.reset({
+      landing_page: this.user.preferences?.landing_page || 'home',
+      default_diagram: this.user.preferences?.default_diagram || null
+    });
+  }
+
+  get isLocalUser(): boolean {
+    return !this.user.provider;
+  }
+
+  passwordsMatch(group: AbstractControl): ValidationErrors | null {
+    return group.get('newPassword').value === group.get('confirmPassword').value ? null : { mismatch: true };
+  }
+
+  saveProfile() {
+    const data = this.profileForm.value;
+    this.jwtAuth.updateProfile(data.displayname, data.currentPassword).subscribe(
+      user => {
+        this.setUser(user);
+        this.snack.open('Profile Updated!', 'OK', { duration: 4000 });
+      },
+      error => {
+        this.snack.open('Unable to update profile! Check the current password.', 'OK', { duration: 4000 });
+      }
+    );
+  }
+
+  changePassword() {
+    const data = this.passwordForm.value;
+    this.jwtAuth.changePassword(data.currentPassword, data.newPassword).subscribe(
+      response => {
+        this.passwordForm.reset();
+        this.snack.open('Password Changed!', 'OK', { duration: 4000 });
+      },
+      error => {
+        this.snack.open('Unable to change the password! Check the current password and the password policy.', 'OK', { duration: 6000 });
+      }
+    );
+  }
+
+  savePreferences() {
+    this.jwtAuth.updatePreferences(this.preferencesForm.value).subscribe(
+      user => {
+        this.setUser(user);
+        this.snack.open('Preferences Saved!', 'OK', { duration: 4000 });
+      },
+      error => {
+        this.snack.open('Unable to save preferences!', 'OK', { duration: 4000 });
+      }
+    );
+  }
+
+  setupTwoFactor() {
+    this.router.navigateByUrl('/sessions/two-factor-setup');
+  }
+}
@@ -0,0 +1,90 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Component, OnInit, OnDestroy } from '@angular/core';
+import { ApiKeyService } from 'src/app/shared/services/api-keys.service';
+import { Subscription } from 'rxjs';
+import { MatDialogRef, MatDialog } from '@angular/material/dialog';
+import { MatSnackBar } from '@angular/material/snack-bar';
+import { ApiKeyDialogComponent } from './dialogs/api-key-dialog.component';
+import { AppLoaderService } from '../../shared/services/app-loader/app-loader.service';
+
+@Component({
+  selector: 'app-api-keys',
+  templateUrl: './api-keys.component.html',
+  styleUrls: ['./api-keys.component.scss']
+})
+export class ApiKeysComponent implements OnInit, OnDestroy {
+  public rows = [];
+  public newKey: string = null;
+  public getItemSub: Subscription;
+
+  constructor(
+    private service: ApiKeyService,
+    private dialog: MatDialog,
+    private snack: MatSnackBar,
+    private loader: AppLoaderService
+  ) { }
+
+  ngOnInit(): void {
+    this.getData();
+  }
+
+  ngOnDestroy() {
+    if (this.getItemSub) {
+      this.getItemSub.unsubscribe()
+    }
+  }
+
+  getData() {
+    this.getItemSub = this.service.getAll()
+      .subscribe(data => {
+        this.rows = data;
+      })
+  }
+
+  delete(row: any) {
+    if(confirm("Are you sure to delete API key " + row.name)) {
+      this.service.delete(row.id).subscribe(
+        data => {
+          this.rows = data;
+          this.snack.open('API Key Deleted!', 'OK', { duration: 4000 })
+        },
+        error => {
+          this.snack.open('Unable to delete API key!', 'OK', { duration: 4000 });
+        }
+      );
+    }
+  }
+
+  add() {
+    let dialogRef: MatDialogRef<any> = this.dialog.open(ApiKeyDialogComponent, {
+      width: '720px',
+      disableClose: true,
+      data: { title: 'Add new API key' }
+    })
+    dialogRef.afterClosed()
+      .subscribe(res => {
+        if(!res) {
+          // If user press cancel
+          return;
+        }
+        this.loader.open();
+        this.service.create(res)
+          .subscribe(
+            data => {
+              // The key is only shown once, the server keeps a hash of it
+              this.newKey = data.key;
+              this.getData();
+              this.loader.close();
+              this.snack.open('API Key Added!', 'OK', { duration: 4000 });
+            },
+            error => {
+              this.loader.close();
+              this.snack.open('Unable to add API key!', 'OK', { duration: 4000 });
+            }
+          )
+      })
+  }
+}
@@ -0,0 +1,49 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Component, OnInit, Inject } from '@angular/core';
+import { MatDialogRef, MAT_DIALOG_DATA } from '@angular/material/dialog';
+import { UntypedFormBuilder, Validators, UntypedFormGroup } from '@angular/forms';
+import { Authorization } from '../../../shared/models/user.model';
+
+@Component({
+  selector: 'app-api-key-dialog',
+  templateUrl: './api-key-dialog.component.html',
+  styleUrls: ['./api-key-dialog.component.scss']
+})
+export class ApiKeyDialogComponent implements OnInit {
+  public itemForm: UntypedFormGroup;
+  roles: any[];
+
+  constructor(
+    @Inject(MAT_DIALOG_DATA) public data: any,
+    public dialogRef: MatDialogRef<ApiKeyDialogComponent>,
+    private fb: UntypedFormBuilder,
+  ) {}
+
+  ngOnInit() {
+    this.roles = [ Authorization.authRoles.admin, Authorization.authRoles.engineer, Authorization.authRoles.viewer ];
+    this.itemForm = this.fb.group({
+      name: ['', Validators.required],
+      role: [Authorization.authRoles.viewer, Validators.required],
+      control_scope: [''],
+      expires_in_days: ['']
+    });
+  }
+
+  submit() {
+    // An empty control scope lets the key control all equipment
+    const scope = this.itemForm.value.control_scope
+      .split(',')
+      .map(s => s.trim())
+      .filter(s => s.length > 0);
+    const days = parseInt(this.itemForm.value.expires_in_days, 10);
+    this.dialogRef.close({
+      name: this.itemForm.value.name,
+      role: this.itemForm.value.role,
+      control_scope: scope.length > 0 ? scope : null,
+      expires_in_days: days > 0 ? days : null
+    })
+  }
+}
@@ -0,0 +1,106 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Component, OnInit, OnDestroy } from '@angular/core';
+import { AuditLogService } from 'src/app/shared/services/audit-log.service';
+import { AuditEntry, AuditFilter } from 'src/app/shared/models/audit-log.model';
+import { Subscription } from 'rxjs';
+import { MatSnackBar } from '@angular/material/snack-bar';
+
+@Component({
+  selector: 'app-audit-log',
+  templateUrl: './audit-log.component.html',
+  styleUrls: ['./audit-log.component.scss']
+})
+export class AuditLogComponent implements OnInit, OnDestroy {
+  public rows: AuditEntry[] = [];
+  public total = 0;
+  public page = 0;
+  public pageSize = 20;
+  public filter: AuditFilter = {};
+  public fromDate: string = '';
+  public toDate: string = '';
+  public selected: AuditEntry = null;
+  public getItemSub: Subscription;
+
+  constructor(
+    private service: AuditLogService,
+    private snack: MatSnackBar
+  ) { }
+
+  ngOnInit(): void {
+    this.getData();
+  }
+
+  ngOnDestroy() {
+    if (this.getItemSub) {
+      this.getItemSub.unsubscribe()
+    }
+  }
+
+  private currentFilter(): AuditFilter {
+    return {
+      ...this.filter,
+      // The server takes unix timestamps in seconds
+      from: this.fromDate ? Math.floor(new Date(this.fromDate).getTime() / 1000) : undefined,
+      to: this.toDate ? Math.floor(new Date(this.toDate).getTime() / 1000) : undefined
+    };
+  }
+
+  getData() {
+    if (this.getItemSub) {
+      this.getItemSub.unsubscribe()
+    }
+    this.getItemSub = this.service.query(this.currentFilter(), this.page, this.pageSize)
+      .subscribe(
+        data => {
+          this.rows = data.entries;
+          this.total = data.total;
+        },
+        error => {
+          this.snack.open('Unable to load audit log!', 'OK', { duration: 4000 });
+        }
+      )
+  }
+
+  search() {
+    this.page = 0;
+    this.selected = null;
+    this.getData();
+  }
+
+  clear() {
+    this.filter = {};
+    this.fromDate = '';
+    this.toDate = '';
+    this.search();
+  }
+
+  setPage(pageInfo: any) {
+    this.page = pageInfo.offset;
+    this.getData();
+  }
+
+  select(event: any) {
+    if (event.type === 'click') {
+      this.selected = event.row;
+    }
+  }
+
+  export(format: string) {
+    this.service.export(this.currentFilter(), format).subscribe(
+      (blob: Blob) => {
+        const url = window.URL.createObjectURL(blob);
+        const a = document.createElement('a');
+        a.href = url;
+        a.download = 'audit-log.' + format;
+        a.click();
+        window.URL.revokeObjectURL(url);
+      },
+      error => {
+        this.snack.open('Unable to export audit log!', 'OK', { duration: 4000 });
+      }
+    );
+  }
+}
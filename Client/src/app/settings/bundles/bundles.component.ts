@@ -0,0 +1,139 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Component, OnInit, OnDestroy } from '@angular/core';
+import { Subscription } from 'rxjs';
+import { MatSnackBar } from '@angular/material/snack-bar';
+import { BundleService } from 'src/app/shared/services/bundle.service';
+import { DiagramsService } from 'src/app/shared/services/diagrams.service';
+import { BundleItem, BundlePreview, ImportedItem, MergeStrategy } from 'src/app/shared/models/bundle.model';
+import { DiagramSummary } from 'src/app/shared/models/diagram.model';
+
+@Component({
+  selector: 'app-bundles',
+  templateUrl: './bundles.component.html',
+  styleUrls: ['./bundles.component.scss']
+})
+export class BundlesComponent implements OnInit, OnDestroy {
+  public diagrams: DiagramSummary[] = [];
+  public selected = new Set<string>();
+  public includeUsers = false;
+  public bundle: any = null;
+  public preview: BundlePreview = null;
+  // Chosen strategy of each item, by kind and id
+  public strategies: { [key: string]: MergeStrategy } = {};
+  public imported: ImportedItem[] = [];
+  public getItemSub: Subscription;
+
+  constructor(
+    private service: BundleService,
+    private diagramsService: DiagramsService,
+    private snack: MatSnackBar
+  ) { }
+
+  ngOnInit(): void {
+    this.getItemSub = this.diagramsService.getAll()
+      .subscribe(
+        data => this.diagrams = data.filter(d => !d.error),
+        error => this.snack.open(error, 'OK', { duration: 4000 })
+      );
+  }
+
+  ngOnDestroy() {
+    if (this.getItemSub) {
+      this.getItemSub.unsubscribe()
+    }
+  }
+
+  toggle(id: string, checked: boolean) {
+    if (checked) {
+      this.selected.add(id);
+    } else {
+      this.selected.delete(id);
+    }
+  }
+
+  toggleAll(checked: boolean) {
+    this.selected = new Set(checked ? this.diagrams.map(d => d.diagramId) : []);
+  }
+
+  export() {
+    this.service.export({ diagram_ids: Array.from(this.selected), include_users: this.includeUsers }).subscribe(
+      (blob: Blob) => {
+        const url = window.URL.createObjectURL(blob);
+        const a = document.createElement('a');
+        a.href = url;
+        a.download = 'hmi-bundle.json';
+        a.click();
+        window.URL.revokeObjectURL(url);
+      },
+      error => {
+        this.snack.open(error, 'OK', { duration: 4000 });
+      }
+    );
+  }
+
+  key(item: BundleItem): string {
+    return item.kind + ':' + item.id;
+  }
+
+  onFileSelected(event: any) {
+    const file: File = event.target.files[0];
+    event.target.value = '';
+    if (!file) {
+      return;
+    }
+    const reader = new FileReader();
+    reader.onload = () => {
+      try {
+        this.bundle = JSON.parse(reader.result as string);
+      }
+      catch (e) {
+        this.snack.open('The file is not a configuration bundle!', 'OK', { duration: 4000 });
+        return;
+      }
+      this.service.preview(this.bundle).subscribe(
+        (preview: BundlePreview) => {
+          this.preview = preview;
+          this.imported = [];
+          this.strategies = {};
+          preview.items.forEach(item => this.strategies[this.key(item)] = item.strategies[0]);
+        },
+        error => {
+          this.cancel();
+          this.snack.open(error, 'OK', { duration: 4000 });
+        }
+      );
+    };
+    reader.readAsText(file);
+  }
+
+  import() {
+    const choices = this.preview.items.map(item => ({
+      kind: item.kind,
+      id: item.id,
+      strategy: this.strategies[this.key(item)]
+    }));
+    this.service.import(this.bundle, choices).subscribe(
+      (items: ImportedItem[]) => {
+        this.imported = items;
+        this.cancel();
+        const failed = items.filter(item => item.error).length;
+        if (failed) {
+          this.snack.open((items.length - failed) + ' items imported, ' + failed + ' failed', 'OK', { duration: 8000 });
+        } else {
+          this.snack.open(items.length + ' items imported, diagrams are imported as drafts', 'OK', { duration: 4000 });
+        }
+      },
+      error => {
+        this.snack.open(error, 'OK', { duration: 8000 });
+      }
+    );
+  }
+
+  cancel() {
+    this.bundle = null;
+    this.preview = null;
+  }
+}
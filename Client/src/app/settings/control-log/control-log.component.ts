@@ -0,0 +1,79 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Component, OnInit, OnDestroy } from '@angular/core';
+import { ControlLogService } from 'src/app/shared/services/control-log.service';
+import { ControlLogFilter, ControlRecord } from 'src/app/shared/models/control-log.model';
+import { Subscription } from 'rxjs';
+import { MatSnackBar } from '@angular/material/snack-bar';
+
+@Component({
+  selector: 'app-control-log',
+  templateUrl: './control-log.component.html',
+  styleUrls: ['./control-log.component.scss']
+})
+export class ControlLogComponent implements OnInit, OnDestroy {
+  public rows: ControlRecord[] = [];
+  public total = 0;
+  public page = 0;
+  public pageSize = 20;
+  public filter: ControlLogFilter = {};
+  public fromDate: string = '';
+  public toDate: string = '';
+  public getItemSub: Subscription;
+
+  constructor(
+    private service: ControlLogService,
+    private snack: MatSnackBar
+  ) { }
+
+  ngOnInit(): void {
+    this.getData();
+  }
+
+  ngOnDestroy() {
+    if (this.getItemSub) {
+      this.getItemSub.unsubscribe()
+    }
+  }
+
+  getData() {
+    if (this.getItemSub) {
+      this.getItemSub.unsubscribe()
+    }
+    const filter: ControlLogFilter = {
+      ...this.filter,
+      // The server takes unix timestamps in seconds
+      from: this.fromDate ? Math.floor(new Date(this.fromDate).getTime() / 1000) : undefined,
+      to: this.toDate ? Math.floor(new Date(this.toDate).getTime() / 1000) : undefined
+    };
+    this.getItemSub = this.service.query(filter, this.page, this.pageSize)
+      .subscribe(
+        data => {
+          this.rows = data.records;
+          this.total = data.total;
+        },
+        error => {
+          this.snack.open('Unable to load control log!', 'OK', { duration: 4000 });
+        }
+      )
+  }
+
+  search() {
+    this.page = 0;
+    this.getData();
+  }
+
+  clear() {
+    this.filter = {};
+    this.fromDate = '';
+    this.toDate = '';
+    this.search();
+  }
+
+  setPage(pageInfo: any) {
+    this.page = pageInfo.offset;
+    this.getData();
+  }
+}
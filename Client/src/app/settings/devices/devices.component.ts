@@ -124,7 +124,12 @@ export class DevicesComponent implements OnInit, OnDestroy {
               },
               error => {
                 this.loader.close();
-                this.snack.open('Unable to update device!', 'OK', { duration: 4000 });                
+                if (this.service.isVersionConflict(error)) {
+                  this.snack.open('Device was changed by someone else, reloaded the latest version!', 'OK', { duration: 4000 });
+                  this.getData();
+                } else {
+                  this.snack.open('Unable to update device!', 'OK', { duration: 4000 });
+                }
               }
             )
         }
@@ -33,7 +33,8 @@ export class DeviceDialogsComponent implements OnInit {
     this.itemForm = this.fb.group({
       mrid: [{ value: item.mrid || '', disabled: disabled }, Validators.compose([Validators.required, Validators.pattern('^[0-9a-f]{8}-[0-9a-f]{4}-[0-5][0-9a-f]{3}-[089ab][0-9a-f]{3}-[0-9a-f]{12}$')])],
       name: [item.name || '', Validators.required],
-      deviceType: [item.deviceType || '', Validators.required]     
+      deviceType: [item.deviceType || '', Validators.required],
+      group: [item.group || '']
     });         
   }
 
@@ -46,6 +47,8 @@ export class DeviceDialogsComponent implements OnInit {
       name: this.itemForm.value.name,
       deviceType: this.itemForm.value.deviceType,
       mrid: this.itemForm.controls['mrid'].value,
+      group: this.itemForm.value.group || null,
+      version: this.data.payload.version,
     })
   }
 }
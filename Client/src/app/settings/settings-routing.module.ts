@@ -8,6 +8,10 @@ import { AppSettingsComponent } from './app-settings/app-settings.component';
 import { UsersComponent } from './users/users.component';
 import { TagsComponent } from './tags/tags.component';
 import { DevicesComponent } from './devices/devices.component';
+import { ApiKeysComponent } from './api-keys/api-keys.component';
+import { AuditLogComponent } from './audit-log/audit-log.component';
+import { ControlLogComponent } from './control-log/control-log.component';
+import { BundlesComponent } from './bundles/bundles.component';
 
 const routes: Routes = [
   {
@@ -29,6 +33,22 @@ const routes: Routes = [
         path: 'devices',
         component: DevicesComponent,
         data: { title: 'DEVICES' }
+      }, {
+        path: 'api-keys',
+        component: ApiKeysComponent,
+        data: { title: 'API KEYS' }
+      }, {
+        path: 'audit-log',
+        component: AuditLogComponent,
+        data: { title: 'AUDIT LOG' }
+      }, {
+        path: 'control-log',
+        component: ControlLogComponent,
+        data: { title: 'CONTROL LOG' }
+      }, {
+        path: 'bundles',
+        component: BundlesComponent,
+        data: { title: 'BUNDLES' }
       }
     ]
   }
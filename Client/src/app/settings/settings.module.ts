@@ -16,10 +16,15 @@ import { NgxDatatableModule } from '@swimlane/ngx-datatable';
 import { DialogsComponent } from './users/dialogs/dialogs.component';
 import { DevicesComponent } from './devices/devices.component';
 import { DeviceDialogsComponent } from './devices/dialogs/devicedialogs.component';
+import { ApiKeysComponent } from './api-keys/api-keys.component';
+import { ApiKeyDialogComponent } from './api-keys/dialogs/api-key-dialog.component';
+import { AuditLogComponent } from './audit-log/audit-log.component';
+import { ControlLogComponent } from './control-log/control-log.component';
+import { BundlesComponent } from './bundles/bundles.component';
 
 
 @NgModule({
-  declarations: [AppSettingsComponent, UsersComponent, TagsComponent, DialogsComponent, DevicesComponent, DeviceDialogsComponent],
+  declarations: [AppSettingsComponent, UsersComponent, TagsComponent, DialogsComponent, DevicesComponent, DeviceDialogsComponent, ApiKeysComponent, ApiKeyDialogComponent, AuditLogComponent, ControlLogComponent, BundlesComponent],
   imports: [
     CommonModule,
     SettingsRoutingModule,
@@ -34,12 +34,25 @@ export class DialogsComponent implements OnInit {
       username: [item.username || '', Validators.required],
       displayname: [item.displayname || '', Validators.required],      
       role: [item.role || '', Validators.required],
-      pwd: [item.pwd || '', Validators.required]
+      // The stored password isn't sent to the client, an empty password keeps it
+      pwd: ['', this.data.isNew ? Validators.required : []],
+      control_scope: [(item.control_scope || []).join(', ')],
+      must_change_password: [item.must_change_password || false]
     });
     this.selectedRole = item.role;
   }
 
-  submit() {    
-    this.dialogRef.close(this.itemForm.value)
+  submit() {
+    // An empty control scope lets the user control all equipment
+    const scope = this.itemForm.value.control_scope
+      .split(',')
+      .map(s => s.trim())
+      .filter(s => s.length > 0);
+    const { pwd, ...item } = this.itemForm.value;
+    this.dialogRef.close({
+      ...item,
+      ...(pwd ? { pwd } : {}),
+      control_scope: scope.length > 0 ? scope : null
+    })
   }
 }
@@ -87,6 +87,32 @@ export class UsersComponent implements OnInit, OnDestroy {
     }
   }
 
+  unlock(user: any) {
+    this.service.unlock(user).subscribe(
+      data => {
+        this.rows = data;
+        this.snack.open('User Unlocked!', 'OK', { duration: 4000 })
+      },
+      error => {
+        this.snack.open('Unable to unlock user!', 'OK', { duration: 4000 });
+      }
+    );
+  }
+
+  resetTwoFactor(user: any) {
+    if(confirm("Are you sure to reset the two-factor authentication of " + user.username)) {
+      this.service.resetTwoFactor(user).subscribe(
+        data => {
+          this.rows = data;
+          this.snack.open('Two-factor authentication reset!', 'OK', { duration: 4000 })
+        },
+        error => {
+          this.snack.open('Unable to reset two-factor authentication!', 'OK', { duration: 4000 });
+        }
+      );
+    }
+  }
+
   addOrEdit(data: any = {}, isNew?) {
     let title = isNew ? 'Add new user' : 'Update user';
     let dialogRef: MatDialogRef<any> = this.dialog.open(DialogsComponent, {
@@ -112,7 +138,7 @@ export class UsersComponent implements OnInit, OnDestroy {
               },
               error => {
                 this.loader.close();
-                this.snack.open('Unable to add user!', 'OK', { duration: 4000 });                
+                this.snack.open('Unable to add user: ' + error, 'OK', { duration: 4000 });                
               }
             )
         } else {          
@@ -125,7 +151,7 @@ export class UsersComponent implements OnInit, OnDestroy {
               },
               error => {
                 this.loader.close();
-                this.snack.open('Unable to update user!', 'OK', { duration: 4000 });                
+                this.snack.open('Unable to update user: ' + error, 'OK', { duration: 4000 });                
               }
             )
         }
@@ -18,6 +18,14 @@ export class AuthGuard implements CanActivate {
 
   canActivate(route: ActivatedRouteSnapshot, state: RouterStateSnapshot) {
     if (this.jwtAuth.isLoggedIn()) {
+      if (this.jwtAuth.mustChangePassword()) {
+        this.router.navigate(["/sessions/change-password"]);
+        return false;
+      }
+      if (this.jwtAuth.mustEnrollTwoFactor()) {
+        this.router.navigate(["/sessions/two-factor-setup"]);
+        return false;
+      }
       return true;
     } else {
       this.router.navigate(["/sessions/signin"], {
@@ -10,8 +10,12 @@ import {
   HttpRequest
 } from "@angular/common/http";
 import { Observable } from "rxjs";
+import { switchMap } from "rxjs/operators";
 import { JwtAuthService } from "../services/auth/jwt-auth.service";
 
+// Requests that must not trigger a token refresh
+const NO_REFRESH = ["login", "refresh", "logout"];
+
 @Injectable()
 export class TokenInterceptor implements HttpInterceptor {
 
@@ -23,6 +27,19 @@ export class TokenInterceptor implements HttpInterceptor {
   ): Observable<HttpEvent<any>> {
     var token = this.jwtAuth.token || this.jwtAuth.getJwtToken();
 
+    if (token
+      && this.jwtAuth.getRefreshToken()
+      && this.jwtAuth.isTokenExpiring(token)
+      && !NO_REFRESH.some(path => req.url.endsWith(path))) {
+      return this.jwtAuth.refresh().pipe(
+        switchMap((res: any) => next.handle(req.clone({
+          setHeaders: {
+            Authorization: `Bearer ${res.token}`
+          },
+        })))
+      );
+    }
+
     var changedReq;
 
     if (token) {
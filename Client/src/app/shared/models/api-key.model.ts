@@ -0,0 +1,22 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+export interface ApiKey {
+  id?: string;
+  name?: string;
+  permissions?: string[];
+  control_scope?: string[];
+  created?: number;
+  created_by?: string;
+  expires?: number;
+  last_used?: number;
+}
+
+export interface ApiKeyRequest {
+  name: string;
+  role?: string;
+  permissions?: string[];
+  control_scope?: string[];
+  expires_in_days?: number;
+}
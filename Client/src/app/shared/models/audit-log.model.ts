@@ -0,0 +1,28 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+export interface AuditEntry {
+  id: string;
+  timestamp: number;
+  actor: string;
+  action: string;
+  target: string;
+  before?: any;
+  after?: any;
+}
+
+export interface AuditPage {
+  total: number;
+  page: number;
+  page_size: number;
+  entries: AuditEntry[];
+}
+
+export interface AuditFilter {
+  actor?: string;
+  action?: string;
+  target?: string;
+  from?: number;
+  to?: number;
+}
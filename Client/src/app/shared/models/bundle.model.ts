@@ -0,0 +1,46 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+export type BundleItemKind = 'diagram' | 'equipment' | 'user';
+
+export type MergeStrategy = 'import' | 'skip' | 'replace' | 'copy';
+
+export interface BundleExportRequest {
+  // All diagrams when left out
+  diagram_ids?: string[];
+  include_users?: boolean;
+}
+
+export interface BundleItem {
+  kind: BundleItemKind;
+  id: string;
+  name: string;
+  // What the item collides with, missing for new items
+  conflict?: string;
+  // The first one is the default
+  strategies: MergeStrategy[];
+}
+
+export interface BundlePreview {
+  exported_at: number;
+  exported_by: string;
+  items: BundleItem[];
+}
+
+export interface BundleChoice {
+  kind: BundleItemKind;
+  id: string;
+  strategy: MergeStrategy;
+}
+
+export interface ImportedItem {
+  kind: BundleItemKind;
+  id: string;
+  name: string;
+  strategy: MergeStrategy;
+  // Id of a diagram imported as a copy
+  new_id?: string;
+  // Why the item wasn't imported
+  error?: string;
+}
@@ -0,0 +1,47 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Change of a single cell, xml is the cell encoded by mxCodec
+export interface ElementOperation {
+    kind: 'add' | 'update' | 'remove',
+    element_id: string,
+    xml?: string
+}
+
+export interface Participant {
+    client_id: string,
+    user_id: string,
+    name: string,
+    selection: string[]
+}
+
+export interface ElementLock {
+    element_id: string,
+    client_id: string,
+    user_id: string,
+    name: string
+}
+
+// Messages of the server for designers editing a diagram together, told apart by type
+export interface EditEvent {
+    type: 'session_state' | 'participant_joined' | 'participant_left' | 'selection' | 'locked' | 'unlocked'
+        | 'lock_denied' | 'operation' | 'operation_rejected' | 'saved' | 'edit_error',
+    diagram_id: string,
+    version?: number,
+    data?: string,
+    sequence?: number,
+    participants?: Participant[],
+    locks?: ElementLock[],
+    participant?: Participant,
+    client_id?: string,
+    element_ids?: string[],
+    lock?: ElementLock,
+    element_id?: string,
+    operation?: ElementOperation,
+    reason?: string,
+    current?: string,
+    user_name?: string,
+    code?: string,
+    message?: string
+}
@@ -0,0 +1,33 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+export interface ControlRecord {
+  id: string;
+  timestamp: number;
+  user_id: string;
+  username: string;
+  reason?: string;
+  mrid: string;
+  control: string;
+  args?: number;
+  args2?: number;
+  profile?: string;
+  subject?: string;
+  published: boolean;
+  error?: string;
+}
+
+export interface ControlLogPage {
+  total: number;
+  page: number;
+  page_size: number;
+  records: ControlRecord[];
+}
+
+export interface ControlLogFilter {
+  mrid?: string;
+  user?: string;
+  from?: number;
+  to?: number;
+}
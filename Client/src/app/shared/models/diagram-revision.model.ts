@@ -0,0 +1,51 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Diagram } from './diagram.model';
+
+export interface DiagramRevision {
+    diagram_id: string,
+    revision: number,
+    author: string,
+    author_name: string,
+    timestamp: number,
+    deleted?: boolean
+}
+
+export interface DiagramRevisionContent extends DiagramRevision {
+    diagram: Diagram
+}
+
+export interface DataBinding {
+    kind: string,
+    path?: string,
+    attributes: { [key: string]: string }
+}
+
+export interface DiagramElement {
+    id: string,
+    element_type?: string,
+    label?: string,
+    mrid?: string,
+    bindings: DataBinding[]
+}
+
+export interface ElementChange {
+    id: string,
+    label?: string,
+    changes: string[],
+    bindings_added: DataBinding[],
+    bindings_removed: DataBinding[]
+}
+
+export interface DiagramDiff {
+    diagram_id: string,
+    from: number,
+    to: number,
+    fields: string[],
+    elements_compared: boolean,
+    added: DiagramElement[],
+    removed: DiagramElement[],
+    changed: ElementChange[]
+}
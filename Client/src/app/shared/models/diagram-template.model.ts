@@ -0,0 +1,37 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Equipment a template leaves open, filled in when a diagram is made from it
+export interface TemplatePlaceholder {
+    name: string,
+    device_type?: string, // any equipment when not set
+    equipment_name?: string, // equipment the template was made from
+    occurrences: number
+}
+
+export interface DiagramTemplate {
+    id: string,
+    name: string,
+    description?: string,
+    diagram_id: string,
+    placeholders: TemplatePlaceholder[],
+    created_by: string,
+    created_date: number // unix timestamp in seconds
+}
+
+export interface SaveDiagramTemplateRequest {
+    diagram_id: string,
+    name: string,
+    description?: string,
+    id?: string, // template to replace
+    placeholders?: { [mrid: string]: string } // placeholder names by mRID
+}
+
+export interface InstantiateDiagramTemplateRequest {
+    template_id: string,
+    name: string,
+    description?: string,
+    location?: string,
+    mapping: { [placeholder: string]: string } // equipment mRID by placeholder name
+}
@@ -0,0 +1,18 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+export interface DiagramProblem {
+    severity: string, // error or warning
+    code: string,
+    element_id?: string,
+    mrid?: string,
+    message: string
+}
+
+export interface DiagramValidationReport {
+    diagram_id: string,
+    errors: number,
+    warnings: number,
+    problems: DiagramProblem[]
+}
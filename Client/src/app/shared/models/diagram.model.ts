@@ -10,5 +10,39 @@ export interface Diagram {
     data? : string,
     createdBy? : string,
     createdDate? : string,
-    backgroundColor? : string
-}
\ No newline at end of file
+    backgroundColor? : string,
+    // Revision the diagram was read at, sent back as If-Match when saving
+    version? : number,
+    // Revision shown on the HMI, missing while the diagram is a draft only
+    published_version? : number
+}
+
+// What the diagram list returns of a diagram, without its graph data
+export interface DiagramSummary {
+    diagramId : string,
+    name? : string,
+    description? : string,
+    location? : string,
+    createdBy? : string,
+    createdDate? : string,
+    // Unix timestamp in seconds of the latest revision
+    updated? : number,
+    version? : number,
+    published_version? : number,
+    // Set when the stored diagram can't be read
+    error? : string
+}
+
+export interface DiagramPage {
+    total : number,
+    page : number,
+    page_size : number,
+    diagrams : DiagramSummary[]
+}
+
+export interface DiagramFilter {
+    search? : string,
+    location? : string,
+    // name, location, createdBy, createdDate or updated, descending with a leading '-'
+    sort? : string
+}
@@ -5,7 +5,10 @@
 export interface Equipment {
     mrid?: string,
     name?: string,
-    device_type?: string
+    device_type?: string,
+    group?: string,
+    // Version the equipment was read at, sent back as If-Match when updating
+    version?: number
 }
 
 export const getEquipmentTypeList = () => {
@@ -12,5 +12,7 @@ export interface Topic {
 }
 
 export interface UpdateData {
-  topic?: Topic
+  topic?: Topic,
+  // Reason given by the operator, recorded with the control
+  reason?: string
 }
\ No newline at end of file
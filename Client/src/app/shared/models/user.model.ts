@@ -11,6 +11,9 @@ export const Authorization = {
   canEditDiagram: (role: string) => {
     return role === Authorization.authRoles.admin || role === Authorization.authRoles.engineer;
   },  
+  canPublishDiagram: (role: string) => {
+    return role === Authorization.authRoles.admin;
+  },
   canUpdateSettings: (role: string) => {
     return role === Authorization.authRoles.admin;
   },
@@ -25,4 +28,15 @@ export interface User {
   role?: string;
   username?: string;
   pwd?: string;
+  control_scope?: string[];
+  locked?: boolean;
+  must_change_password?: boolean;
+  two_factor_enabled?: boolean;
+  provider?: string;
+  preferences?: UserPreferences;
+}
+
+export interface UserPreferences {
+  default_diagram?: string;
+  landing_page?: string; // home, diagrams or default_diagram
 }
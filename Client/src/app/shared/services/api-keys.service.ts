@@ -0,0 +1,48 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Injectable } from '@angular/core';
+import { HttpClient, HttpErrorResponse } from '@angular/common/http'
+import { environment } from '../../../environments/environment';
+import { ApiKey, ApiKeyRequest } from '../models/api-key.model'
+import { Observable, throwError } from 'rxjs';
+import { catchError } from 'rxjs/internal/operators';
+
+@Injectable({
+  providedIn: 'root'
+})
+
+export class ApiKeyService {
+  private endpoint = environment.apiUrl;
+  constructor(private httpClient: HttpClient) { }
+
+  private handleError(error: HttpErrorResponse): any {
+    if (error.error instanceof ErrorEvent) {
+      console.error('An error occurred:', error.error.message);
+    } else {
+      console.error(
+        `Backend returned code ${error.status}, ` +
+        `body was: ${error.error}`);
+    }
+    return throwError('An error occurred.  Check if the server is running and accessible.');
+  }
+
+  getAll() : Observable<any> {
+    return this.httpClient.get<ApiKey[]>(this.endpoint + 'get-api-keys').pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  create(request: ApiKeyRequest) : Observable<any> {
+    return this.httpClient.post(this.endpoint + 'create-api-key', request).pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  delete(id: string) : Observable<any> {
+    return this.httpClient.post<ApiKey[]>(this.endpoint + 'delete-api-key', { id: id }).pipe(
+      catchError(this.handleError)
+    );
+  }
+}
@@ -0,0 +1,56 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Injectable } from '@angular/core';
+import { HttpClient, HttpErrorResponse, HttpParams } from '@angular/common/http'
+import { environment } from '../../../environments/environment';
+import { AuditFilter, AuditPage } from '../models/audit-log.model'
+import { Observable, throwError } from 'rxjs';
+import { catchError } from 'rxjs/internal/operators';
+
+@Injectable({
+  providedIn: 'root'
+})
+
+export class AuditLogService {
+  private endpoint = environment.apiUrl;
+  constructor(private httpClient: HttpClient) { }
+
+  private handleError(error: HttpErrorResponse): any {
+    if (error.error instanceof ErrorEvent) {
+      console.error('An error occurred:', error.error.message);
+    } else {
+      console.error(
+        `Backend returned code ${error.status}, ` +
+        `body was: ${error.error}`);
+    }
+    return throwError('An error occurred.  Check if the server is running and accessible.');
+  }
+
+  private params(filter: AuditFilter): HttpParams {
+    let params = new HttpParams();
+    Object.keys(filter).forEach(key => {
+      if (filter[key] !== undefined && filter[key] !== null && filter[key] !== '') {
+        params = params.set(key, String(filter[key]));
+      }
+    });
+    return params;
+  }
+
+  query(filter: AuditFilter, page: number, pageSize: number) : Observable<any> {
+    const params = this.params(filter)
+      .set('page', String(page))
+      .set('page_size', String(pageSize));
+    return this.httpClient.get<AuditPage>(this.endpoint + 'audit-log', { params }).pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  export(filter: AuditFilter, format: string) : Observable<any> {
+    const params = this.params(filter).set('format', format);
+    return this.httpClient.get(this.endpoint + 'audit-log/export', { params, responseType: 'blob' }).pipe(
+      catchError(this.handleError)
+    );
+  }
+}
@@ -6,9 +6,9 @@ import { Injectable } from "@angular/core";
 import { LocalStoreService } from "../local-store.service";
 import { HttpClient } from "@angular/common/http";
 import { Router, ActivatedRoute } from "@angular/router";
-import { map, catchError } from "rxjs/operators";
-import { User } from "../../models/user.model";
-import { of, BehaviorSubject, throwError } from "rxjs";
+import { map, catchError, finalize, shareReplay } from "rxjs/operators";
+import { User, UserPreferences } from "../../models/user.model";
+import { of, BehaviorSubject, throwError, Observable } from "rxjs";
 import { environment } from "../../../../environments/environment";
 import jwt_decode from 'jwt-decode';
 
@@ -23,7 +23,9 @@ export class JwtAuthService {
   signingIn: Boolean;
   return: string;
   JWT_TOKEN = "JWT_TOKEN";
+  REFRESH_TOKEN = "REFRESH_TOKEN";
   APP_USER = "HMI_USER";
+  private refreshing$: Observable<any>;
 
   constructor(
     private ls: LocalStoreService,
@@ -41,8 +43,13 @@ export class JwtAuthService {
     return this.http.post(`${environment.apiUrl}login`, {"username": username, "pwd": password})
       .pipe(
         map((res: any) => {
-          this.setUserAndToken(res.token, res.user, !!res);
           this.signingIn = false;
+          // Accounts with two-factor authentication get the tokens after the code is verified
+          if (res.two_factor_token) {
+            return res;
+          }
+          this.setUserAndToken(res.token, res.user, !!res);
+          this.ls.setItem(this.REFRESH_TOKEN, res.refresh_token);
           return res;
         }),
         catchError((error) => {
@@ -69,11 +76,171 @@ export class JwtAuthService {
       );
   }
 
+  /*
+    Exchanges the refresh token for a new access token.  Concurrent callers share
+    the same request since a refresh token can only be used once.
+  */
+  public refresh(): Observable<any> {
+    if (!this.refreshing$) {
+      this.refreshing$ = this.http.post(`${environment.apiUrl}refresh`, {"refresh_token": this.getRefreshToken()})
+        .pipe(
+          map((res: any) => {
+            this.setUserAndToken(res.token, res.user, true);
+            this.ls.setItem(this.REFRESH_TOKEN, res.refresh_token);
+            return res;
+          }),
+          catchError((error) => {
+            this.signout();
+            return throwError(error);
+          }),
+          finalize(() => this.refreshing$ = null),
+          shareReplay(1)
+        );
+    }
+    return this.refreshing$;
+  }
+
+  /*
+    Second step of a sign in with two-factor authentication, the code is one of the
+    authenticator app or a recovery code
+  */
+  public verifyTwoFactor(twoFactorToken: string, code: string) {
+    return this.http.post(`${environment.apiUrl}two-factor/login`, {"two_factor_token": twoFactorToken, "code": code})
+      .pipe(
+        map((res: any) => {
+          this.setUserAndToken(res.token, res.user, true);
+          this.ls.setItem(this.REFRESH_TOKEN, res.refresh_token);
+          return res;
+        })
+      );
+  }
+
+  /*
+    Generates a new authenticator secret, the response has the QR code to scan
+  */
+  public twoFactorSetup(): Observable<any> {
+    return this.http.post(`${environment.apiUrl}two-factor/setup`, {});
+  }
+
+  /*
+    Enables two-factor authentication with a code of the new secret.  The response
+    has the recovery codes and the tokens of a new session.
+  */
+  public enableTwoFactor(code: string) {
+    return this.http.post(`${environment.apiUrl}two-factor/enable`, {"code": code})
+      .pipe(
+        map((res: any) => {
+          this.setUserAndToken(res.token, res.user, true);
+          this.ls.setItem(this.REFRESH_TOKEN, res.refresh_token);
+          return res;
+        })
+      );
+  }
+
+  /*
+    Changes the display name of the signed in user, the current password is required
+  */
+  public updateProfile(displayname: string, currentPassword: string) {
+    return this.http.post(`${environment.apiUrl}update-profile`, {"displayname": displayname, "current_pwd": currentPassword})
+      .pipe(
+        map((user: User) => {
+          this.setUserAndToken(this.getJwtToken(), user, true);
+          return user;
+        })
+      );
+  }
+
+  public updatePreferences(preferences: UserPreferences) {
+    return this.http.post(`${environment.apiUrl}update-preferences`, preferences)
+      .pipe(
+        map((user: User) => {
+          this.setUserAndToken(this.getJwtToken(), user, true);
+          return user;
+        })
+      );
+  }
+
+  /*
+    Page to show after signing in, from the user's preferences
+  */
+  landingUrl(): string {
+    const preferences = this.getUser()?.preferences;
+    switch (preferences?.landing_page) {
+      case 'diagrams':
+        return '/diagrams';
+      case 'default_diagram':
+        return preferences.default_diagram ? '/hmi?id=' + preferences.default_diagram : '/';
+      default:
+        return '/';
+    }
+  }
+
+  mustEnrollTwoFactor(): Boolean {
+    const decodeToken = this.getDecodedAccessToken(this.getJwtToken());
+    return !!decodeToken?.must_enroll_two_factor;
+  }
+
+  /*
+    Changes the password of the signed in user.  The server ends all sessions of
+    the user and hands back the tokens of a new one.
+  */
+  public changePassword(currentPassword: string, newPassword: string) {
+    return this.http.post(`${environment.apiUrl}change-password`, {"current_pwd": currentPassword, "new_pwd": newPassword})
+      .pipe(
+        map((res: any) => {
+          this.setUserAndToken(res.token, res.user, true);
+          this.ls.setItem(this.REFRESH_TOKEN, res.refresh_token);
+          return res;
+        })
+      );
+  }
+
+  mustChangePassword(): Boolean {
+    return !!this.getUser()?.must_change_password;
+  }
+
+  /*
+    Information about the single sign-on provider configured on the server
+  */
+  public ssoConfig(): Observable<any> {
+    return this.http.get(`${environment.apiUrl}oidc/config`);
+  }
+
+  public ssoSignin() {
+    window.location.href = `${environment.apiUrl}oidc/login`;
+  }
+
+  /*
+    Stores the tokens handed back by the server after a single sign-on login
+  */
+  public completeSsoSignin(token: string, refreshToken: string) {
+    this.ls.setItem(this.APP_USER, null);
+    this.setUserAndToken(token, this.getUser(), true);
+    this.ls.setItem(this.REFRESH_TOKEN, refreshToken);
+  }
+
   public signout() {
+    const refreshToken = this.getRefreshToken();
+    if (refreshToken) {
+      this.http.post(`${environment.apiUrl}logout`, {"refresh_token": refreshToken})
+        .subscribe({ error: () => {} });
+    }
+    this.ls.setItem(this.REFRESH_TOKEN, null);
     this.setUserAndToken(null, null, false);
     this.router.navigateByUrl("sessions/signin");
   }
 
+  /*
+    True when the access token expires within the given number of seconds
+  */
+  isTokenExpiring(token: string, seconds: number = 60): Boolean {
+    const decodeToken = this.getDecodedAccessToken(token);
+    if (!decodeToken || !decodeToken.exp) {
+      return false;
+    }
+    return decodeToken.exp - seconds < Date.now() / 1000;
+  }
+
   isLoggedIn(): Boolean {
     return !!this.getJwtToken();
   }
@@ -81,6 +248,10 @@ export class JwtAuthService {
   getJwtToken() {
     return this.ls.getItem(this.JWT_TOKEN);
   }
+
+  getRefreshToken() {
+    return this.ls.getItem(this.REFRESH_TOKEN);
+  }
   getUser() : User {        
     var usr = this.ls.getItem(this.APP_USER);
     if (usr == null) {
@@ -0,0 +1,47 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Injectable } from '@angular/core';
+import { HttpClient, HttpErrorResponse } from '@angular/common/http'
+import { environment } from '../../../environments/environment';
+import { BundleChoice, BundleExportRequest, BundlePreview, ImportedItem } from '../models/bundle.model'
+import { Observable, throwError } from 'rxjs';
+import { catchError, map } from 'rxjs/operators';
+
+@Injectable({
+  providedIn: 'root'
+})
+
+export class BundleService {
+  private endpoint = environment.apiUrl;
+  constructor(private httpClient: HttpClient) { }
+
+  // Keeps the message of the server, e.g. why a bundle was refused
+  private handleError(error: HttpErrorResponse): any {
+    console.error(`Backend returned code ${error.status}`, error.error);
+    if (error.error?.message) {
+      return throwError(error.error.message);
+    }
+    return throwError('An error occurred.  Check if the server is running and accessible.');
+  }
+
+  export(request: BundleExportRequest) : Observable<any> {
+    return this.httpClient.post(this.endpoint + 'bundle/export', request, { responseType: 'blob' }).pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  preview(bundle: any) : Observable<any> {
+    return this.httpClient.post<BundlePreview>(this.endpoint + 'bundle/preview', bundle).pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  import(bundle: any, choices: BundleChoice[]) : Observable<any> {
+    return this.httpClient.post<any>(this.endpoint + 'bundle/import', { bundle, choices }).pipe(
+      map(result => result.items as ImportedItem[]),
+      catchError(this.handleError)
+    );
+  }
+}
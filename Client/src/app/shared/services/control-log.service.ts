@@ -0,0 +1,44 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Injectable } from '@angular/core';
+import { HttpClient, HttpErrorResponse, HttpParams } from '@angular/common/http'
+import { environment } from '../../../environments/environment';
+import { ControlLogFilter, ControlLogPage } from '../models/control-log.model'
+import { Observable, throwError } from 'rxjs';
+import { catchError } from 'rxjs/internal/operators';
+
+@Injectable({
+  providedIn: 'root'
+})
+
+export class ControlLogService {
+  private endpoint = environment.apiUrl;
+  constructor(private httpClient: HttpClient) { }
+
+  private handleError(error: HttpErrorResponse): any {
+    if (error.error instanceof ErrorEvent) {
+      console.error('An error occurred:', error.error.message);
+    } else {
+      console.error(
+        `Backend returned code ${error.status}, ` +
+        `body was: ${error.error}`);
+    }
+    return throwError('An error occurred.  Check if the server is running and accessible.');
+  }
+
+  query(filter: ControlLogFilter, page: number, pageSize: number) : Observable<any> {
+    let params = new HttpParams()
+      .set('page', String(page))
+      .set('page_size', String(pageSize));
+    Object.keys(filter).forEach(key => {
+      if (filter[key] !== undefined && filter[key] !== null && filter[key] !== '') {
+        params = params.set(key, String(filter[key]));
+      }
+    });
+    return this.httpClient.get<ControlLogPage>(this.endpoint + 'control-log', { params }).pipe(
+      catchError(this.handleError)
+    );
+  }
+}
@@ -0,0 +1,55 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Injectable } from '@angular/core';
+import { Observable } from 'rxjs';
+import { filter, map } from 'rxjs/operators';
+import { WebSocketService } from '../../core/services/web-socket.service';
+import { EditEvent, ElementOperation } from '../models/collaboration.model';
+
+// Edit sessions of designers working on the same diagram, over the data websocket
+@Injectable({
+  providedIn: 'root'
+})
+export class DiagramCollaborationService {
+
+  constructor(private wsService: WebSocketService) { }
+
+  // Events of the edit session of the diagram
+  events(diagramId: string): Observable<EditEvent> {
+    return this.wsService.wsMessages$.pipe(
+      map(message => <any>message as EditEvent),
+      filter(message => !!message && !!message.type && message.diagram_id === diagramId)
+    );
+  }
+
+  join(diagramId: string) {
+    this.wsService.sendWsData({ type: 'join', diagram_id: diagramId });
+  }
+
+  leave(diagramId: string) {
+    this.wsService.sendWsData({ type: 'leave', diagram_id: diagramId });
+  }
+
+  select(diagramId: string, elementIds: string[]) {
+    this.wsService.sendWsData({ type: 'select', diagram_id: diagramId, element_ids: elementIds });
+  }
+
+  lock(diagramId: string, elementId: string) {
+    this.wsService.sendWsData({ type: 'lock', diagram_id: diagramId, element_id: elementId });
+  }
+
+  unlock(diagramId: string, elementId: string) {
+    this.wsService.sendWsData({ type: 'unlock', diagram_id: diagramId, element_id: elementId });
+  }
+
+  sendOperation(diagramId: string, operation: ElementOperation) {
+    this.wsService.sendWsData({ type: 'operation', diagram_id: diagramId, operation });
+  }
+
+  // Saves the merged diagram, force saves over revisions saved by others meanwhile
+  save(diagramId: string, force: boolean = false) {
+    this.wsService.sendWsData({ type: 'save', diagram_id: diagramId, force });
+  }
+}
@@ -4,13 +4,17 @@
 
 import { Injectable } from '@angular/core';
 import { environment } from '../../../environments/environment';
-import { Diagram } from '../models/diagram.model'
+import { Diagram, DiagramFilter, DiagramPage } from '../models/diagram.model'
+import { DiagramDiff, DiagramRevision, DiagramRevisionContent } from '../models/diagram-revision.model';
+import { DiagramValidationReport } from '../models/diagram-validation.model';
+import { DiagramTemplate, InstantiateDiagramTemplateRequest, SaveDiagramTemplateRequest } from '../models/diagram-template.model';
 import { Equipment } from '../models/equipment.model';
 import { Command } from '../models/command.model';
 import { UpdateData } from '../models/topic.model'
 import { catchError } from 'rxjs/internal/operators';
-import { HttpClient, HttpErrorResponse } from '@angular/common/http';
-import { Observable, throwError } from 'rxjs';
+import { HttpClient, HttpErrorResponse, HttpHeaders, HttpParams, HttpResponse } from '@angular/common/http';
+import { EMPTY, Observable, throwError } from 'rxjs';
+import { expand, map, reduce } from 'rxjs/operators';
 
 
 @Injectable({
@@ -33,27 +37,105 @@ export class DiagramsService {
     return throwError('An error occurred.  Check if the server is running and accessible.');    
   }
 
+  // If-Match header for a record read at the version, a record without version
+  // has never been saved
+  private ifMatch(version?: number): HttpHeaders {
+    let headers = new HttpHeaders();
+    if (version !== undefined && version !== null) {
+      headers = headers.set('If-Match', '"' + version + '"');
+    }
+    return headers;
+  }
+
+  // Version from the ETag header of the response
+  private version(response: HttpResponse<any>): number | undefined {
+    const etag = response.headers.get('ETag');
+    if (!etag) {
+      return undefined;
+    }
+    const version = parseInt(etag.replace(/^W\//, '').replace(/"/g, ''), 10);
+    return isNaN(version) ? undefined : version;
+  }
+
+  // A stale write is answered with 409, the body holds the current version and record
+  isVersionConflict(error: any): boolean {
+    return error instanceof HttpErrorResponse && error.status === 409 && error.error?.code === 'version_conflict';
+  }
+
+  // A diagram with validation errors is refused with 422 when the server blocks invalid
+  // saves, the body holds the problems found
+  isInvalidDiagram(error: any): boolean {
+    return error instanceof HttpErrorResponse && error.status === 422 && error.error?.code === 'invalid_diagram';
+  }
+
   private extractData(res: Response): any {
     const body = res;
     return body || { };
   }
 
-  getAll() : Observable<any> {  
-    
-    return this.httpClient.get<Diagram>(this.endpoint + 'get-diagrams').pipe(
+  // Metadata of all diagrams, for choosing one, read with the largest page the server allows
+  getAll() : Observable<any> {
+    const pageSize = 500;
+    return this.query({}, 0, pageSize).pipe(
+      expand((page: DiagramPage) => (page.page + 1) * pageSize < page.total
+        ? this.query({}, page.page + 1, pageSize)
+        : EMPTY),
+      reduce((diagrams: any[], page: DiagramPage) => diagrams.concat(page.diagrams), [])
+    );
+  }
+
+  // Metadata of the diagrams matching the filter, a page of them when a page size is given
+  query(filter: DiagramFilter, page: number = 0, pageSize?: number) : Observable<any> {
+    let params = new HttpParams();
+    Object.keys(filter).forEach(key => {
+      if (filter[key]) {
+        params = params.set(key, String(filter[key]));
+      }
+    });
+    if (pageSize) {
+      params = params.set('page', String(page)).set('page_size', String(pageSize));
+    }
+    return this.httpClient.get<DiagramPage>(this.endpoint + 'get-diagrams', { params }).pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  // The published diagram, or the latest draft for editing
+  get(id: string, draft: boolean = false) : any {    
+    let params = new HttpParams().set('id', id);
+    if (draft) {
+      params = params.set('draft', 'true');
+    }
+    return this.httpClient.get<Diagram>(this.endpoint + 'get-diagram', { params }).pipe(
       catchError(this.handleError)
     );
   }
 
-  get(id: string) : any {    
-    return this.httpClient.get<Diagram>(this.endpoint + 'get-diagram?id='+id).pipe(
+  // Problems of the diagram as drawn, e.g. elements of equipment that doesn't exist
+  validate(diagram: Diagram) : Observable<any> {
+    return this.httpClient.post<DiagramValidationReport>(this.endpoint + 'validate-diagram', diagram).pipe(
       catchError(this.handleError)
     );
   }
 
+  // Makes the revision, by default the latest draft, the one shown on the HMI.
+  // A draft that doesn't pass validation is refused with 400 and the problems found.
+  publish(id: string, revision?: number) : Observable<any> {
+    return this.httpClient.post<any>(this.endpoint + 'publish-diagram', { id, revision });
+  }
+
+  // Saves the diagram if nobody saved it since it was read, the diagram gets the new version
   update(diagram: Diagram) : Observable<any> {    
     console.log("Updating diagram!");        
-    return this.httpClient.post<Diagram>(this.endpoint + 'save-diagram', diagram);
+    return this.httpClient.post<any>(this.endpoint + 'save-diagram', diagram, {
+      headers: this.ifMatch(diagram.version),
+      observe: 'response'
+    }).pipe(
+      map(response => {
+        diagram.version = this.version(response);
+        return response.body;
+      })
+    );
   }
 
   delete(id: string) : any {
@@ -64,11 +146,77 @@ export class DiagramsService {
   }
 
   create(diagram: Diagram) {
-    return this.httpClient.post<Diagram>(this.endpoint + 'save-diagram', diagram).pipe(
+    return this.httpClient.post<any>(this.endpoint + 'save-diagram', diagram).pipe(
       catchError(this.handleError)
     );  
   }
 
+  getRevisions(id: string) : Observable<any> {
+    const params = new HttpParams().set('id', id);
+    return this.httpClient.get<DiagramRevision[]>(this.endpoint + 'diagram-revisions', { params }).pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  getRevision(id: string, revision: number) : Observable<any> {
+    const params = new HttpParams().set('id', id).set('revision', String(revision));
+    return this.httpClient.get<DiagramRevisionContent>(this.endpoint + 'diagram-revision', { params }).pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  // Compares two revisions, "to" defaults to the latest one
+  diff(id: string, from: number, to?: number) : Observable<any> {
+    let params = new HttpParams().set('id', id).set('from', String(from));
+    if (to) {
+      params = params.set('to', String(to));
+    }
+    return this.httpClient.get<DiagramDiff>(this.endpoint + 'diagram-diff', { params }).pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  // Version is the latest revision the history was read at, undefined for a deleted diagram
+  revert(id: string, revision: number, version?: number) : Observable<any> {
+    const headers = this.ifMatch(version);
+    return this.httpClient.post<DiagramRevisionContent>(this.endpoint + 'revert-diagram', { id, revision }, { headers }).pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  // Image of the published diagram drawn by the server, with the latest values and
+  // switchgear positions when live is set. Width scales the image, e.g. for thumbnails.
+  render(id: string, format: 'svg' | 'png' = 'svg', live: boolean = false, width?: number) : Observable<Blob> {
+    let params = new HttpParams().set('id', id).set('format', format).set('live', String(live));
+    if (width) {
+      params = params.set('width', String(width));
+    }
+    return this.httpClient.get(this.endpoint + 'render-diagram', { params, responseType: 'blob' }).pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  getTemplates() : Observable<any> {
+    return this.httpClient.get<DiagramTemplate[]>(this.endpoint + 'diagram-templates').pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  // Saves the latest draft of the diagram as a template, its mRIDs become placeholders
+  saveTemplate(request: SaveDiagramTemplateRequest) : Observable<any> {
+    return this.httpClient.post<DiagramTemplate>(this.endpoint + 'diagram-templates/save', request);
+  }
+
+  deleteTemplate(id: string) : Observable<any> {
+    return this.httpClient.post<DiagramTemplate>(this.endpoint + 'diagram-templates/delete', { id });
+  }
+
+  // Makes a new draft from the template. A mapping with equipment that doesn't exist or
+  // doesn't have the device type of its placeholder is refused with 422 and the problems found.
+  instantiateTemplate(request: InstantiateDiagramTemplateRequest) : Observable<any> {
+    return this.httpClient.post<Diagram>(this.endpoint + 'diagram-templates/instantiate', request);
+  }
+
   updateData(data: UpdateData) {
     return this.httpClient.post<UpdateData>(this.endpoint + 'update-data', data).pipe(
       catchError(this.handleError)
@@ -88,9 +236,9 @@ export class DiagramsService {
   }
 
   updateEquipment(eq: Equipment) : Observable<any> {    
-    return this.httpClient.post<Equipment>(this.endpoint + 'update-equipment', eq).pipe(
-      catchError(this.handleError)
-    );  
+    return this.httpClient.post<Equipment>(this.endpoint + 'update-equipment', eq, {
+      headers: this.ifMatch(eq.version)
+    });  
   }
 
   deleteEquipment(id: string) : Observable<any>  {
@@ -61,7 +61,11 @@ export class NavigationService {
       visible: Authorization.canUpdateSettings(this.userRole),
       sub: [        
         { name: "Users", state: "users" },
-        { name: "Devices", state: "devices" }              
+        { name: "Devices", state: "devices" },
+        { name: "API Keys", state: "api-keys" },
+        { name: "Audit Log", state: "audit-log" },
+        { name: "Control Log", state: "control-log" },
+        { name: "Bundles", state: "bundles" }
       ]
     }
   ]
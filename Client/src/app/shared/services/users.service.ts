@@ -24,6 +24,10 @@ export class UserService {
       console.error(
         `Backend returned code ${error.status}, ` +
         `body was: ${error.error}`);
+      // The server explains rejected requests, e.g. a password not matching the policy
+      if (error.error?.message) {
+        return throwError(error.error.message);
+      }
     }
     return throwError('An error occurred.  Check if the server is running and accessible.');    
   }
@@ -51,6 +55,18 @@ export class UserService {
     return this.httpClient.post<User>(this.endpoint + 'delete-user', user);
   }
 
+  unlock(user: User) : Observable<any> {
+    return this.httpClient.post<User>(this.endpoint + 'unlock-user', user).pipe(
+      catchError(this.handleError)
+    );
+  }
+
+  resetTwoFactor(user: User) : Observable<any> {
+    return this.httpClient.post<User>(this.endpoint + 'reset-two-factor', user).pipe(
+      catchError(this.handleError)
+    );
+  }
+
   create(user: User) : Observable<any> {
     return this.httpClient.post<User>(this.endpoint + 'create-user', user).pipe(
       catchError(this.handleError)
@@ -0,0 +1,69 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Component, OnInit, ViewChild } from '@angular/core';
+import { Router } from '@angular/router';
+import { MatButton } from '@angular/material/button';
+import { MatProgressBar } from '@angular/material/progress-bar';
+import { Validators, UntypedFormGroup, UntypedFormControl, AbstractControl, ValidationErrors } from '@angular/forms';
+import { JwtAuthService } from '../../../shared/services/auth/jwt-auth.service';
+
+@Component({
+  selector: 'app-change-password',
+  templateUrl: './change-password.component.html',
+  styleUrls: ['./change-password.component.scss']
+})
+export class ChangePasswordComponent implements OnInit {
+  @ViewChild(MatProgressBar) progressBar: MatProgressBar;
+  @ViewChild(MatButton) submitButton: MatButton;
+
+  passwordForm: UntypedFormGroup;
+  errorMsg = '';
+  required = false;
+
+  constructor(
+    private jwtAuth: JwtAuthService,
+    private router: Router
+  ) { }
+
+  ngOnInit() {
+    if (!this.jwtAuth.isLoggedIn()) {
+      this.router.navigateByUrl('/sessions/signin');
+      return;
+    }
+    this.required = !!this.jwtAuth.mustChangePassword();
+
+    this.passwordForm = new UntypedFormGroup({
+      currentPassword: new UntypedFormControl('', Validators.required),
+      newPassword: new UntypedFormControl('', Validators.required),
+      confirmPassword: new UntypedFormControl('', Validators.required)
+    }, { validators: this.passwordsMatch });
+  }
+
+  passwordsMatch(group: AbstractControl): ValidationErrors | null {
+    return group.get('newPassword').value === group.get('confirmPassword').value ? null : { mismatch: true };
+  }
+
+  submit() {
+    const data = this.passwordForm.value;
+
+    this.submitButton.disabled = true;
+    this.progressBar.mode = 'indeterminate';
+    this.errorMsg = '';
+
+    this.jwtAuth.changePassword(data.currentPassword, data.newPassword)
+    .subscribe(response => {
+      this.router.navigateByUrl(this.jwtAuth.return || '/');
+    }, err => {
+      this.submitButton.disabled = false;
+      this.progressBar.mode = 'determinate';
+      this.errorMsg = "Unable to change the password. Check the current password and that the new one meets the password policy.";
+      console.log(err);
+    })
+  }
+
+  signout() {
+    this.jwtAuth.signout();
+  }
+}
@@ -11,6 +11,8 @@ import { SharedMaterialModule } from '../../../app/shared/shared-material.module
 
 import { FlexLayoutModule } from '@angular/flex-layout';
 import { SigninComponent } from './signin/signin.component';
+import { ChangePasswordComponent } from './change-password/change-password.component';
+import { TwoFactorSetupComponent } from './two-factor-setup/two-factor-setup.component';
 import { SessionsRoutes } from "./sessions.routing";
 import { NotFoundComponent } from './not-found/not-found.component';
 import { ErrorComponent } from './error/error.component';
@@ -25,6 +27,6 @@ import { ErrorComponent } from './error/error.component';
     PerfectScrollbarModule,
     RouterModule.forChild(SessionsRoutes)
   ],
-  declarations: [SigninComponent, NotFoundComponent, ErrorComponent]
+  declarations: [SigninComponent, ChangePasswordComponent, TwoFactorSetupComponent, NotFoundComponent, ErrorComponent]
 })
 export class SessionsModule { }
\ No newline at end of file
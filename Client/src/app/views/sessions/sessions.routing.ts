@@ -5,6 +5,8 @@
 import { Routes } from "@angular/router";
 
 import { SigninComponent } from "./signin/signin.component";
+import { ChangePasswordComponent } from "./change-password/change-password.component";
+import { TwoFactorSetupComponent } from "./two-factor-setup/two-factor-setup.component";
 import { NotFoundComponent } from "./not-found/not-found.component";
 import { ErrorComponent } from "./error/error.component";
 
@@ -16,6 +18,16 @@ export const SessionsRoutes: Routes = [
         path: "signin",
         component: SigninComponent,
         data: { title: "Signin" }
+      },
+      {
+        path: "change-password",
+        component: ChangePasswordComponent,
+        data: { title: "Change Password" }
+      },
+      {
+        path: "two-factor-setup",
+        component: TwoFactorSetupComponent,
+        data: { title: "Two-Factor Authentication" }
       },             
       {
         path: "404",
@@ -21,7 +21,10 @@ export class SigninComponent implements OnInit, AfterViewInit, OnDestroy {
   @ViewChild(MatButton) submitButton: MatButton;
 
   signinForm: UntypedFormGroup;
+  codeForm: UntypedFormGroup;
+  twoFactorToken: string = null;
   errorMsg = '';
+  ssoName: string = null;
   
   private _unsubscribeAll: Subject<any>;
 
@@ -39,6 +42,37 @@ export class SigninComponent implements OnInit, AfterViewInit, OnDestroy {
       username: new UntypedFormControl('', Validators.required),
       password: new UntypedFormControl('', Validators.required)      
     });
+
+    this.codeForm = new UntypedFormGroup({
+      code: new UntypedFormControl('', Validators.required)
+    });
+
+    this.jwtAuth.ssoConfig().subscribe(
+      (config: any) => this.ssoName = config.enabled ? config.display_name : null,
+      () => this.ssoName = null
+    );
+
+    this.handleSsoResult();
+  }
+
+  /*
+    The server redirects back here after a single sign-on login with either the
+    tokens or an error in the URL fragment
+  */
+  handleSsoResult() {
+    const params = new URLSearchParams(window.location.hash.substring(1));
+    history.replaceState(null, '', window.location.pathname);
+
+    if (params.has('token') && params.has('refresh_token')) {
+      this.jwtAuth.completeSsoSignin(params.get('token'), params.get('refresh_token'));
+      this.router.navigateByUrl('/');
+    } else if (params.has('error')) {
+      this.errorMsg = "Single sign-on failed";
+    }
+  }
+
+  ssoSignin() {
+    this.jwtAuth.ssoSignin();
   }
 
   ngAfterViewInit() {
@@ -60,15 +94,60 @@ export class SigninComponent implements OnInit, AfterViewInit, OnDestroy {
     
     this.jwtAuth.signin(signinData.username, signinData.password)
     .subscribe(response => {
-      this.router.navigateByUrl(this.jwtAuth.return);
+      if (response.two_factor_token) {
+        this.twoFactorToken = response.two_factor_token;
+        this.progressBar.mode = 'determinate';
+        return;
+      }
+      this.signedIn(response);
     }, err => {
       this.submitButton.disabled = false;
       this.progressBar.mode = 'determinate';
-      this.errorMsg = "Invalid username or password";
+      // Locked accounts and throttled logins are explained by the server
+      this.errorMsg = (err.status === 423 || err.status === 429) && err.error?.message
+        ? err.error.message
+        : "Invalid username or password";
+      console.log(err);
+    })
+  }
+
+  verifyCode() {
+    this.progressBar.mode = 'indeterminate';
+    this.errorMsg = '';
+
+    this.jwtAuth.verifyTwoFactor(this.twoFactorToken, this.codeForm.value.code)
+    .subscribe(response => {
+      this.signedIn(response);
+    }, err => {
+      this.progressBar.mode = 'determinate';
+      this.codeForm.reset();
+      this.errorMsg = "Invalid code";
       console.log(err);
     })
   }
 
+  cancelTwoFactor() {
+    this.twoFactorToken = null;
+    this.codeForm.reset();
+    this.signinForm.reset();
+    this.errorMsg = '';
+    if (this.submitButton) {
+      this.submitButton.disabled = false;
+    }
+  }
+
+  private signedIn(response: any) {
+    if (response.user?.must_change_password) {
+      this.router.navigateByUrl('/sessions/change-password');
+      return;
+    }
+    if (this.jwtAuth.return === '/') {
+      this.router.navigateByUrl(this.jwtAuth.landingUrl());
+      return;
+    }
+    this.router.navigateByUrl(this.jwtAuth.return);
+  }
+
   autoSignIn() {    
     if(this.jwtAuth.return === '/') {
       return
@@ -0,0 +1,75 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+import { Component, OnInit, ViewChild } from '@angular/core';
+import { Router } from '@angular/router';
+import { MatProgressBar } from '@angular/material/progress-bar';
+import { Validators, UntypedFormGroup, UntypedFormControl } from '@angular/forms';
+import { JwtAuthService } from '../../../shared/services/auth/jwt-auth.service';
+
+@Component({
+  selector: 'app-two-factor-setup',
+  templateUrl: './two-factor-setup.component.html',
+  styleUrls: ['./two-factor-setup.component.scss']
+})
+export class TwoFactorSetupComponent implements OnInit {
+  @ViewChild(MatProgressBar) progressBar: MatProgressBar;
+
+  codeForm: UntypedFormGroup;
+  qrImage: string = null;
+  secret: string = null;
+  recoveryCodes: string[] = null;
+  errorMsg = '';
+
+  constructor(
+    private jwtAuth: JwtAuthService,
+    private router: Router
+  ) { }
+
+  ngOnInit() {
+    if (!this.jwtAuth.isLoggedIn()) {
+      this.router.navigateByUrl('/sessions/signin');
+      return;
+    }
+
+    this.codeForm = new UntypedFormGroup({
+      code: new UntypedFormControl('', Validators.required)
+    });
+
+    this.jwtAuth.twoFactorSetup().subscribe(
+      (enrollment: any) => {
+        this.secret = enrollment.secret;
+        this.qrImage = 'data:image/svg+xml;base64,' + btoa(enrollment.qr_svg);
+      },
+      err => {
+        this.errorMsg = "Unable to set up two-factor authentication";
+        console.log(err);
+      }
+    );
+  }
+
+  submit() {
+    this.progressBar.mode = 'indeterminate';
+    this.errorMsg = '';
+
+    this.jwtAuth.enableTwoFactor(this.codeForm.value.code)
+    .subscribe((response: any) => {
+      this.progressBar.mode = 'determinate';
+      this.recoveryCodes = response.recovery_codes;
+    }, err => {
+      this.progressBar.mode = 'determinate';
+      this.codeForm.reset();
+      this.errorMsg = "Invalid code";
+      console.log(err);
+    })
+  }
+
+  done() {
+    this.router.navigateByUrl(this.jwtAuth.return || '/');
+  }
+
+  signout() {
+    this.jwtAuth.signout();
+  }
+}
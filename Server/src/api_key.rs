@@ -0,0 +1,290 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::audit::record_audit;
+use crate::auth::{Permission, Role};
+use crate::error::Error;
+use crate::storage::storage;
+use chrono::prelude::*;
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use subtle::ConstantTimeEq;
+use warp::{reject, reply::json, Rejection, Reply};
+
+lazy_static! {
+    /// Keys of the database, every change is written to it before it is made here
+    static ref API_KEYS: RwLock<HashMap<String, ApiKey>> = RwLock::new(load_api_keys());
+}
+
+/// Prefix of the subject an API key authenticates as, to tell it apart from user ids
+pub const API_KEY_SUBJECT_PREFIX: &str = "api-key:";
+
+/// Seconds between writes of the last used time of a key
+const LAST_USED_RESOLUTION: i64 = 60;
+
+/// A key for scripts and other machine clients, sent in the `X-API-Key` header.
+///
+/// The key is handed out once as `<id>.<secret>`, only a SHA-256 hash of the secret is
+/// kept. A key grants its own set of permissions instead of a user's role.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    key_hash: String,
+    pub permissions: Vec<Permission>,
+    /// Equipment groups and MRIDs the key may control, `None` or `*` for everything
+    #[serde(default)]
+    pub control_scope: Option<Vec<String>>,
+    pub created: i64,
+    pub created_by: String,
+    #[serde(default)]
+    pub expires: Option<i64>,
+    #[serde(default)]
+    pub last_used: Option<i64>,
+}
+
+impl ApiKey {
+    fn is_expired(&self) -> bool {
+        self.expires
+            .map_or(false, |expires| expires <= Utc::now().timestamp())
+    }
+
+    /// Copy of the key without the hash, to be sent to a client
+    fn public(&self) -> ApiKey {
+        let mut key = self.clone();
+        key.key_hash.clear();
+        key
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyRequest {
+    pub name: String,
+    /// Grants the permissions of the role, used when no permissions are given
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub permissions: Option<Vec<Permission>>,
+    #[serde(default)]
+    pub control_scope: Option<Vec<String>>,
+    /// Days until the key expires, `None` for a key that doesn't expire
+    #[serde(default)]
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteApiKeyRequest {
+    pub id: String,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyCreated {
+    /// The key itself, it can't be retrieved again
+    pub key: String,
+    pub api_key: ApiKey,
+}
+
+/// Checks the key and that it grants the permission, returns the subject of the key
+pub fn authorize_api_key(key: &str, permission: Permission) -> Result<String, Error> {
+    let (id, secret) = split_key(key)?;
+    let presented_hash = hash_secret(secret);
+
+    // The id part of the key names the entry, only that entry's hash is compared
+    let now = Utc::now().timestamp();
+    let stale = {
+        let keys = API_KEYS.read().unwrap();
+        let api_key = match keys.get(id) {
+            Some(k) if !k.is_expired() && hash_matches(&presented_hash, &k.key_hash) => k,
+            _ => return Err(Error::InvalidApiKeyError),
+        };
+
+        if !api_key.permissions.contains(&permission) {
+            return Err(Error::NoPermissionError);
+        }
+        is_stale(api_key.last_used, now)
+    };
+    if stale {
+        record_use(id, now);
+    }
+
+    Ok(format!("{}{}", API_KEY_SUBJECT_PREFIX, id))
+}
+
+/// True when the recorded last use is older than `LAST_USED_RESOLUTION`
+fn is_stale(last_used: Option<i64>, now: i64) -> bool {
+    last_used.map_or(true, |last| now - last >= LAST_USED_RESOLUTION)
+}
+
+/// Records the use of the key, unless a concurrent request just did
+fn record_use(id: &str, now: i64) {
+    {
+        let mut keys = API_KEYS.write().unwrap();
+        match keys.get_mut(id) {
+            Some(k) if is_stale(k.last_used, now) => k.last_used = Some(now),
+            _ => return,
+        }
+    }
+    if let Err(e) = storage().set_api_key_last_used(id, now) {
+        error!("Unable to record the use of API key {}: {}", id, e);
+    }
+}
+
+pub fn get_api_key(id: &str) -> Option<ApiKey> {
+    API_KEYS.read().unwrap().get(id).map(|k| k.public())
+}
+
+fn split_key(key: &str) -> Result<(&str, &str), Error> {
+    let mut parts = key.trim().splitn(2, '.');
+    match (parts.next(), parts.next()) {
+        (Some(id), Some(secret)) if !id.is_empty() && !secret.is_empty() => Ok((id, secret)),
+        _ => Err(Error::InvalidApiKeyError),
+    }
+}
+
+/// The secret is random, a fast hash is enough to keep it from being read from storage
+fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// Compares in constant time, so the time taken doesn't tell how much of the hash matched
+fn hash_matches(presented: &str, stored: &str) -> bool {
+    presented.as_bytes().ct_eq(stored.as_bytes()).into()
+}
+
+fn api_key_list(keys: &HashMap<String, ApiKey>) -> Vec<ApiKey> {
+    let mut list: Vec<ApiKey> = keys.values().map(|k| k.public()).collect();
+    list.sort_by_key(|k| k.created);
+    list
+}
+
+pub async fn get_api_keys_handler(_id: String) -> std::result::Result<impl Reply, Rejection> {
+    Ok(json(&api_key_list(&API_KEYS.read().unwrap())))
+}
+
+pub async fn create_api_key_handler(
+    id: String,
+    request: ApiKeyRequest,
+) -> std::result::Result<impl Reply, Rejection> {
+    if request.name.trim().is_empty() {
+        return Err(reject::custom(Error::AddApiKeyError));
+    }
+
+    let permissions = match (request.permissions, request.role) {
+        (Some(permissions), _) => permissions,
+        (None, Some(role)) => Role::from_str(&role).permissions().to_vec(),
+        (None, None) => return Err(reject::custom(Error::AddApiKeyError)),
+    };
+
+    let now = Utc::now();
+    let secret: String = (0..2)
+        .map(|_| uuid::Uuid::new_v4().simple().to_string())
+        .collect();
+    let api_key = ApiKey {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        name: request.name.trim().to_string(),
+        key_hash: hash_secret(&secret),
+        permissions,
+        control_scope: request.control_scope,
+        created: now.timestamp(),
+        created_by: id.clone(),
+        expires: request
+            .expires_in_days
+            .filter(|days| *days > 0)
+            .map(|days| (now + chrono::Duration::days(days)).timestamp()),
+        last_used: None,
+    };
+    let key = format!("{}.{}", api_key.id, secret);
+
+    storage()
+        .insert_api_key(&api_key)
+        .map_err(|e| reject::custom(e))?;
+    API_KEYS
+        .write()
+        .unwrap()
+        .insert(api_key.id.clone(), api_key.clone());
+    info!(
+        "API key {} ({}) created by {}",
+        api_key.id, api_key.name, id
+    );
+    record_audit(
+        &id,
+        "api_key.create",
+        &api_key.id,
+        None,
+        Some(&api_key.public()),
+    );
+
+    Ok(json(&ApiKeyCreated {
+        key,
+        api_key: api_key.public(),
+    }))
+}
+
+pub async fn delete_api_key_handler(
+    id: String,
+    request: DeleteApiKeyRequest,
+) -> std::result::Result<impl Reply, Rejection> {
+    let deleted = storage()
+        .delete_api_key(&request.id)
+        .map_err(|e| reject::custom(e))?;
+    API_KEYS.write().unwrap().remove(&request.id);
+    if let Some(api_key) = deleted {
+        info!(
+            "API key {} ({}) deleted by {}",
+            api_key.id, api_key.name, id
+        );
+        record_audit(
+            &id,
+            "api_key.delete",
+            &api_key.id,
+            Some(&api_key.public()),
+            None,
+        );
+    }
+    Ok(json(&api_key_list(&API_KEYS.read().unwrap())))
+}
+
+fn load_api_keys() -> HashMap<String, ApiKey> {
+    match storage().api_keys() {
+        Ok(keys) => keys.into_iter().map(|k| (k.id.clone(), k)).collect(),
+        Err(e) => {
+            error!("Unable to read API keys: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_is_split_into_id_and_secret() {
+        assert_eq!(split_key(" id.secret\n").unwrap(), ("id", "secret"));
+        assert!(split_key("id").is_err());
+        assert!(split_key(".secret").is_err());
+        assert!(split_key("id.").is_err());
+    }
+
+    #[test]
+    fn use_is_recorded_once_per_resolution() {
+        assert!(is_stale(None, 1000));
+        assert!(!is_stale(Some(1000), 1000 + LAST_USED_RESOLUTION - 1));
+        assert!(is_stale(Some(1000), 1000 + LAST_USED_RESOLUTION));
+    }
+
+    #[test]
+    fn only_the_secret_of_the_key_matches() {
+        let stored = hash_secret("secret");
+        assert!(hash_matches(&hash_secret("secret"), &stored));
+        assert!(!hash_matches(&hash_secret("secreT"), &stored));
+        // Keys listed to clients have no hash
+        assert!(!hash_matches(&hash_secret("secret"), ""));
+    }
+}
@@ -0,0 +1,239 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::Error;
+use crate::storage::storage;
+use chrono::prelude::*;
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use warp::{
+    http::header::CONTENT_DISPOSITION, http::header::CONTENT_TYPE, reject, reply, Rejection, Reply,
+};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// A change made by an administrator, kept in the `audit_log` table of the database.
+///
+/// Entries are only ever added, the server never changes or removes them.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: String,
+    /// Unix timestamp in seconds
+    pub timestamp: i64,
+    /// Id of the acting user, or `api-key:<id>` for an API key
+    pub actor: String,
+    /// What was done, e.g. `user.create` or `diagram.delete`
+    pub action: String,
+    /// Id of the changed user, equipment, diagram or API key
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<Value>,
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    /// Zero based page number
+    #[serde(default)]
+    pub page: usize,
+    #[serde(default)]
+    pub page_size: Option<usize>,
+    #[serde(default)]
+    pub actor: Option<String>,
+    /// Matches the action itself or, ending with a dot, all actions starting with it
+    #[serde(default)]
+    pub action: Option<String>,
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Unix timestamp in seconds of the oldest entry
+    #[serde(default)]
+    pub from: Option<i64>,
+    /// Unix timestamp in seconds of the newest entry
+    #[serde(default)]
+    pub to: Option<i64>,
+    /// `json` (default) or `csv`, only used for exports
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AuditPage {
+    pub total: usize,
+    pub page: usize,
+    pub page_size: usize,
+    pub entries: Vec<AuditEntry>,
+}
+
+/// Adds an entry to the audit log, `before` and `after` are the state of the target
+/// around the change, `None` when it didn't exist
+pub fn record_audit<T: Serialize>(
+    actor: &str,
+    action: &str,
+    target: &str,
+    before: Option<&T>,
+    after: Option<&T>,
+) {
+    let entry = AuditEntry {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        timestamp: Utc::now().timestamp(),
+        actor: actor.to_string(),
+        action: action.to_string(),
+        target: target.to_string(),
+        before: before.and_then(|b| serde_json::to_value(b).ok()),
+        after: after.and_then(|a| serde_json::to_value(a).ok()),
+    };
+    if let Err(e) = storage().insert_audit_entry(&entry) {
+        error!(
+            "Unable to write audit entry {} on {} by {}: {}",
+            entry.action, entry.target, entry.actor, e
+        );
+    }
+}
+
+// GET
+pub async fn audit_log_handler(
+    _id: String,
+    query: AuditQuery,
+) -> std::result::Result<impl Reply, Rejection> {
+    let page_size = query
+        .page_size
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .max(1)
+        .min(MAX_PAGE_SIZE);
+    let (total, entries) = storage()
+        .audit_entries(&query, Some((query.page, page_size)))
+        .map_err(|e| reject::custom(e))?;
+
+    Ok(reply::json(&AuditPage {
+        total,
+        page: query.page,
+        page_size,
+        entries,
+    }))
+}
+
+// GET
+pub async fn export_audit_log_handler(
+    id: String,
+    query: AuditQuery,
+) -> std::result::Result<impl Reply, Rejection> {
+    let (_, entries) = storage()
+        .audit_entries(&query, None)
+        .map_err(|e| reject::custom(e))?;
+    let csv = query.format.as_deref() == Some("csv");
+    let (content_type, extension, body) = if csv {
+        ("text/csv", "csv", to_csv(&entries))
+    } else {
+        (
+            "application/json",
+            "json",
+            serde_json::to_string_pretty(&entries).map_err(|e| reject::custom(Error::from(e)))?,
+        )
+    };
+    record_audit::<Value>(&id, "audit.export", "audit-log", None, None);
+
+    let file_name = format!(
+        "audit-log-{}.{}",
+        Utc::now().format("%Y%m%d%H%M%S"),
+        extension
+    );
+    Ok(reply::with_header(
+        reply::with_header(body, CONTENT_TYPE, content_type),
+        CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", file_name),
+    ))
+}
+
+fn to_csv(entries: &[AuditEntry]) -> String {
+    let mut csv = String::from("id,timestamp,actor,action,target,before,after\n");
+    for e in entries {
+        let time = Utc
+            .timestamp_opt(e.timestamp, 0)
+            .single()
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_default();
+        let fields = [
+            e.id.clone(),
+            time,
+            e.actor.clone(),
+            e.action.clone(),
+            e.target.clone(),
+            e.before.as_ref().map(|v| v.to_string()).unwrap_or_default(),
+            e.after.as_ref().map(|v| v.to_string()).unwrap_or_default(),
+        ];
+        let row: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Quotes the value when needed. Values that spreadsheets would take for a formula get a
+/// leading `'`, so that opening an export can't run what a user put in a name.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(&['=', '+', '-', '@', '\t', '\r'][..]) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("user.create"), "user.create");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(
+            csv_field(r#"{"name":"Feeder","id":"m1"}"#),
+            r#""{""name"":""Feeder"",""id"":""m1""}""#
+        );
+    }
+
+    #[test]
+    fn csv_fields_are_not_formulas() {
+        assert_eq!(csv_field("=1+2"), "'=1+2");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("\t=1"), "'\t=1");
+        assert_eq!(
+            csv_field("=HYPERLINK(\"x\",\"y\")"),
+            "\"'=HYPERLINK(\"\"x\"\",\"\"y\"\")\""
+        );
+        // Only the start of the value counts
+        assert_eq!(csv_field("a=1"), "a=1");
+    }
+
+    #[test]
+    fn csv_rows_of_entries() {
+        let entry = AuditEntry {
+            id: "e1".to_string(),
+            timestamp: 0,
+            actor: "=cmd".to_string(),
+            action: "user.update".to_string(),
+            target: "u1".to_string(),
+            before: None,
+            after: Some(serde_json::json!({ "name": "a" })),
+        };
+        assert_eq!(
+            to_csv(&[entry]),
+            "id,timestamp,actor,action,target,before,after\n\
+             e1,1970-01-01T00:00:00+00:00,'=cmd,user.update,u1,,\"{\"\"name\"\":\"\"a\"\"}\"\n"
+        );
+    }
+}
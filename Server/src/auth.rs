@@ -2,18 +2,32 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::api_key::{authorize_api_key, get_api_key, API_KEY_SUBJECT_PREFIX};
+use crate::audit::record_audit;
+use crate::auth_provider::authenticate;
 use crate::error::Error;
+use crate::handler::Equipment;
+use crate::keyring::{keyring, KeyRing};
+use crate::lockout::{
+    login_retry_after, record_failed_login, record_successful_login, reset_failed_logins,
+};
+use crate::password_policy::{check_password, remember_password};
+use crate::session::{
+    access_token_ttl, create_session, end_session, end_user_sessions, is_session_active,
+    refresh_session,
+};
+use crate::storage::storage;
+use crate::two_factor::{
+    confirm_enrollment, finish_login, generate_recovery_codes, is_two_factor_required,
+    matching_recovery_code, pending_login, start_enrollment, start_login, verify_totp,
+};
 use chrono::prelude::*;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
-use log::error;
+use jsonwebtoken::{decode, decode_header, encode, Header, Validation};
+use log::{error, info, warn};
 use pwhash::bcrypt;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt;
-use std::fs;
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use warp::{
@@ -28,7 +42,7 @@ use warp::{
 pub type Result<T> = std::result::Result<T, Rejection>;
 
 const BEARER: &str = "Bearer ";
-const JWT_SECRET: &[u8] = b"openfmbsecrete2@2@";
+const API_KEY_HEADER: &str = "x-api-key";
 
 pub type Users = Arc<RwLock<HashMap<String, User>>>;
 
@@ -36,9 +50,63 @@ pub type Users = Arc<RwLock<HashMap<String, User>>>;
 pub struct User {
     pub id: String,
     pub username: String,
+    /// Left out by the client when an administrator edits a user without changing the
+    /// password
+    #[serde(default)]
     pub pwd: String,
     pub displayname: String,
     pub role: String,
+    /// Equipment groups and MRIDs the user may control, `None` or `*` for everything
+    #[serde(default)]
+    pub control_scope: Option<Vec<String>>,
+    /// Authentication provider of an external user, `None` for local users
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// Set after too many failed logins, only an administrator can unlock the account
+    #[serde(default)]
+    pub locked: bool,
+    /// The user can't do anything but change the password until this is cleared
+    #[serde(default)]
+    pub must_change_password: bool,
+    /// Hashes of earlier passwords, which can't be used again
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub password_history: Vec<String>,
+    /// Logins need a code of the user's authenticator app
+    #[serde(default)]
+    pub two_factor_enabled: bool,
+    /// Base32 TOTP secret shared with the authenticator app
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    /// Hashes of the unused recovery codes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recovery_codes: Vec<String>,
+    #[serde(default)]
+    pub preferences: UserPreferences,
+}
+
+/// Settings the users choose for themselves
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct UserPreferences {
+    /// Id of the diagram the user works with most
+    #[serde(default)]
+    pub default_diagram: Option<String>,
+    #[serde(default)]
+    pub landing_page: LandingPage,
+}
+
+/// Page shown after logging in
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LandingPage {
+    Home,
+    Diagrams,
+    DefaultDiagram,
+}
+
+impl Default for LandingPage {
+    fn default() -> Self {
+        LandingPage::Home
+    }
 }
 
 impl User {
@@ -49,6 +117,76 @@ impl User {
             pwd: String::from(""),
             displayname: String::from(""),
             role: String::from(""),
+            control_scope: None,
+            provider: None,
+            locked: false,
+            must_change_password: false,
+            password_history: vec![],
+            two_factor_enabled: false,
+            totp_secret: None,
+            recovery_codes: vec![],
+            preferences: UserPreferences::default(),
+        }
+    }
+
+    /// Removes the password hashes and second factor secrets before the user is sent
+    /// to a client
+    fn clear_secrets(&mut self) {
+        self.pwd.clear();
+        self.password_history.clear();
+        self.totp_secret = None;
+        self.recovery_codes.clear();
+    }
+
+    /// Copy of the user without secrets, as recorded in the audit log
+    pub(crate) fn without_secrets(&self) -> User {
+        let mut usr = self.clone();
+        usr.clear_secrets();
+        usr
+    }
+
+    /// True when the user has to set up a second factor before doing anything else.
+    /// The identity provider is responsible for the second factor of single sign-on users.
+    fn must_enroll_two_factor(&self) -> bool {
+        is_two_factor_required(&self.role)
+            && !self.two_factor_enabled
+            && self.provider.as_deref() != Some(crate::oidc::PROVIDER_NAME)
+    }
+
+    /// True when the user's control scope isn't restricted to some of the equipment
+    pub fn has_full_control_scope(&self) -> bool {
+        match &self.control_scope {
+            Some(scope) => scope.iter().any(|s| s == "*"),
+            None => true,
+        }
+    }
+
+    /// True when the equipment with the MRID, optionally belonging to a group, is in the
+    /// user's control scope
+    pub fn can_control(&self, mrid: &str, group: Option<&str>) -> bool {
+        match &self.control_scope {
+            Some(scope) => scope.iter().any(|s| {
+                s == "*"
+                    || s.eq_ignore_ascii_case(mrid)
+                    || group.map_or(false, |g| s.eq_ignore_ascii_case(g))
+            }),
+            None => true,
+        }
+    }
+
+    /// True when the control is in the user's control scope. Controls of equipment are
+    /// matched by its MRID and group, both ignoring case, controls without equipment act
+    /// on the whole microgrid and need a full scope.
+    pub fn may_control(&self, mrid: Option<&str>, equipment: &[Equipment]) -> bool {
+        match mrid {
+            Some(mrid) => {
+                let group = equipment
+                    .iter()
+                    .find(|eq| eq.mrid.eq_ignore_ascii_case(mrid))
+                    .and_then(|eq| eq.group.as_deref());
+                self.can_control(mrid, group)
+            }
+            None => self.has_full_control_scope(),
         }
     }
 }
@@ -62,9 +200,53 @@ pub struct LoginRequest {
 #[derive(Serialize)]
 pub struct LoginResponse {
     pub token: String,
+    pub refresh_token: String,
     pub user: User,
 }
 
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// Sent instead of the tokens when the login needs a second factor
+#[derive(Serialize)]
+pub struct TwoFactorChallenge {
+    pub two_factor_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorLoginRequest {
+    pub two_factor_token: String,
+    /// Code of the authenticator app or a recovery code
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorCodeRequest {
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct TwoFactorEnabledResponse {
+    pub recovery_codes: Vec<String>,
+    pub token: String,
+    pub refresh_token: String,
+    pub user: User,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateProfileRequest {
+    pub displayname: String,
+    pub current_pwd: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_pwd: String,
+    pub new_pwd: String,
+}
+
 #[derive(Clone, PartialEq)]
 pub enum Role {
     Admin,
@@ -80,6 +262,29 @@ impl Role {
             _ => Role::Viewer,
         }
     }
+
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::Admin => &[
+                Permission::ViewData,
+                Permission::IssueControl,
+                Permission::EditDiagram,
+                Permission::PublishDiagram,
+                Permission::ManageEquipment,
+                Permission::ManageUsers,
+            ],
+            Role::Engineer => &[
+                Permission::ViewData,
+                Permission::IssueControl,
+                Permission::EditDiagram,
+            ],
+            Role::Viewer => &[Permission::ViewData],
+        }
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
 }
 
 impl fmt::Display for Role {
@@ -92,57 +297,159 @@ impl fmt::Display for Role {
     }
 }
 
+/// Operations a route can require, granted to users through their role
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Permission {
+    /// View diagrams, equipment and live data
+    ViewData,
+    /// Send controls to devices and the microgrid controller
+    IssueControl,
+    /// Create, modify and delete diagram drafts
+    EditDiagram,
+    /// Make a diagram draft the version the HMI shows
+    PublishDiagram,
+    /// Create, modify and delete equipment
+    ManageEquipment,
+    /// Create, modify and delete users
+    ManageUsers,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 struct Claims {
     sub: String,
     name: String,
     role: String,
+    sid: String,
+    /// Only the password change is allowed with the token
+    #[serde(default)]
+    must_change_password: bool,
+    /// Only the second factor setup is allowed with the token
+    #[serde(default)]
+    must_enroll_two_factor: bool,
     exp: usize,
 }
 
-pub fn with_auth(role: Role) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+pub fn with_auth(
+    permission: Permission,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    headers_cloned()
+        .map(move |headers: HeaderMap<HeaderValue>| (Some(permission), headers))
+        .and_then(authorize)
+}
+
+/// Accepts any logged in user, including users who still have to change their password
+/// or set up a second factor
+pub fn with_any_auth() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
     headers_cloned()
-        .map(move |headers: HeaderMap<HeaderValue>| (role.clone(), headers))
+        .map(move |headers: HeaderMap<HeaderValue>| (None, headers))
         .and_then(authorize)
 }
 
-pub fn create_jwt(uid: &str, name: &str, role: &Role) -> std::result::Result<String, Error> {
+/// Same as `with_auth` but takes the token from the `token` query parameter, for
+/// websocket connections where the browser can't set an authorization header
+pub fn with_auth_query(
+    permission: Permission,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .map(move |query: HashMap<String, String>| (Some(permission), query))
+        .and_then(authorize_query)
+}
+
+pub fn create_jwt(usr: &User, session_id: &str) -> std::result::Result<String, Error> {
+    sign_jwt(keyring(), usr, session_id)
+}
+
+fn sign_jwt(keyring: &KeyRing, usr: &User, session_id: &str) -> std::result::Result<String, Error> {
     let expiration = Utc::now()
-        .checked_add_signed(chrono::Duration::days(7))
+        .checked_add_signed(access_token_ttl())
         .expect("valid timestamp")
         .timestamp();
 
     let claims = Claims {
-        sub: uid.to_owned(),
-        name: name.to_owned(),
-        role: role.to_string(),
+        sub: usr.id.to_owned(),
+        name: usr.displayname.to_owned(),
+        role: Role::from_str(&usr.role).to_string(),
+        sid: session_id.to_owned(),
+        must_change_password: usr.must_change_password,
+        must_enroll_two_factor: usr.must_enroll_two_factor(),
         exp: expiration as usize,
     };
-    let header = Header::new(Algorithm::HS512);
-    encode(&header, &claims, &EncodingKey::from_secret(JWT_SECRET))
-        .map_err(|_| Error::JWTTokenCreationError)
+    let key = keyring.signing_key();
+    let encoding_key = key.encoding_key().ok_or(Error::JWTTokenCreationError)?;
+
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(key.kid.clone());
+    encode(&header, &claims, encoding_key).map_err(|_| Error::JWTTokenCreationError)
 }
 
 async fn authorize(
-    (role, headers): (Role, HeaderMap<HeaderValue>),
+    (permission, headers): (Option<Permission>, HeaderMap<HeaderValue>),
 ) -> std::result::Result<String, Rejection> {
+    // API keys are only accepted by routes that require a permission
+    if let Some(key) = headers.get(API_KEY_HEADER) {
+        let key = std::str::from_utf8(key.as_bytes())
+            .map_err(|_| reject::custom(Error::InvalidApiKeyError))?;
+        return match permission {
+            Some(permission) => authorize_api_key(key, permission).map_err(|e| reject::custom(e)),
+            None => Err(reject::custom(Error::NoPermissionError)),
+        };
+    }
+
     match jwt_from_header(&headers) {
-        Ok(jwt) => {
-            let decoded = decode::<Claims>(
-                &jwt,
-                &DecodingKey::from_secret(JWT_SECRET),
-                &Validation::new(Algorithm::HS512),
-            )
-            .map_err(|_| reject::custom(Error::JWTTokenError))?;
+        Ok(jwt) => verify_jwt(&jwt, permission).map_err(|e| reject::custom(e)),
+        Err(e) => return Err(reject::custom(e)),
+    }
+}
 
-            if role == Role::Admin && Role::from_str(&decoded.claims.role) != Role::Admin {
-                return Err(reject::custom(Error::NoPermissionError));
-            }
+async fn authorize_query(
+    (permission, query): (Option<Permission>, HashMap<String, String>),
+) -> std::result::Result<String, Rejection> {
+    match query.get("token") {
+        Some(jwt) => verify_jwt(jwt, permission).map_err(|e| reject::custom(e)),
+        None => Err(reject::custom(Error::NoAuthHeaderError)),
+    }
+}
 
-            Ok(decoded.claims.sub)
+/// Validates the token and checks that its role grants the permission, returns the user id.
+/// Without a permission any valid token is accepted.
+fn verify_jwt(jwt: &str, permission: Option<Permission>) -> std::result::Result<String, Error> {
+    check_jwt(keyring(), jwt, permission, is_session_active)
+}
+
+/// `verify_jwt` with the keys and the sessions to check against
+fn check_jwt(
+    keyring: &KeyRing,
+    jwt: &str,
+    permission: Option<Permission>,
+    is_active: impl Fn(&str) -> bool,
+) -> std::result::Result<String, Error> {
+    let header = decode_header(jwt).map_err(|_| Error::JWTTokenError)?;
+    let key = header
+        .kid
+        .and_then(|kid| keyring.verification_key(&kid))
+        .ok_or(Error::JWTTokenError)?;
+
+    let decoded = decode::<Claims>(jwt, key.decoding_key(), &Validation::new(key.algorithm))
+        .map_err(|_| Error::JWTTokenError)?;
+
+    // Tokens are revoked together with the session they were issued for
+    if !is_active(&decoded.claims.sid) {
+        return Err(Error::JWTTokenError);
+    }
+
+    if let Some(permission) = permission {
+        if decoded.claims.must_change_password {
+            return Err(Error::PasswordChangeRequiredError);
+        }
+        if decoded.claims.must_enroll_two_factor {
+            return Err(Error::TwoFactorEnrollmentRequiredError);
+        }
+        if !Role::from_str(&decoded.claims.role).has_permission(permission) {
+            return Err(Error::NoPermissionError);
         }
-        Err(e) => return Err(reject::custom(e)),
     }
+
+    Ok(decoded.claims.sub)
 }
 
 fn jwt_from_header(headers: &HeaderMap<HeaderValue>) -> std::result::Result<String, Error> {
@@ -169,170 +476,952 @@ fn hash_password(password: &str) -> String {
     }
 }
 
-fn verify_password(password: &str, hash: &str) -> bool {
+pub(crate) fn verify_password(password: &str, hash: &str) -> bool {
     bcrypt::verify(password, hash)
 }
 
-pub async fn login_handler(body: LoginRequest) -> Result<impl Reply> {
-    let users = Arc::new(RwLock::new(init_users()));
+/// Runs `f` on the blocking thread pool. bcrypt is slow on purpose, images take a while to
+/// render and storage calls wait for the database, doing any of them on an async worker
+/// thread, or hashing inside a storage transaction, would hold up everyone else.
+pub(crate) async fn run_blocking<T, F>(f: F) -> std::result::Result<T, Error>
+where
+    F: FnOnce() -> std::result::Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::StorageError(format!("blocking task failed: {}", e)))?
+}
 
-    let mut token = String::from("");
+/// Starts a session for an authenticated user, returns the access and refresh tokens
+pub fn issue_tokens(usr: &User) -> std::result::Result<(String, String), Error> {
+    let (session, refresh_token) = create_session(&usr.id)?;
+    let token = create_jwt(usr, &session.id)?;
+    Ok((token, refresh_token))
+}
 
-    let mut usr = User::empty();
+pub async fn login_handler(address: Option<String>, body: LoginRequest) -> Result<impl Reply> {
+    if let Some(wait) = login_retry_after(&body.username, address.as_deref()) {
+        warn!(
+            "Login of {} from {:?} refused, next attempt allowed in {:?}",
+            body.username, address, wait
+        );
+        return Err(reject::custom(Error::TooManyLoginAttemptsError(
+            wait.as_secs() + 1,
+        )));
+    }
 
-    users
-        .read()
-        .await
-        .iter()
-        .filter(|(_, user)| user.username == body.username)
-        .filter(|(_, user)| verify_password(&body.pwd, &user.pwd))
-        .for_each(|(uid, user)| {
-            token = create_jwt(&uid, &user.displayname, &Role::from_str(&user.role))
-                .map_err(|e| reject::custom(e))
-                .unwrap();
-            usr = user.clone();
-        });
+    let authenticated = authenticate(&body.username, &body.pwd).await;
+
+    if let Ok(mut usr) = accept_login(authenticated) {
+        if usr.two_factor_enabled {
+            return Ok(reply::json(&TwoFactorChallenge {
+                two_factor_token: start_login(&usr.id, &usr.username),
+            }));
+        }
+
+        record_successful_login(&body.username, address.as_deref());
+
+        let (token, refresh_token) = issue_tokens(&usr).map_err(|e| reject::custom(e))?;
 
-    if token.len() > 0 {
         // Delete password
-        usr.pwd = String::from("");
+        usr.clear_secrets();
         return Ok(reply::json(&LoginResponse {
             token: token,
+            refresh_token: refresh_token,
             user: usr,
         }));
     }
 
+    warn!("Failed login of {} from {:?}", body.username, address);
+    if record_failed_login(&body.username, address.as_deref()) {
+        lock_user(&body.username);
+    }
+
     Err(reject::custom(Error::WrongCredentialsError))
 }
 
-pub async fn profile_handler(_id: String) -> Result<impl Reply> {
+pub async fn refresh_handler(body: RefreshRequest) -> Result<impl Reply> {
+    let (session, refresh_token) =
+        refresh_session(&body.refresh_token).map_err(|e| reject::custom(e))?;
+
+    let user = storage()
+        .user(&session.user_id)
+        .map_err(|e| reject::custom(e))?;
+    let mut usr = match user {
+        Some(u) => u,
+        None => {
+            end_user_sessions(&session.user_id);
+            return Err(reject::custom(Error::InvalidRefreshTokenError));
+        }
+    };
+
+    let token = create_jwt(&usr, &session.id).map_err(|e| reject::custom(e))?;
+
+    usr.clear_secrets();
+    Ok(reply::json(&LoginResponse {
+        token: token,
+        refresh_token: refresh_token,
+        user: usr,
+    }))
+}
+
+pub async fn logout_handler(body: RefreshRequest) -> Result<impl Reply> {
+    end_session(&body.refresh_token).map_err(|e| reject::custom(e))?;
     Ok(StatusCode::OK)
 }
 
-fn get_user_file() -> String {
-    let app_dir = std::env::var("APP_DIR_NAME").unwrap_or_else(|_| "".into());
-    if app_dir != "" {
-        return format!("/{}/users.json", app_dir);
+/// Account of the logged in user
+pub async fn profile_handler(id: String) -> Result<impl Reply> {
+    match get_user(&id) {
+        Some(mut usr) => {
+            usr.clear_secrets();
+            Ok(json(&usr))
+        }
+        None => Err(reject::custom(Error::NoPermissionError)),
     }
-    "users.json".to_string()
 }
 
-pub fn init_users() -> HashMap<String, User> {
-    let file = get_user_file();
+/// Changes the display name of the logged in user, the current password has to be given
+pub async fn update_profile_handler(id: String, body: UpdateProfileRequest) -> Result<impl Reply> {
+    let displayname = body.displayname.trim();
+    if displayname.is_empty() {
+        return Err(reject::custom(Error::UpdateProfileError));
+    }
+
+    let usr = match storage().user(&id).map_err(|e| reject::custom(e))? {
+        // The profile of an external user is managed by its provider
+        Some(usr) if usr.provider.is_none() => usr,
+        _ => return Err(reject::custom(Error::NoPermissionError)),
+    };
+    let checked_hash = usr.pwd.clone();
+    let current_pwd = body.current_pwd;
+    run_blocking(move || match verify_password(&current_pwd, &usr.pwd) {
+        true => Ok(()),
+        false => Err(Error::WrongCredentialsError),
+    })
+    .await
+    .map_err(|e| reject::custom(e))?;
+
+    let mut usr = storage()
+        .update_user(&id, &mut |usr| {
+            // The password was changed since it was checked
+            if usr.pwd != checked_hash {
+                return Err(Error::WrongCredentialsError);
+            }
+            usr.displayname = displayname.to_string();
+            Ok(())
+        })
+        .map_err(|e| reject::custom(e))?
+        .ok_or_else(|| reject::custom(Error::NoPermissionError))?;
+
+    usr.clear_secrets();
+    Ok(json(&usr))
+}
 
-    if !Path::new(&file).exists() {
-        let mut users: Vec<User> = vec![];
+/// Saves the preferences of the logged in user
+pub async fn update_preferences_handler(
+    id: String,
+    preferences: UserPreferences,
+) -> Result<impl Reply> {
+    let mut usr = storage()
+        .update_user(&id, &mut |usr| {
+            usr.preferences = preferences.clone();
+            Ok(())
+        })
+        .map_err(|e| reject::custom(e))?
+        .ok_or_else(|| reject::custom(Error::NoPermissionError))?;
+
+    usr.clear_secrets();
+    Ok(json(&usr))
+}
+
+/// Creates the default administrator when there are no users, e.g. on a new installation
+pub fn init_users() {
+    match storage().users() {
+        Ok(users) if users.is_empty() => {
+            let admin = User {
+                id: String::from("e2a1eaff-c4ea-4f28-bd59-d88fc2882f39"),
+                username: String::from("admin"),
+                pwd: hash_password("hm1admin"),
+                displayname: String::from("Administrator"),
+                role: String::from("Admin"),
+                control_scope: None,
+                provider: None,
+                locked: false,
+                must_change_password: true,
+                password_history: vec![],
+                two_factor_enabled: false,
+                totp_secret: None,
+                recovery_codes: vec![],
+                preferences: UserPreferences::default(),
+            };
+            match storage().insert_user(&admin) {
+                Ok(()) => warn!("No users found, created the default administrator"),
+                Err(e) => error!("Unable to create the default administrator: {}", e),
+            }
+        }
+        Ok(_) => {}
+        Err(e) => error!("Unable to read users: {}", e),
+    }
+}
 
-        users.push(User {
-            id: String::from("e2a1eaff-c4ea-4f28-bd59-d88fc2882f39"),
-            username: String::from("admin"),
-            pwd: hash_password("hm1admin"),
-            displayname: String::from("Administrator"),
-            role: String::from("Admin"),
+/// The authenticated user, when the account isn't locked. The password is checked before
+/// the lock and a locked account fails as wrong credentials do, so that a login doesn't
+/// tell which usernames exist or are locked.
+fn accept_login(authenticated: Option<User>) -> std::result::Result<User, Error> {
+    match authenticated {
+        Some(usr) if !usr.locked => Ok(usr),
+        Some(usr) => {
+            warn!("Login of locked account {}", usr.username);
+            Err(Error::WrongCredentialsError)
+        }
+        None => Err(Error::WrongCredentialsError),
+    }
+}
+
+/// True when the user is the only administrator who isn't locked. Locking that account
+/// would leave nobody to unlock the others, its logins are only slowed down.
+fn is_last_unlocked_admin(usr: &User, users: &[User]) -> bool {
+    let is_unlocked_admin = |u: &User| !u.locked && Role::from_str(&u.role) == Role::Admin;
+    is_unlocked_admin(usr) && !users.iter().any(|u| u.id != usr.id && is_unlocked_admin(u))
+}
+
+fn lock_user(username: &str) {
+    let id = match storage().user_by_username(username) {
+        Ok(Some(usr)) if !usr.locked => match storage().users() {
+            Ok(users) if is_last_unlocked_admin(&usr, &users) => {
+                warn!(
+                    "Last unlocked administrator {} not locked after failed logins",
+                    usr.username
+                );
+                return;
+            }
+            Ok(_) => usr.id,
+            Err(e) => {
+                error!("Unable to lock account {}: {}", username, e);
+                return;
+            }
+        },
+        _ => return,
+    };
+
+    let mut locked = false;
+    let result = storage().update_user(&id, &mut |usr| {
+        locked = !usr.locked;
+        usr.locked = true;
+        Ok(())
+    });
+    match result {
+        Ok(Some(usr)) if locked => error!(
+            "Account {} locked after too many failed logins",
+            usr.username
+        ),
+        Ok(_) => {}
+        Err(e) => error!("Unable to lock account {}: {}", username, e),
+    }
+}
+
+/// Looks up the user by id. The subject of an API key gives a user named after the key
+/// and with its control scope.
+pub fn get_user(id: &str) -> Option<User> {
+    if let Some(key_id) = id.strip_prefix(API_KEY_SUBJECT_PREFIX) {
+        return get_api_key(key_id).map(|key| User {
+            id: id.to_string(),
+            username: key.name.clone(),
+            displayname: key.name,
+            control_scope: key.control_scope,
+            ..User::empty()
         });
+    }
+    storage().user(id).ok().flatten()
+}
 
-        let _ = save_user_list(file.clone(), &users);
+/// Whether the user, or the API key the id is the subject of, is granted the permission
+pub fn has_permission(id: &str, permission: Permission) -> bool {
+    if let Some(key_id) = id.strip_prefix(API_KEY_SUBJECT_PREFIX) {
+        return get_api_key(key_id).map_or(false, |key| key.permissions.contains(&permission));
+    }
+    match storage().user(id) {
+        Ok(Some(user)) => Role::from_str(&user.role).has_permission(permission),
+        _ => false,
     }
-    load_users(file).unwrap()
 }
 
-fn load_users(file_path: String) -> std::io::Result<HashMap<String, User>> {
-    let mut map: HashMap<String, User> = HashMap::new();
-    let users: Vec<User> = get_user_list(file_path).unwrap();
+/// Creates or updates the local record of a user authenticated by an external provider,
+/// so that sessions and control scopes work the same as for local users.
+/// The record has no password and can't be used to log in locally.
+pub fn provision_external_user(
+    provider: &str,
+    username: &str,
+    displayname: &str,
+    role: &Role,
+) -> Option<User> {
+    let existing = storage().user_by_username(username).ok()?;
 
-    for usr in users.iter() {
-        map.insert(usr.id.clone(), usr.clone());
+    let usr = match existing {
+        Some(existing) => {
+            if existing.provider.as_deref() != Some(provider) {
+                error!(
+                    "User {} from {} conflicts with an existing user",
+                    username, provider
+                );
+                return None;
+            }
+            let mut role_changed = false;
+            let usr = storage()
+                .update_user(&existing.id, &mut |usr| {
+                    role_changed = usr.role != role.to_string();
+                    usr.displayname = displayname.to_string();
+                    usr.role = role.to_string();
+                    Ok(())
+                })
+                .ok()??;
+            if role_changed {
+                end_user_sessions(&usr.id);
+            }
+            usr
+        }
+        None => {
+            let usr = User {
+                id: uuid::Uuid::new_v4().to_string(),
+                username: username.to_string(),
+                pwd: String::from(""),
+                displayname: displayname.to_string(),
+                role: role.to_string(),
+                control_scope: None,
+                provider: Some(provider.to_string()),
+                locked: false,
+                must_change_password: false,
+                password_history: vec![],
+                two_factor_enabled: false,
+                totp_secret: None,
+                recovery_codes: vec![],
+                preferences: UserPreferences::default(),
+            };
+            storage().insert_user(&usr).ok()?;
+            usr
+        }
+    };
+
+    Some(usr)
+}
+
+/// All users without their secrets, as sent to the user administration
+fn user_list() -> std::result::Result<Vec<User>, Error> {
+    let mut list = storage().users()?;
+    for usr in list.iter_mut() {
+        usr.clear_secrets();
     }
+    Ok(list)
+}
 
-    Ok(map)
+pub async fn get_users_handler(_id: String) -> Result<impl Reply> {
+    Ok(json(&user_list().map_err(|e| reject::custom(e))?))
 }
 
-fn save_user_list(file_path: String, users: &Vec<User>) -> std::io::Result<()> {
-    let json = serde_json::to_string(&users).unwrap();
-    fs::write(file_path, json).expect("Unable to write file");
+pub async fn delete_user_handler(id: String, user: User) -> Result<impl Reply> {
+    let removed = storage()
+        .delete_user(&user.id)
+        .map_err(|e| reject::custom(e))?;
 
-    Ok(())
+    if let Some(removed) = removed {
+        end_user_sessions(&user.id);
+        record_audit(
+            &id,
+            "user.delete",
+            &user.id,
+            Some(&removed.without_secrets()),
+            None,
+        );
+    }
+    Ok(json(&user_list().map_err(|e| reject::custom(e))?))
 }
 
-fn get_user_list(file_path: String) -> std::io::Result<Vec<User>> {
-    let map: Vec<User> = vec![];
+pub async fn update_user_handler(id: String, user: User) -> Result<impl Reply> {
+    // The new password is checked and hashed up front, the transaction only compares the
+    // hash it was checked against. External users have no local password.
+    let existing = storage().user(&user.id).map_err(|e| reject::custom(e))?;
+    let new_password = match existing {
+        Some(existing) if existing.provider.is_none() && !user.pwd.is_empty() => {
+            let pwd = user.pwd.clone();
+            run_blocking(move || {
+                if verify_password(&pwd, &existing.pwd) {
+                    return Ok(None);
+                }
+                check_password(&pwd, &previous_passwords(&existing))?;
+                Ok(Some((existing.pwd, hash_password(&pwd))))
+            })
+            .await
+            .map_err(|e| reject::custom(e))?
+        }
+        _ => None,
+    };
+    let password_changed = new_password.is_some();
 
-    if let Ok(mut file) = File::open(file_path.clone()) {
-        let mut contents = String::new();
-        if let Ok(_) = file.read_to_string(&mut contents) {
-            let users: Vec<User> =
-                serde_json::from_str(&contents).expect("User json file was not well-formatted");
-            return Ok(users);
-        } else {
-            error!("Unable to read user file: {}", file_path);
+    let mut before = None;
+    let mut revoke_sessions = false;
+    let after = storage()
+        .update_user(&user.id, &mut |usr| {
+            before = Some(usr.without_secrets());
+            let is_local = usr.provider.is_none();
+            let must_change_password = is_local && user.must_change_password;
+            if let Some((checked_hash, new_hash)) = &new_password {
+                if &usr.pwd != checked_hash {
+                    return Err(Error::VersionConflictError {
+                        version: None,
+                        current: None,
+                    });
+                }
+                let replaced = std::mem::replace(&mut usr.pwd, new_hash.clone());
+                remember_password(&mut usr.password_history, replaced);
+            }
+            // Existing tokens still carry the old role and password change flag, and a new
+            // password must log out whoever knew the old one
+            revoke_sessions = usr.role != user.role
+                || password_changed
+                || (must_change_password && !usr.must_change_password);
+            usr.displayname = user.displayname.clone();
+            usr.role = user.role.clone();
+            usr.control_scope = user.control_scope.clone();
+            usr.must_change_password = must_change_password;
+            Ok(())
+        })
+        .map_err(|e| reject::custom(e))?;
+
+    if let (Some(before), Some(after)) = (before, after) {
+        if revoke_sessions {
+            end_user_sessions(&user.id);
         }
-    } else {
-        error!("Unable to open user file: {}", file_path);
+        // The hashes are left out of the entry, a changed password shows in the action
+        let action = if password_changed {
+            "user.update.password"
+        } else {
+            "user.update"
+        };
+        record_audit(
+            &id,
+            action,
+            &user.id,
+            Some(&before),
+            Some(&after.without_secrets()),
+        );
     }
+    Ok(json(&user_list().map_err(|e| reject::custom(e))?))
+}
 
-    Ok(map)
+pub async fn create_user_handler(id: String, user: User) -> Result<impl Reply> {
+    let pwd = user.pwd.clone();
+    let hash = run_blocking(move || {
+        check_password(&pwd, &[])?;
+        Ok(hash_password(&pwd))
+    })
+    .await
+    .map_err(|e| reject::custom(e))?;
+    // Only the account details come from the request, as when users are imported. The
+    // user is a local one, enrolls a second factor and has no other secrets yet.
+    let mut usr = user.without_secrets();
+    usr.pwd = hash;
+    usr.provider = None;
+    usr.locked = false;
+    usr.two_factor_enabled = false;
+    usr.must_change_password = user.must_change_password;
+    storage().insert_user(&usr).map_err(|e| reject::custom(e))?;
+    record_audit(
+        &id,
+        "user.create",
+        &user.id,
+        None,
+        Some(&usr.without_secrets()),
+    );
+
+    Ok(json(&user_list().map_err(|e| reject::custom(e))?))
 }
 
-pub async fn get_users_handler(_id: String) -> Result<impl Reply> {
-    let mut list = get_user_list(get_user_file()).unwrap();
-    for usr in list.iter_mut() {
-        usr.pwd.clear();
+pub async fn unlock_user_handler(id: String, user: User) -> Result<impl Reply> {
+    let mut before = None;
+    let after = storage()
+        .update_user(&user.id, &mut |usr| {
+            before = Some(usr.without_secrets());
+            usr.locked = false;
+            Ok(())
+        })
+        .map_err(|e| reject::custom(e))?;
+
+    if let (Some(before), Some(after)) = (before, after) {
+        reset_failed_logins(&after.username);
+        info!("Account {} unlocked by {}", after.username, id);
+        record_audit(
+            &id,
+            "user.unlock",
+            &user.id,
+            Some(&before),
+            Some(&after.without_secrets()),
+        );
     }
+    Ok(json(&user_list().map_err(|e| reject::custom(e))?))
+}
+
+/// Changes the password of the logged in user, the current password has to be given.
+///
+/// All sessions of the user end, the response carries the tokens of a new session.
+pub async fn change_password_handler(
+    id: String,
+    body: ChangePasswordRequest,
+) -> Result<impl Reply> {
+    let usr = match storage().user(&id).map_err(|e| reject::custom(e))? {
+        // The password of an external user is managed by its provider
+        Some(usr) if usr.provider.is_some() => {
+            return Err(reject::custom(Error::NoPermissionError))
+        }
+        Some(usr) => usr,
+        None => return Err(reject::custom(Error::WrongCredentialsError)),
+    };
+    let checked_hash = usr.pwd.clone();
+    let ChangePasswordRequest {
+        current_pwd,
+        new_pwd,
+    } = body;
+    let new_hash = run_blocking(move || {
+        if !verify_password(&current_pwd, &usr.pwd) {
+            warn!("Password change of {} with a wrong password", usr.username);
+            return Err(Error::WrongCredentialsError);
+        }
+        check_password(&new_pwd, &previous_passwords(&usr))?;
+        Ok(hash_password(&new_pwd))
+    })
+    .await
+    .map_err(|e| reject::custom(e))?;
+
+    let mut usr = storage()
+        .update_user(&id, &mut |usr| {
+            // The password was changed since it was checked
+            if usr.pwd != checked_hash {
+                return Err(Error::WrongCredentialsError);
+            }
+            let replaced = std::mem::replace(&mut usr.pwd, new_hash.clone());
+            remember_password(&mut usr.password_history, replaced);
+            usr.must_change_password = false;
+            Ok(())
+        })
+        .map_err(|e| reject::custom(e))?
+        .ok_or_else(|| reject::custom(Error::WrongCredentialsError))?;
+    info!("User {} changed the password", usr.username);
+
+    end_user_sessions(&usr.id);
+    let (token, refresh_token) = issue_tokens(&usr).map_err(|e| reject::custom(e))?;
 
-    Ok(json(&list))
+    usr.clear_secrets();
+    Ok(reply::json(&LoginResponse {
+        token: token,
+        refresh_token: refresh_token,
+        user: usr,
+    }))
 }
 
-pub async fn delete_user_handler(_id: String, user: User) -> Result<impl Reply> {
-    let mut list = get_user_list(get_user_file()).unwrap();
+/// Hashes of the current and earlier passwords of the user
+fn previous_passwords(usr: &User) -> Vec<String> {
+    let mut previous = vec![usr.pwd.clone()];
+    previous.extend(usr.password_history.iter().cloned());
+    previous
+}
 
-    if let Some(pos) = list.iter().position(|x| *x.id == user.id) {
-        list.remove(pos);
+/// Second step of a login with two-factor authentication, checks the code of the
+/// authenticator app or a recovery code and issues the tokens
+pub async fn two_factor_login_handler(
+    address: Option<String>,
+    body: TwoFactorLoginRequest,
+) -> Result<impl Reply> {
+    let login = match pending_login(&body.two_factor_token) {
+        Some(login) => login,
+        None => return Err(reject::custom(Error::InvalidTwoFactorCodeError)),
+    };
+    if let Some(wait) = login_retry_after(&login.username, address.as_deref()) {
+        return Err(reject::custom(Error::TooManyLoginAttemptsError(
+            wait.as_secs() + 1,
+        )));
+    }
 
-        let _ = save_user_list(get_user_file(), &list);
+    let user = storage()
+        .user(&login.user_id)
+        .map_err(|e| reject::custom(e))?;
+    let mut usr = match user {
+        Some(usr) if !usr.locked => usr,
+        _ => return Err(reject::custom(Error::AccountLockedError)),
+    };
+
+    let totp_verified = match &usr.totp_secret {
+        Some(secret) => verify_totp(&usr.id, &usr.username, secret, &body.code)
+            .map_err(|e| reject::custom(e))?,
+        None => false,
+    };
+    let mut recovery_code_used = false;
+    if !totp_verified && !usr.recovery_codes.is_empty() {
+        let hashes = usr.recovery_codes.clone();
+        let code = body.code.clone();
+        let matched = run_blocking(move || Ok(matching_recovery_code(&hashes, &code)))
+            .await
+            .map_err(|e| reject::custom(e))?;
+        if let Some(matched) = matched {
+            // Removed in a transaction of its own, a code used by a concurrent login is gone
+            let user = storage()
+                .update_user(&usr.id, &mut |usr| {
+                    if let Some(pos) = usr.recovery_codes.iter().position(|h| h == &matched) {
+                        usr.recovery_codes.remove(pos);
+                        recovery_code_used = true;
+                    }
+                    Ok(())
+                })
+                .map_err(|e| reject::custom(e))?;
+            if let Some(user) = user {
+                usr = user;
+            }
+        }
     }
-    for usr in list.iter_mut() {
-        usr.pwd.clear();
+
+    if !totp_verified && !recovery_code_used {
+        warn!(
+            "Wrong second factor for {} from {:?}",
+            login.username, address
+        );
+        if record_failed_login(&login.username, address.as_deref()) {
+            lock_user(&login.username);
+        }
+        return Err(reject::custom(Error::InvalidTwoFactorCodeError));
     }
-    Ok(json(&list))
-}
 
-pub async fn update_user_handler(_id: String, user: User) -> Result<impl Reply> {
-    let mut list = get_user_list(get_user_file()).unwrap();
+    finish_login(&body.two_factor_token);
+    record_successful_login(&login.username, address.as_deref());
 
-    if let Some(pos) = list.iter().position(|x| *x.id == user.id) {
-        let mut usr = list.get_mut(pos).unwrap();
-        usr.displayname = user.displayname;
-        usr.role = user.role;
-        usr.pwd = hash_password(&user.pwd);
-        let _ = save_user_list(get_user_file(), &list);
+    if recovery_code_used {
+        warn!(
+            "User {} logged in with a recovery code, {} left",
+            usr.username,
+            usr.recovery_codes.len()
+        );
     }
-    for usr in list.iter_mut() {
-        usr.pwd.clear();
+
+    let (token, refresh_token) = issue_tokens(&usr).map_err(|e| reject::custom(e))?;
+
+    usr.clear_secrets();
+    Ok(reply::json(&LoginResponse {
+        token: token,
+        refresh_token: refresh_token,
+        user: usr,
+    }))
+}
+
+/// Generates a new TOTP secret for the logged in user, to be confirmed with
+/// `two_factor_enable_handler`
+pub async fn two_factor_setup_handler(id: String) -> Result<impl Reply> {
+    let usr = match get_user(&id) {
+        Some(usr) if !usr.two_factor_enabled => usr,
+        _ => return Err(reject::custom(Error::TwoFactorError)),
+    };
+
+    let enrollment = start_enrollment(&usr.id, &usr.username).map_err(|e| reject::custom(e))?;
+    Ok(json(&enrollment))
+}
+
+/// Enables two-factor authentication once the user entered a code of the new secret.
+///
+/// The recovery codes are only shown in the response. All sessions of the user end, the
+/// response carries the tokens of a new session.
+pub async fn two_factor_enable_handler(
+    id: String,
+    body: TwoFactorCodeRequest,
+) -> Result<impl Reply> {
+    let usr = match storage().user(&id).map_err(|e| reject::custom(e))? {
+        Some(usr) if !usr.two_factor_enabled => usr,
+        _ => return Err(reject::custom(Error::TwoFactorError)),
+    };
+    let secret =
+        confirm_enrollment(&usr.id, &usr.username, &body.code).map_err(|e| reject::custom(e))?;
+    let (recovery_codes, hashes) = run_blocking(generate_recovery_codes)
+        .await
+        .map_err(|e| reject::custom(e))?;
+
+    let mut usr = storage()
+        .update_user(&id, &mut |usr| {
+            if usr.two_factor_enabled {
+                return Err(Error::TwoFactorError);
+            }
+            usr.two_factor_enabled = true;
+            usr.totp_secret = Some(secret.clone());
+            usr.recovery_codes = hashes.clone();
+            Ok(())
+        })
+        .map_err(|e| reject::custom(e))?
+        .ok_or_else(|| reject::custom(Error::TwoFactorError))?;
+    info!("User {} enabled two-factor authentication", usr.username);
+
+    end_user_sessions(&usr.id);
+    let (token, refresh_token) = issue_tokens(&usr).map_err(|e| reject::custom(e))?;
+
+    usr.clear_secrets();
+    Ok(reply::json(&TwoFactorEnabledResponse {
+        recovery_codes,
+        token,
+        refresh_token,
+        user: usr,
+    }))
+}
+
+/// Removes the second factor of a user who lost the authenticator and recovery codes
+pub async fn reset_two_factor_handler(id: String, user: User) -> Result<impl Reply> {
+    let mut before = None;
+    let after = storage()
+        .update_user(&user.id, &mut |usr| {
+            before = Some(usr.without_secrets());
+            usr.two_factor_enabled = false;
+            usr.totp_secret = None;
+            usr.recovery_codes.clear();
+            Ok(())
+        })
+        .map_err(|e| reject::custom(e))?;
+
+    if let (Some(before), Some(after)) = (before, after) {
+        info!("Second factor of {} reset by {}", after.username, id);
+        end_user_sessions(&user.id);
+        record_audit(
+            &id,
+            "user.reset_two_factor",
+            &user.id,
+            Some(&before),
+            Some(&after.without_secrets()),
+        );
     }
-    Ok(json(&list))
+    Ok(json(&user_list().map_err(|e| reject::custom(e))?))
 }
 
-pub async fn create_user_handler(_id: String, user: User) -> Result<impl Reply> {
-    let mut list = get_user_list(get_user_file()).unwrap();
-    if let Some(_pos) = list.iter().position(|x| {
-        *x.id.to_lowercase() == user.id.to_lowercase()
-            || *x.username.to_lowercase() == user.username.to_lowercase()
-    }) {
-        // same user id/username already exists
-        error!(
-            "User with same id/username ({}/{}) already exists",
-            user.id, user.username
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+
+    const ALL: [Permission; 6] = [
+        Permission::ViewData,
+        Permission::IssueControl,
+        Permission::EditDiagram,
+        Permission::PublishDiagram,
+        Permission::ManageEquipment,
+        Permission::ManageUsers,
+    ];
+
+    fn test_keyring() -> KeyRing {
+        let mut config = Config::default();
+        config
+            .set(
+                "auth.keys",
+                vec![[("kid", "k1"), ("secret", "s3cret")]
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<HashMap<String, String>>()],
+            )
+            .unwrap();
+        KeyRing::from_config(&config).unwrap()
+    }
+
+    fn user(role: &str) -> User {
+        let mut usr = User::empty();
+        usr.id = format!("{}-id", role);
+        usr.username = role.to_lowercase();
+        usr.role = role.to_string();
+        // Roles that have to set up a second factor get tokens for the setup only
+        usr.two_factor_enabled = true;
+        usr
+    }
+
+    fn granted(role: Role) -> Vec<Permission> {
+        ALL.iter()
+            .copied()
+            .filter(|p| role.has_permission(*p))
+            .collect()
+    }
+
+    #[test]
+    fn roles_grant_their_permissions() {
+        assert_eq!(granted(Role::Admin), ALL.to_vec());
+        assert_eq!(
+            granted(Role::Engineer),
+            vec![
+                Permission::ViewData,
+                Permission::IssueControl,
+                Permission::EditDiagram
+            ]
         );
+        assert_eq!(granted(Role::Viewer), vec![Permission::ViewData]);
+        // Unknown roles get the least permissions
+        assert!(Role::from_str("Operator") == Role::Viewer);
+        assert!(Role::from_str("admin") == Role::Viewer);
+    }
+
+    #[test]
+    fn viewer_tokens_can_only_view() {
+        let keyring = test_keyring();
+        let token = sign_jwt(&keyring, &user("Viewer"), "s1").unwrap();
+        let check = |permission| check_jwt(&keyring, &token, permission, |_| true);
+
+        assert_eq!(check(Some(Permission::ViewData)).unwrap(), "Viewer-id");
+        assert_eq!(check(None).unwrap(), "Viewer-id");
+        for permission in &[
+            Permission::IssueControl,
+            Permission::EditDiagram,
+            Permission::PublishDiagram,
+            Permission::ManageUsers,
+        ] {
+            assert!(matches!(
+                check(Some(*permission)),
+                Err(Error::NoPermissionError)
+            ));
+        }
+    }
 
-        return Err(reject::custom(Error::AddUserError));
-    } else {
-        let mut usr = user.clone();
-        usr.pwd = hash_password(&user.pwd);
-        list.push(usr);
-        let _ = save_user_list(get_user_file(), &list);
+    #[test]
+    fn engineer_tokens_can_operate_and_edit_but_not_publish() {
+        let keyring = test_keyring();
+        let token = sign_jwt(&keyring, &user("Engineer"), "s1").unwrap();
+        let check = |permission| check_jwt(&keyring, &token, Some(permission), |_| true);
+
+        assert!(check(Permission::IssueControl).is_ok());
+        assert!(check(Permission::EditDiagram).is_ok());
+        assert!(matches!(
+            check(Permission::PublishDiagram),
+            Err(Error::NoPermissionError)
+        ));
     }
-    for usr in list.iter_mut() {
-        usr.pwd.clear();
+
+    #[test]
+    fn tokens_of_ended_sessions_and_other_keys_are_refused() {
+        let keyring = test_keyring();
+        let token = sign_jwt(&keyring, &user("Admin"), "s1").unwrap();
+        assert!(matches!(
+            check_jwt(&keyring, &token, None, |sid| sid != "s1"),
+            Err(Error::JWTTokenError)
+        ));
+        assert!(matches!(
+            check_jwt(&keyring, "not a token", None, |_| true),
+            Err(Error::JWTTokenError)
+        ));
+
+        let mut config = Config::default();
+        config
+            .set(
+                "auth.keys",
+                vec![[("kid", "k1"), ("secret", "other")]
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<HashMap<String, String>>()],
+            )
+            .unwrap();
+        let other = KeyRing::from_config(&config).unwrap();
+        assert!(matches!(
+            check_jwt(&other, &token, None, |_| true),
+            Err(Error::JWTTokenError)
+        ));
+    }
+
+    fn equipment() -> Vec<Equipment> {
+        [("M-1", Some("Feeder A")), ("m-2", None)]
+            .iter()
+            .map(|(mrid, group)| Equipment {
+                mrid: mrid.to_string(),
+                name: mrid.to_string(),
+                device_type: Some("breaker".to_string()),
+                group: group.map(|g| g.to_string()),
+                version: None,
+            })
+            .collect()
+    }
+
+    fn scoped(scope: Option<&[&str]>) -> User {
+        let mut usr = user("Engineer");
+        usr.control_scope = scope.map(|s| s.iter().map(|s| s.to_string()).collect());
+        usr
+    }
+
+    #[test]
+    fn controls_are_limited_to_the_scope() {
+        let equipment = equipment();
+        let by_mrid = scoped(Some(&["M-2"]));
+        assert!(by_mrid.may_control(Some("m-2"), &equipment));
+        assert!(!by_mrid.may_control(Some("m-1"), &equipment));
+
+        // The group is found whatever the case of the MRID in the control
+        let by_group = scoped(Some(&["feeder a"]));
+        assert!(by_group.may_control(Some("M-1"), &equipment));
+        assert!(by_group.may_control(Some("m-1"), &equipment));
+        assert!(!by_group.may_control(Some("m-2"), &equipment));
+        assert!(!by_group.may_control(Some("unknown"), &equipment));
+
+        let empty = scoped(Some(&[]));
+        assert!(!empty.has_full_control_scope());
+        assert!(!empty.may_control(Some("m-1"), &equipment));
+    }
+
+    #[test]
+    fn microgrid_controls_need_a_full_scope() {
+        let equipment = equipment();
+        for full in &[
+            scoped(None),
+            scoped(Some(&["*"])),
+            scoped(Some(&["m-2", "*"])),
+        ] {
+            assert!(full.has_full_control_scope());
+            assert!(full.may_control(None, &equipment));
+            assert!(full.may_control(Some("anything"), &equipment));
+        }
+        assert!(!scoped(Some(&["M-1"])).may_control(None, &equipment));
+        assert!(!scoped(Some(&["Feeder A"])).may_control(None, &equipment));
+    }
+
+    #[test]
+    fn locked_accounts_fail_like_wrong_credentials() {
+        let mut locked = user("Admin");
+        locked.locked = true;
+        assert!(matches!(
+            accept_login(Some(locked)),
+            Err(Error::WrongCredentialsError)
+        ));
+        assert!(matches!(
+            accept_login(None),
+            Err(Error::WrongCredentialsError)
+        ));
+        assert!(accept_login(Some(user("Viewer"))).is_ok());
+    }
+
+    #[test]
+    fn the_last_unlocked_admin_is_not_locked() {
+        let named = |id: &str, role: &str, locked: bool| {
+            let mut usr = user(role);
+            usr.id = id.to_string();
+            usr.locked = locked;
+            usr
+        };
+        let admin = named("a1", "Admin", false);
+        let locked_admin = named("a2", "Admin", true);
+        let engineer = named("e1", "Engineer", false);
+
+        let users = vec![admin.clone(), locked_admin.clone(), engineer.clone()];
+        assert!(is_last_unlocked_admin(&admin, &users));
+        assert!(!is_last_unlocked_admin(&engineer, &users));
+        assert!(!is_last_unlocked_admin(&locked_admin, &users));
+
+        let other_admin = named("a3", "Admin", false);
+        let users = vec![admin.clone(), other_admin, engineer];
+        assert!(!is_last_unlocked_admin(&admin, &users));
+    }
+
+    #[test]
+    fn pending_password_change_only_allows_routes_without_permission() {
+        let keyring = test_keyring();
+        let mut usr = user("Admin");
+        usr.must_change_password = true;
+        let token = sign_jwt(&keyring, &usr, "s1").unwrap();
+        assert!(check_jwt(&keyring, &token, None, |_| true).is_ok());
+        assert!(matches!(
+            check_jwt(&keyring, &token, Some(Permission::ViewData), |_| true),
+            Err(Error::PasswordChangeRequiredError)
+        ));
     }
-    Ok(json(&list))
 }
@@ -0,0 +1,112 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::auth::{run_blocking, verify_password, User};
+use crate::error::Error;
+use crate::ldap::LdapAuthProvider;
+use crate::storage::storage;
+use config::Config;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use std::future::Future;
+use std::pin::Pin;
+
+lazy_static! {
+    static ref PROVIDERS: Vec<Box<dyn AuthProvider>> = load_providers(&riker::load_config());
+}
+
+pub type AuthResult<'a> = Pin<Box<dyn Future<Output = Result<Option<User>, Error>> + Send + 'a>>;
+
+/// A backend that verifies user credentials.
+///
+/// `authenticate` resolves to the authenticated user, to `None` when the credentials are
+/// wrong or unknown to the provider, and to an error when the backend can't be reached.
+pub trait AuthProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn authenticate<'a>(&'a self, username: &'a str, password: &'a str) -> AuthResult<'a>;
+}
+
+/// Users and bcrypt password hashes kept in the HMI database
+pub struct FileAuthProvider;
+
+impl AuthProvider for FileAuthProvider {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn authenticate<'a>(&'a self, username: &'a str, password: &'a str) -> AuthResult<'a> {
+        Box::pin(async move {
+            let user = match storage().user_by_username(username)? {
+                Some(user) if user.provider.is_none() => user,
+                _ => return Ok(None),
+            };
+            let password = password.to_string();
+            run_blocking(
+                move || Ok(Some(user).filter(|user| verify_password(&password, &user.pwd))),
+            )
+            .await
+        })
+    }
+}
+
+/// Tries the configured providers in order until one of them accepts the credentials.
+///
+/// A provider that fails doesn't stop the others from being tried, so the local users
+/// keep working when the directory is unavailable.
+pub async fn authenticate(username: &str, password: &str) -> Option<User> {
+    if username.is_empty() || password.is_empty() {
+        return None;
+    }
+
+    for provider in PROVIDERS.iter() {
+        match provider.authenticate(username, password).await {
+            Ok(Some(user)) => {
+                info!("User {} authenticated by {}", username, provider.name());
+                return Some(user);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!(
+                    "Authentication provider {} failed for {}: {}",
+                    provider.name(),
+                    username,
+                    e
+                );
+            }
+        }
+    }
+
+    None
+}
+
+fn load_providers(config: &Config) -> Vec<Box<dyn AuthProvider>> {
+    let names: Vec<String> = config
+        .get("auth.providers")
+        .unwrap_or_else(|_| vec!["local".to_string()]);
+
+    let mut providers: Vec<Box<dyn AuthProvider>> = vec![];
+    for name in names.iter() {
+        match name.as_str() {
+            "local" => providers.push(Box::new(FileAuthProvider)),
+            "ldap" => match LdapAuthProvider::from_config(config) {
+                Ok(p) => providers.push(Box::new(p)),
+                Err(e) => error!("Unable to configure LDAP authentication: {}", e),
+            },
+            _ => warn!("Unknown authentication provider: {}", name),
+        }
+    }
+
+    if providers.is_empty() {
+        warn!("No usable authentication provider configured, using local users");
+        providers.push(Box::new(FileAuthProvider));
+    }
+
+    providers
+}
+
+/// Loads the providers eagerly so that configuration problems are logged at startup
+pub fn init_providers() {
+    lazy_static::initialize(&PROVIDERS);
+}
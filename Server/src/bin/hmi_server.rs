@@ -12,13 +12,22 @@ use tokio::sync::RwLock;
 
 use warp::Filter;
 
+use hmi_server::auth_provider::init_providers;
 use hmi_server::coordinator::StartProcessingMessages;
+use hmi_server::error::handle_rejection;
+use hmi_server::keyring::init_keyring;
+use hmi_server::lockout::with_client_address;
 use hmi_server::logs::{setup_logger, SystemEventLog};
+use hmi_server::storage::init_storage;
 
 use hmi_server::hmi::{
     coordinator::*, hmi::*, hmi_publisher::*, hmi_subscriber::*, monitor::*, processor::*,
 };
-use hmi_server::{auth::*, handler::*};
+use hmi_server::{
+    api_key::*, audit::*, auth::*, bundle::*, control_log::*, diagram_history::*,
+    diagram_publish::*, diagram_render::*, diagram_template::*, diagram_validation::*, handler::*,
+    oidc::*,
+};
 
 use riker::actor::Tell;
 use riker::actor::{ActorRef, ActorRefFactory};
@@ -38,6 +47,13 @@ async fn server_setup() {
 
     setup_logger(&config).unwrap();
 
+    // Fail fast on a bad JWT key configuration rather than on the first login
+    init_keyring();
+    init_providers();
+    // Migrates the database and imports the JSON files of earlier versions
+    init_storage();
+    init_users();
+
     //Create the actor system that will manage all of the actors instantiate during runtime
     let sys = ActorSystem::with_config("coordinator", config.clone()).unwrap();
 
@@ -84,91 +100,292 @@ async fn server_setup() {
 
     let login_routes = warp::path("login")
         .and(warp::post())
+        .and(with_client_address())
         .and(warp::body::json())
         .and_then(login_handler);
 
+    let refresh_routes = warp::path("refresh")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(refresh_handler);
+
+    let logout_routes = warp::path("logout")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(logout_handler);
+
+    let oidc_config = warp::path!("oidc" / "config")
+        .and(warp::get())
+        .and_then(oidc_config_handler);
+
+    let oidc_login = warp::path!("oidc" / "login")
+        .and(warp::get())
+        .and_then(oidc_login_handler);
+
+    let oidc_callback = warp::path!("oidc" / "callback")
+        .and(warp::get())
+        .and(warp::query())
+        .and_then(oidc_callback_handler);
+
+    let two_factor_login = warp::path!("two-factor" / "login")
+        .and(warp::post())
+        .and(with_client_address())
+        .and(warp::body::json())
+        .and_then(two_factor_login_handler);
+
+    let two_factor_setup = warp::path!("two-factor" / "setup")
+        .and(warp::post())
+        .and(with_any_auth())
+        .and_then(two_factor_setup_handler);
+
+    let two_factor_enable = warp::path!("two-factor" / "enable")
+        .and(warp::post())
+        .and(with_any_auth())
+        .and(warp::body::json())
+        .and_then(two_factor_enable_handler);
+
+    let change_password = warp::path("change-password")
+        .and(warp::post())
+        .and(with_any_auth())
+        .and(warp::body::json())
+        .and_then(change_password_handler);
+
     let user_profile = warp::path("profile")
         .and(warp::get())
-        .and(with_auth(Role::Viewer))
+        .and(with_auth(Permission::ViewData))
         .and_then(profile_handler);
 
+    let update_profile = warp::path("update-profile")
+        .and(warp::post())
+        .and(with_auth(Permission::ViewData))
+        .and(warp::body::json())
+        .and_then(update_profile_handler);
+
+    let update_preferences = warp::path("update-preferences")
+        .and(warp::post())
+        .and(with_auth(Permission::ViewData))
+        .and(warp::body::json())
+        .and_then(update_preferences_handler);
+
     let get_users = warp::path("get-users")
         .and(warp::get())
-        .and(with_auth(Role::Admin))
+        .and(with_auth(Permission::ManageUsers))
         .and_then(get_users_handler);
 
     let delete_user = warp::path("delete-user")
         .and(warp::post())
-        .and(with_auth(Role::Admin))
+        .and(with_auth(Permission::ManageUsers))
         .and(warp::body::json())
         .and_then(delete_user_handler);
 
     let update_user = warp::path("update-user")
         .and(warp::post())
-        .and(with_auth(Role::Admin))
+        .and(with_auth(Permission::ManageUsers))
         .and(warp::body::json())
         .and_then(update_user_handler);
 
+    let unlock_user = warp::path("unlock-user")
+        .and(warp::post())
+        .and(with_auth(Permission::ManageUsers))
+        .and(warp::body::json())
+        .and_then(unlock_user_handler);
+
+    let reset_two_factor = warp::path("reset-two-factor")
+        .and(warp::post())
+        .and(with_auth(Permission::ManageUsers))
+        .and(warp::body::json())
+        .and_then(reset_two_factor_handler);
+
+    let get_api_keys = warp::path("get-api-keys")
+        .and(warp::get())
+        .and(with_auth(Permission::ManageUsers))
+        .and_then(get_api_keys_handler);
+
+    let create_api_key = warp::path("create-api-key")
+        .and(warp::post())
+        .and(with_auth(Permission::ManageUsers))
+        .and(warp::body::json())
+        .and_then(create_api_key_handler);
+
+    let delete_api_key = warp::path("delete-api-key")
+        .and(warp::post())
+        .and(with_auth(Permission::ManageUsers))
+        .and(warp::body::json())
+        .and_then(delete_api_key_handler);
+
+    let audit_log = warp::path!("audit-log")
+        .and(warp::get())
+        .and(with_auth(Permission::ManageUsers))
+        .and(warp::query())
+        .and_then(audit_log_handler);
+
+    let export_audit_log = warp::path!("audit-log" / "export")
+        .and(warp::get())
+        .and(with_auth(Permission::ManageUsers))
+        .and(warp::query())
+        .and_then(export_audit_log_handler);
+
     let create_user = warp::path("create-user")
         .and(warp::post())
-        .and(with_auth(Role::Admin))
+        .and(with_auth(Permission::ManageUsers))
         .and(warp::body::json())
         .and_then(create_user_handler);
 
     let save = warp::path("save-diagram");
     let save_routes = save
         .and(warp::post())
+        .and(with_auth(Permission::EditDiagram))
+        .and(warp::header::optional::<String>("if-match"))
         .and(warp::body::json())
         .and_then(save_handler);
 
     let delete = warp::path("delete-diagram");
     let delete_routes = delete
         .and(warp::post())
+        .and(with_auth(Permission::EditDiagram))
         .and(warp::body::json())
         .and_then(delete_handler);
 
     let list = warp::path("get-diagrams");
 
-    let list_routes = list.and(warp::get()).and_then(list_handler);
+    let list_routes = list
+        .and(warp::get())
+        .and(with_auth(Permission::ViewData))
+        .and(warp::query())
+        .and_then(list_handler);
 
     let design = warp::path("get-diagram");
     let design_routes = design
         .and(warp::get())
+        .and(with_auth(Permission::ViewData))
         .and(warp::query())
         .and_then(diagram_handler);
 
+    let diagram_revisions = warp::path!("diagram-revisions")
+        .and(warp::get())
+        .and(with_auth(Permission::ViewData))
+        .and(warp::query())
+        .and_then(diagram_revisions_handler);
+
+    let diagram_revision = warp::path!("diagram-revision")
+        .and(warp::get())
+        .and(with_auth(Permission::ViewData))
+        .and(warp::query())
+        .and_then(diagram_revision_handler);
+
+    let diagram_diff = warp::path!("diagram-diff")
+        .and(warp::get())
+        .and(with_auth(Permission::ViewData))
+        .and(warp::query())
+        .and_then(diagram_diff_handler);
+
+    let revert_diagram = warp::path!("revert-diagram")
+        .and(warp::post())
+        .and(with_auth(Permission::EditDiagram))
+        .and(warp::header::optional::<String>("if-match"))
+        .and(warp::body::json())
+        .and_then(revert_diagram_handler);
+
+    let validate_diagram = warp::path!("validate-diagram")
+        .and(warp::post())
+        .and(with_auth(Permission::EditDiagram))
+        .and(warp::body::json())
+        .and_then(validate_diagram_handler);
+
+    let publish_diagram = warp::path!("publish-diagram")
+        .and(warp::post())
+        .and(with_auth(Permission::PublishDiagram))
+        .and(warp::body::json())
+        .and_then(publish_diagram_handler);
+
+    let export_bundle = warp::path!("bundle" / "export")
+        .and(warp::post())
+        .and(with_auth(Permission::ManageEquipment))
+        .and(warp::body::json())
+        .and_then(export_bundle_handler);
+
+    let preview_bundle = warp::path!("bundle" / "preview")
+        .and(warp::post())
+        .and(with_auth(Permission::ManageEquipment))
+        .and(warp::body::json())
+        .and_then(preview_bundle_handler);
+
+    let import_bundle = warp::path!("bundle" / "import")
+        .and(warp::post())
+        .and(with_auth(Permission::ManageEquipment))
+        .and(warp::body::json())
+        .and_then(import_bundle_handler);
+
+    let render_diagram = warp::path!("render-diagram")
+        .and(warp::get())
+        .and(with_auth(Permission::ViewData))
+        .and(warp::query())
+        .and_then(render_diagram_handler);
+
+    let diagram_templates = warp::path!("diagram-templates")
+        .and(warp::get())
+        .and(with_auth(Permission::EditDiagram))
+        .and_then(diagram_templates_handler);
+
+    let save_diagram_template = warp::path!("diagram-templates" / "save")
+        .and(warp::post())
+        .and(with_auth(Permission::EditDiagram))
+        .and(warp::body::json())
+        .and_then(save_diagram_template_handler);
+
+    let delete_diagram_template = warp::path!("diagram-templates" / "delete")
+        .and(warp::post())
+        .and(with_auth(Permission::EditDiagram))
+        .and(warp::body::json())
+        .and_then(delete_diagram_template_handler);
+
+    let instantiate_diagram_template = warp::path!("diagram-templates" / "instantiate")
+        .and(warp::post())
+        .and(with_auth(Permission::EditDiagram))
+        .and(warp::body::json())
+        .and_then(instantiate_diagram_template_handler);
+
     let update = warp::path!("update-data")
+        .and(with_auth(Permission::IssueControl))
         .and(warp::body::json())
         .and(with_processor(processor.clone()))
         .and(with_hmi(hmi_actor.clone()))
         .and_then(data_handler);
 
+    let control_log = warp::path!("control-log")
+        .and(warp::get())
+        .and(with_auth(Permission::IssueControl))
+        .and(warp::query())
+        .and_then(control_log_handler);
+
     let data_route = warp::path("data")
         .and(warp::ws())
         .and(warp::path::param())
+        .and(with_auth_query(Permission::ViewData))
         .and(with_clients(clients.clone()))
         .and_then(connect_handler);
 
     let equipment_routes = warp::path("equipment-list")
         .and(warp::get())
-        .and(with_auth(Role::Admin))
+        .and(with_auth(Permission::ViewData))
         .and_then(equipment_handler);
 
     let delete_equipment = warp::path("delete-equipment")
         .and(warp::post())
-        .and(with_auth(Role::Admin))
+        .and(with_auth(Permission::ManageEquipment))
         .and(warp::body::json())
         .and_then(delete_equipment_handler);
 
     let update_equipment = warp::path("update-equipment")
         .and(warp::post())
-        .and(with_auth(Role::Admin))
+        .and(with_auth(Permission::ManageEquipment))
+        .and(warp::header::optional::<String>("if-match"))
         .and(warp::body::json())
         .and_then(update_equipment_handler);
 
     let create_equipment = warp::path("create-equipment")
         .and(warp::post())
-        .and(with_auth(Role::Admin))
+        .and(with_auth(Permission::ManageEquipment))
         .and(warp::body::json())
         .and_then(create_equipment_handler);
 
@@ -187,12 +404,13 @@ async fn server_setup() {
             "upgrade",
             "authorization",
             "Accept",
-        ]);
+            "if-match",
+        ])
+        .expose_headers(vec!["etag"]);
 
-    let static_dir = "Client/dist/openfmb-hmi/";
-    let index = "Client/dist/openfmb-hmi/index.html";
+    let index = format!("{}index.html", STATIC_DIR);
 
-    let static_route = warp::fs::dir(static_dir);
+    let static_route = warp::fs::dir(STATIC_DIR);
     let is_spa = true;
 
     // These filters are needed so that when hit "F5" on browser, pages are refreshed correctly
@@ -204,7 +422,7 @@ async fn server_setup() {
                 Err(warp::reject::not_found())
             }
         })
-        .and(warp::fs::file(index))
+        .and(warp::fs::file(index.clone()))
         .map(|_, file| file);
 
     let hmi = warp::path("hmi")
@@ -215,7 +433,7 @@ async fn server_setup() {
                 Err(warp::reject::not_found())
             }
         })
-        .and(warp::fs::file(index))
+        .and(warp::fs::file(index.clone()))
         .map(|_, file| file);
 
     let diagrams = warp::path("diagrams")
@@ -226,7 +444,7 @@ async fn server_setup() {
                 Err(warp::reject::not_found())
             }
         })
-        .and(warp::fs::file(index))
+        .and(warp::fs::file(index.clone()))
         .map(|_, file| file);
 
     let data_connect = warp::path("data-connect")
@@ -237,7 +455,7 @@ async fn server_setup() {
                 Err(warp::reject::not_found())
             }
         })
-        .and(warp::fs::file(index))
+        .and(warp::fs::file(index.clone()))
         .map(|_, file| file);
 
     let designer = warp::path("designer")
@@ -248,7 +466,7 @@ async fn server_setup() {
                 Err(warp::reject::not_found())
             }
         })
-        .and(warp::fs::file(index))
+        .and(warp::fs::file(index.clone()))
         .map(|_, file| file);
 
     let inspector = warp::path("inspector")
@@ -259,7 +477,7 @@ async fn server_setup() {
                 Err(warp::reject::not_found())
             }
         })
-        .and(warp::fs::file(index))
+        .and(warp::fs::file(index.clone()))
         .map(|_, file| file);
 
     let settings = warp::path("settings")
@@ -270,7 +488,7 @@ async fn server_setup() {
                 Err(warp::reject::not_found())
             }
         })
-        .and(warp::fs::file(index))
+        .and(warp::fs::file(index.clone()))
         .map(|_, file| file);
 
     let sessions = warp::path("sessions")
@@ -281,7 +499,7 @@ async fn server_setup() {
                 Err(warp::reject::not_found())
             }
         })
-        .and(warp::fs::file(index))
+        .and(warp::fs::file(index.clone()))
         .map(|_, file| file);
 
     let routes = static_route
@@ -294,11 +512,29 @@ async fn server_setup() {
         .or(settings)
         .or(sessions)
         .or(login_routes)
+        .or(refresh_routes)
+        .or(logout_routes)
+        .or(oidc_config)
+        .or(oidc_login)
+        .or(oidc_callback)
+        .or(two_factor_login)
+        .or(two_factor_setup)
+        .or(two_factor_enable)
+        .or(change_password)
         .or(user_profile)
+        .or(update_profile)
+        .or(update_preferences)
         .or(get_users)
         .or(delete_user)
         .or(update_user)
         .or(create_user)
+        .or(unlock_user)
+        .or(reset_two_factor)
+        .or(get_api_keys)
+        .or(create_api_key)
+        .or(delete_api_key)
+        .or(audit_log)
+        .or(export_audit_log)
         .or(save_routes)
         .or(delete_routes)
         .or(list_routes)
@@ -307,8 +543,24 @@ async fn server_setup() {
         .or(update_equipment)
         .or(create_equipment)
         .or(design_routes)
+        .or(diagram_revisions)
+        .or(diagram_revision)
+        .or(diagram_diff)
+        .or(revert_diagram)
+        .or(validate_diagram)
+        .or(publish_diagram)
+        .or(export_bundle)
+        .or(preview_bundle)
+        .or(import_bundle)
+        .or(render_diagram)
+        .or(diagram_templates)
+        .or(save_diagram_template)
+        .or(delete_diagram_template)
+        .or(instantiate_diagram_template)
         .or(data_route)
         .or(update)
+        .or(control_log)
+        .recover(handle_rejection)
         .with(cors)
         .with(warp::log("warp::server"));
 
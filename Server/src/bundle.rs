@@ -0,0 +1,778 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::audit::record_audit;
+use crate::auth::{has_permission, run_blocking, Permission, User};
+use crate::diagram_history::{author_name, parse_elements};
+use crate::diagram_id::{is_safe_diagram_id, new_diagram_id};
+use crate::diagram_validation::check_before_save;
+use crate::error::Error;
+use crate::handler::{read_equipment_list, Diagram, Equipment};
+use crate::session::end_user_sessions;
+use crate::storage::{storage, Precondition};
+use chrono::prelude::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use warp::{
+    http::header::CONTENT_DISPOSITION, http::header::CONTENT_TYPE, reject, reply, Rejection, Reply,
+};
+
+/// Value of the `format` field of every bundle
+pub const BUNDLE_FORMAT: &str = "openfmb-hmi-bundle";
+
+/// Layout of the bundles written, bundles of later versions are refused
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Diagrams, the equipment they show and optionally users, to move the configuration of
+/// a site from one server to another
+#[derive(Serialize, Deserialize)]
+pub struct Bundle {
+    pub format: String,
+    pub version: u32,
+    /// Unix timestamp in seconds
+    pub exported_at: i64,
+    /// Username of the exporting user
+    #[serde(default)]
+    pub exported_by: String,
+    /// Latest drafts of the diagrams
+    #[serde(default)]
+    pub diagrams: Vec<Diagram>,
+    #[serde(default)]
+    pub equipment: Vec<Equipment>,
+    /// Users without their password hashes and second factor secrets
+    #[serde(default)]
+    pub users: Vec<User>,
+}
+
+#[derive(Deserialize)]
+pub struct ExportBundleRequest {
+    /// Defaults to all diagrams
+    #[serde(default)]
+    pub diagram_ids: Option<Vec<String>>,
+    #[serde(default)]
+    pub include_users: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemKind {
+    Diagram,
+    Equipment,
+    User,
+}
+
+impl fmt::Display for ItemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ItemKind::Diagram => write!(f, "diagram"),
+            ItemKind::Equipment => write!(f, "equipment"),
+            ItemKind::User => write!(f, "user"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeStrategy {
+    /// Adds an item that doesn't exist yet
+    Import,
+    /// Leaves the item out
+    Skip,
+    /// Overwrites the existing item, users keep their password and second factor
+    Replace,
+    /// Adds the diagram next to the existing one, under a new id
+    Copy,
+}
+
+/// An item of the bundle and how it can be merged
+#[derive(Serialize)]
+pub struct BundleItem {
+    pub kind: ItemKind,
+    /// Diagram id, MRID or user id
+    pub id: String,
+    pub name: String,
+    /// What the item collides with, `None` for new items
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflict: Option<String>,
+    /// Strategies that can be chosen for the item, the first one is the default
+    pub strategies: Vec<MergeStrategy>,
+}
+
+#[derive(Serialize)]
+pub struct BundlePreview {
+    pub exported_at: i64,
+    pub exported_by: String,
+    pub items: Vec<BundleItem>,
+}
+
+#[derive(Deserialize)]
+pub struct BundleChoice {
+    pub kind: ItemKind,
+    pub id: String,
+    pub strategy: MergeStrategy,
+}
+
+#[derive(Deserialize)]
+pub struct ImportBundleRequest {
+    pub bundle: Bundle,
+    /// Items left out are merged with their default strategy
+    #[serde(default)]
+    pub choices: Vec<BundleChoice>,
+}
+
+/// What was done with an item of the bundle
+#[derive(Serialize)]
+pub struct ImportedItem {
+    pub kind: ItemKind,
+    pub id: String,
+    pub name: String,
+    pub strategy: MergeStrategy,
+    /// Id of a diagram imported as a copy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_id: Option<String>,
+    /// Why the item wasn't imported, the other items are imported anyway
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BundleImportResult {
+    pub items: Vec<ImportedItem>,
+}
+
+/// Refuses files that aren't bundles, bundles of later versions and bundles with an item
+/// twice
+fn check_bundle(bundle: &Bundle) -> Result<(), Error> {
+    if bundle.format != BUNDLE_FORMAT {
+        return Err(Error::InvalidRequestError(
+            "the file is not a configuration bundle".to_string(),
+        ));
+    }
+    if bundle.version > BUNDLE_VERSION {
+        return Err(Error::InvalidRequestError(format!(
+            "bundle version {} is newer than this server",
+            bundle.version
+        )));
+    }
+    let ids = bundle
+        .diagrams
+        .iter()
+        .map(|d| (ItemKind::Diagram, d.diagramId.as_str()))
+        .chain(
+            bundle
+                .equipment
+                .iter()
+                .map(|eq| (ItemKind::Equipment, eq.mrid.as_str())),
+        )
+        .chain(bundle.users.iter().map(|u| (ItemKind::User, u.id.as_str())));
+    let mut seen = HashSet::new();
+    for (kind, id) in ids {
+        if !seen.insert((kind, id)) {
+            return Err(Error::InvalidRequestError(format!(
+                "{} {} is in the bundle more than once",
+                kind, id
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// What the items of a bundle can collide with on this server
+struct ExistingItems {
+    mrids: HashSet<String>,
+    diagram_ids: HashSet<String>,
+    users: Vec<User>,
+}
+
+impl ExistingItems {
+    fn read() -> Result<ExistingItems, Error> {
+        Ok(ExistingItems {
+            mrids: read_equipment_list()?
+                .into_iter()
+                .map(|eq| eq.mrid)
+                .collect(),
+            diagram_ids: storage()
+                .diagram_summaries(false)?
+                .into_iter()
+                .map(|d| d.diagramId)
+                .collect(),
+            users: storage().users()?,
+        })
+    }
+
+    /// The user with the id or, failing that, with the username, which ignores case
+    fn user(&self, user: &User) -> Option<&User> {
+        self.users.iter().find(|u| u.id == user.id).or_else(|| {
+            self.users
+                .iter()
+                .find(|u| u.username.eq_ignore_ascii_case(&user.username))
+        })
+    }
+}
+
+/// The items of the bundle, in the order they are imported, with what they collide with
+fn preview(bundle: &Bundle) -> Result<Vec<BundleItem>, Error> {
+    Ok(bundle_items(bundle, &ExistingItems::read()?))
+}
+
+fn bundle_items(bundle: &Bundle, existing: &ExistingItems) -> Vec<BundleItem> {
+    let mut items = vec![];
+
+    for eq in bundle.equipment.iter() {
+        let (conflict, strategies) = if existing.mrids.contains(&eq.mrid) {
+            (
+                Some("equipment with the MRID exists".to_string()),
+                vec![MergeStrategy::Skip, MergeStrategy::Replace],
+            )
+        } else {
+            (None, vec![MergeStrategy::Import, MergeStrategy::Skip])
+        };
+        items.push(BundleItem {
+            kind: ItemKind::Equipment,
+            id: eq.mrid.clone(),
+            name: eq.name.clone(),
+            conflict,
+            strategies,
+        });
+    }
+
+    for user in bundle.users.iter() {
+        let (conflict, strategies) = match existing.user(user) {
+            Some(existing) if existing.id == user.id => (
+                Some("a user with the id exists".to_string()),
+                vec![MergeStrategy::Skip, MergeStrategy::Replace],
+            ),
+            Some(existing) => (
+                Some(format!("user {} has the username", existing.id)),
+                vec![MergeStrategy::Skip, MergeStrategy::Replace],
+            ),
+            None => (None, vec![MergeStrategy::Import, MergeStrategy::Skip]),
+        };
+        items.push(BundleItem {
+            kind: ItemKind::User,
+            id: user.id.clone(),
+            name: user.username.clone(),
+            conflict,
+            strategies,
+        });
+    }
+
+    for diagram in bundle.diagrams.iter() {
+        let (conflict, strategies) = if !is_safe_diagram_id(&diagram.diagramId) {
+            (
+                Some("the id is not safe, the diagram can only be copied".to_string()),
+                vec![MergeStrategy::Copy, MergeStrategy::Skip],
+            )
+        } else if existing.diagram_ids.contains(&diagram.diagramId) {
+            (
+                Some("a diagram with the id exists".to_string()),
+                vec![
+                    MergeStrategy::Skip,
+                    MergeStrategy::Replace,
+                    MergeStrategy::Copy,
+                ],
+            )
+        } else {
+            (None, vec![MergeStrategy::Import, MergeStrategy::Skip])
+        };
+        items.push(BundleItem {
+            kind: ItemKind::Diagram,
+            id: diagram.diagramId.clone(),
+            name: diagram.name.clone().unwrap_or_default(),
+            conflict,
+            strategies,
+        });
+    }
+    items
+}
+
+/// The user with the id or, failing that, with the username
+fn existing_user(user: &User) -> Result<Option<User>, Error> {
+    match storage().user(&user.id)? {
+        Some(existing) => Ok(Some(existing)),
+        None => storage().user_by_username(&user.username),
+    }
+}
+
+/// The chosen strategy of the item, its default when none was chosen
+fn strategy_of(
+    choices: &HashMap<(ItemKind, &str), MergeStrategy>,
+    item: &BundleItem,
+) -> Result<MergeStrategy, Error> {
+    match choices.get(&(item.kind, item.id.as_str())) {
+        Some(strategy) if item.strategies.contains(strategy) => Ok(*strategy),
+        Some(strategy) => Err(Error::InvalidRequestError(format!(
+            "{:?} can't be used for {} {}",
+            strategy, item.kind, item.id
+        ))),
+        None => Ok(item.strategies[0]),
+    }
+}
+
+/// Equipment shown by the diagrams, diagrams whose data can't be read show none
+fn referenced_equipment(diagrams: &[Diagram]) -> Result<Vec<Equipment>, Error> {
+    let mut mrids = HashSet::new();
+    for diagram in diagrams.iter() {
+        match parse_elements(&diagram.data) {
+            Ok(elements) => mrids.extend(elements.into_values().filter_map(|e| e.mrid)),
+            Err(e) => warn!(
+                "Equipment of diagram {} not exported, its data can't be read: {}",
+                diagram.diagramId, e
+            ),
+        }
+    }
+    Ok(read_equipment_list()?
+        .into_iter()
+        .filter(|eq| mrids.contains(&eq.mrid))
+        .map(|mut eq| {
+            eq.version = None;
+            eq
+        })
+        .collect())
+}
+
+fn export_bundle(id: &str, request: &ExportBundleRequest) -> Result<Bundle, Error> {
+    let diagram_ids = match &request.diagram_ids {
+        Some(ids) => ids.clone(),
+        None => storage()
+            .diagram_summaries(false)?
+            .into_iter()
+            .filter(|d| d.error.is_none())
+            .map(|d| d.diagramId)
+            .collect(),
+    };
+    let mut diagrams = vec![];
+    for diagram_id in diagram_ids.iter() {
+        let mut diagram = storage()
+            .diagram(diagram_id)?
+            .ok_or_else(|| Error::NotFoundError(format!("diagram {}", diagram_id)))?;
+        diagram.version = None;
+        diagram.published_version = None;
+        diagrams.push(diagram);
+    }
+    let users = if request.include_users {
+        storage()
+            .users()?
+            .iter()
+            .map(|usr| usr.without_secrets())
+            .collect()
+    } else {
+        vec![]
+    };
+
+    Ok(Bundle {
+        format: BUNDLE_FORMAT.to_string(),
+        version: BUNDLE_VERSION,
+        exported_at: Utc::now().timestamp(),
+        exported_by: author_name(id),
+        equipment: referenced_equipment(&diagrams)?,
+        diagrams,
+        users,
+    })
+}
+
+fn import_equipment(id: &str, eq: &Equipment, strategy: MergeStrategy) -> Result<(), Error> {
+    let mut eq = eq.clone();
+    eq.version = None;
+    if strategy == MergeStrategy::Replace {
+        if let Some((before, _)) = storage().update_equipment(&eq, Precondition::Any)? {
+            record_audit(id, "equipment.update", &eq.mrid, Some(&before), Some(&eq));
+            return Ok(());
+        }
+    }
+    storage().insert_equipment(&eq)?;
+    record_audit(id, "equipment.create", &eq.mrid, None, Some(&eq));
+    Ok(())
+}
+
+/// New users have no password, an administrator has to set one before they can log in.
+/// Replaced users keep their username, password and second factor.
+fn import_user(id: &str, user: &User, strategy: MergeStrategy) -> Result<(), Error> {
+    if strategy == MergeStrategy::Replace {
+        if let Some(existing) = existing_user(user)? {
+            let mut before = None;
+            let mut role_changed = false;
+            let after = storage().update_user(&existing.id, &mut |usr| {
+                before = Some(usr.without_secrets());
+                role_changed = usr.role != user.role;
+                usr.displayname = user.displayname.clone();
+                usr.role = user.role.clone();
+                usr.control_scope = user.control_scope.clone();
+                usr.preferences = user.preferences.clone();
+                Ok(())
+            })?;
+            if let (Some(before), Some(after)) = (before, after) {
+                // Existing tokens still carry the old role
+                if role_changed {
+                    end_user_sessions(&after.id);
+                }
+                record_audit(
+                    id,
+                    "user.update",
+                    &after.id,
+                    Some(&before),
+                    Some(&after.without_secrets()),
+                );
+            }
+            return Ok(());
+        }
+    }
+    let mut usr = user.without_secrets();
+    usr.locked = false;
+    usr.two_factor_enabled = false;
+    usr.must_change_password = usr.provider.is_none();
+    storage().insert_user(&usr)?;
+    record_audit(id, "user.create", &usr.id, None, Some(&usr));
+    Ok(())
+}
+
+/// Resolves to the id of a diagram imported as a copy
+fn import_diagram(
+    id: &str,
+    diagram: &Diagram,
+    strategy: MergeStrategy,
+) -> Result<Option<String>, Error> {
+    let mut diagram = diagram.clone();
+    diagram.version = None;
+    diagram.published_version = None;
+    let (precondition, new_id) = match strategy {
+        MergeStrategy::Replace => (Precondition::Any, None),
+        MergeStrategy::Copy => (Precondition::New, Some(new_diagram_id())),
+        _ => (Precondition::New, None),
+    };
+    if let Some(new_id) = &new_id {
+        diagram.diagramId = new_id.clone();
+    }
+    check_before_save(&diagram)?;
+    let (before, _) = storage().save_diagram(&diagram, id, &author_name(id), precondition)?;
+    record_audit(
+        id,
+        if before.is_some() {
+            "diagram.update"
+        } else {
+            "diagram.create"
+        },
+        &diagram.diagramId,
+        before.as_ref(),
+        Some(&diagram),
+    );
+    Ok(new_id)
+}
+
+fn import_bundle(id: &str, request: &ImportBundleRequest) -> Result<Vec<ImportedItem>, Error> {
+    let bundle = &request.bundle;
+    check_bundle(bundle)?;
+    let choices: HashMap<(ItemKind, &str), MergeStrategy> = request
+        .choices
+        .iter()
+        .map(|c| ((c.kind, c.id.as_str()), c.strategy))
+        .collect();
+    let items = preview(bundle)?;
+
+    // Every choice is checked before anything is written
+    let mut planned = vec![];
+    for item in items {
+        let strategy = strategy_of(&choices, &item)?;
+        if strategy == MergeStrategy::Skip {
+            continue;
+        }
+        let permission = match item.kind {
+            ItemKind::Diagram => Permission::EditDiagram,
+            ItemKind::Equipment => Permission::ManageEquipment,
+            ItemKind::User => Permission::ManageUsers,
+        };
+        if !has_permission(id, permission) {
+            return Err(Error::NoPermissionError);
+        }
+        planned.push((item, strategy));
+    }
+
+    // Each item is written on its own, an item that fails is reported and the others are
+    // still imported
+    let mut imported = vec![];
+    for (item, strategy) in planned {
+        let result = match item.kind {
+            ItemKind::Equipment => match bundle.equipment.iter().find(|eq| eq.mrid == item.id) {
+                Some(eq) => import_equipment(id, eq, strategy).map(|_| None),
+                None => Ok(None),
+            },
+            ItemKind::User => match bundle.users.iter().find(|u| u.id == item.id) {
+                Some(user) => import_user(id, user, strategy).map(|_| None),
+                None => Ok(None),
+            },
+            ItemKind::Diagram => match bundle.diagrams.iter().find(|d| d.diagramId == item.id) {
+                Some(diagram) => import_diagram(id, diagram, strategy),
+                None => Ok(None),
+            },
+        };
+        let (new_id, error) = match result {
+            Ok(new_id) => (new_id, None),
+            Err(e) => {
+                warn!(
+                    "{} {} of the bundle not imported: {}",
+                    item.kind, item.id, e
+                );
+                (None, Some(e.to_string()))
+            }
+        };
+        imported.push(ImportedItem {
+            kind: item.kind,
+            id: item.id,
+            name: item.name,
+            strategy,
+            new_id,
+            error,
+        });
+    }
+    Ok(imported)
+}
+
+// POST
+/// Bundle of the diagrams, the equipment they show and optionally the users, as a file
+pub async fn export_bundle_handler(
+    id: String,
+    request: ExportBundleRequest,
+) -> Result<impl Reply, Rejection> {
+    if request.include_users && !has_permission(&id, Permission::ManageUsers) {
+        return Err(reject::custom(Error::NoPermissionError));
+    }
+    let author = id.clone();
+    let bundle = run_blocking(move || export_bundle(&author, &request))
+        .await
+        .map_err(|e| reject::custom(e))?;
+    let body = serde_json::to_string_pretty(&bundle).map_err(|e| reject::custom(Error::from(e)))?;
+    record_audit(
+        &id,
+        "bundle.export",
+        "bundle",
+        None,
+        Some(&json!({
+            "diagrams": bundle.diagrams.iter().map(|d| &d.diagramId).collect::<Vec<_>>(),
+            "equipment": bundle.equipment.len(),
+            "users": bundle.users.len(),
+        })),
+    );
+
+    let file_name = format!("hmi-bundle-{}.json", Utc::now().format("%Y%m%d%H%M%S"));
+    Ok(reply::with_header(
+        reply::with_header(body, CONTENT_TYPE, "application/json"),
+        CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", file_name),
+    ))
+}
+
+// POST
+/// Items of the bundle with their conflicts, nothing is imported
+pub async fn preview_bundle_handler(_id: String, bundle: Bundle) -> Result<impl Reply, Rejection> {
+    let (bundle, items) = run_blocking(move || {
+        check_bundle(&bundle)?;
+        let items = preview(&bundle)?;
+        Ok((bundle, items))
+    })
+    .await
+    .map_err(|e| reject::custom(e))?;
+    Ok(reply::json(&BundlePreview {
+        exported_at: bundle.exported_at,
+        exported_by: bundle.exported_by,
+        items,
+    }))
+}
+
+// POST
+/// Imports the items of the bundle with the chosen strategies, equipment and users before
+/// the diagrams showing them. Diagrams are imported as drafts. Items that can't be
+/// imported are reported with their error.
+pub async fn import_bundle_handler(
+    id: String,
+    request: ImportBundleRequest,
+) -> Result<impl Reply, Rejection> {
+    let author = id.clone();
+    let (request, items) = run_blocking(move || {
+        let items = import_bundle(&author, &request)?;
+        Ok((request, items))
+    })
+    .await
+    .map_err(|e| reject::custom(e))?;
+    record_audit::<Value>(
+        &id,
+        "bundle.import",
+        "bundle",
+        None,
+        Some(&json!({
+            "exported_at": request.bundle.exported_at,
+            "exported_by": request.bundle.exported_by,
+            "items": items.len(),
+            "failed": items.iter().filter(|item| item.error.is_some()).count(),
+        })),
+    );
+    Ok(reply::json(&BundleImportResult { items }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle() -> Bundle {
+        serde_json::from_value(json!({
+            "format": BUNDLE_FORMAT,
+            "version": BUNDLE_VERSION,
+            "exported_at": 0,
+            "diagrams": [
+                {"diagramId": "new-diagram", "name": "New"},
+                {"diagramId": "feeder", "name": "Feeder"},
+                {"diagramId": "feeder 2", "name": "Unsafe"},
+            ],
+            "equipment": [
+                {"mrid": "m1", "name": "Breaker", "deviceType": "breaker"},
+                {"mrid": "m2", "name": "Solar", "deviceType": "solar"},
+            ],
+            "users": [
+                {"id": "u1", "username": "admin", "displayname": "", "role": "Admin"},
+                {"id": "u2", "username": "Operator", "displayname": "", "role": "Viewer"},
+                {"id": "u3", "username": "engineer", "displayname": "", "role": "Engineer"},
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn existing() -> ExistingItems {
+        ExistingItems {
+            mrids: vec!["m1".to_string()].into_iter().collect(),
+            diagram_ids: vec!["feeder".to_string()].into_iter().collect(),
+            users: serde_json::from_value(json!([
+                {"id": "u1", "username": "admin", "displayname": "", "role": "Admin"},
+                {"id": "u9", "username": "operator", "displayname": "", "role": "Viewer"},
+            ]))
+            .unwrap(),
+        }
+    }
+
+    fn item<'a>(items: &'a [BundleItem], kind: ItemKind, id: &str) -> &'a BundleItem {
+        items
+            .iter()
+            .find(|item| item.kind == kind && item.id == id)
+            .unwrap()
+    }
+
+    #[test]
+    fn items_are_listed_in_import_order() {
+        let items = bundle_items(&bundle(), &existing());
+        let kinds: Vec<ItemKind> = items.iter().map(|item| item.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ItemKind::Equipment,
+                ItemKind::Equipment,
+                ItemKind::User,
+                ItemKind::User,
+                ItemKind::User,
+                ItemKind::Diagram,
+                ItemKind::Diagram,
+                ItemKind::Diagram,
+            ]
+        );
+    }
+
+    #[test]
+    fn new_items_are_imported_by_default() {
+        let items = bundle_items(&bundle(), &existing());
+        for (kind, id) in &[
+            (ItemKind::Equipment, "m2"),
+            (ItemKind::User, "u3"),
+            (ItemKind::Diagram, "new-diagram"),
+        ] {
+            let item = item(&items, *kind, id);
+            assert!(item.conflict.is_none());
+            assert_eq!(
+                item.strategies,
+                vec![MergeStrategy::Import, MergeStrategy::Skip]
+            );
+        }
+    }
+
+    #[test]
+    fn conflicting_items_are_skipped_by_default() {
+        let items = bundle_items(&bundle(), &existing());
+        let eq = item(&items, ItemKind::Equipment, "m1");
+        assert!(eq.conflict.is_some());
+        assert_eq!(
+            eq.strategies,
+            vec![MergeStrategy::Skip, MergeStrategy::Replace]
+        );
+
+        let same_id = item(&items, ItemKind::User, "u1");
+        assert_eq!(
+            same_id.conflict.as_deref(),
+            Some("a user with the id exists")
+        );
+        // Usernames are compared ignoring case
+        let same_username = item(&items, ItemKind::User, "u2");
+        assert_eq!(
+            same_username.conflict.as_deref(),
+            Some("user u9 has the username")
+        );
+        assert_eq!(
+            same_username.strategies,
+            vec![MergeStrategy::Skip, MergeStrategy::Replace]
+        );
+
+        let diagram = item(&items, ItemKind::Diagram, "feeder");
+        assert!(diagram.conflict.is_some());
+        assert_eq!(
+            diagram.strategies,
+            vec![
+                MergeStrategy::Skip,
+                MergeStrategy::Replace,
+                MergeStrategy::Copy
+            ]
+        );
+    }
+
+    #[test]
+    fn diagrams_with_unsafe_ids_can_only_be_copied() {
+        let items = bundle_items(&bundle(), &existing());
+        let diagram = item(&items, ItemKind::Diagram, "feeder 2");
+        assert!(diagram.conflict.is_some());
+        assert_eq!(
+            diagram.strategies,
+            vec![MergeStrategy::Copy, MergeStrategy::Skip]
+        );
+    }
+
+    #[test]
+    fn choices_have_to_be_allowed_for_the_item() {
+        let items = bundle_items(&bundle(), &existing());
+        let diagram = item(&items, ItemKind::Diagram, "feeder");
+        let mut choices = HashMap::new();
+        assert_eq!(strategy_of(&choices, diagram).unwrap(), MergeStrategy::Skip);
+        choices.insert((ItemKind::Diagram, "feeder"), MergeStrategy::Copy);
+        assert_eq!(strategy_of(&choices, diagram).unwrap(), MergeStrategy::Copy);
+        choices.insert((ItemKind::Diagram, "feeder"), MergeStrategy::Import);
+        assert!(matches!(
+            strategy_of(&choices, diagram),
+            Err(Error::InvalidRequestError(_))
+        ));
+    }
+
+    #[test]
+    fn foreign_newer_and_repeated_bundles_are_refused() {
+        assert!(check_bundle(&bundle()).is_ok());
+
+        let mut foreign = bundle();
+        foreign.format = "something-else".to_string();
+        assert!(check_bundle(&foreign).is_err());
+
+        let mut newer = bundle();
+        newer.version = BUNDLE_VERSION + 1;
+        assert!(check_bundle(&newer).is_err());
+
+        let mut repeated = bundle();
+        let eq = repeated.equipment[0].clone();
+        repeated.equipment.push(eq);
+        assert!(check_bundle(&repeated).is_err());
+    }
+}
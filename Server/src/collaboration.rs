@@ -0,0 +1,966 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Edit sessions of designers working on the same diagram over the `/data` websocket.
+//!
+//! The server keeps the working copy of each diagram being edited. Designers send
+//! element-level operations, which are applied to the working copy in the order they
+//! arrive and broadcast to every participant, the sender included. Elements can be locked
+//! by one participant at a time, and the working copy is only saved to the diagram store
+//! when a participant asks for it.
+
+use crate::audit::record_audit;
+use crate::auth::{get_user, has_permission, run_blocking, Permission};
+use crate::diagram_history::author_name;
+use crate::diagram_validation::check_before_save;
+use crate::error::Error;
+use crate::handler::{Clients, Diagram};
+use crate::storage::{storage, Precondition};
+use lazy_static::lazy_static;
+use log::{error, info};
+use roxmltree::{Document, Node};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use warp::ws::Message;
+
+lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<String, EditSession>> = Mutex::new(HashMap::new());
+    static ref LOCK_TIMEOUT: Duration = Duration::from_secs(
+        riker::load_config()
+            .get_int("diagrams.edit_lock_timeout")
+            .unwrap_or(DEFAULT_LOCK_TIMEOUT)
+            .max(1) as u64
+    );
+}
+
+const DEFAULT_LOCK_TIMEOUT: i64 = 120; // seconds
+
+/// Model of a diagram without any cells but the default parents
+const EMPTY_MODEL: &str =
+    "<mxGraphModel><root><mxCell id=\"0\"/><mxCell id=\"1\" parent=\"0\"/></root></mxGraphModel>";
+
+/// Messages of designers, told apart from topic registrations by their `type`
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EditRequest {
+    /// Starts editing the diagram, answered with the session state
+    Join {
+        diagram_id: String,
+    },
+    Leave {
+        diagram_id: String,
+    },
+    /// Elements selected by the designer, shown to the other participants
+    Select {
+        diagram_id: String,
+        element_ids: Vec<String>,
+    },
+    Lock {
+        diagram_id: String,
+        element_id: String,
+    },
+    Unlock {
+        diagram_id: String,
+        element_id: String,
+    },
+    Operation {
+        diagram_id: String,
+        operation: ElementOperation,
+    },
+    /// Saves the working copy as a new revision, `force` saves over revisions saved by
+    /// others since the session started
+    Save {
+        diagram_id: String,
+        #[serde(default)]
+        force: bool,
+    },
+}
+
+impl EditRequest {
+    fn diagram_id(&self) -> &str {
+        match self {
+            EditRequest::Join { diagram_id }
+            | EditRequest::Leave { diagram_id }
+            | EditRequest::Select { diagram_id, .. }
+            | EditRequest::Lock { diagram_id, .. }
+            | EditRequest::Unlock { diagram_id, .. }
+            | EditRequest::Operation { diagram_id, .. }
+            | EditRequest::Save { diagram_id, .. } => diagram_id,
+        }
+    }
+}
+
+/// Change of a single cell of the mxGraph model. `xml` is the cell as encoded by mxCodec.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ElementOperation {
+    Add {
+        element_id: String,
+        xml: String,
+    },
+    Update {
+        element_id: String,
+        xml: String,
+    },
+    /// Removes the element and the elements it contains
+    Remove {
+        element_id: String,
+    },
+}
+
+impl ElementOperation {
+    fn element_id(&self) -> &str {
+        match self {
+            ElementOperation::Add { element_id, .. }
+            | ElementOperation::Update { element_id, .. }
+            | ElementOperation::Remove { element_id } => element_id,
+        }
+    }
+}
+
+/// Messages sent to designers
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EditEvent {
+    /// Working copy of the diagram for a participant who joined
+    SessionState {
+        diagram_id: String,
+        /// Revision the working copy is based on
+        version: Option<i64>,
+        data: String,
+        /// Number of the last applied operation
+        sequence: u64,
+        participants: Vec<Participant>,
+        locks: Vec<ElementLock>,
+    },
+    ParticipantJoined {
+        diagram_id: String,
+        participant: Participant,
+    },
+    ParticipantLeft {
+        diagram_id: String,
+        client_id: String,
+    },
+    Selection {
+        diagram_id: String,
+        client_id: String,
+        element_ids: Vec<String>,
+    },
+    Locked {
+        diagram_id: String,
+        lock: ElementLock,
+    },
+    Unlocked {
+        diagram_id: String,
+        element_id: String,
+    },
+    /// The element is locked by someone else
+    LockDenied {
+        diagram_id: String,
+        lock: ElementLock,
+    },
+    /// Applied operation, in the order of `sequence`
+    Operation {
+        diagram_id: String,
+        sequence: u64,
+        client_id: String,
+        operation: ElementOperation,
+    },
+    /// The operation was not applied, `current` is the element in the working copy
+    OperationRejected {
+        diagram_id: String,
+        operation: ElementOperation,
+        reason: String,
+        current: Option<String>,
+    },
+    Saved {
+        diagram_id: String,
+        version: i64,
+        user_name: String,
+    },
+    EditError {
+        diagram_id: String,
+        code: String,
+        message: String,
+        /// Current revision of the diagram, for version conflicts
+        version: Option<i64>,
+    },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Participant {
+    /// Websocket session of the designer, a user can take part more than once
+    pub client_id: String,
+    pub user_id: String,
+    pub name: String,
+    pub selection: Vec<String>,
+}
+
+/// Soft lock of an element, released by its holder, when the holder leaves or after
+/// `diagrams.edit_lock_timeout` seconds without changes by the holder
+#[derive(Serialize, Debug, Clone)]
+pub struct ElementLock {
+    pub element_id: String,
+    pub client_id: String,
+    pub user_id: String,
+    pub name: String,
+    #[serde(skip)]
+    acquired: Instant,
+}
+
+impl ElementLock {
+    fn is_expired(&self) -> bool {
+        self.acquired.elapsed() > *LOCK_TIMEOUT
+    }
+}
+
+struct EditSession {
+    /// The diagram as last loaded or saved, `version` is the revision it was read at
+    diagram: Diagram,
+    document: GraphDocument,
+    participants: BTreeMap<String, Participant>,
+    locks: HashMap<String, ElementLock>,
+    sequence: u64,
+}
+
+impl EditSession {
+    fn open(diagram_id: &str) -> Result<EditSession, Error> {
+        let diagram = storage()
+            .diagram(diagram_id)?
+            .ok_or_else(|| Error::NotFoundError(format!("diagram {}", diagram_id)))?;
+        let document = GraphDocument::parse(diagram.data.as_deref()).map_err(|e| {
+            Error::InvalidRequestError(format!("diagram can't be edited together: {}", e))
+        })?;
+        Ok(EditSession {
+            diagram,
+            document,
+            participants: BTreeMap::new(),
+            locks: HashMap::new(),
+            sequence: 0,
+        })
+    }
+
+    /// Lock of another participant on the element
+    fn foreign_lock(&self, element_id: &str, client_id: &str) -> Option<&ElementLock> {
+        self.locks
+            .get(element_id)
+            .filter(|lock| lock.client_id != client_id && !lock.is_expired())
+    }
+
+    /// Lock of another participant on an element the operation changes. A removal also
+    /// changes the elements it removes with the element.
+    fn operation_lock(
+        &self,
+        operation: &ElementOperation,
+        client_id: &str,
+    ) -> Option<&ElementLock> {
+        let element_ids = match operation {
+            ElementOperation::Remove { element_id } => self.document.contained_ids(element_id),
+            _ => vec![operation.element_id().to_string()],
+        };
+        element_ids
+            .iter()
+            .find_map(|element_id| self.foreign_lock(element_id, client_id))
+    }
+
+    fn state(&self) -> EditEvent {
+        EditEvent::SessionState {
+            diagram_id: self.diagram.diagramId.clone(),
+            version: self.diagram.version,
+            data: self.document.to_data(),
+            sequence: self.sequence,
+            participants: self.participants.values().cloned().collect(),
+            locks: self
+                .locks
+                .values()
+                .filter(|lock| !lock.is_expired())
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Queues the event for every participant but `except`
+    fn broadcast(&self, event: EditEvent, except: Option<&str>, outbox: &mut Outbox) {
+        for client_id in self.participants.keys() {
+            if Some(client_id.as_str()) != except {
+                outbox.push((client_id.clone(), event.clone()));
+            }
+        }
+    }
+
+    /// Removes the participant and releases its locks
+    fn leave(&mut self, client_id: &str, outbox: &mut Outbox) {
+        if self.participants.remove(client_id).is_none() {
+            return;
+        }
+        let diagram_id = self.diagram.diagramId.clone();
+        let released: Vec<String> = self
+            .locks
+            .values()
+            .filter(|lock| lock.client_id == client_id)
+            .map(|lock| lock.element_id.clone())
+            .collect();
+        for element_id in released {
+            self.locks.remove(&element_id);
+            self.broadcast(
+                EditEvent::Unlocked {
+                    diagram_id: diagram_id.clone(),
+                    element_id,
+                },
+                None,
+                outbox,
+            );
+        }
+        self.broadcast(
+            EditEvent::ParticipantLeft {
+                diagram_id,
+                client_id: client_id.to_string(),
+            },
+            None,
+            outbox,
+        );
+    }
+}
+
+/// Events to send, with the client to send them to
+type Outbox = Vec<(String, EditEvent)>;
+
+/// Handles a message of a designer and sends the resulting events
+pub async fn handle_edit_request(
+    client_id: &str,
+    user_id: &str,
+    request: EditRequest,
+    clients: &Clients,
+) {
+    let diagram_id = request.diagram_id().to_string();
+    // Joining and saving read and write the database
+    let (client, user) = (client_id.to_string(), user_id.to_string());
+    let outbox = match run_blocking(move || process(&client, &user, request)).await {
+        Ok(outbox) => outbox,
+        Err(e) => {
+            if e.status().is_server_error() {
+                error!("Edit request of {} failed: {}", client_id, e);
+            }
+            let version = match &e {
+                Error::VersionConflictError { version, .. } => *version,
+                _ => None,
+            };
+            vec![(
+                client_id.to_string(),
+                EditEvent::EditError {
+                    diagram_id,
+                    code: e.code().to_string(),
+                    message: e.message(),
+                    version,
+                },
+            )]
+        }
+    };
+    deliver(outbox, clients).await;
+}
+
+/// Takes the client out of the sessions it joined, when its websocket closes
+pub async fn client_disconnected(client_id: &str, clients: &Clients) {
+    let mut outbox = vec![];
+    if let Ok(mut sessions) = SESSIONS.lock() {
+        for session in sessions.values_mut() {
+            session.leave(client_id, &mut outbox);
+        }
+        sessions.retain(|_, session| !session.participants.is_empty());
+    }
+    deliver(outbox, clients).await;
+}
+
+fn process(client_id: &str, user_id: &str, request: EditRequest) -> Result<Outbox, Error> {
+    // Participants work on the draft of the diagram
+    match &request {
+        EditRequest::Join { .. }
+        | EditRequest::Lock { .. }
+        | EditRequest::Operation { .. }
+        | EditRequest::Save { .. } => check_permission(user_id, Permission::EditDiagram)?,
+        _ => {}
+    }
+    match request {
+        EditRequest::Join { diagram_id } => join(client_id, user_id, &diagram_id),
+        EditRequest::Save { diagram_id, force } => save(client_id, user_id, &diagram_id, force),
+        request => edit(client_id, user_id, request),
+    }
+}
+
+/// The edit sessions, held only while a session is changed. The database is read and
+/// written without holding them.
+fn lock_sessions() -> Result<MutexGuard<'static, HashMap<String, EditSession>>, Error> {
+    SESSIONS
+        .lock()
+        .map_err(|_| Error::StorageError("edit sessions poisoned".to_string()))
+}
+
+/// The session of the diagram, when the client takes part in it
+fn joined_session<'a>(
+    sessions: &'a mut HashMap<String, EditSession>,
+    diagram_id: &str,
+    client_id: &str,
+) -> Result<&'a mut EditSession, Error> {
+    match sessions.get_mut(diagram_id) {
+        Some(session) if session.participants.contains_key(client_id) => Ok(session),
+        _ => Err(Error::InvalidRequestError(format!(
+            "not editing diagram {}",
+            diagram_id
+        ))),
+    }
+}
+
+fn join(client_id: &str, user_id: &str, diagram_id: &str) -> Result<Outbox, Error> {
+    let name = user_name(user_id);
+    let mut outbox = vec![];
+
+    let mut sessions = lock_sessions()?;
+    if !sessions.contains_key(diagram_id) {
+        drop(sessions);
+        let opened = EditSession::open(diagram_id)?;
+        sessions = lock_sessions()?;
+        // Another designer may have started the session meanwhile, theirs is kept
+        if !sessions.contains_key(diagram_id) {
+            sessions.insert(diagram_id.to_string(), opened);
+            info!("Edit session of diagram {} started", diagram_id);
+        }
+    }
+    let session = sessions.get_mut(diagram_id).unwrap();
+    if !session.participants.contains_key(client_id) {
+        let participant = Participant {
+            client_id: client_id.to_string(),
+            user_id: user_id.to_string(),
+            name,
+            selection: vec![],
+        };
+        session.broadcast(
+            EditEvent::ParticipantJoined {
+                diagram_id: diagram_id.to_string(),
+                participant: participant.clone(),
+            },
+            None,
+            &mut outbox,
+        );
+        session
+            .participants
+            .insert(client_id.to_string(), participant);
+    }
+    outbox.push((client_id.to_string(), session.state()));
+    Ok(outbox)
+}
+
+fn save(client_id: &str, user_id: &str, diagram_id: &str, force: bool) -> Result<Outbox, Error> {
+    let (mut diagram, user_name) = {
+        let mut sessions = lock_sessions()?;
+        let session = joined_session(&mut sessions, diagram_id, client_id)?;
+        let mut diagram = session.diagram.clone();
+        diagram.data = Some(session.document.to_data());
+        (diagram, session.participants[client_id].name.clone())
+    };
+
+    let precondition = match diagram.version {
+        Some(version) if !force => Precondition::Version(version),
+        _ => Precondition::Any,
+    };
+    check_before_save(&diagram)?;
+    let (before, revision) =
+        storage().save_diagram(&diagram, user_id, &author_name(user_id), precondition)?;
+    record_audit(
+        user_id,
+        "diagram.update",
+        diagram_id,
+        before.as_ref(),
+        Some(&diagram),
+    );
+
+    let mut outbox = vec![];
+    let mut sessions = lock_sessions()?;
+    if let Some(session) = sessions.get_mut(diagram_id) {
+        diagram.version = Some(revision.revision);
+        // A save forced meanwhile may already have moved the session past this revision
+        if session.diagram.version < diagram.version {
+            session.diagram = diagram;
+        }
+        session.broadcast(
+            EditEvent::Saved {
+                diagram_id: diagram_id.to_string(),
+                version: revision.revision,
+                user_name,
+            },
+            None,
+            &mut outbox,
+        );
+    }
+    Ok(outbox)
+}
+
+/// Requests that only change the session
+fn edit(client_id: &str, user_id: &str, request: EditRequest) -> Result<Outbox, Error> {
+    let mut sessions = lock_sessions()?;
+    let mut outbox = vec![];
+    let diagram_id = request.diagram_id().to_string();
+    let session = joined_session(&mut sessions, &diagram_id, client_id)?;
+
+    match request {
+        EditRequest::Join { .. } | EditRequest::Save { .. } => {}
+        EditRequest::Leave { .. } => {
+            session.leave(client_id, &mut outbox);
+            if session.participants.is_empty() {
+                sessions.remove(&diagram_id);
+                info!("Edit session of diagram {} ended", diagram_id);
+            }
+        }
+        EditRequest::Select { element_ids, .. } => {
+            if let Some(participant) = session.participants.get_mut(client_id) {
+                participant.selection = element_ids.clone();
+            }
+            session.broadcast(
+                EditEvent::Selection {
+                    diagram_id,
+                    client_id: client_id.to_string(),
+                    element_ids,
+                },
+                Some(client_id),
+                &mut outbox,
+            );
+        }
+        EditRequest::Lock { element_id, .. } => {
+            if let Some(lock) = session.foreign_lock(&element_id, client_id) {
+                outbox.push((
+                    client_id.to_string(),
+                    EditEvent::LockDenied {
+                        diagram_id,
+                        lock: lock.clone(),
+                    },
+                ));
+                return Ok(outbox);
+            }
+            let lock = ElementLock {
+                element_id: element_id.clone(),
+                client_id: client_id.to_string(),
+                user_id: user_id.to_string(),
+                name: session.participants[client_id].name.clone(),
+                acquired: Instant::now(),
+            };
+            session.locks.insert(element_id, lock.clone());
+            session.broadcast(EditEvent::Locked { diagram_id, lock }, None, &mut outbox);
+        }
+        EditRequest::Unlock { element_id, .. } => {
+            let held = session
+                .locks
+                .get(&element_id)
+                .map(|lock| lock.client_id == client_id || lock.is_expired())
+                .unwrap_or(false);
+            if held {
+                session.locks.remove(&element_id);
+                session.broadcast(
+                    EditEvent::Unlocked {
+                        diagram_id,
+                        element_id,
+                    },
+                    None,
+                    &mut outbox,
+                );
+            }
+        }
+        EditRequest::Operation { operation, .. } => {
+            let element_id = operation.element_id().to_string();
+            let result = match session.operation_lock(&operation, client_id) {
+                Some(lock) => Err(format!(
+                    "{} is being edited by {}",
+                    lock.element_id, lock.name
+                )),
+                None => session.document.apply(&operation),
+            };
+            let removed = match result {
+                Ok(removed) => removed,
+                Err(reason) => {
+                    outbox.push((
+                        client_id.to_string(),
+                        EditEvent::OperationRejected {
+                            diagram_id,
+                            current: session.document.cell(&element_id),
+                            operation,
+                            reason,
+                        },
+                    ));
+                    return Ok(outbox);
+                }
+            };
+
+            if let Some(lock) = session.locks.get_mut(&element_id) {
+                if lock.client_id == client_id {
+                    lock.acquired = Instant::now();
+                }
+            }
+            for element_id in removed {
+                if session.locks.remove(&element_id).is_some() {
+                    session.broadcast(
+                        EditEvent::Unlocked {
+                            diagram_id: diagram_id.clone(),
+                            element_id,
+                        },
+                        None,
+                        &mut outbox,
+                    );
+                }
+            }
+            session.sequence += 1;
+            session.broadcast(
+                EditEvent::Operation {
+                    diagram_id,
+                    sequence: session.sequence,
+                    client_id: client_id.to_string(),
+                    operation,
+                },
+                None,
+                &mut outbox,
+            );
+        }
+    }
+    Ok(outbox)
+}
+
+fn check_permission(user_id: &str, permission: Permission) -> Result<(), Error> {
+    if has_permission(user_id, permission) {
+        Ok(())
+    } else {
+        Err(Error::NoPermissionError)
+    }
+}
+
+fn user_name(user_id: &str) -> String {
+    match get_user(user_id) {
+        Some(user) if !user.displayname.is_empty() => user.displayname,
+        Some(user) => user.username,
+        None => user_id.to_string(),
+    }
+}
+
+async fn deliver(outbox: Outbox, clients: &Clients) {
+    if outbox.is_empty() {
+        return;
+    }
+    let clients = clients.read().await;
+    for (client_id, event) in outbox {
+        let sender = match clients.get(&client_id).and_then(|c| c.sender.as_ref()) {
+            Some(sender) => sender,
+            None => continue,
+        };
+        match serde_json::to_string(&event) {
+            Ok(json) => {
+                let _ = sender.send(Ok(Message::text(json)));
+            }
+            Err(e) => error!("Unable to send edit event to {}: {}", client_id, e),
+        }
+    }
+}
+
+/// Cell of the mxGraph model, kept as the XML it was received as
+struct Cell {
+    id: String,
+    parent: Option<String>,
+    xml: String,
+}
+
+impl Cell {
+    fn parse(xml: &str) -> Result<Cell, String> {
+        let doc = Document::parse(xml).map_err(|e| e.to_string())?;
+        Cell::from_node(doc.root_element(), xml).ok_or_else(|| "element has no id".to_string())
+    }
+
+    fn from_node(node: Node, xml: &str) -> Option<Cell> {
+        let id = node.attribute("id")?;
+        // Cells with a user object keep the parent on the wrapped mxCell
+        let parent = node.attribute("parent").or_else(|| {
+            node.children()
+                .find(|n| n.has_tag_name("mxCell"))
+                .and_then(|n| n.attribute("parent"))
+        });
+        Some(Cell {
+            id: id.to_string(),
+            parent: parent.map(|p| p.to_string()),
+            xml: xml[node.range()].to_string(),
+        })
+    }
+}
+
+/// Diagram data split into the cells of its model, so that cells can be changed one by one
+struct GraphDocument {
+    /// Text before and after the `root` element holding the cells
+    head: String,
+    tail: String,
+    cells: Vec<Cell>,
+}
+
+impl GraphDocument {
+    fn parse(data: Option<&str>) -> Result<GraphDocument, String> {
+        let data = match data {
+            Some(data) if !data.trim().is_empty() => data,
+            _ => EMPTY_MODEL,
+        };
+        let doc = Document::parse(data).map_err(|e| e.to_string())?;
+        let root = doc
+            .descendants()
+            .find(|n| n.has_tag_name("root"))
+            .ok_or_else(|| "model has no root".to_string())?;
+        let mut cells = vec![];
+        for node in root.children().filter(|n| n.is_element()) {
+            match Cell::from_node(node, data) {
+                Some(cell) => cells.push(cell),
+                None => return Err("model has a cell without id".to_string()),
+            }
+        }
+        Ok(GraphDocument {
+            head: data[..root.range().start].to_string(),
+            tail: data[root.range().end..].to_string(),
+            cells,
+        })
+    }
+
+    fn to_data(&self) -> String {
+        let mut data = self.head.clone();
+        data.push_str("<root>");
+        for cell in self.cells.iter() {
+            data.push_str(&cell.xml);
+        }
+        data.push_str("</root>");
+        data.push_str(&self.tail);
+        data
+    }
+
+    fn position(&self, id: &str) -> Option<usize> {
+        self.cells.iter().position(|cell| cell.id == id)
+    }
+
+    fn cell(&self, id: &str) -> Option<String> {
+        self.position(id).map(|i| self.cells[i].xml.clone())
+    }
+
+    /// Ids of the element and of the elements it contains, directly or through others
+    fn contained_ids(&self, id: &str) -> Vec<String> {
+        let mut ids = vec![id.to_string()];
+        let mut contained: HashSet<&str> = HashSet::new();
+        contained.insert(id);
+        loop {
+            let children: Vec<&str> = self
+                .cells
+                .iter()
+                .filter(|cell| !contained.contains(cell.id.as_str()))
+                .filter(|cell| match &cell.parent {
+                    Some(parent) => contained.contains(parent.as_str()),
+                    None => false,
+                })
+                .map(|cell| cell.id.as_str())
+                .collect();
+            if children.is_empty() {
+                return ids;
+            }
+            for child in children {
+                contained.insert(child);
+                ids.push(child.to_string());
+            }
+        }
+    }
+
+    /// Applies the operation, resolves to the ids of removed elements
+    fn apply(&mut self, operation: &ElementOperation) -> Result<Vec<String>, String> {
+        match operation {
+            ElementOperation::Add { element_id, xml } => {
+                let cell = parse_cell(element_id, xml)?;
+                if self.position(element_id).is_some() {
+                    return Err(format!("{} already exists", element_id));
+                }
+                self.cells.push(cell);
+                Ok(vec![])
+            }
+            ElementOperation::Update { element_id, xml } => {
+                let cell = parse_cell(element_id, xml)?;
+                let i = self
+                    .position(element_id)
+                    .ok_or_else(|| format!("{} not found", element_id))?;
+                self.cells[i] = cell;
+                Ok(vec![])
+            }
+            ElementOperation::Remove { element_id } => {
+                if element_id == "0" || element_id == "1" {
+                    return Err("the default parents can't be changed".to_string());
+                }
+                if self.position(element_id).is_none() {
+                    return Err(format!("{} not found", element_id));
+                }
+                // Elements contained by removed elements go with them
+                let removed = self.contained_ids(element_id);
+                self.cells.retain(|cell| !removed.contains(&cell.id));
+                Ok(removed)
+            }
+        }
+    }
+}
+
+fn parse_cell(element_id: &str, xml: &str) -> Result<Cell, String> {
+    let cell = Cell::parse(xml)?;
+    if cell.id != element_id {
+        return Err(format!("element {} has id {}", element_id, cell.id));
+    }
+    if cell.id == "0" || cell.id == "1" {
+        return Err("the default parents can't be changed".to_string());
+    }
+    Ok(cell)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document() -> GraphDocument {
+        GraphDocument::parse(Some(
+            r#"<mxGraphModel grid="1"><root><mxCell id="0"/><mxCell id="1" parent="0"/><mxCell id="g" parent="1" vertex="1"/><object id="b" label="Breaker"><mxCell parent="g" vertex="1"/></object><mxCell id="e" parent="1" edge="1"/></root></mxGraphModel>"#,
+        ))
+        .unwrap()
+    }
+
+    fn ids(document: &GraphDocument) -> Vec<&str> {
+        document.cells.iter().map(|cell| cell.id.as_str()).collect()
+    }
+
+    #[test]
+    fn empty_data_is_an_empty_model() {
+        let document = GraphDocument::parse(None).unwrap();
+        assert_eq!(document.to_data(), EMPTY_MODEL);
+        assert!(GraphDocument::parse(Some("<mxGraphModel/>")).is_err());
+    }
+
+    #[test]
+    fn cells_are_added_and_updated() {
+        let mut document = document();
+        let add = ElementOperation::Add {
+            element_id: "n".to_string(),
+            xml: r#"<mxCell id="n" value="Note" parent="1" vertex="1"/>"#.to_string(),
+        };
+        assert_eq!(document.apply(&add).unwrap(), Vec::<String>::new());
+        assert!(document.apply(&add).unwrap_err().contains("already exists"));
+        assert_eq!(ids(&document), vec!["0", "1", "g", "b", "e", "n"]);
+
+        let update = ElementOperation::Update {
+            element_id: "b".to_string(),
+            xml: r#"<object id="b" label="Main"><mxCell parent="g" vertex="1"/></object>"#
+                .to_string(),
+        };
+        document.apply(&update).unwrap();
+        assert!(document.cell("b").unwrap().contains("Main"));
+        assert!(document
+            .to_data()
+            .starts_with(r#"<mxGraphModel grid="1"><root>"#));
+        assert!(document.to_data().contains(r#"label="Main""#));
+
+        let missing = ElementOperation::Update {
+            element_id: "x".to_string(),
+            xml: r#"<mxCell id="x" parent="1"/>"#.to_string(),
+        };
+        assert!(document.apply(&missing).unwrap_err().contains("not found"));
+    }
+
+    #[test]
+    fn operations_must_name_the_cell_they_carry() {
+        let mut document = document();
+        let other_id = ElementOperation::Add {
+            element_id: "n".to_string(),
+            xml: r#"<mxCell id="m" parent="1"/>"#.to_string(),
+        };
+        assert!(document.apply(&other_id).is_err());
+        let default_parent = ElementOperation::Update {
+            element_id: "1".to_string(),
+            xml: r#"<mxCell id="1" parent="0"/>"#.to_string(),
+        };
+        assert!(document.apply(&default_parent).is_err());
+        let broken = ElementOperation::Add {
+            element_id: "n".to_string(),
+            xml: "<mxCell id=\"n\"".to_string(),
+        };
+        assert!(document.apply(&broken).is_err());
+        assert_eq!(ids(&document), vec!["0", "1", "g", "b", "e"]);
+    }
+
+    #[test]
+    fn removing_a_cell_removes_what_it_contains() {
+        let mut document = document();
+        let mut removed = document
+            .apply(&ElementOperation::Remove {
+                element_id: "g".to_string(),
+            })
+            .unwrap();
+        removed.sort();
+        assert_eq!(removed, vec!["b", "g"]);
+        assert_eq!(ids(&document), vec!["0", "1", "e"]);
+
+        for element_id in &["g", "1"] {
+            assert!(document
+                .apply(&ElementOperation::Remove {
+                    element_id: element_id.to_string(),
+                })
+                .is_err());
+        }
+    }
+
+    fn session() -> EditSession {
+        EditSession {
+            diagram: serde_json::from_value(serde_json::json!({ "diagramId": "d1" })).unwrap(),
+            document: document(),
+            participants: BTreeMap::new(),
+            locks: HashMap::new(),
+            sequence: 0,
+        }
+    }
+
+    fn lock(session: &mut EditSession, element_id: &str, client_id: &str) {
+        session.locks.insert(
+            element_id.to_string(),
+            ElementLock {
+                element_id: element_id.to_string(),
+                client_id: client_id.to_string(),
+                user_id: format!("user-{}", client_id),
+                name: format!("Designer {}", client_id),
+                acquired: Instant::now(),
+            },
+        );
+    }
+
+    fn remove(element_id: &str) -> ElementOperation {
+        ElementOperation::Remove {
+            element_id: element_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn removal_is_blocked_by_locks_on_contained_elements() {
+        let mut session = session();
+        let mut contained = session.document.contained_ids("g");
+        contained.sort();
+        assert_eq!(contained, vec!["b", "g"]);
+        assert_eq!(session.document.contained_ids("e"), vec!["e"]);
+
+        // Client B holds the breaker inside the group client A removes
+        lock(&mut session, "b", "B");
+        let blocking = session.operation_lock(&remove("g"), "A").unwrap();
+        assert_eq!(blocking.element_id, "b");
+        assert_eq!(blocking.client_id, "B");
+        assert!(session.operation_lock(&remove("e"), "A").is_none());
+        // The holder of the lock may remove the group
+        assert!(session.operation_lock(&remove("g"), "B").is_none());
+
+        let update = ElementOperation::Update {
+            element_id: "g".to_string(),
+            xml: r#"<mxCell id="g" parent="1" vertex="1"/>"#.to_string(),
+        };
+        assert!(session.operation_lock(&update, "A").is_none());
+        lock(&mut session, "g", "B");
+        assert_eq!(
+            session.operation_lock(&update, "A").unwrap().element_id,
+            "g"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use chrono::prelude::*;
+use lazy_static::lazy_static;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::sync::Mutex;
+use warp::{reply::json, Rejection, Reply};
+
+lazy_static! {
+    // Serializes writes so that records of concurrent controls don't interleave
+    static ref CONTROL_LOG_LOCK: Mutex<()> = Mutex::new(());
+}
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+const MAX_REASON_LENGTH: usize = 500;
+
+/// The authenticated user a control was issued by
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ControlIssuer {
+    pub user_id: String,
+    pub username: String,
+    /// Why the operator issued the control, as entered in the HMI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl ControlIssuer {
+    pub fn new(user_id: &str, username: &str, reason: Option<String>) -> ControlIssuer {
+        ControlIssuer {
+            user_id: user_id.to_string(),
+            username: username.to_string(),
+            reason: reason
+                .map(|r| r.trim().chars().take(MAX_REASON_LENGTH).collect::<String>())
+                .filter(|r| !r.is_empty()),
+        }
+    }
+}
+
+/// A control sent to the microgrid, one JSON document per line of the control log
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ControlRecord {
+    pub id: String,
+    /// Unix timestamp in seconds
+    pub timestamp: i64,
+    #[serde(flatten)]
+    pub issuer: ControlIssuer,
+    /// Target device, empty for controls of the whole microgrid
+    pub mrid: String,
+    /// The control, e.g. `Open` or `SetWNetMag`
+    pub control: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub args: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub args2: Option<f64>,
+    /// OpenFMB profile the control was resolved to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// NATS subject the control was published on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    pub published: bool,
+    /// Why the control was not published
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ControlLogQuery {
+    /// Zero based page number
+    #[serde(default)]
+    pub page: usize,
+    #[serde(default)]
+    pub page_size: Option<usize>,
+    #[serde(default)]
+    pub mrid: Option<String>,
+    /// Matches the user id or the username
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Unix timestamp in seconds of the oldest record
+    #[serde(default)]
+    pub from: Option<i64>,
+    /// Unix timestamp in seconds of the newest record
+    #[serde(default)]
+    pub to: Option<i64>,
+}
+
+impl ControlLogQuery {
+    fn matches(&self, record: &ControlRecord) -> bool {
+        self.mrid.as_ref().map_or(true, |m| record.mrid == *m)
+            && self.user.as_ref().map_or(true, |u| {
+                record.issuer.user_id == *u || record.issuer.username == *u
+            })
+            && self.from.map_or(true, |from| record.timestamp >= from)
+            && self.to.map_or(true, |to| record.timestamp <= to)
+    }
+}
+
+#[derive(Serialize)]
+pub struct ControlLogPage {
+    pub total: usize,
+    pub page: usize,
+    pub page_size: usize,
+    pub records: Vec<ControlRecord>,
+}
+
+/// Appends the control and the result of publishing it to the control log
+pub fn record_control(
+    issuer: &ControlIssuer,
+    mrid: &str,
+    control: &str,
+    args: (Option<f64>, Option<f64>),
+    profile: Option<String>,
+    publish_result: Option<(String, std::result::Result<(), String>)>,
+) {
+    let (subject, published, error) = match publish_result {
+        Some((subject, Ok(()))) => (Some(subject), true, None),
+        Some((subject, Err(e))) => (Some(subject), false, Some(e)),
+        None => (None, false, Some("Unsupported control".to_string())),
+    };
+    let record = ControlRecord {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        timestamp: Utc::now().timestamp(),
+        issuer: issuer.clone(),
+        mrid: mrid.to_string(),
+        control: control.to_string(),
+        args: args.0,
+        args2: args.1,
+        profile,
+        subject,
+        published,
+        error,
+    };
+    let line = match serde_json::to_string(&record) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("Unable to serialize control record: {}", e);
+            return;
+        }
+    };
+
+    let _lock = CONTROL_LOG_LOCK.lock().unwrap();
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(get_control_log_file())
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(e) = result {
+        error!(
+            "Unable to write control {} on {} by {}: {}",
+            record.control, record.mrid, record.issuer.username, e
+        );
+    }
+}
+
+// GET
+pub async fn control_log_handler(
+    _id: String,
+    query: ControlLogQuery,
+) -> std::result::Result<impl Reply, Rejection> {
+    let page_size = query
+        .page_size
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .max(1)
+        .min(MAX_PAGE_SIZE);
+
+    let file_path = get_control_log_file();
+    let mut records: Vec<ControlRecord> = match File::open(&file_path) {
+        Ok(file) => {
+            let _lock = CONTROL_LOG_LOCK.lock().unwrap();
+            BufReader::new(file)
+                .lines()
+                .filter_map(|line| line.ok())
+                .filter(|line| !line.trim().is_empty())
+                .filter_map(|line| match serde_json::from_str::<ControlRecord>(&line) {
+                    Ok(record) => Some(record),
+                    Err(e) => {
+                        error!("Unable to parse control record in {}: {}", file_path, e);
+                        None
+                    }
+                })
+                .filter(|record| query.matches(record))
+                .collect()
+        }
+        Err(_) => vec![],
+    };
+    records.reverse();
+
+    Ok(json(&ControlLogPage {
+        total: records.len(),
+        page: query.page,
+        page_size,
+        records: records
+            .into_iter()
+            .skip(query.page.saturating_mul(page_size))
+            .take(page_size)
+            .collect(),
+    }))
+}
+
+fn get_control_log_file() -> String {
+    let app_dir = std::env::var("APP_DIR_NAME").unwrap_or_else(|_| "".into());
+    if app_dir != "" {
+        return format!("/{}/controls.log", app_dir);
+    }
+    "controls.log".to_string()
+}
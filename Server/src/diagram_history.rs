@@ -0,0 +1,565 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::audit::record_audit;
+use crate::auth::get_user;
+use crate::diagram_validation::check_before_save;
+use crate::error::Error;
+use crate::handler::Diagram;
+use crate::storage::{storage, Precondition};
+use log::warn;
+use roxmltree::{Document, Node};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use warp::{reject, reply::json, Rejection, Reply};
+
+/// Arrays of the user object of a cell that bind it to OpenFMB data, and the kind of
+/// binding they hold
+const BINDING_ARRAYS: &[(&str, &str)] = &[
+    ("displayData", "display"),
+    ("controlData", "control"),
+    ("visibilityData", "visibility"),
+];
+
+/// A saved state of a diagram
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DiagramRevision {
+    pub diagram_id: String,
+    /// Numbered from 1 for each diagram
+    pub revision: i64,
+    /// Id of the user who saved the revision, or `api-key:<id>` for an API key
+    pub author: String,
+    /// Username of the author at the time of the save
+    pub author_name: String,
+    /// Unix timestamp in seconds
+    pub timestamp: i64,
+    /// True for the revision recording the deletion of the diagram, it holds the diagram
+    /// as it was deleted
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+#[derive(Serialize)]
+pub struct DiagramRevisionContent {
+    #[serde(flatten)]
+    pub revision: DiagramRevision,
+    pub diagram: Diagram,
+}
+
+#[derive(Deserialize)]
+pub struct DiagramRevisionsQuery {
+    pub id: String,
+}
+
+#[derive(Deserialize)]
+pub struct DiagramRevisionQuery {
+    pub id: String,
+    pub revision: i64,
+}
+
+#[derive(Deserialize)]
+pub struct DiagramDiffQuery {
+    pub id: String,
+    pub from: i64,
+    /// Defaults to the latest revision
+    #[serde(default)]
+    pub to: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct RevertDiagramRequest {
+    pub id: String,
+    pub revision: i64,
+}
+
+/// A cell of the diagram, as far as comparing revisions is concerned
+#[derive(Clone, Debug, Serialize)]
+pub struct DiagramElement {
+    pub id: String,
+    /// Symbol type, e.g. `breaker` or `measure-box`, `edge` for a connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub element_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mrid: Option<String>,
+    pub bindings: Vec<DataBinding>,
+    #[serde(skip)]
+    properties: String,
+    #[serde(skip)]
+    style: String,
+    #[serde(skip)]
+    geometry: String,
+    /// Ids of the source and target cells of a connection, empty when an end is loose
+    #[serde(skip)]
+    pub(crate) connection: (String, String),
+    #[serde(skip)]
+    pub(crate) edge: bool,
+    /// Control the element sends, e.g. `SetModBlkOn`
+    #[serde(skip)]
+    pub(crate) verb: Option<String>,
+}
+
+/// OpenFMB data point a cell displays, controls or is shown by
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct DataBinding {
+    /// `display`, `control` or `visibility`
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Other settings of the binding, e.g. its label or scale
+    pub attributes: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+pub struct ElementChange {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// What changed: `type`, `label`, `mrid`, `properties`, `style`, `geometry`,
+    /// `connection` or `bindings`
+    pub changes: Vec<String>,
+    pub bindings_added: Vec<DataBinding>,
+    pub bindings_removed: Vec<DataBinding>,
+}
+
+#[derive(Serialize)]
+pub struct DiagramDiff {
+    pub diagram_id: String,
+    pub from: i64,
+    pub to: i64,
+    /// Fields of the diagram itself that changed, e.g. `name`
+    pub fields: Vec<String>,
+    /// False when the data of a revision can't be read as a diagram, the element lists are
+    /// empty then
+    pub elements_compared: bool,
+    pub added: Vec<DiagramElement>,
+    pub removed: Vec<DiagramElement>,
+    pub changed: Vec<ElementChange>,
+}
+
+/// Username recorded as the author of a revision
+pub fn author_name(id: &str) -> String {
+    get_user(id).map(|usr| usr.username).unwrap_or_default()
+}
+
+fn find_revision(id: &str, revision: i64) -> Result<(DiagramRevision, Diagram), Rejection> {
+    storage()
+        .diagram_revision(id, revision)
+        .map_err(|e| reject::custom(e))?
+        .ok_or_else(|| {
+            reject::custom(Error::NotFoundError(format!(
+                "revision {} of diagram {}",
+                revision, id
+            )))
+        })
+}
+
+// GET
+pub async fn diagram_revisions_handler(
+    _id: String,
+    query: DiagramRevisionsQuery,
+) -> Result<impl Reply, Rejection> {
+    let revisions = storage()
+        .diagram_revisions(&query.id)
+        .map_err(|e| reject::custom(e))?;
+    if revisions.is_empty() {
+        return Err(reject::custom(Error::NotFoundError(format!(
+            "diagram {}",
+            query.id
+        ))));
+    }
+    Ok(json(&revisions))
+}
+
+// GET
+pub async fn diagram_revision_handler(
+    _id: String,
+    query: DiagramRevisionQuery,
+) -> Result<impl Reply, Rejection> {
+    let (revision, diagram) = find_revision(&query.id, query.revision)?;
+    Ok(json(&DiagramRevisionContent { revision, diagram }))
+}
+
+// GET
+pub async fn diagram_diff_handler(
+    _id: String,
+    query: DiagramDiffQuery,
+) -> Result<impl Reply, Rejection> {
+    let to = match query.to {
+        Some(to) => to,
+        None => storage()
+            .diagram_revisions(&query.id)
+            .map_err(|e| reject::custom(e))?
+            .first()
+            .map(|r| r.revision)
+            .ok_or_else(|| reject::custom(Error::NotFoundError(format!("diagram {}", query.id))))?,
+    };
+    let (_, before) = find_revision(&query.id, query.from)?;
+    let (_, after) = find_revision(&query.id, to)?;
+
+    Ok(json(&diff_diagrams(
+        &query.id, query.from, to, &before, &after,
+    )))
+}
+
+/// Saves an earlier revision as the new latest revision, the revisions in between are kept.
+/// `If-Match` names the latest revision the client saw, as when saving.
+// POST
+pub async fn revert_diagram_handler(
+    id: String,
+    if_match: Option<String>,
+    request: RevertDiagramRequest,
+) -> Result<impl Reply, Rejection> {
+    let precondition =
+        Precondition::from_if_match(if_match.as_deref()).map_err(|e| reject::custom(e))?;
+    let (_, mut diagram) = find_revision(&request.id, request.revision)?;
+    check_before_save(&diagram).map_err(|e| reject::custom(e))?;
+    let (before, revision) = storage()
+        .save_diagram(&diagram, &id, &author_name(&id), precondition)
+        .map_err(|e| reject::custom(e))?;
+    diagram.version = Some(revision.revision);
+    record_audit(
+        &id,
+        "diagram.revert",
+        &request.id,
+        before.as_ref(),
+        Some(&diagram),
+    );
+
+    Ok(json(&DiagramRevisionContent { revision, diagram }))
+}
+
+/// Compares the fields and the cells of two states of a diagram
+pub fn diff_diagrams(
+    id: &str,
+    from: i64,
+    to: i64,
+    before: &Diagram,
+    after: &Diagram,
+) -> DiagramDiff {
+    let mut fields = vec![];
+    let mut compare = |name: &str, a: &Option<String>, b: &Option<String>| {
+        if a != b {
+            fields.push(name.to_string());
+        }
+    };
+    compare("name", &before.name, &after.name);
+    compare("description", &before.description, &after.description);
+    compare("location", &before.location, &after.location);
+    compare(
+        "backgroundColor",
+        &before.backgroundColor,
+        &after.backgroundColor,
+    );
+    compare("data", &before.data, &after.data);
+
+    let mut diff = DiagramDiff {
+        diagram_id: id.to_string(),
+        from,
+        to,
+        fields,
+        elements_compared: false,
+        added: vec![],
+        removed: vec![],
+        changed: vec![],
+    };
+
+    let (mut old, new) = match (parse_elements(&before.data), parse_elements(&after.data)) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!(
+                "Unable to compare revisions {} and {} of diagram {}: {}",
+                from, to, id, e
+            );
+            return diff;
+        }
+    };
+    diff.elements_compared = true;
+
+    for (cell_id, element) in new.into_iter() {
+        match old.remove(&cell_id) {
+            Some(previous) => {
+                if let Some(change) = compare_elements(&previous, &element) {
+                    diff.changed.push(change);
+                }
+            }
+            None => diff.added.push(element),
+        }
+    }
+    diff.removed = old.into_iter().map(|(_, element)| element).collect();
+    diff
+}
+
+fn compare_elements(before: &DiagramElement, after: &DiagramElement) -> Option<ElementChange> {
+    let mut changes = vec![];
+    let mut compare = |name: &str, changed: bool| {
+        if changed {
+            changes.push(name.to_string());
+        }
+    };
+    compare("type", before.element_type != after.element_type);
+    compare("label", before.label != after.label);
+    compare("mrid", before.mrid != after.mrid);
+    compare("properties", before.properties != after.properties);
+    compare("style", before.style != after.style);
+    compare("geometry", before.geometry != after.geometry);
+    compare("connection", before.connection != after.connection);
+
+    let bindings_added: Vec<DataBinding> = after
+        .bindings
+        .iter()
+        .filter(|b| !before.bindings.contains(b))
+        .cloned()
+        .collect();
+    let bindings_removed: Vec<DataBinding> = before
+        .bindings
+        .iter()
+        .filter(|b| !after.bindings.contains(b))
+        .cloned()
+        .collect();
+    if !bindings_added.is_empty() || !bindings_removed.is_empty() {
+        changes.push("bindings".to_string());
+    }
+
+    if changes.is_empty() {
+        return None;
+    }
+    Some(ElementChange {
+        id: after.id.clone(),
+        label: after.label.clone(),
+        changes,
+        bindings_added,
+        bindings_removed,
+    })
+}
+
+/// Cells of the mxGraph model saved by the designer, by id. Layers and the root cell are
+/// left out.
+pub(crate) fn parse_elements(
+    data: &Option<String>,
+) -> Result<BTreeMap<String, DiagramElement>, String> {
+    let mut elements = BTreeMap::new();
+    let data = match data.as_deref() {
+        Some(data) if !data.trim().is_empty() => data,
+        _ => return Ok(elements),
+    };
+    let doc = Document::parse(data).map_err(|e| e.to_string())?;
+    let root = match doc.descendants().find(|n| n.has_tag_name("root")) {
+        Some(root) => root,
+        None => return Ok(elements),
+    };
+
+    for cell in root.children().filter(|n| n.is_element()) {
+        let id = match cell.attribute("id") {
+            Some(id) => id.to_string(),
+            None => continue,
+        };
+        match cell.attribute("parent") {
+            Some(parent) if parent != "0" => {}
+            _ => continue,
+        }
+
+        let user_object = cell
+            .descendants()
+            .find(|n| n.is_element() && n.attribute("as") == Some("userObject"));
+        let element_type = user_object
+            .and_then(|u| u.attribute("type"))
+            .map(|t| t.to_string())
+            .or_else(|| {
+                if cell.attribute("edge") == Some("1") {
+                    Some("edge".to_string())
+                } else {
+                    None
+                }
+            });
+        let label = user_object
+            .and_then(|u| u.attribute("label"))
+            .or_else(|| cell.attribute("value"))
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_string());
+        let geometry = cell
+            .children()
+            .find(|n| n.has_tag_name("mxGeometry"))
+            .map(|g| signature(g, &[]))
+            .unwrap_or_default();
+        let binding_arrays: Vec<&str> = BINDING_ARRAYS.iter().map(|(name, _)| *name).collect();
+
+        elements.insert(
+            id.clone(),
+            DiagramElement {
+                id,
+                element_type,
+                label,
+                mrid: user_object
+                    .and_then(|u| u.attribute("mRID"))
+                    .filter(|m| !m.is_empty())
+                    .map(|m| m.to_string()),
+                bindings: user_object.map(bindings).unwrap_or_default(),
+                properties: user_object
+                    .map(|u| signature(u, &binding_arrays))
+                    .unwrap_or_default(),
+                style: cell.attribute("style").unwrap_or_default().to_string(),
+                geometry,
+                connection: (
+                    cell.attribute("source").unwrap_or_default().to_string(),
+                    cell.attribute("target").unwrap_or_default().to_string(),
+                ),
+                edge: cell.attribute("edge") == Some("1"),
+                verb: user_object
+                    .and_then(|u| u.attribute("verb"))
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.to_string()),
+            },
+        );
+    }
+    Ok(elements)
+}
+
+fn bindings(user_object: Node) -> Vec<DataBinding> {
+    let mut bindings = vec![];
+    for array in user_object.children().filter(|n| n.is_element()) {
+        let kind = match BINDING_ARRAYS
+            .iter()
+            .find(|(name, _)| array.attribute("as") == Some(*name))
+        {
+            Some((_, kind)) => kind,
+            None => continue,
+        };
+        for item in array.children().filter(|n| n.is_element()) {
+            let mut attributes: BTreeMap<String, String> = item
+                .attributes()
+                .map(|a| (a.name().to_string(), a.value().to_string()))
+                .collect();
+            bindings.push(DataBinding {
+                kind: kind.to_string(),
+                path: attributes.remove("path"),
+                attributes,
+            });
+        }
+    }
+    bindings.sort();
+    bindings
+}
+
+/// Text that is equal for elements with the same attributes and children, whatever the
+/// order of the attributes. Children named in `skip` are left out.
+fn signature(node: Node, skip: &[&str]) -> String {
+    let mut attributes: Vec<String> = node
+        .attributes()
+        .map(|a| format!("{}={:?}", a.name(), a.value()))
+        .collect();
+    attributes.sort();
+
+    let children: Vec<String> = node
+        .children()
+        .filter(|n| n.is_element())
+        .filter(|n| n.attribute("as").map_or(true, |a| !skip.contains(&a)))
+        .map(|n| signature(n, skip))
+        .collect();
+
+    format!(
+        "{}[{}]({})",
+        node.tag_name().name(),
+        attributes.join(","),
+        children.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagram(name: &str, cells: &str) -> Diagram {
+        Diagram {
+            diagramId: "d".to_string(),
+            name: Some(name.to_string()),
+            description: None,
+            location: None,
+            data: Some(format!(
+                r#"<mxGraphModel><root><mxCell id="0"/><mxCell id="1" parent="0"/>{}</root></mxGraphModel>"#,
+                cells
+            )),
+            createdDate: None,
+            createdBy: None,
+            backgroundColor: None,
+            version: None,
+            published_version: None,
+        }
+    }
+
+    const BREAKER: &str = r#"<mxCell id="b1" parent="1" vertex="1"><Object as="userObject" label="Breaker 1" type="breaker" mRID="m1"><Array as="displayData"><Object path="a.b" label="Status"/></Array></Object><mxGeometry x="10" y="20" as="geometry"/></mxCell>"#;
+
+    #[test]
+    fn same_diagram_has_no_changes() {
+        let d = diagram("Feeder", BREAKER);
+        let diff = diff_diagrams("d", 1, 2, &d, &d);
+        assert!(diff.elements_compared);
+        assert!(diff.fields.is_empty());
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.changed.is_empty());
+    }
+
+    #[test]
+    fn added_removed_and_changed_elements() {
+        let before = diagram(
+            "Feeder",
+            &format!(
+                r#"{}<mxCell id="e1" parent="1" edge="1" source="b1" target="x"/>"#,
+                BREAKER
+            ),
+        );
+        let moved = BREAKER
+            .replace(r#"x="10""#, r#"x="30""#)
+            .replace(r#"path="a.b""#, r#"path="a.c""#);
+        let after = diagram(
+            "Feeder 2",
+            &format!(
+                r#"{}<mxCell id="t1" value="Note" parent="1" vertex="1"/>"#,
+                moved
+            ),
+        );
+        let diff = diff_diagrams("d", 1, 2, &before, &after);
+
+        assert!(diff.fields.contains(&"name".to_string()));
+        assert!(diff.fields.contains(&"data".to_string()));
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].label.as_deref(), Some("Note"));
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].element_type.as_deref(), Some("edge"));
+
+        assert_eq!(diff.changed.len(), 1);
+        let change = &diff.changed[0];
+        assert_eq!(change.id, "b1");
+        assert_eq!(change.changes, vec!["geometry", "bindings"]);
+        assert_eq!(change.bindings_added[0].path.as_deref(), Some("a.c"));
+        assert_eq!(change.bindings_removed[0].path.as_deref(), Some("a.b"));
+        assert_eq!(change.bindings_added[0].attributes["label"], "Status");
+    }
+
+    #[test]
+    fn attribute_order_is_not_a_change() {
+        let reordered = BREAKER.replace(
+            r#"label="Breaker 1" type="breaker""#,
+            r#"type="breaker" label="Breaker 1""#,
+        );
+        let diff = diff_diagrams(
+            "d",
+            1,
+            2,
+            &diagram("Feeder", BREAKER),
+            &diagram("Feeder", &reordered),
+        );
+        assert!(diff.changed.is_empty());
+    }
+
+    #[test]
+    fn unreadable_data_only_compares_fields() {
+        let mut broken = diagram("Feeder", BREAKER);
+        broken.data = Some("<mxGraphModel>".to_string());
+        let diff = diff_diagrams("d", 1, 2, &diagram("Feeder", BREAKER), &broken);
+        assert!(!diff.elements_compared);
+        assert_eq!(diff.fields, vec!["data"]);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+    }
+}
@@ -0,0 +1,67 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::Error;
+
+/// Longest diagram id accepted, ids the server generates are 36 characters
+pub const MAX_DIAGRAM_ID_LENGTH: usize = 64;
+
+/// Whether the id only has letters, digits, `-` and `_`, so that it can be used in URLs,
+/// file names and exports as it is
+pub fn is_safe_diagram_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_DIAGRAM_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Refuses ids that aren't safe, see `is_safe_diagram_id`
+pub fn check_diagram_id(id: &str) -> Result<(), Error> {
+    if is_safe_diagram_id(id) {
+        Ok(())
+    } else {
+        Err(Error::InvalidDiagramIdError(id.to_string()))
+    }
+}
+
+/// Id for a new diagram
+pub fn new_diagram_id() -> String {
+    uuid::Uuid::new_v4().hyphenated().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_that_could_leave_a_path_are_refused() {
+        for id in &[
+            "../x", "a/b", "a\\b", "..", ".", "a.json", "a b", "é", "a\0b", "",
+        ] {
+            assert!(!is_safe_diagram_id(id), "{:?} was accepted", id);
+            assert!(matches!(
+                check_diagram_id(id),
+                Err(Error::InvalidDiagramIdError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn ids_are_limited_in_length() {
+        assert!(is_safe_diagram_id(&"a".repeat(MAX_DIAGRAM_ID_LENGTH)));
+        assert!(!is_safe_diagram_id(&"a".repeat(MAX_DIAGRAM_ID_LENGTH + 1)));
+    }
+
+    #[test]
+    fn generated_and_plain_ids_are_accepted() {
+        let id = new_diagram_id();
+        assert_eq!(id.len(), 36);
+        assert!(is_safe_diagram_id(&id));
+        assert!(check_diagram_id(&id).is_ok());
+        for id in &["feeder", "Feeder_1", "substation-A", "0"] {
+            assert!(is_safe_diagram_id(id), "{:?} was refused", id);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::audit::record_audit;
+use crate::diagram_validation::validate_diagram;
+use crate::error::Error;
+use crate::storage::storage;
+use serde::{Deserialize, Serialize};
+use warp::{reject, reply::json, Rejection, Reply};
+
+#[derive(Deserialize)]
+pub struct PublishDiagramRequest {
+    pub id: String,
+    /// Defaults to the latest revision
+    #[serde(default)]
+    pub revision: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct PublishedDiagram {
+    pub diagram_id: String,
+    pub published_version: i64,
+    /// Revision published before, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_version: Option<i64>,
+}
+
+/// Makes a revision of the diagram the one the HMI shows, once it passes validation without
+/// errors
+// POST
+pub async fn publish_diagram_handler(
+    id: String,
+    request: PublishDiagramRequest,
+) -> Result<impl Reply, Rejection> {
+    let revision = match request.revision {
+        Some(revision) => revision,
+        None => storage()
+            .diagram_revisions(&request.id)
+            .map_err(|e| reject::custom(e))?
+            .first()
+            .map(|r| r.revision)
+            .ok_or_else(|| {
+                reject::custom(Error::NotFoundError(format!("diagram {}", request.id)))
+            })?,
+    };
+    let (_, diagram) = storage()
+        .diagram_revision(&request.id, revision)
+        .map_err(|e| reject::custom(e))?
+        .ok_or_else(|| {
+            reject::custom(Error::NotFoundError(format!(
+                "revision {} of diagram {}",
+                revision, request.id
+            )))
+        })?;
+
+    validate_diagram(&diagram)
+        .and_then(|report| report.into_result())
+        .map_err(|e| reject::custom(e))?;
+
+    let previous = storage()
+        .publish_diagram(&request.id, revision)
+        .map_err(|e| reject::custom(e))?;
+    record_audit(
+        &id,
+        "diagram.publish",
+        &request.id,
+        previous.as_ref(),
+        Some(&revision),
+    );
+
+    Ok(json(&PublishedDiagram {
+        diagram_id: request.id,
+        published_version: revision,
+        previous_version: previous,
+    }))
+}
@@ -0,0 +1,921 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::auth::{has_permission, run_blocking, Permission};
+use crate::diagram_template::escape_attribute as escape;
+use crate::error::Error;
+use crate::handler::{DataValue, Diagram, STATIC_DIR};
+use crate::live_values::{live_values, DeviceValues};
+use crate::storage::storage;
+use base64::Engine;
+use chrono::prelude::*;
+use lazy_static::lazy_static;
+use roxmltree::{Document, Node};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use warp::{
+    http::header::CACHE_CONTROL, http::header::CONTENT_TYPE, reject, reply, Rejection, Reply,
+};
+
+/// Symbols whose image shows the position of the device, `<type>-closed.svg`,
+/// `<type>-open.svg` or `<type>-invalid.svg`
+const SWITCHGEAR_TYPES: [&str; 6] = [
+    "breaker",
+    "switch-vertical",
+    "switch-horizontal",
+    "recloser",
+    "pcc",
+    "button-pcc",
+];
+
+/// Space around the cells
+const MARGIN: f64 = 10.0;
+
+/// Largest width or height of a rendered image in pixels, larger diagrams are scaled down
+pub const MAX_IMAGE_SIZE: u32 = 4096;
+
+/// mxGraph defaults for what the style of a cell doesn't set
+const DEFAULT_FILL: &str = "#C3D9FF";
+const DEFAULT_STROKE: &str = "#6482B9";
+const DEFAULT_FONT_COLOR: &str = "#000000";
+const DEFAULT_FONT_SIZE: f64 = 11.0;
+
+lazy_static! {
+    /// Symbol images as data URIs by path. Files that can't be read aren't kept, they are
+    /// tried again the next time, e.g. once the client is built.
+    static ref IMAGES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+#[cfg(feature = "png")]
+lazy_static! {
+    /// Fonts installed on the server, loaded once as loading them reads every font file
+    static ref FONTS: resvg::usvg::fontdb::Database = {
+        let mut fonts = resvg::usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+        fonts
+    };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderFormat {
+    Svg,
+    Png,
+}
+
+impl Default for RenderFormat {
+    fn default() -> Self {
+        RenderFormat::Svg
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RenderDiagramQuery {
+    pub id: String,
+    #[serde(default)]
+    pub format: RenderFormat,
+    /// Show the latest values received and the position of switchgear
+    #[serde(default)]
+    pub live: bool,
+    /// Render the latest draft instead of the published version, editors only
+    #[serde(default)]
+    pub draft: bool,
+    /// Width of the image in pixels, the diagram is scaled to it, e.g. for thumbnails. At
+    /// most `MAX_IMAGE_SIZE`.
+    #[serde(default)]
+    pub width: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RenderOptions {
+    pub live: bool,
+    pub width: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bounds {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
+
+impl Bounds {
+    fn center(&self) -> (f64, f64) {
+        (self.x + self.width / 2.0, self.y + self.height / 2.0)
+    }
+}
+
+/// Area the drawing covers
+struct Extent {
+    min: (f64, f64),
+    max: (f64, f64),
+}
+
+impl Extent {
+    fn new() -> Extent {
+        Extent {
+            min: (f64::MAX, f64::MAX),
+            max: (f64::MIN, f64::MIN),
+        }
+    }
+
+    fn add(&mut self, x: f64, y: f64) {
+        self.min = (self.min.0.min(x), self.min.1.min(y));
+        self.max = (self.max.0.max(x), self.max.1.max(y));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.min.0 > self.max.0
+    }
+}
+
+/// `key=value` pairs of an mxGraph style, a name without value, e.g. `text`, is a named style
+fn parse_style(style: &str) -> HashMap<&str, &str> {
+    style
+        .split(';')
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.split_once('=') {
+            Some((key, value)) => (key, value),
+            None => (s, ""),
+        })
+        .collect()
+}
+
+fn number(node: Option<Node>, name: &str) -> f64 {
+    node.and_then(|n| n.attribute(name))
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Shortest text for the number, at most 2 decimals
+fn format_number(value: f64) -> String {
+    let text = format!("{:.2}", value);
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+fn color<'a>(style: &HashMap<&str, &'a str>, key: &str, default: &'a str) -> &'a str {
+    match style.get(key) {
+        Some(c) if !c.is_empty() => c,
+        _ => default,
+    }
+}
+
+/// Text of an HTML label, line breaks become separate lines
+fn label_lines(label: &str) -> Vec<String> {
+    let mut text = String::new();
+    let mut rest = label;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let tag = rest[start + 1..end].trim().to_lowercase();
+        if tag.starts_with("br") || tag.starts_with("div") || tag.starts_with("/div") {
+            text.push('\n');
+        }
+        rest = &rest[end + 1..];
+    }
+    text.push_str(rest);
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+/// Symbol image as a data URI, so that the image shows wherever the SVG is opened
+fn image_data(path: &str) -> Option<String> {
+    let mime = if path.ends_with(".svg") {
+        "image/svg+xml"
+    } else if path.ends_with(".png") {
+        "image/png"
+    } else {
+        return None;
+    };
+    if path.starts_with('/') || path.split(['/', '\\']).any(|part| part == "..") {
+        return None;
+    }
+    if let Some(image) = IMAGES.lock().ok()?.get(path) {
+        return Some(image.clone());
+    }
+    let data = std::fs::read(format!("{}{}", STATIC_DIR, path)).ok()?;
+    let image = format!(
+        "data:{};base64,{}",
+        mime,
+        base64::engine::general_purpose::STANDARD.encode(data)
+    );
+    IMAGES.lock().ok()?.insert(path.to_string(), image.clone());
+    Some(image)
+}
+
+fn format_value(value: &DataValue) -> String {
+    match value {
+        DataValue::Bool(b) => b.to_string(),
+        DataValue::Double(d) => format_number(*d),
+        DataValue::String(s) => s.clone(),
+    }
+}
+
+/// Position the HMI shows for the value, see `Helpers.convertPos` of the client
+fn position(value: Option<&DataValue>) -> &'static str {
+    match value {
+        Some(DataValue::Double(pos)) if *pos == 2.0 => "closed",
+        Some(DataValue::Double(pos)) if *pos == 3.0 => "open",
+        _ => "invalid",
+    }
+}
+
+struct Renderer<'a> {
+    options: RenderOptions,
+    /// Absolute bounds of the vertices by cell id
+    bounds: HashMap<&'a str, Bounds>,
+    devices: HashMap<String, Option<DeviceValues>>,
+    extent: Extent,
+    edges: String,
+    vertices: String,
+}
+
+impl<'a> Renderer<'a> {
+    fn device(&mut self, mrid: &str) -> Option<&DeviceValues> {
+        self.devices
+            .entry(mrid.to_string())
+            .or_insert_with(|| live_values(mrid))
+            .as_ref()
+    }
+
+    /// Label, unit and latest value of the display bindings of the cell
+    fn display_values(&mut self, user_object: Node) -> Vec<(String, String, Option<DataValue>)> {
+        let mrid = user_object.attribute("mRID").unwrap_or_default();
+        let items: Vec<Node> = user_object
+            .children()
+            .filter(|n| n.is_element() && n.attribute("as") == Some("displayData"))
+            .flat_map(|array| array.children().filter(|n| n.is_element()))
+            .collect();
+        items
+            .into_iter()
+            .map(|item| {
+                let value = item.attribute("path").and_then(|path| {
+                    self.device(mrid)
+                        .and_then(|device| device.get(path))
+                        .cloned()
+                });
+                (
+                    item.attribute("label").unwrap_or_default().to_string(),
+                    item.attribute("measurement")
+                        .unwrap_or_default()
+                        .to_string(),
+                    value,
+                )
+            })
+            .collect()
+    }
+
+    /// Position of the switchgear from its bindings, the position binding when there is one
+    fn switchgear_state(&mut self, user_object: Option<Node>) -> &'static str {
+        let user_object = match user_object {
+            Some(u) => u,
+            None => return position(None),
+        };
+        let mrid = user_object.attribute("mRID").unwrap_or_default();
+        let mut paths: Vec<&str> = user_object
+            .descendants()
+            .filter(|n| n.is_element())
+            .filter_map(|n| n.attribute("path"))
+            .collect();
+        paths.sort_by_key(|p| !p.to_lowercase().contains(".pos."));
+        let value = self
+            .device(mrid)
+            .and_then(|device| paths.iter().find_map(|p| device.get(p)).cloned());
+        position(value.as_ref())
+    }
+
+    fn vertex(&mut self, cell: Node<'a, 'a>, user_object: Option<Node<'a, 'a>>) {
+        let id = cell.attribute("id").unwrap_or_default();
+        let geometry = cell.children().find(|n| n.has_tag_name("mxGeometry"));
+        let origin = cell
+            .attribute("parent")
+            .and_then(|p| self.bounds.get(p))
+            .map(|b| (b.x, b.y))
+            .unwrap_or_default();
+        let b = Bounds {
+            x: origin.0 + number(geometry, "x"),
+            y: origin.1 + number(geometry, "y"),
+            width: number(geometry, "width"),
+            height: number(geometry, "height"),
+        };
+        self.bounds.insert(id, b);
+        self.extent.add(b.x, b.y);
+        self.extent.add(b.x + b.width, b.y + b.height);
+
+        let style = parse_style(cell.attribute("style").unwrap_or_default());
+        let symbol = user_object
+            .and_then(|u| u.attribute("type"))
+            .unwrap_or_default();
+        let rotation = style
+            .get("rotation")
+            .and_then(|r| r.parse::<f64>().ok())
+            .filter(|r| *r != 0.0)
+            .map(|r| {
+                let (cx, cy) = b.center();
+                format!(
+                    " transform=\"rotate({} {} {})\"",
+                    format_number(r),
+                    format_number(cx),
+                    format_number(cy)
+                )
+            })
+            .unwrap_or_default();
+
+        if style.get("shape") == Some(&"image") {
+            let mut image = style.get("image").map(|i| i.to_string());
+            if self.options.live && SWITCHGEAR_TYPES.contains(&symbol) {
+                let state = format!(
+                    "assets/images/toolbar/{}-{}.svg",
+                    symbol,
+                    self.switchgear_state(user_object)
+                );
+                if image_data(&state).is_some() {
+                    image = Some(state);
+                }
+            }
+            let href = image
+                .as_deref()
+                .map(|i| image_data(i).unwrap_or_else(|| i.to_string()))
+                .unwrap_or_default();
+            let _ = writeln!(
+                self.vertices,
+                "<image x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" preserveAspectRatio=\"none\" xlink:href=\"{}\"{}/>",
+                format_number(b.x),
+                format_number(b.y),
+                format_number(b.width),
+                format_number(b.height),
+                escape(&href),
+                rotation
+            );
+        } else if !style.contains_key("text") {
+            let fill = color(&style, "fillColor", DEFAULT_FILL);
+            let stroke = color(&style, "strokeColor", DEFAULT_STROKE);
+            let stroke_width = style.get("strokeWidth").copied().unwrap_or("1");
+            let dashed = if style.get("dashed") == Some(&"1") {
+                " stroke-dasharray=\"3 3\""
+            } else {
+                ""
+            };
+            if style.get("shape") == Some(&"ellipse") || style.contains_key("ellipse") {
+                let (cx, cy) = b.center();
+                let _ = writeln!(
+                    self.vertices,
+                    "<ellipse cx=\"{}\" cy=\"{}\" rx=\"{}\" ry=\"{}\" fill=\"{}\" stroke=\"{}\" stroke-width=\"{}\"{}{}/>",
+                    format_number(cx),
+                    format_number(cy),
+                    format_number(b.width / 2.0),
+                    format_number(b.height / 2.0),
+                    escape(fill),
+                    escape(stroke),
+                    escape(stroke_width),
+                    dashed,
+                    rotation
+                );
+            } else {
+                let radius = if style.get("rounded") == Some(&"1") {
+                    b.width.min(b.height) * 0.15
+                } else {
+                    0.0
+                };
+                let _ = writeln!(
+                    self.vertices,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"{}\" fill=\"{}\" stroke=\"{}\" stroke-width=\"{}\"{}{}/>",
+                    format_number(b.x),
+                    format_number(b.y),
+                    format_number(b.width),
+                    format_number(b.height),
+                    format_number(radius),
+                    escape(fill),
+                    escape(stroke),
+                    escape(stroke_width),
+                    dashed,
+                    rotation
+                );
+            }
+        }
+
+        let label = user_object
+            .and_then(|u| u.attribute("label"))
+            .or_else(|| cell.attribute("value"))
+            .unwrap_or_default();
+        let mut lines = label_lines(label);
+        if self.options.live && !SWITCHGEAR_TYPES.contains(&symbol) {
+            if let Some(u) = user_object {
+                for (label, measurement, value) in self.display_values(u) {
+                    let value = value
+                        .as_ref()
+                        .map(format_value)
+                        .unwrap_or_else(|| "-".to_string());
+                    let line = [label.as_str(), &value, &measurement]
+                        .iter()
+                        .filter(|s| !s.is_empty())
+                        .cloned()
+                        .collect::<Vec<&str>>()
+                        .join(" ");
+                    lines.push(line);
+                }
+            }
+        }
+        if !lines.is_empty() {
+            self.text(&b, &style, user_object, &lines);
+        }
+    }
+
+    /// Lines of text centered in the cell, or below it for images and
+    /// `verticalLabelPosition=bottom`
+    fn text(
+        &mut self,
+        b: &Bounds,
+        style: &HashMap<&str, &str>,
+        user_object: Option<Node>,
+        lines: &[String],
+    ) {
+        let font_size = user_object
+            .and_then(|u| u.attribute("fontSize"))
+            .or_else(|| style.get("fontSize").copied())
+            .and_then(|s| s.parse::<f64>().ok())
+            .unwrap_or(DEFAULT_FONT_SIZE);
+        let font_color = user_object
+            .and_then(|u| u.attribute("foreColor"))
+            .filter(|c| !c.is_empty())
+            .unwrap_or_else(|| color(style, "fontColor", DEFAULT_FONT_COLOR));
+        let (anchor, x) = match style.get("align") {
+            Some(&"left") => ("start", b.x + 2.0),
+            Some(&"right") => ("end", b.x + b.width - 2.0),
+            _ => ("middle", b.x + b.width / 2.0),
+        };
+        let line_height = font_size * 1.2;
+        let height = line_height * lines.len() as f64;
+        let below = style.get("shape") == Some(&"image")
+            || style.get("verticalLabelPosition") == Some(&"bottom");
+        let top = if below {
+            b.y + b.height + 2.0
+        } else if style.get("verticalAlign") == Some(&"top") {
+            b.y + 2.0
+        } else {
+            b.y + (b.height - height) / 2.0
+        };
+        let _ = writeln!(
+            self.vertices,
+            "<text x=\"{}\" y=\"{}\" font-family=\"Helvetica, Arial, sans-serif\" font-size=\"{}\" fill=\"{}\" text-anchor=\"{}\">",
+            format_number(x),
+            format_number(top),
+            format_number(font_size),
+            escape(font_color),
+            anchor
+        );
+        for (i, line) in lines.iter().enumerate() {
+            let _ = writeln!(
+                self.vertices,
+                "<tspan x=\"{}\" y=\"{}\">{}</tspan>",
+                format_number(x),
+                format_number(top + line_height * (i as f64 + 0.8)),
+                escape(line)
+            );
+        }
+        let _ = writeln!(self.vertices, "</text>");
+        self.extent.add(x, top + height);
+    }
+
+    fn edge(&mut self, cell: Node) {
+        let geometry = cell.children().find(|n| n.has_tag_name("mxGeometry"));
+        let point = |name: &str| {
+            geometry
+                .and_then(|g| {
+                    g.children()
+                        .find(|n| n.has_tag_name("mxPoint") && n.attribute("as") == Some(name))
+                })
+                .map(|p| (number(Some(p), "x"), number(Some(p), "y")))
+        };
+        let terminal = |id: Option<&str>| id.and_then(|id| self.bounds.get(id)).map(|b| b.center());
+        let source = terminal(cell.attribute("source")).or_else(|| point("sourcePoint"));
+        let target = terminal(cell.attribute("target")).or_else(|| point("targetPoint"));
+        let (source, target) = match (source, target) {
+            (Some(source), Some(target)) => (source, target),
+            _ => return,
+        };
+        let waypoints: Vec<(f64, f64)> = geometry
+            .and_then(|g| {
+                g.children()
+                    .find(|n| n.has_tag_name("Array") && n.attribute("as") == Some("points"))
+            })
+            .map(|array| {
+                array
+                    .children()
+                    .filter(|n| n.has_tag_name("mxPoint"))
+                    .map(|p| (number(Some(p), "x"), number(Some(p), "y")))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let style = parse_style(cell.attribute("style").unwrap_or_default());
+        // The designer routes connections orthogonally unless the style says otherwise, the
+        // route is approximated with a bend half way between two points
+        let orthogonal = style.get("edgeStyle").map_or(true, |s| {
+            *s == "orthogonalEdgeStyle" || *s == "elbowEdgeStyle"
+        });
+        let mut points = vec![source];
+        points.extend(waypoints);
+        points.push(target);
+        let mut route = vec![points[0]];
+        for pair in points.windows(2) {
+            let ((x1, y1), (x2, y2)) = (pair[0], pair[1]);
+            if orthogonal && x1 != x2 && y1 != y2 {
+                let middle = (x1 + x2) / 2.0;
+                route.push((middle, y1));
+                route.push((middle, y2));
+            }
+            route.push((x2, y2));
+        }
+        for (x, y) in &route {
+            self.extent.add(*x, *y);
+        }
+
+        let _ = writeln!(
+            self.edges,
+            "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\"{}/>",
+            route
+                .iter()
+                .map(|(x, y)| format!("{},{}", format_number(*x), format_number(*y)))
+                .collect::<Vec<String>>()
+                .join(" "),
+            escape(color(&style, "strokeColor", DEFAULT_STROKE)),
+            escape(style.get("strokeWidth").copied().unwrap_or("2")),
+            if style.get("dashed") == Some(&"1") {
+                " stroke-dasharray=\"3 3\""
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+/// SVG of the diagram as the HMI draws it, with the latest values received when
+/// `options.live` is set. Images are at most `MAX_IMAGE_SIZE` wide and high.
+pub fn render_svg(diagram: &Diagram, options: RenderOptions) -> Result<String, Error> {
+    if matches!(options.width, Some(w) if w > MAX_IMAGE_SIZE) {
+        return Err(Error::InvalidRequestError(format!(
+            "the width can be at most {} pixels",
+            MAX_IMAGE_SIZE
+        )));
+    }
+    let data = diagram.data.as_deref().unwrap_or_default();
+    let doc = if data.trim().is_empty() {
+        None
+    } else {
+        Some(Document::parse(data).map_err(|e| {
+            Error::InvalidRequestError(format!("the diagram data can't be read: {}", e))
+        })?)
+    };
+    let mut renderer = Renderer {
+        options,
+        bounds: HashMap::new(),
+        devices: HashMap::new(),
+        extent: Extent::new(),
+        edges: String::new(),
+        vertices: String::new(),
+    };
+
+    let cells: Vec<Node> = doc
+        .as_ref()
+        .and_then(|doc| doc.descendants().find(|n| n.has_tag_name("root")))
+        .map(|root| {
+            root.descendants()
+                .filter(|n| n.is_element() && n.attribute("parent").map_or(false, |p| p != "0"))
+                .filter(|n| n.attribute("id").is_some())
+                .collect()
+        })
+        .unwrap_or_default();
+    // Connections are drawn below the symbols, after every symbol has its place
+    for cell in &cells {
+        if cell.attribute("vertex") == Some("1") && cell.attribute("visible") != Some("0") {
+            let user_object = cell
+                .children()
+                .find(|n| n.is_element() && n.attribute("as") == Some("userObject"));
+            renderer.vertex(*cell, user_object);
+        }
+    }
+    for cell in &cells {
+        if cell.attribute("edge") == Some("1") && cell.attribute("visible") != Some("0") {
+            renderer.edge(*cell);
+        }
+    }
+
+    let mut footer = String::new();
+    if options.live {
+        let (x, y) = if renderer.extent.is_empty() {
+            (0.0, 0.0)
+        } else {
+            (renderer.extent.min.0, renderer.extent.max.1 + 20.0)
+        };
+        let _ = writeln!(
+            footer,
+            "<text x=\"{}\" y=\"{}\" font-family=\"Helvetica, Arial, sans-serif\" font-size=\"10\" fill=\"#606060\">Live values as of {}</text>",
+            format_number(x),
+            format_number(y),
+            Utc::now().format("%Y-%m-%d %H:%M:%S UTC")
+        );
+        renderer.extent.add(x, y);
+    }
+    if renderer.extent.is_empty() {
+        renderer.extent.add(0.0, 0.0);
+    }
+
+    let (min, max) = (renderer.extent.min, renderer.extent.max);
+    let view = Bounds {
+        x: min.0 - MARGIN,
+        y: min.1 - MARGIN,
+        width: max.0 - min.0 + 2.0 * MARGIN,
+        height: max.1 - min.1 + 2.0 * MARGIN,
+    };
+    let mut scale = options
+        .width
+        .filter(|w| *w > 0)
+        .map_or(1.0, |w| w as f64 / view.width);
+    let largest = (view.width * scale).max(view.height * scale);
+    if largest > MAX_IMAGE_SIZE as f64 {
+        scale *= MAX_IMAGE_SIZE as f64 / largest;
+    }
+    let background = diagram
+        .backgroundColor
+        .as_deref()
+        .filter(|c| !c.is_empty())
+        .unwrap_or("#ffffff");
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" version=\"1.1\" width=\"{}\" height=\"{}\" viewBox=\"{} {} {} {}\">",
+        format_number((view.width * scale).ceil()),
+        format_number((view.height * scale).ceil()),
+        format_number(view.x),
+        format_number(view.y),
+        format_number(view.width),
+        format_number(view.height)
+    );
+    let _ = writeln!(
+        svg,
+        "<title>{}</title>",
+        escape(diagram.name.as_deref().unwrap_or(&diagram.diagramId))
+    );
+    let _ = writeln!(
+        svg,
+        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+        format_number(view.x),
+        format_number(view.y),
+        format_number(view.width),
+        format_number(view.height),
+        escape(background)
+    );
+    svg.push_str(&renderer.edges);
+    svg.push_str(&renderer.vertices);
+    svg.push_str(&footer);
+    svg.push_str("</svg>\n");
+    Ok(svg)
+}
+
+/// PNG of the SVG, text is drawn with the fonts installed on the server
+#[cfg(feature = "png")]
+pub fn render_png(svg: &str) -> Result<Vec<u8>, Error> {
+    use resvg::usvg::{self, TreeParsing, TreeTextToPath};
+
+    let failed = |e: String| Error::InvalidRequestError(format!("can't render PNG: {}", e));
+    let mut tree =
+        usvg::Tree::from_str(svg, &usvg::Options::default()).map_err(|e| failed(e.to_string()))?;
+    tree.convert_text(&FONTS);
+    let tree = resvg::Tree::from_usvg(&tree);
+    let size = tree.size.to_int_size();
+    let mut pixmap = resvg::tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or_else(|| failed("the image is empty".to_string()))?;
+    tree.render(resvg::tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    pixmap.encode_png().map_err(|e| failed(e.to_string()))
+}
+
+#[cfg(not(feature = "png"))]
+pub fn render_png(_svg: &str) -> Result<Vec<u8>, Error> {
+    Err(Error::InvalidRequestError(
+        "PNG rendering is not enabled on this server, build it with the png feature".to_string(),
+    ))
+}
+
+/// Image of the published diagram, or of the latest draft for editors
+// GET
+pub async fn render_diagram_handler(
+    id: String,
+    query: RenderDiagramQuery,
+) -> Result<impl Reply, Rejection> {
+    if query.draft && !has_permission(&id, Permission::EditDiagram) {
+        return Err(reject::custom(Error::NoPermissionError));
+    }
+    let options = RenderOptions {
+        live: query.live,
+        width: query.width,
+    };
+    // Large diagrams take a while to draw, more so as PNG
+    let (diagram_id, draft, format) = (query.id.clone(), query.draft, query.format);
+    let (body, content_type) = run_blocking(move || {
+        let diagram = if draft {
+            storage().diagram(&diagram_id)?
+        } else {
+            storage().published_diagram(&diagram_id)?
+        }
+        .ok_or_else(|| {
+            Error::NotFoundError(if draft {
+                format!("diagram {}", diagram_id)
+            } else {
+                format!("published diagram {}", diagram_id)
+            })
+        })?;
+        let svg = render_svg(&diagram, options)?;
+        Ok(match format {
+            RenderFormat::Svg => (svg.into_bytes(), "image/svg+xml"),
+            RenderFormat::Png => (render_png(&svg)?, "image/png"),
+        })
+    })
+    .await
+    .map_err(|e| reject::custom(e))?;
+    // Live images are out of date as soon as the next message arrives
+    let cache = if query.live { "no-store" } else { "no-cache" };
+
+    Ok(reply::with_header(
+        reply::with_header(body, CONTENT_TYPE, content_type),
+        CACHE_CONTROL,
+        cache,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::live_values::update_live_values;
+
+    fn diagram(cells: &[&str]) -> Diagram {
+        Diagram {
+            diagramId: "feeder".to_string(),
+            name: Some("Feeder <1>".to_string()),
+            description: None,
+            location: None,
+            data: Some(format!(
+                r#"<mxGraphModel><root><mxCell id="0"/><mxCell id="1" parent="0"/>{}</root></mxGraphModel>"#,
+                cells.concat()
+            )),
+            createdDate: None,
+            createdBy: None,
+            backgroundColor: Some("#202020".to_string()),
+            version: None,
+            published_version: None,
+        }
+    }
+
+    fn vertex(id: &str, style: &str, x: f64, y: f64, width: f64, height: f64) -> String {
+        format!(
+            r#"<mxCell id="{}" value="{}" style="{}" parent="1" vertex="1"><mxGeometry x="{}" y="{}" width="{}" height="{}" as="geometry"/></mxCell>"#,
+            id, id, style, x, y, width, height
+        )
+    }
+
+    fn svg_size(svg: &str) -> (f64, f64) {
+        let doc = Document::parse(svg).unwrap();
+        let root = doc.root_element();
+        (number(Some(root), "width"), number(Some(root), "height"))
+    }
+
+    #[test]
+    fn empty_diagram_is_the_background() {
+        let mut empty = diagram(&[]);
+        empty.data = None;
+        let svg = render_svg(&empty, RenderOptions::default()).unwrap();
+        assert!(svg.contains("<title>Feeder &lt;1&gt;</title>"));
+        assert!(svg.contains(r##"<rect x="-10" y="-10" width="20" height="20" fill="#202020"/>"##));
+        assert_eq!(svg_size(&svg), (20.0, 20.0));
+    }
+
+    #[test]
+    fn cells_are_drawn_with_their_style() {
+        let svg = render_svg(
+            &diagram(&[
+                &vertex("a", "rounded=1;fillColor=#ff0000", 0.0, 0.0, 40.0, 20.0),
+                &vertex("b", "ellipse;dashed=1", 100.0, 100.0, 20.0, 20.0),
+                &vertex("c", "text;align=left", 0.0, 200.0, 60.0, 20.0),
+                r#"<mxCell id="l" parent="1" edge="1" source="a" target="b"><mxGeometry relative="1" as="geometry"/></mxCell>"#,
+                r#"<mxCell id="h" parent="1" vertex="1" visible="0"><mxGeometry x="900" y="900" width="10" height="10" as="geometry"/></mxCell>"#,
+            ]),
+            RenderOptions::default(),
+        )
+        .unwrap();
+        assert!(svg.contains(
+            r##"<rect x="0" y="0" width="40" height="20" rx="3" fill="#ff0000" stroke="#6482B9" stroke-width="1"/>"##
+        ));
+        assert!(svg.contains(r#"<ellipse cx="110" cy="110" rx="10" ry="10""#));
+        assert!(svg.contains(r#"stroke-dasharray="3 3"/>"#));
+        // Text cells have no shape, only their label
+        assert!(!svg.contains(r#"<rect x="0" y="200""#));
+        assert!(svg.contains(r#"text-anchor="start""#));
+        // The connection bends half way between the centers of its terminals
+        assert!(svg.contains(r#"<polyline points="20,10 65,10 65,110 110,110""#));
+        // Hidden cells are left out
+        assert_eq!(svg_size(&svg), (140.0, 240.0));
+        // Connections are drawn below the symbols
+        assert!(svg.find("<polyline").unwrap() < svg.find("<ellipse").unwrap());
+    }
+
+    #[test]
+    fn images_are_scaled_to_the_width() {
+        let data = diagram(&[&vertex("a", "", 0.0, 0.0, 180.0, 80.0)]);
+        let svg = render_svg(
+            &data,
+            RenderOptions {
+                live: false,
+                width: Some(100),
+            },
+        )
+        .unwrap();
+        assert_eq!(svg_size(&svg), (100.0, 50.0));
+        assert!(svg.contains(r#"viewBox="-10 -10 200 100""#));
+    }
+
+    #[test]
+    fn images_are_at_most_the_largest_size() {
+        let tall = diagram(&[&vertex("a", "", 0.0, 0.0, 1000.0, 20000.0)]);
+        let (width, height) = svg_size(&render_svg(&tall, RenderOptions::default()).unwrap());
+        assert_eq!(height, MAX_IMAGE_SIZE as f64);
+        assert!(width < 300.0);
+
+        let small = diagram(&[&vertex("a", "", 0.0, 0.0, 100.0, 100.0)]);
+        let options = |width| RenderOptions {
+            live: false,
+            width: Some(width),
+        };
+        let (width, height) = svg_size(&render_svg(&small, options(MAX_IMAGE_SIZE)).unwrap());
+        assert_eq!((width, height), (4096.0, 4096.0));
+        assert!(matches!(
+            render_svg(&small, options(MAX_IMAGE_SIZE + 1)),
+            Err(Error::InvalidRequestError(_))
+        ));
+    }
+
+    #[test]
+    fn live_images_show_the_latest_values() {
+        let mrid = "render-test-device";
+        let path = "SolarReadingProfile.mapping.readingMMXU.W.net.cVal.mag.f";
+        update_live_values(
+            mrid,
+            vec![(path.to_string(), DataValue::Double(12.345))]
+                .into_iter()
+                .collect(),
+        );
+        let cell = format!(
+            r#"<mxCell id="s" parent="1" vertex="1" style="shape=image;image=assets/images/none.svg"><Object as="userObject" type="solar" label="PV" mRID="{}"><Array as="displayData"><Object label="Power" measurement="kW" path="{}"/><Object label="State" path="SolarStatusProfile.mapping.x"/></Array></Object><mxGeometry x="0" y="0" width="40" height="40" as="geometry"/></mxCell>"#,
+            mrid, path
+        );
+        let data = diagram(&[&cell]);
+
+        let svg = render_svg(&data, RenderOptions::default()).unwrap();
+        assert!(svg.contains(">PV</tspan>"));
+        assert!(!svg.contains("Power"));
+
+        let svg = render_svg(
+            &data,
+            RenderOptions {
+                live: true,
+                width: None,
+            },
+        )
+        .unwrap();
+        assert!(svg.contains(">Power 12.35 kW</tspan>"));
+        assert!(svg.contains(">State -</tspan>"));
+        assert!(svg.contains("Live values as of"));
+        // Images that can't be read are linked
+        assert!(svg.contains(r#"xlink:href="assets/images/none.svg""#));
+    }
+
+    #[test]
+    fn images_outside_the_client_are_not_read() {
+        assert!(image_data("../Cargo.toml.svg").is_none());
+        assert!(image_data("/etc/passwd.svg").is_none());
+        assert!(image_data("assets/images/none.txt").is_none());
+    }
+
+    #[test]
+    fn unreadable_data_is_refused() {
+        let mut broken = diagram(&[]);
+        broken.data = Some("<mxGraphModel>".to_string());
+        assert!(matches!(
+            render_svg(&broken, RenderOptions::default()),
+            Err(Error::InvalidRequestError(_))
+        ));
+    }
+}
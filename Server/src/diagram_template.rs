@@ -0,0 +1,739 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::audit::record_audit;
+use crate::diagram_history::author_name;
+use crate::diagram_id::new_diagram_id;
+use crate::diagram_validation::{check_before_save, DiagramProblem, Severity};
+use crate::error::Error;
+use crate::handler::{read_equipment_list, Diagram, Equipment};
+use crate::storage::{storage, Precondition};
+use chrono::prelude::*;
+use roxmltree::{Attribute, Document, Node};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use warp::{reject, reply::json, Rejection, Reply};
+
+/// Longest placeholder name accepted
+pub const MAX_PLACEHOLDER_LENGTH: usize = 64;
+
+/// Diagram saved with placeholders in place of the mRIDs of its equipment, to draw the same
+/// single-line layout for several sites
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DiagramTemplate {
+    pub id: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Diagram the template was made from
+    pub diagram_id: String,
+    /// Diagram data where each mRID is `{{placeholder}}`
+    pub data: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_color: Option<String>,
+    pub placeholders: Vec<TemplatePlaceholder>,
+    pub created_by: String,
+    /// Unix timestamp in seconds
+    pub created_date: i64,
+}
+
+/// Equipment the template leaves open
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TemplatePlaceholder {
+    pub name: String,
+    /// Device type the equipment filling in the placeholder must have, any equipment when
+    /// it is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_type: Option<String>,
+    /// Equipment the diagram showed when the template was made
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub equipment_name: Option<String>,
+    /// Number of mRID attributes the placeholder stands for
+    pub occurrences: usize,
+}
+
+#[derive(Deserialize)]
+pub struct SaveDiagramTemplateRequest {
+    pub diagram_id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Template to replace, a new template is made when it is not set
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Placeholder names by mRID, the others get the device type and a number, e.g.
+    /// `breaker-2`
+    #[serde(default)]
+    pub placeholders: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteDiagramTemplateRequest {
+    pub id: String,
+}
+
+#[derive(Deserialize)]
+pub struct InstantiateDiagramTemplateRequest {
+    pub template_id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub location: Option<String>,
+    /// Equipment mRID by placeholder name, every placeholder must be mapped
+    pub mapping: BTreeMap<String, String>,
+}
+
+/// What the template list shows of a template, without its drawing
+#[derive(Serialize)]
+pub struct DiagramTemplateSummary {
+    pub id: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub diagram_id: String,
+    pub placeholders: Vec<TemplatePlaceholder>,
+    pub created_by: String,
+    pub created_date: i64,
+}
+
+impl From<DiagramTemplate> for DiagramTemplateSummary {
+    fn from(template: DiagramTemplate) -> Self {
+        DiagramTemplateSummary {
+            id: template.id,
+            name: template.name,
+            description: template.description,
+            diagram_id: template.diagram_id,
+            placeholders: template.placeholders,
+            created_by: template.created_by,
+            created_date: template.created_date,
+        }
+    }
+}
+
+fn is_safe_placeholder(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_PLACEHOLDER_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn placeholder_token(name: &str) -> String {
+    format!("{{{{{}}}}}", name)
+}
+
+/// Name of the placeholder `value` is the token of
+fn placeholder_of(value: &str) -> Option<&str> {
+    value
+        .strip_prefix("{{")
+        .and_then(|v| v.strip_suffix("}}"))
+        .filter(|name| is_safe_placeholder(name))
+}
+
+fn is_mrid_attribute(name: &str) -> bool {
+    name == "mRID" || name == "mrid"
+}
+
+/// The `mRID` attributes of the cells, with the user objects of the cells and the
+/// data-connect bindings under them
+fn mrid_attributes<'a, 'input>(doc: &'a Document<'input>) -> Vec<Attribute<'a, 'input>> {
+    let in_user_object = |n: &Node| {
+        n.ancestors()
+            .any(|a| a.is_element() && a.attribute("as") == Some("userObject"))
+    };
+    doc.descendants()
+        .filter(|n| n.is_element() && in_user_object(n))
+        .flat_map(|n| n.attributes())
+        .filter(|a| is_mrid_attribute(a.name()) && !a.value().is_empty())
+        .collect()
+}
+
+pub(crate) fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Replaces the values of the `mRID` attributes `replace` has a value for, the rest of the
+/// data is kept as it is
+fn rewrite_mrids(
+    data: &str,
+    mut replace: impl FnMut(&str) -> Option<String>,
+) -> Result<String, Error> {
+    let doc = Document::parse(data)
+        .map_err(|e| Error::InvalidRequestError(format!("diagram data can't be read: {}", e)))?;
+    let mut edits = vec![];
+    for attribute in mrid_attributes(&doc) {
+        let value = match replace(attribute.value()) {
+            Some(value) => value,
+            None => continue,
+        };
+        // The attribute starts at its name, the value is between the quotes after `=`
+        let rest = &data[attribute.position()..];
+        let start = rest.find(['"', '\'']).map(|i| attribute.position() + i + 1);
+        let end = start.and_then(|start| {
+            let quote = &data[start - 1..start];
+            data[start..].find(quote).map(|i| start + i)
+        });
+        match (start, end) {
+            (Some(start), Some(end)) => edits.push((start, end, escape_attribute(&value))),
+            _ => {
+                return Err(Error::InvalidRequestError(
+                    "diagram data can't be read".to_string(),
+                ))
+            }
+        }
+    }
+
+    let mut rewritten = String::with_capacity(data.len());
+    let mut last = 0;
+    edits.sort_by_key(|(start, _, _)| *start);
+    for (start, end, value) in edits {
+        rewritten.push_str(&data[last..start]);
+        rewritten.push_str(&value);
+        last = end;
+    }
+    rewritten.push_str(&data[last..]);
+    Ok(rewritten)
+}
+
+/// Template of the diagram, each distinct mRID becomes a placeholder
+pub fn make_template(
+    diagram: &Diagram,
+    request: &SaveDiagramTemplateRequest,
+    equipment: &[Equipment],
+) -> Result<(String, Vec<TemplatePlaceholder>), Error> {
+    let data = diagram.data.as_deref().unwrap_or_default();
+    let doc = Document::parse(data)
+        .map_err(|e| Error::InvalidRequestError(format!("diagram data can't be read: {}", e)))?;
+
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut placeholders: Vec<TemplatePlaceholder> = vec![];
+    let mut counters: HashMap<String, usize> = HashMap::new();
+    for attribute in mrid_attributes(&doc) {
+        let mrid = attribute.value();
+        if let Some(name) = names.get(mrid) {
+            if let Some(p) = placeholders.iter_mut().find(|p| &p.name == name) {
+                p.occurrences += 1;
+            }
+            continue;
+        }
+        let eq = equipment.iter().find(|e| e.mrid == mrid);
+        let device_type = eq.and_then(|e| e.device_type.clone());
+        let name = match request.placeholders.get(mrid) {
+            Some(name) => name.clone(),
+            None => loop {
+                let prefix = device_type.as_deref().unwrap_or("equipment");
+                let counter = counters.entry(prefix.to_string()).or_insert(0);
+                *counter += 1;
+                let name = format!("{}-{}", prefix, counter);
+                if !request.placeholders.values().any(|n| *n == name) {
+                    break name;
+                }
+            },
+        };
+        if !is_safe_placeholder(&name) {
+            return Err(Error::InvalidRequestError(format!(
+                "placeholder name '{}' may only have letters, digits, '-' and '_'",
+                name
+            )));
+        }
+        if placeholders.iter().any(|p| p.name == name) {
+            return Err(Error::InvalidRequestError(format!(
+                "placeholder name '{}' is given to more than one mRID",
+                name
+            )));
+        }
+        names.insert(mrid.to_string(), name.clone());
+        placeholders.push(TemplatePlaceholder {
+            name,
+            device_type,
+            equipment_name: eq.map(|e| e.name.clone()),
+            occurrences: 1,
+        });
+    }
+    if let Some(mrid) = request
+        .placeholders
+        .keys()
+        .find(|m| !names.contains_key(*m))
+    {
+        return Err(Error::InvalidRequestError(format!(
+            "mRID {} is not in the diagram",
+            mrid
+        )));
+    }
+
+    let data = rewrite_mrids(data, |mrid| names.get(mrid).map(|n| placeholder_token(n)))?;
+    Ok((data, placeholders))
+}
+
+fn template_problem(code: &'static str, mrid: Option<&str>, message: String) -> DiagramProblem {
+    DiagramProblem {
+        severity: Severity::Error,
+        code,
+        element_id: None,
+        mrid: mrid.map(|m| m.to_string()),
+        message,
+    }
+}
+
+/// Data of a diagram made from the template, every placeholder must be mapped to equipment
+/// of its device type
+pub fn instantiate(
+    template: &DiagramTemplate,
+    mapping: &BTreeMap<String, String>,
+    equipment: &[Equipment],
+) -> Result<String, Error> {
+    let mut problems = vec![];
+    for placeholder in &template.placeholders {
+        let mrid = match mapping.get(&placeholder.name) {
+            Some(mrid) => mrid,
+            None => {
+                problems.push(template_problem(
+                    "unmapped_placeholder",
+                    None,
+                    format!(
+                        "placeholder {} is not mapped to equipment",
+                        placeholder.name
+                    ),
+                ));
+                continue;
+            }
+        };
+        let eq = match equipment.iter().find(|e| &e.mrid == mrid) {
+            Some(eq) => eq,
+            None => {
+                problems.push(template_problem(
+                    "unknown_equipment",
+                    Some(mrid),
+                    format!(
+                        "placeholder {} is mapped to {} which is not in the equipment list",
+                        placeholder.name, mrid
+                    ),
+                ));
+                continue;
+            }
+        };
+        if let Some(device_type) = &placeholder.device_type {
+            if eq.device_type.as_ref() != Some(device_type) {
+                problems.push(template_problem(
+                    "incompatible_device_type",
+                    Some(mrid),
+                    format!(
+                        "placeholder {} needs a {}, {} is a {}",
+                        placeholder.name,
+                        device_type,
+                        eq.name,
+                        eq.device_type.as_deref().unwrap_or("device without a type")
+                    ),
+                ));
+            }
+        }
+    }
+    for name in mapping.keys() {
+        if !template.placeholders.iter().any(|p| &p.name == name) {
+            problems.push(template_problem(
+                "unknown_placeholder",
+                None,
+                format!("template {} has no placeholder {}", template.name, name),
+            ));
+        }
+    }
+    if !problems.is_empty() {
+        return Err(Error::InvalidDiagramError {
+            errors: problems.len(),
+            problems: serde_json::to_value(&problems)?,
+        });
+    }
+
+    rewrite_mrids(&template.data, |value| {
+        placeholder_of(value).and_then(|name| mapping.get(name).cloned())
+    })
+}
+
+fn find_template(id: &str) -> Result<DiagramTemplate, Error> {
+    storage()
+        .diagram_template(id)?
+        .ok_or_else(|| Error::NotFoundError(format!("diagram template {}", id)))
+}
+
+// GET
+pub async fn diagram_templates_handler(_id: String) -> Result<impl Reply, Rejection> {
+    let templates: Vec<DiagramTemplateSummary> = storage()
+        .diagram_templates()
+        .map_err(|e| reject::custom(e))?
+        .into_iter()
+        .map(DiagramTemplateSummary::from)
+        .collect();
+    Ok(json(&templates))
+}
+
+/// Saves the latest revision of a diagram as a template
+// POST
+pub async fn save_diagram_template_handler(
+    id: String,
+    request: SaveDiagramTemplateRequest,
+) -> Result<impl Reply, Rejection> {
+    if request.name.trim().is_empty() {
+        return Err(reject::custom(Error::InvalidRequestError(
+            "a template needs a name".to_string(),
+        )));
+    }
+    let diagram = storage()
+        .diagram(&request.diagram_id)
+        .map_err(|e| reject::custom(e))?
+        .ok_or_else(|| {
+            reject::custom(Error::NotFoundError(format!(
+                "diagram {}",
+                request.diagram_id
+            )))
+        })?;
+    let existing = match &request.id {
+        Some(template_id) => Some(find_template(template_id).map_err(|e| reject::custom(e))?),
+        None => None,
+    };
+    let equipment = read_equipment_list().map_err(|e| reject::custom(e))?;
+    let (data, placeholders) =
+        make_template(&diagram, &request, &equipment).map_err(|e| reject::custom(e))?;
+
+    let template = DiagramTemplate {
+        id: existing
+            .as_ref()
+            .map(|t| t.id.clone())
+            .unwrap_or_else(new_diagram_id),
+        name: request.name.trim().to_string(),
+        description: request.description.clone(),
+        diagram_id: diagram.diagramId.clone(),
+        data,
+        background_color: diagram.backgroundColor.clone(),
+        placeholders,
+        created_by: author_name(&id),
+        created_date: Utc::now().timestamp(),
+    };
+    let before = storage()
+        .save_diagram_template(&template)
+        .map_err(|e| reject::custom(e))?;
+    record_audit(
+        &id,
+        if before.is_some() {
+            "diagram_template.update"
+        } else {
+            "diagram_template.create"
+        },
+        &template.id,
+        before.map(DiagramTemplateSummary::from).as_ref(),
+        Some(&DiagramTemplateSummary::from(template.clone())),
+    );
+
+    Ok(json(&DiagramTemplateSummary::from(template)))
+}
+
+// POST
+pub async fn delete_diagram_template_handler(
+    id: String,
+    request: DeleteDiagramTemplateRequest,
+) -> Result<impl Reply, Rejection> {
+    let removed = storage()
+        .delete_diagram_template(&request.id)
+        .map_err(|e| reject::custom(e))?
+        .ok_or_else(|| {
+            reject::custom(Error::NotFoundError(format!(
+                "diagram template {}",
+                request.id
+            )))
+        })?;
+    record_audit::<Value>(&id, "diagram_template.delete", &removed.id, None, None);
+
+    Ok(json(&DiagramTemplateSummary::from(removed)))
+}
+
+/// Makes a new diagram draft from a template, with the placeholders replaced by the mRIDs
+/// of the mapped equipment
+// POST
+pub async fn instantiate_diagram_template_handler(
+    id: String,
+    request: InstantiateDiagramTemplateRequest,
+) -> Result<impl Reply, Rejection> {
+    if request.name.trim().is_empty() {
+        return Err(reject::custom(Error::InvalidRequestError(
+            "a diagram needs a name".to_string(),
+        )));
+    }
+    let template = find_template(&request.template_id).map_err(|e| reject::custom(e))?;
+    let equipment = read_equipment_list().map_err(|e| reject::custom(e))?;
+    let data =
+        instantiate(&template, &request.mapping, &equipment).map_err(|e| reject::custom(e))?;
+
+    let mut diagram = Diagram {
+        diagramId: new_diagram_id(),
+        name: Some(request.name.trim().to_string()),
+        description: request.description.clone().or(template.description),
+        location: request.location.clone(),
+        data: Some(data),
+        createdDate: Some(Utc::now().format("%Y-%m-%d").to_string()),
+        createdBy: Some(author_name(&id)),
+        backgroundColor: template.background_color,
+        version: None,
+        published_version: None,
+    };
+    check_before_save(&diagram).map_err(|e| reject::custom(e))?;
+    let (_, revision) = storage()
+        .save_diagram(&diagram, &id, &author_name(&id), Precondition::New)
+        .map_err(|e| reject::custom(e))?;
+    diagram.version = Some(revision.revision);
+    record_audit(
+        &id,
+        "diagram.create",
+        &diagram.diagramId,
+        None,
+        Some(&diagram),
+    );
+
+    Ok(json(&diagram))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two breakers, the first also bound in its displayed data, a solar inverter and a
+    /// device that isn't in the equipment list
+    const DATA: &str = r#"<mxGraphModel><root><mxCell id="0"/><mxCell id="1" parent="0"/><mxCell id="b1" parent="1" vertex="1"><Object as="userObject" label="B1" mRID="m1"><Array as="displayData"><Object path="BreakerStatusProfile" mRID="m1"/></Array></Object></mxCell><mxCell id="b2" parent="1" vertex="1"><Object as="userObject" label="B2" mRID='m2'/></mxCell><mxCell id="s1" parent="1" vertex="1"><Object as="userObject" label="S1" mRID="m3"/></mxCell><mxCell id="x1" parent="1" vertex="1"><Object as="userObject" label="X1" mRID="m9"/></mxCell><mxCell id="n1" parent="1" mRID="m1"/></root></mxGraphModel>"#;
+
+    fn equipment() -> Vec<Equipment> {
+        [
+            ("m1", "breaker"),
+            ("m2", "breaker"),
+            ("m3", "solar"),
+            ("n1", "breaker"),
+            ("n2", "breaker"),
+            ("n3", "solar"),
+            ("n4", "solar"),
+        ]
+        .iter()
+        .map(|(mrid, device_type)| Equipment {
+            mrid: mrid.to_string(),
+            name: format!("Device {}", mrid),
+            device_type: Some(device_type.to_string()),
+            group: None,
+            version: None,
+        })
+        .collect()
+    }
+
+    fn diagram() -> Diagram {
+        Diagram {
+            diagramId: "feeder".to_string(),
+            name: Some("Feeder".to_string()),
+            description: None,
+            location: None,
+            data: Some(DATA.to_string()),
+            createdDate: None,
+            createdBy: None,
+            backgroundColor: None,
+            version: None,
+            published_version: None,
+        }
+    }
+
+    fn request(placeholders: &[(&str, &str)]) -> SaveDiagramTemplateRequest {
+        SaveDiagramTemplateRequest {
+            diagram_id: "feeder".to_string(),
+            name: "Feeder".to_string(),
+            description: None,
+            id: None,
+            placeholders: placeholders
+                .iter()
+                .map(|(mrid, name)| (mrid.to_string(), name.to_string()))
+                .collect(),
+        }
+    }
+
+    fn template(placeholders: &[(&str, &str)]) -> DiagramTemplate {
+        let (data, placeholders) =
+            make_template(&diagram(), &request(placeholders), &equipment()).unwrap();
+        DiagramTemplate {
+            id: "t".to_string(),
+            name: "Feeder".to_string(),
+            description: None,
+            diagram_id: "feeder".to_string(),
+            data,
+            background_color: None,
+            placeholders,
+            created_by: "one".to_string(),
+            created_date: 0,
+        }
+    }
+
+    fn mapping(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, mrid)| (name.to_string(), mrid.to_string()))
+            .collect()
+    }
+
+    fn problem_codes(error: Error) -> Vec<String> {
+        match error {
+            Error::InvalidDiagramError { problems, .. } => problems
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|p| p["code"].as_str().unwrap().to_string())
+                .collect(),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn each_distinct_mrid_becomes_a_placeholder() {
+        let template = template(&[]);
+        let summary: Vec<(&str, Option<&str>, usize)> = template
+            .placeholders
+            .iter()
+            .map(|p| (p.name.as_str(), p.device_type.as_deref(), p.occurrences))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("breaker-1", Some("breaker"), 2),
+                ("breaker-2", Some("breaker"), 1),
+                ("solar-1", Some("solar"), 1),
+                ("equipment-1", None, 1),
+            ]
+        );
+        assert_eq!(
+            template.placeholders[0].equipment_name.as_deref(),
+            Some("Device m1")
+        );
+        assert!(template.data.contains(r#"label="B1" mRID="{{breaker-1}}""#));
+        assert!(template
+            .data
+            .contains(r#"path="BreakerStatusProfile" mRID="{{breaker-1}}""#));
+        assert!(template.data.contains("mRID='{{breaker-2}}'"));
+        // Only cells' user objects and their bindings carry equipment
+        assert!(template
+            .data
+            .contains(r#"<mxCell id="n1" parent="1" mRID="m1"/>"#));
+    }
+
+    #[test]
+    fn chosen_placeholder_names_are_kept_free() {
+        let template = template(&[("m2", "breaker-1"), ("m3", "pv_array")]);
+        let names: Vec<&str> = template
+            .placeholders
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["breaker-2", "breaker-1", "pv_array", "equipment-1"]
+        );
+    }
+
+    #[test]
+    fn placeholder_names_are_checked() {
+        let refused = |placeholders: &[(&str, &str)]| {
+            matches!(
+                make_template(&diagram(), &request(placeholders), &equipment()),
+                Err(Error::InvalidRequestError(_))
+            )
+        };
+        assert!(refused(&[("m1", "main breaker")]));
+        assert!(refused(&[("m1", "{{main}}")]));
+        assert!(refused(&[("m1", "main"), ("m2", "main")]));
+        assert!(refused(&[("m7", "main")]));
+        assert!(!refused(&[("m1", "main")]));
+    }
+
+    #[test]
+    fn instantiating_fills_in_the_placeholders() {
+        let template = template(&[]);
+        let data = instantiate(
+            &template,
+            &mapping(&[
+                ("breaker-1", "n1"),
+                ("breaker-2", "n2"),
+                ("solar-1", "n3"),
+                ("equipment-1", "n4"),
+            ]),
+            &equipment(),
+        )
+        .unwrap();
+        let expected = DATA
+            .replace(r#"mRID="m1"><Array"#, r#"mRID="n1"><Array"#)
+            .replace(
+                r#"path="BreakerStatusProfile" mRID="m1""#,
+                r#"path="BreakerStatusProfile" mRID="n1""#,
+            )
+            .replace("mRID='m2'", "mRID='n2'")
+            .replace(r#"mRID="m3""#, r#"mRID="n3""#)
+            .replace(r#"mRID="m9""#, r#"mRID="n4""#);
+        assert_eq!(data, expected);
+
+        // Mapping the placeholders back gives the diagram the template was made from
+        let data = instantiate(
+            &template,
+            &mapping(&[
+                ("breaker-1", "m1"),
+                ("breaker-2", "m2"),
+                ("solar-1", "m3"),
+                ("equipment-1", "m3"),
+            ]),
+            &equipment(),
+        )
+        .unwrap();
+        assert_eq!(data, DATA.replace(r#"mRID="m9""#, r#"mRID="m3""#));
+    }
+
+    #[test]
+    fn every_placeholder_needs_equipment_of_its_type() {
+        let template = template(&[]);
+        let error = instantiate(
+            &template,
+            &mapping(&[
+                ("breaker-1", "n3"),
+                ("solar-1", "m7"),
+                ("equipment-1", "n4"),
+                ("breaker-9", "n1"),
+            ]),
+            &equipment(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            problem_codes(error),
+            vec![
+                "incompatible_device_type",
+                "unmapped_placeholder",
+                "unknown_equipment",
+                "unknown_placeholder",
+            ]
+        );
+    }
+
+    #[test]
+    fn rewritten_values_are_escaped_and_the_rest_is_kept() {
+        let data = r#"<root><a as="userObject" mRID="m1" label="&amp; x"><b mRID='m2'/><c mrid="m1"/></a></root>"#;
+        let rewritten = rewrite_mrids(data, |mrid| {
+            if mrid == "m1" {
+                Some("\"n&1\"".to_string())
+            } else {
+                None
+            }
+        })
+        .unwrap();
+        assert_eq!(
+            rewritten,
+            r#"<root><a as="userObject" mRID="&quot;n&amp;1&quot;" label="&amp; x"><b mRID='m2'/><c mrid="&quot;n&amp;1&quot;"/></a></root>"#
+        );
+        assert!(matches!(
+            rewrite_mrids("<root>", |_| None),
+            Err(Error::InvalidRequestError(_))
+        ));
+    }
+}
@@ -0,0 +1,686 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::diagram_history::{parse_elements, DiagramElement};
+use crate::error::Error;
+use crate::handler::{read_equipment_list, Diagram, Equipment, STATIC_DIR};
+use crate::hmi_publisher::{common_control_profile, supports_control};
+use lazy_static::lazy_static;
+use log::warn;
+use microgrid_protobuf as microgrid;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+use warp::{reject, reply::json, Rejection, Reply};
+
+lazy_static! {
+    static ref BLOCK_INVALID_SAVES: bool = riker::load_config()
+        .get_bool("diagrams.block_invalid_saves")
+        .unwrap_or(false);
+    /// Keys of the points of the OpenFMB profiles, `None` when the client isn't built
+    static ref POINTS: Option<HashSet<String>> = load_points();
+}
+
+/// Models of the OpenFMB profiles the designer picks points from, in the client folder
+const POINTS_FILE: &str = "assets/json/openfmb-models.json";
+
+/// Symbols that stand for a single device and connect to the lines of the diagram
+const DEVICE_SYMBOLS: &[&str] = &[
+    "breaker",
+    "switch-vertical",
+    "switch-horizontal",
+    "recloser",
+    "regulator",
+    "battery",
+    "battery-horizontal",
+    "battery3d",
+];
+
+/// Start of the names of the OpenFMB profiles devices of a type publish. Bindings of
+/// equipment of other types, e.g. `generic`, are not checked.
+const DEVICE_PROFILES: &[(&str, &str)] = &[
+    ("breaker", "breaker"),
+    ("capbank", "capbank"),
+    ("ess", "ess"),
+    ("generation", "generation"),
+    ("generator", "generation"),
+    ("load", "load"),
+    ("meter", "meter"),
+    ("recloser", "recloser"),
+    ("regulator", "regulator"),
+    ("resource", "resource"),
+    ("solar", "solar"),
+    ("switch", "switch"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The diagram doesn't work as drawn on the HMI
+    Error,
+    /// The diagram works but is likely not what was meant
+    Warning,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DiagramProblem {
+    pub severity: Severity,
+    /// Stable name of the check, e.g. `unknown_equipment`
+    pub code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub element_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mrid: Option<String>,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct ValidationReport {
+    pub diagram_id: String,
+    pub errors: usize,
+    pub warnings: usize,
+    pub problems: Vec<DiagramProblem>,
+}
+
+impl ValidationReport {
+    fn new(diagram_id: &str, problems: Vec<DiagramProblem>) -> ValidationReport {
+        let errors = problems
+            .iter()
+            .filter(|p| p.severity == Severity::Error)
+            .count();
+        ValidationReport {
+            diagram_id: diagram_id.to_string(),
+            errors,
+            warnings: problems.len() - errors,
+            problems,
+        }
+    }
+
+    /// Fails with the problems found when there are errors
+    pub fn into_result(self) -> Result<ValidationReport, Error> {
+        if self.errors == 0 {
+            return Ok(self);
+        }
+        Err(Error::InvalidDiagramError {
+            errors: self.errors,
+            problems: serde_json::to_value(&self.problems)?,
+        })
+    }
+}
+
+fn problem(
+    severity: Severity,
+    code: &'static str,
+    element: &DiagramElement,
+    message: String,
+) -> DiagramProblem {
+    DiagramProblem {
+        severity,
+        code,
+        element_id: Some(element.id.clone()),
+        mrid: element.mrid.clone(),
+        message,
+    }
+}
+
+fn element_name(element: &DiagramElement) -> &str {
+    element.label.as_deref().unwrap_or(&element.id)
+}
+
+/// Checks the cells of the diagram against the equipment list and the controls the
+/// server can send
+pub fn validate_diagram(diagram: &Diagram) -> Result<ValidationReport, Error> {
+    let equipment: HashMap<String, Equipment> = read_equipment_list()?
+        .into_iter()
+        .map(|eq| (eq.mrid.clone(), eq))
+        .collect();
+    Ok(check_diagram(diagram, &equipment, POINTS.as_ref()))
+}
+
+fn check_diagram(
+    diagram: &Diagram,
+    equipment: &HashMap<String, Equipment>,
+    points: Option<&HashSet<String>>,
+) -> ValidationReport {
+    let elements = match parse_elements(&diagram.data) {
+        Ok(elements) => elements,
+        Err(e) => {
+            return ValidationReport::new(
+                &diagram.diagramId,
+                vec![DiagramProblem {
+                    severity: Severity::Error,
+                    code: "unreadable_data",
+                    element_id: None,
+                    mrid: None,
+                    message: format!("the diagram data can't be read: {}", e),
+                }],
+            )
+        }
+    };
+
+    let mut problems = vec![];
+    for element in elements.values() {
+        let name = element_name(element);
+        match &element.mrid {
+            Some(mrid) => match equipment.get(mrid) {
+                Some(eq) => {
+                    check_bindings(element, eq, points, &mut problems);
+                    check_controls(element, eq, &mut problems);
+                }
+                None => problems.push(problem(
+                    Severity::Error,
+                    "unknown_equipment",
+                    element,
+                    format!("{} refers to equipment {} which doesn't exist", name, mrid),
+                )),
+            },
+            None if !element.bindings.is_empty() => problems.push(problem(
+                Severity::Error,
+                "binding_without_equipment",
+                element,
+                format!("{} has data bindings but no equipment", name),
+            )),
+            None => {}
+        }
+    }
+    check_duplicate_devices(&elements, &mut problems);
+    check_connections(&elements, &mut problems);
+
+    ValidationReport::new(&diagram.diagramId, problems)
+}
+
+/// Refuses saving a diagram with errors, when `diagrams.block_invalid_saves` is set
+pub fn check_before_save(diagram: &Diagram) -> Result<(), Error> {
+    if *BLOCK_INVALID_SAVES {
+        validate_diagram(diagram)?.into_result()?;
+    }
+    Ok(())
+}
+
+/// Displayed and visibility data has to be a point of a profile the device publishes. Only
+/// the profile is checked when the models of the profiles can't be read.
+fn check_bindings(
+    element: &DiagramElement,
+    eq: &Equipment,
+    points: Option<&HashSet<String>>,
+    problems: &mut Vec<DiagramProblem>,
+) {
+    let prefix = eq.device_type.as_deref().and_then(|t| {
+        DEVICE_PROFILES
+            .iter()
+            .find(|(device_type, _)| *device_type == t)
+            .map(|(_, prefix)| *prefix)
+    });
+    for binding in element.bindings.iter().filter(|b| b.kind != "control") {
+        let path = match &binding.path {
+            Some(path) => path,
+            None => continue,
+        };
+        if let Some(points) = points {
+            if !points.contains(&point_key(path)) {
+                problems.push(problem(
+                    Severity::Warning,
+                    "unknown_point",
+                    element,
+                    format!(
+                        "{} shows {} which no OpenFMB profile has",
+                        element_name(element),
+                        path
+                    ),
+                ));
+                continue;
+            }
+        }
+        let prefix = match prefix {
+            Some(prefix) => prefix,
+            None => continue,
+        };
+        let profile = path.split('.').next().unwrap_or_default().to_lowercase();
+        if !profile.ends_with("profile") || !profile.starts_with(prefix) {
+            problems.push(problem(
+                Severity::Warning,
+                "unknown_point",
+                element,
+                format!(
+                    "{} shows {} which {} devices like {} don't publish",
+                    element_name(element),
+                    path,
+                    eq.device_type.as_deref().unwrap_or_default(),
+                    eq.name
+                ),
+            ));
+        }
+    }
+}
+
+/// Points of the profile models the designer picks from
+fn load_points() -> Option<HashSet<String>> {
+    let file = format!("{}{}", STATIC_DIR, POINTS_FILE);
+    let models = std::fs::read_to_string(&file)
+        .map_err(|e| e.to_string())
+        .and_then(|data| serde_json::from_str::<Value>(&data).map_err(|e| e.to_string()));
+    match models {
+        Ok(models) => Some(model_points(&models)),
+        Err(e) => {
+            warn!(
+                "Unable to read {}, bindings are only checked by profile: {}",
+                file, e
+            );
+            None
+        }
+    }
+}
+
+/// Keys of the points of the models, which are modules holding profiles with their topics
+fn model_points(models: &Value) -> HashSet<String> {
+    models
+        .as_object()
+        .into_iter()
+        .flat_map(|modules| modules.values())
+        .filter_map(|profiles| profiles.as_array())
+        .flatten()
+        .filter_map(|profile| profile.get("topics").and_then(|t| t.as_array()))
+        .flatten()
+        .filter_map(|topic| topic.pointer("/attributes/path").and_then(|p| p.as_str()))
+        .map(point_key)
+        .collect()
+}
+
+/// Path of the point ignoring case, underscores and array indexes, as values of incoming
+/// messages are looked up
+fn point_key(path: &str) -> String {
+    let mut key = String::with_capacity(path.len());
+    let mut in_index = false;
+    for c in path.chars() {
+        match c {
+            '[' => {
+                in_index = true;
+                key.push(c);
+            }
+            ']' => {
+                in_index = false;
+                key.push(c);
+            }
+            _ if in_index => {}
+            '_' => {}
+            _ => key.extend(c.to_lowercase()),
+        }
+    }
+    key
+}
+
+/// Controls the element sends have to be published for the device, the same way
+/// `HmiPublisher` picks the control profile
+fn check_controls(element: &DiagramElement, eq: &Equipment, problems: &mut Vec<DiagramProblem>) {
+    for binding in element.bindings.iter().filter(|b| b.kind == "control") {
+        let path = match &binding.path {
+            Some(path) => path,
+            None => continue,
+        };
+        if microgrid::microgrid_control::ControlMessage::from_str(path).is_ok()
+            || microgrid::device_control::DeviceControlMessage::from_str(path).is_ok()
+        {
+            continue;
+        }
+
+        let control_name = match microgrid::generic_control::ControlType::from_str(path) {
+            Ok(_) => path.as_str(),
+            Err(_) => match (&element.verb, element.element_type.as_deref()) {
+                (Some(verb), _) => verb.as_str(),
+                (None, Some("set-point-button")) => "SetValue",
+                _ => continue,
+            },
+        };
+        let control = match microgrid::generic_control::ControlType::from_str(control_name) {
+            Ok(control) => control,
+            Err(_) => {
+                problems.push(problem(
+                    Severity::Warning,
+                    "unknown_control",
+                    element,
+                    format!(
+                        "{} sends {} which is not a known control",
+                        element_name(element),
+                        control_name
+                    ),
+                ));
+                continue;
+            }
+        };
+
+        let token = path.split('.').next().unwrap_or_default();
+        let profile_name = if path.contains('.') && token.ends_with("Profile") {
+            Some(token)
+        } else {
+            eq.device_type.as_deref().and_then(common_control_profile)
+        };
+        let supported = profile_name.map_or(false, |p| supports_control(p, &control));
+        if !supported {
+            problems.push(problem(
+                Severity::Error,
+                "unsupported_control",
+                element,
+                format!(
+                    "{} sends {} which can't be sent to {} devices like {}",
+                    element_name(element),
+                    control_name,
+                    eq.device_type.as_deref().unwrap_or("untyped"),
+                    eq.name
+                ),
+            ));
+        }
+    }
+}
+
+/// A device drawn twice can be controlled from either symbol without the other showing it
+fn check_duplicate_devices(
+    elements: &BTreeMap<String, DiagramElement>,
+    problems: &mut Vec<DiagramProblem>,
+) {
+    let mut seen: HashMap<&str, &DiagramElement> = HashMap::new();
+    for element in elements.values().filter(|e| is_device(e)) {
+        let mrid = match &element.mrid {
+            Some(mrid) => mrid,
+            None => continue,
+        };
+        match seen.get(mrid.as_str()) {
+            Some(first) => problems.push(problem(
+                Severity::Warning,
+                "duplicate_mrid",
+                element,
+                format!(
+                    "{} and {} are both equipment {}",
+                    element_name(first),
+                    element_name(element),
+                    mrid
+                ),
+            )),
+            None => {
+                seen.insert(mrid, element);
+            }
+        }
+    }
+}
+
+/// Lines should connect at both ends and devices should be connected to a line
+fn check_connections(
+    elements: &BTreeMap<String, DiagramElement>,
+    problems: &mut Vec<DiagramProblem>,
+) {
+    let mut connected: HashSet<&str> = HashSet::new();
+    for element in elements.values().filter(|e| e.edge) {
+        let (source, target) = &element.connection;
+        connected.insert(source.as_str());
+        connected.insert(target.as_str());
+        let loose = match (source.is_empty(), target.is_empty()) {
+            (true, true) => "either end",
+            (true, false) => "its start",
+            (false, true) => "its end",
+            (false, false) => continue,
+        };
+        problems.push(problem(
+            Severity::Warning,
+            "unconnected_terminal",
+            element,
+            format!("{} is not connected at {}", element_name(element), loose),
+        ));
+    }
+    for element in elements.values().filter(|e| is_device(e)) {
+        if !connected.contains(element.id.as_str()) {
+            problems.push(problem(
+                Severity::Warning,
+                "unconnected_terminal",
+                element,
+                format!("{} is not connected to any line", element_name(element)),
+            ));
+        }
+    }
+}
+
+fn is_device(element: &DiagramElement) -> bool {
+    element
+        .element_type
+        .as_deref()
+        .map_or(false, |t| DEVICE_SYMBOLS.contains(&t))
+}
+
+// POST
+pub async fn validate_diagram_handler(
+    _id: String,
+    diagram: Diagram,
+) -> Result<impl Reply, Rejection> {
+    Ok(json(
+        &validate_diagram(&diagram).map_err(|e| reject::custom(e))?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = "BreakerStatusProfile.mapping.breakerStatus.breakerStatusXCBR.statusAndEventXCBR.Pos.phs3.stVal";
+
+    fn equipment() -> HashMap<String, Equipment> {
+        [("m1", "breaker"), ("m2", "breaker"), ("m3", "solar")]
+            .iter()
+            .map(|(mrid, device_type)| {
+                let eq = Equipment {
+                    mrid: mrid.to_string(),
+                    name: format!("Device {}", mrid),
+                    device_type: Some(device_type.to_string()),
+                    group: None,
+                    version: None,
+                };
+                (eq.mrid.clone(), eq)
+            })
+            .collect()
+    }
+
+    /// A device symbol with the equipment, displayed points and sent controls
+    fn device(id: &str, mrid: &str, display: &[&str], control: &[&str]) -> String {
+        let array = |name: &str, paths: &[&str]| {
+            let items: String = paths
+                .iter()
+                .map(|p| format!(r#"<Object path="{}"/>"#, p))
+                .collect();
+            format!(r#"<Array as="{}">{}</Array>"#, name, items)
+        };
+        format!(
+            r#"<mxCell id="{}" parent="1" vertex="1"><Object as="userObject" type="breaker" label="{}" mRID="{}">{}{}</Object><mxGeometry as="geometry"/></mxCell>"#,
+            id,
+            id,
+            mrid,
+            array("displayData", display),
+            array("controlData", control)
+        )
+    }
+
+    fn line(id: &str, source: &str, target: &str) -> String {
+        format!(
+            r#"<mxCell id="{}" parent="1" edge="1" source="{}" target="{}"/>"#,
+            id, source, target
+        )
+    }
+
+    fn check(cells: &[String], points: Option<&HashSet<String>>) -> ValidationReport {
+        let diagram = Diagram {
+            diagramId: "d".to_string(),
+            name: None,
+            description: None,
+            location: None,
+            data: Some(format!(
+                r#"<mxGraphModel><root><mxCell id="0"/><mxCell id="1" parent="0"/>{}</root></mxGraphModel>"#,
+                cells.concat()
+            )),
+            createdDate: None,
+            createdBy: None,
+            backgroundColor: None,
+            version: None,
+            published_version: None,
+        };
+        check_diagram(&diagram, &equipment(), points)
+    }
+
+    fn codes(report: &ValidationReport) -> Vec<(&str, Option<&str>)> {
+        report
+            .problems
+            .iter()
+            .map(|p| (p.code, p.element_id.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn connected_device_with_its_points_is_valid() {
+        let report = check(
+            &[
+                device("b1", "m1", &[STATUS], &["Open", "Close"]),
+                line("l1", "b1", "b2"),
+                device("b2", "m2", &[], &[]),
+            ],
+            None,
+        );
+        assert_eq!(codes(&report), vec![]);
+        assert!(report.into_result().is_ok());
+    }
+
+    #[test]
+    fn equipment_has_to_exist() {
+        let mut unbound = device("b2", "", &[STATUS], &[]);
+        unbound = unbound.replace(r#" mRID="""#, "");
+        let report = check(
+            &[
+                device("b1", "missing", &[], &[]),
+                line("l1", "b1", "b2"),
+                unbound,
+            ],
+            None,
+        );
+        assert_eq!(
+            codes(&report),
+            vec![
+                ("unknown_equipment", Some("b1")),
+                ("binding_without_equipment", Some("b2"))
+            ]
+        );
+        assert!(matches!(
+            report.into_result(),
+            Err(Error::InvalidDiagramError { errors: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn controls_have_to_be_sent_to_the_device() {
+        let report = check(
+            &[
+                device("b1", "m1", &[], &["ResetBreaker", "SetModBlkOn"]),
+                line("l1", "b1", "b2"),
+                device("b2", "m3", &[], &["ResetSolar", "SetStateKindOn"]),
+            ],
+            None,
+        );
+        let problems: Vec<(&str, &str)> = report
+            .problems
+            .iter()
+            .map(|p| (p.code, p.message.split(' ').nth(2).unwrap_or_default()))
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                ("unsupported_control", "SetModBlkOn"),
+                ("unsupported_control", "SetStateKindOn"),
+            ]
+        );
+    }
+
+    #[test]
+    fn points_are_looked_up_in_the_profile_models() {
+        let models = serde_json::json!({
+            "breakermodule": [{
+                "name": "BreakerStatusProfile",
+                "topics": [
+                    {"attributes": {"path": STATUS}},
+                    {"attributes": {"path": "BreakerReadingProfile.mapping.breakerReading[0].readingMMXU.W.net.cVal.mag"}},
+                ]
+            }]
+        });
+        let points = model_points(&models);
+        assert_eq!(points.len(), 2);
+
+        let report = check(
+            &[
+                device(
+                    "b1",
+                    "m1",
+                    &[
+                        // Case and array indexes don't matter, as for incoming values
+                        &STATUS.to_lowercase(),
+                        "BreakerReadingProfile.mapping.breakerReading[1].readingMMXU.W.net.cVal.mag",
+                        "BreakerStatusProfile.mapping.breakerStatus.noSuchPoint",
+                    ],
+                    &[],
+                ),
+                line("l1", "b1", "b2"),
+                device("b2", "m3", &[STATUS], &[]),
+            ],
+            Some(&points),
+        );
+        let problems: Vec<(&str, &str)> = report
+            .problems
+            .iter()
+            .map(|p| (p.element_id.as_deref().unwrap(), p.message.as_str()))
+            .collect();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert_eq!(problems[0].0, "b1");
+        assert!(problems[0]
+            .1
+            .contains("noSuchPoint which no OpenFMB profile has"));
+        assert_eq!(problems[1].0, "b2");
+        assert!(problems[1]
+            .1
+            .contains("solar devices like Device m3 don't publish"));
+    }
+
+    #[test]
+    fn profile_is_checked_without_the_models() {
+        let report = check(
+            &[
+                device("b1", "m1", &["BreakerStatusProfile.mapping.anything"], &[]),
+                line("l1", "b1", "b2"),
+                device("b2", "m3", &[STATUS], &[]),
+            ],
+            None,
+        );
+        assert_eq!(codes(&report), vec![("unknown_point", Some("b2"))]);
+    }
+
+    #[test]
+    fn duplicate_and_unconnected_devices() {
+        let report = check(
+            &[
+                device("b1", "m1", &[], &[]),
+                device("b2", "m1", &[], &[]),
+                line("l1", "b1", ""),
+            ],
+            None,
+        );
+        assert_eq!(
+            codes(&report),
+            vec![
+                ("duplicate_mrid", Some("b2")),
+                ("unconnected_terminal", Some("l1")),
+                ("unconnected_terminal", Some("b2")),
+            ]
+        );
+        assert_eq!(report.warnings, 3);
+        assert!(report.into_result().is_ok());
+    }
+
+    #[test]
+    fn unreadable_data_is_an_error() {
+        let report = check(&["<mxCell".to_string()], None);
+        assert_eq!(codes(&report), vec![("unreadable_data", None)]);
+        assert_eq!(report.errors, 1);
+    }
+}
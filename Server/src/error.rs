@@ -2,7 +2,15 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::storage::etag;
+use log::error;
+use serde::Serialize;
+use std::convert::Infallible;
 use thiserror::Error;
+use warp::{
+    http::{HeaderValue, StatusCode},
+    Rejection, Reply,
+};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -12,16 +20,407 @@ pub enum Error {
     JWTTokenError,
     #[error("jwt token creation error")]
     JWTTokenCreationError,
+    #[error("authentication provider error: {0}")]
+    AuthProviderError(String),
+    #[error("invalid refresh token")]
+    InvalidRefreshTokenError,
+    #[error("invalid jwt key configuration: {0}")]
+    JWTKeyError(String),
     #[error("no auth header")]
     NoAuthHeaderError,
     #[error("invalid auth header")]
     InvalidAuthHeaderError,
     #[error("no permission")]
     NoPermissionError,
+    #[error("{0} is outside of the user's control scope")]
+    ControlScopeError(String),
+    #[error("account locked")]
+    AccountLockedError,
+    #[error("too many login attempts, retry in {0} seconds")]
+    TooManyLoginAttemptsError(u64),
+    #[error("password change required")]
+    PasswordChangeRequiredError,
+    #[error("{0}")]
+    PasswordPolicyError(String),
+    #[error("two-factor authentication error")]
+    TwoFactorError,
+    #[error("invalid two-factor code")]
+    InvalidTwoFactorCodeError,
+    #[error("two-factor authentication setup required")]
+    TwoFactorEnrollmentRequiredError,
+    #[error("invalid api key")]
+    InvalidApiKeyError,
+    #[error("add api key failed")]
+    AddApiKeyError,
+    #[error("update profile failed")]
+    UpdateProfileError,
     #[error("add user failed")]
     AddUserError,
     #[error("add device failed")]
     AddDeviceError,
+    #[error("invalid request: {0}")]
+    InvalidRequestError(String),
+    #[error("{0} not found")]
+    NotFoundError(String),
+    #[error("storage error: {0}")]
+    StorageError(String),
+    #[error("If-Match header required to replace an existing record")]
+    PreconditionRequiredError,
+    #[error("the record was changed by someone else")]
+    VersionConflictError {
+        /// Current version, `None` when the record has been removed
+        version: Option<i64>,
+        /// Current record, for the client to merge with
+        current: Option<serde_json::Value>,
+    },
+    #[error("invalid diagram id {0:?}, ids have up to 64 letters, digits, '-' and '_'")]
+    InvalidDiagramIdError(String),
+    #[error("the diagram has {errors} validation errors")]
+    InvalidDiagramError {
+        errors: usize,
+        /// Problems found, as reported by `validate-diagram`
+        problems: serde_json::Value,
+    },
 }
 
 impl warp::reject::Reject for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::StorageError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::StorageError(e.to_string())
+    }
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::WrongCredentialsError
+            | Error::JWTTokenError
+            | Error::InvalidRefreshTokenError
+            | Error::NoAuthHeaderError
+            | Error::InvalidAuthHeaderError
+            | Error::InvalidTwoFactorCodeError
+            | Error::InvalidApiKeyError => StatusCode::UNAUTHORIZED,
+            Error::NoPermissionError
+            | Error::ControlScopeError(_)
+            | Error::PasswordChangeRequiredError
+            | Error::TwoFactorEnrollmentRequiredError => StatusCode::FORBIDDEN,
+            Error::AccountLockedError => StatusCode::LOCKED,
+            Error::TooManyLoginAttemptsError(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::PasswordPolicyError(_)
+            | Error::TwoFactorError
+            | Error::AddApiKeyError
+            | Error::UpdateProfileError
+            | Error::InvalidRequestError(_)
+            | Error::InvalidDiagramIdError(_) => StatusCode::BAD_REQUEST,
+            Error::AddUserError | Error::AddDeviceError | Error::VersionConflictError { .. } => {
+                StatusCode::CONFLICT
+            }
+            Error::PreconditionRequiredError => StatusCode::PRECONDITION_REQUIRED,
+            Error::InvalidDiagramError { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::NotFoundError(_) => StatusCode::NOT_FOUND,
+            Error::AuthProviderError(_) => StatusCode::BAD_GATEWAY,
+            Error::JWTTokenCreationError | Error::JWTKeyError(_) | Error::StorageError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Stable name of the error for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::WrongCredentialsError => "wrong_credentials",
+            Error::JWTTokenError => "invalid_token",
+            Error::JWTTokenCreationError => "token_creation_failed",
+            Error::AuthProviderError(_) => "auth_provider_error",
+            Error::InvalidRefreshTokenError => "invalid_refresh_token",
+            Error::JWTKeyError(_) => "key_configuration_error",
+            Error::NoAuthHeaderError => "no_auth_header",
+            Error::InvalidAuthHeaderError => "invalid_auth_header",
+            Error::NoPermissionError => "no_permission",
+            Error::ControlScopeError(_) => "outside_control_scope",
+            Error::AccountLockedError => "account_locked",
+            Error::TooManyLoginAttemptsError(_) => "too_many_login_attempts",
+            Error::PasswordChangeRequiredError => "password_change_required",
+            Error::PasswordPolicyError(_) => "password_policy",
+            Error::TwoFactorError => "two_factor_error",
+            Error::InvalidTwoFactorCodeError => "invalid_two_factor_code",
+            Error::TwoFactorEnrollmentRequiredError => "two_factor_enrollment_required",
+            Error::InvalidApiKeyError => "invalid_api_key",
+            Error::AddApiKeyError => "add_api_key_failed",
+            Error::UpdateProfileError => "update_profile_failed",
+            Error::AddUserError => "user_exists",
+            Error::AddDeviceError => "device_exists",
+            Error::InvalidRequestError(_) => "invalid_request",
+            Error::NotFoundError(_) => "not_found",
+            Error::StorageError(_) => "storage_error",
+            Error::PreconditionRequiredError => "precondition_required",
+            Error::VersionConflictError { .. } => "version_conflict",
+            Error::InvalidDiagramIdError(_) => "invalid_diagram_id",
+            Error::InvalidDiagramError { .. } => "invalid_diagram",
+        }
+    }
+
+    /// Message for the client, details of server side failures are only logged
+    pub fn message(&self) -> String {
+        match self {
+            Error::JWTTokenCreationError | Error::JWTKeyError(_) | Error::StorageError(_) => {
+                "internal server error".to_string()
+            }
+            Error::AuthProviderError(_) => "authentication provider unavailable".to_string(),
+            _ => self.to_string(),
+        }
+    }
+}
+
+/// Body of every error response
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub status: u16,
+    pub code: String,
+    pub message: String,
+    /// Seconds to wait before retrying, for throttled requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    /// Current version of the record, for version conflicts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+    /// Current record, for version conflicts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<serde_json::Value>,
+    /// Problems found in a diagram that failed validation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub problems: Option<serde_json::Value>,
+}
+
+/// Turns rejections into JSON error responses with a matching status code
+pub async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply, Infallible> {
+    let (status, code, message, retry_after) = if let Some(e) = err.find::<Error>() {
+        let status = e.status();
+        if status.is_server_error() {
+            error!("Request failed: {}", e);
+        }
+        let retry_after = match e {
+            Error::TooManyLoginAttemptsError(seconds) => Some(*seconds),
+            _ => None,
+        };
+        (status, e.code(), e.message(), retry_after)
+    } else if err.is_not_found() {
+        (
+            StatusCode::NOT_FOUND,
+            "not_found",
+            "not found".to_string(),
+            None,
+        )
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        (
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            format!("invalid request body: {}", e),
+            None,
+        )
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        (
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            e.to_string(),
+            None,
+        )
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        (
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            e.to_string(),
+            None,
+        )
+    } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+        (
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            e.to_string(),
+            None,
+        )
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            "payload too large".to_string(),
+            None,
+        )
+    } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            "unsupported media type".to_string(),
+            None,
+        )
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            "method not allowed".to_string(),
+            None,
+        )
+    } else {
+        error!("Unhandled rejection: {:?}", err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "internal server error".to_string(),
+            None,
+        )
+    };
+
+    let (version, current) = match err.find::<Error>() {
+        Some(Error::VersionConflictError { version, current }) => (*version, current.clone()),
+        _ => (None, None),
+    };
+    let problems = match err.find::<Error>() {
+        Some(Error::InvalidDiagramError { problems, .. }) => Some(problems.clone()),
+        _ => None,
+    };
+
+    let body = warp::reply::json(&ErrorResponse {
+        status: status.as_u16(),
+        code: code.to_string(),
+        message,
+        retry_after,
+        version,
+        current,
+        problems,
+    });
+    let mut response = warp::reply::with_status(body, status).into_response();
+    if let Some(seconds) = retry_after {
+        response
+            .headers_mut()
+            .insert("retry-after", HeaderValue::from(seconds));
+    }
+    if let Some(version) = version {
+        if let Ok(value) = HeaderValue::from_str(&etag(version)) {
+            response.headers_mut().insert("etag", value);
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use warp::http::HeaderMap;
+
+    /// Status, headers and JSON body of the reply to the rejection
+    async fn reply(rejection: Rejection) -> (StatusCode, HeaderMap, Value) {
+        let response = handle_rejection(rejection).await.unwrap().into_response();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        (status, headers, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn errors_reply_with_their_status_and_code() {
+        let cases = vec![
+            (
+                Error::WrongCredentialsError,
+                StatusCode::UNAUTHORIZED,
+                "wrong_credentials",
+            ),
+            (
+                Error::NoPermissionError,
+                StatusCode::FORBIDDEN,
+                "no_permission",
+            ),
+            (
+                Error::NotFoundError("diagram x".to_string()),
+                StatusCode::NOT_FOUND,
+                "not_found",
+            ),
+            (
+                Error::StorageError("disk full".to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "storage_error",
+            ),
+        ];
+        for (error, status, code) in cases {
+            let (reply_status, headers, body) = reply(warp::reject::custom(error)).await;
+            assert_eq!(reply_status, status, "{}", code);
+            assert_eq!(body["status"], status.as_u16(), "{}", code);
+            assert_eq!(body["code"], code);
+            assert!(headers.get("retry-after").is_none(), "{}", code);
+            assert!(headers.get("etag").is_none(), "{}", code);
+        }
+    }
+
+    #[tokio::test]
+    async fn version_conflicts_carry_the_current_version() {
+        let (status, headers, body) = reply(warp::reject::custom(Error::VersionConflictError {
+            version: Some(4),
+            current: Some(json!({ "mrid": "m1" })),
+        }))
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "version_conflict");
+        assert_eq!(body["version"], 4);
+        assert_eq!(body["current"], json!({ "mrid": "m1" }));
+        assert_eq!(headers["etag"], "\"4\"");
+
+        // A record that was removed meanwhile has no version
+        let (status, headers, body) = reply(warp::reject::custom(Error::VersionConflictError {
+            version: None,
+            current: None,
+        }))
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body.get("version").is_none());
+        assert!(headers.get("etag").is_none());
+    }
+
+    #[tokio::test]
+    async fn throttled_logins_tell_when_to_retry() {
+        let (status, headers, body) =
+            reply(warp::reject::custom(Error::TooManyLoginAttemptsError(30))).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["code"], "too_many_login_attempts");
+        assert_eq!(body["retry_after"], 30);
+        assert_eq!(headers["retry-after"], "30");
+    }
+
+    #[tokio::test]
+    async fn rejections_of_warp_filters() {
+        let (status, _, body) = reply(warp::reject::not_found()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "not_found");
+
+        let rejection = warp::test::request()
+            .filter(&warp::header::<String>("x-api-key"))
+            .await
+            .unwrap_err();
+        let (status, _, body) = reply(rejection).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_request");
+
+        let rejection = warp::test::request()
+            .method("POST")
+            .header("content-type", "application/json")
+            .body("{\"name\":")
+            .filter(&warp::body::json::<Value>())
+            .await
+            .unwrap_err();
+        let (status, _, body) = reply(rejection).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_request");
+        assert!(body["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid request body"));
+    }
+}
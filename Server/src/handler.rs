@@ -3,8 +3,16 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::hmi;
+use crate::audit::record_audit;
+use crate::auth::{get_user, has_permission, run_blocking, Permission, User};
+use crate::collaboration::{client_disconnected, handle_edit_request, EditRequest};
+use crate::control_log::ControlIssuer;
 use crate::coordinator::StartProcessingMessages;
+use crate::diagram_history::author_name;
+use crate::diagram_id::{check_diagram_id, new_diagram_id};
+use crate::diagram_validation::check_before_save;
 use crate::error::Error;
+use crate::storage::{etag, storage, Precondition};
 use futures::{FutureExt, StreamExt};
 use hmi::coordinator::{CoordinatorOptions, CoordinatorStatus};
 use hmi::hmi::HmiMsg;
@@ -14,31 +22,42 @@ use riker::actors::*;
 use serde::{Deserialize, Serialize};
 use serde_json::from_str;
 use std::collections::HashMap;
-use std::fs;
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::{mpsc, RwLock};
 use tokio_stream::wrappers::UnboundedReceiverStream;
-use warp::{http::StatusCode, reply::json, ws::Message, ws::WebSocket, Rejection, Reply};
+use warp::{
+    http::{header::ETAG, StatusCode},
+    reply::{json, with_header},
+    ws::Message,
+    ws::WebSocket,
+    Rejection, Reply,
+};
 
 use microgrid_protobuf as microgrid;
 
 pub type Result<T> = std::result::Result<T, Rejection>;
 pub type Clients = Arc<RwLock<HashMap<String, Client>>>;
 
+/// Folder the client is served from, the server reads symbol images and the OpenFMB models
+/// from it too
+pub const STATIC_DIR: &str = "Client/dist/openfmb-hmi/";
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
 #[derive(Debug, Clone)]
 pub struct MicrogridControl {
     pub text: String,
     pub message: microgrid::microgrid_control::ControlMessage,
+    pub issuer: ControlIssuer,
 }
 
 #[derive(Debug, Clone)]
 pub struct DeviceControl {
     pub text: String,
     pub message: microgrid::device_control::DeviceControlMessage,
+    pub issuer: ControlIssuer,
 }
 
 #[derive(Debug, Clone)]
@@ -49,6 +68,7 @@ pub struct GenericControl {
     pub profile_name: Option<String>,
     pub args: Option<f64>,
     pub args2: Option<f64>,
+    pub issuer: ControlIssuer,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -88,19 +108,148 @@ pub struct Equipment {
     pub name: String,
     #[serde(rename = "deviceType")]
     pub device_type: Option<String>,
+    /// Area or group the equipment belongs to, used to scope user controls
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Version the equipment was read at, updates are refused when it is out of date
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
 }
 
 #[allow(non_snake_case)]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Diagram {
-    diagramId: String,
-    name: Option<String>,
-    description: Option<String>,
-    location: Option<String>,
-    data: Option<String>,
-    createdDate: Option<String>,
-    createdBy: Option<String>,
-    backgroundColor: Option<String>,
+    pub diagramId: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub data: Option<String>,
+    pub createdDate: Option<String>,
+    pub createdBy: Option<String>,
+    pub backgroundColor: Option<String>,
+    /// Revision the diagram was read at, saves are refused when it is out of date
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+    /// Revision the HMI shows, a diagram that was never published is not shown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_version: Option<i64>,
+}
+
+/// What the diagram list shows of a diagram, without its drawing
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct DiagramSummary {
+    pub diagramId: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub createdDate: Option<String>,
+    pub createdBy: Option<String>,
+    /// Unix timestamp in seconds of the latest revision
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub published_version: Option<i64>,
+    /// Why the stored diagram can't be read, only the id and name are known then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DiagramListQuery {
+    /// Zero based page number
+    #[serde(default)]
+    pub page: usize,
+    /// Defaults to 50, at most 500 diagrams are listed at once
+    #[serde(default)]
+    pub page_size: Option<usize>,
+    /// Matches part of the name, description, location or author, ignoring case
+    #[serde(default)]
+    pub search: Option<String>,
+    /// Matches the whole location, ignoring case
+    #[serde(default)]
+    pub location: Option<String>,
+    /// `name` (default), `location`, `createdBy`, `createdDate` or `updated`, descending
+    /// with a leading `-`
+    #[serde(default)]
+    pub sort: Option<String>,
+}
+
+impl DiagramListQuery {
+    fn matches(&self, diagram: &DiagramSummary) -> bool {
+        let search = self.search.as_deref().map(str::trim).unwrap_or_default();
+        let location = self.location.as_deref().map(str::trim).unwrap_or_default();
+        let contains = |field: &Option<String>| {
+            field
+                .as_deref()
+                .map_or(false, |f| f.to_lowercase().contains(&search.to_lowercase()))
+        };
+        (search.is_empty()
+            || contains(&diagram.name)
+            || contains(&diagram.description)
+            || contains(&diagram.location)
+            || contains(&diagram.createdBy))
+            && (location.is_empty()
+                || diagram
+                    .location
+                    .as_deref()
+                    .map_or(false, |l| l.trim().eq_ignore_ascii_case(location)))
+    }
+
+    fn sort(&self, diagrams: &mut [DiagramSummary]) -> std::result::Result<(), Error> {
+        let sort = self.sort.as_deref().unwrap_or("name");
+        let (descending, field) = match sort.strip_prefix('-') {
+            Some(field) => (true, field),
+            None => (false, sort),
+        };
+        let text = |value: &Option<String>| value.as_deref().unwrap_or_default().to_lowercase();
+        match field {
+            "name" => diagrams.sort_by_cached_key(|d| text(&d.name)),
+            "location" => diagrams.sort_by_cached_key(|d| text(&d.location)),
+            "createdBy" => diagrams.sort_by_cached_key(|d| text(&d.createdBy)),
+            "createdDate" => diagrams.sort_by_cached_key(|d| text(&d.createdDate)),
+            "updated" => diagrams.sort_by_key(|d| d.updated),
+            _ => {
+                return Err(Error::InvalidRequestError(format!(
+                    "diagrams can't be sorted by {}",
+                    field
+                )))
+            }
+        }
+        if descending {
+            diagrams.reverse();
+        }
+        Ok(())
+    }
+
+    /// The requested page of the filtered and sorted diagrams
+    fn page(&self, diagrams: Vec<DiagramSummary>) -> DiagramPage {
+        let page_size = self
+            .page_size
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .max(1)
+            .min(MAX_PAGE_SIZE);
+        DiagramPage {
+            total: diagrams.len(),
+            page: self.page,
+            page_size,
+            diagrams: diagrams
+                .into_iter()
+                .skip(self.page.saturating_mul(page_size))
+                .take(page_size)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct DiagramPage {
+    pub total: usize,
+    pub page: usize,
+    pub page_size: usize,
+    pub diagrams: Vec<DiagramSummary>,
 }
 
 #[derive(Serialize, Debug)]
@@ -109,6 +258,15 @@ pub struct Response {
     message: String,
 }
 
+/// Answer to a diagram save, with the id the server gave a new diagram
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug)]
+pub struct SaveDiagramResponse {
+    success: bool,
+    message: String,
+    diagramId: String,
+}
+
 #[derive(Serialize, Debug)]
 pub struct RegisterResponse {
     url: String,
@@ -119,6 +277,9 @@ pub struct UpdateMessage {
     pub profile: Option<String>,
     pub topic: Topic,
     pub session_id: Option<String>,
+    /// Reason given by the operator for a control, recorded in the control log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
 }
 
 impl UpdateMessage {
@@ -127,6 +288,7 @@ impl UpdateMessage {
             profile: profile,
             topic: topic,
             session_id: Some(session_id),
+            reason: None,
         }
     }
 }
@@ -140,6 +302,9 @@ pub struct UpdateMessages {
 #[derive(Deserialize)]
 pub struct DiagramQuery {
     id: String,
+    /// Load the latest draft instead of the published version, editors only
+    #[serde(default)]
+    draft: bool,
 }
 
 impl UpdateMessages {
@@ -152,14 +317,26 @@ impl UpdateMessages {
 }
 
 pub async fn data_handler(
+    id: String,
     update: UpdateMessage,
     processor: ActorRef<ProcessorMsg>,
     hmi: ActorRef<HmiMsg>,
 ) -> Result<impl Reply> {
     info!("Handle data: {:?}", update);
 
+    let user_id = id.clone();
+    let user = run_blocking(move || Ok(get_user(&user_id)))
+        .await
+        .map_err(|e| warp::reject::custom(e))?;
+    let user = match user {
+        Some(user) => user,
+        None => return Err(warp::reject::custom(Error::NoPermissionError)),
+    };
+    let issuer = ControlIssuer::new(&user.id, &user.username, update.reason.clone());
+
     // This action is applied to all client sessions
     if update.topic.name == "ToggleEnvironment" {
+        check_control_scope(&user, None).await?;
         hmi.tell(
             StartProcessingMessages {
                 pubsub_options: CoordinatorOptions::toggle_environment(),
@@ -172,26 +349,31 @@ pub async fn data_handler(
     if let Ok(microgrid_control) =
         microgrid::microgrid_control::ControlMessage::from_str(&update.topic.name)
     {
+        check_control_scope(&user, None).await?;
         processor.tell(
             MicrogridControl {
                 text: update.topic.name.clone(),
                 message: microgrid_control,
+                issuer,
             },
             None,
         );
     } else if let Ok(device_control) =
         microgrid::device_control::DeviceControlMessage::from_str(&update.topic.name)
     {
+        check_control_scope(&user, Some(&update.topic.mrid)).await?;
         processor.tell(
             DeviceControl {
                 text: update.topic.mrid.clone(),
                 message: device_control,
+                issuer,
             },
             None,
         );
     } else if let Ok(generic_control) =
         microgrid::generic_control::ControlType::from_str(&update.topic.name)
     {
+        check_control_scope(&user, Some(&update.topic.mrid)).await?;
         processor.tell(
             GenericControl {
                 text: update.topic.name.clone(),
@@ -200,11 +382,13 @@ pub async fn data_handler(
                 profile_name: None,
                 args: update.topic.args.clone(),
                 args2: update.topic.args2.clone(),
+                issuer,
             },
             None,
         );
     } else if let Some(action) = &update.topic.action {
         if let Ok(generic_control) = microgrid::generic_control::ControlType::from_str(action) {
+            check_control_scope(&user, Some(&update.topic.mrid)).await?;
             processor.tell(
                 GenericControl {
                     text: update.topic.name.clone(),
@@ -213,6 +397,7 @@ pub async fn data_handler(
                     profile_name: None,
                     args: update.topic.args.clone(),
                     args2: update.topic.args2.clone(),
+                    issuer,
                 },
                 None,
             );
@@ -229,6 +414,29 @@ pub async fn data_handler(
     Ok(StatusCode::OK)
 }
 
+/// Rejects controls targeting equipment outside of the user's control scope.
+/// Controls without a target device act on the whole microgrid and need a full scope.
+async fn check_control_scope(user: &User, mrid: Option<&str>) -> Result<()> {
+    // The groups of the equipment are only needed for users with a restricted scope
+    let allowed = user.has_full_control_scope() || {
+        let equipment = run_blocking(read_equipment_list)
+            .await
+            .map_err(|e| warp::reject::custom(e))?;
+        user.may_control(mrid, &equipment)
+    };
+
+    if !allowed {
+        let target = mrid.unwrap_or("microgrid").to_string();
+        error!(
+            "User {} is not allowed to control {}",
+            user.username, target
+        );
+        return Err(warp::reject::custom(Error::ControlScopeError(target)));
+    }
+
+    Ok(())
+}
+
 pub fn get_profile_name(topic_name: &str) -> String {
     let token: Vec<&str> = topic_name.split(".").collect();
     token[0].to_string()
@@ -264,6 +472,7 @@ pub async fn send_status(status: CoordinatorStatus, clients: Clients) -> Result<
             UpdateMessage {
                 profile: None,
                 session_id: None,
+                reason: None,
                 topic: Topic {
                     name: hmi_pubsub_status_connected,
                     mrid: status.server_id.clone(),
@@ -276,6 +485,7 @@ pub async fn send_status(status: CoordinatorStatus, clients: Clients) -> Result<
             UpdateMessage {
                 profile: None,
                 session_id: None,
+                reason: None,
                 topic: Topic {
                     name: hmi_pubsub_status_environment,
                     mrid: status.server_id.clone(),
@@ -288,6 +498,7 @@ pub async fn send_status(status: CoordinatorStatus, clients: Clients) -> Result<
             UpdateMessage {
                 profile: None,
                 session_id: None,
+                reason: None,
                 topic: Topic {
                     name: hmi_coordinator_active,
                     mrid: status.server_id.clone(),
@@ -300,6 +511,7 @@ pub async fn send_status(status: CoordinatorStatus, clients: Clients) -> Result<
             UpdateMessage {
                 profile: None,
                 session_id: None,
+                reason: None,
                 topic: Topic {
                     name: hmi_coordinator_comm_ok,
                     mrid: status.server_id.clone(),
@@ -338,47 +550,76 @@ pub async fn send_inspector_messages(
     Ok(StatusCode::OK)
 }
 
-fn get_diagram_folder() -> String {
-    let app_dir = std::env::var("APP_DIR_NAME").unwrap_or_else(|_| "".into());
-    let mut diagrams_dir = "diagrams".to_string();
-    if app_dir != "" {
-        diagrams_dir = format!("/{}/diagrams", app_dir);
-    }
-
-    if !Path::new(&diagrams_dir).exists() {
-        match fs::create_dir(&diagrams_dir) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("{}", e);
-            }
-        }
-    }
-    diagrams_dir
-}
-
 // POST
-pub async fn save_handler(request: Diagram) -> Result<impl Reply> {
-    let j = serde_json::to_string(&request).unwrap();
-
-    write_json(
-        format!("{}/{}.json", get_diagram_folder(), request.diagramId),
-        j,
-    )
-    .unwrap();
-
-    Ok(json(&Response {
-        success: true,
-        message: "".to_string(),
-    }))
+pub async fn save_handler(
+    id: String,
+    if_match: Option<String>,
+    mut request: Diagram,
+) -> Result<impl Reply> {
+    // New diagrams sent without an id get one from the server
+    if request.diagramId.is_empty() {
+        request.diagramId = new_diagram_id();
+    }
+    check_diagram_id(&request.diagramId).map_err(|e| warp::reject::custom(e))?;
+    let precondition =
+        Precondition::from_if_match(if_match.as_deref()).map_err(|e| warp::reject::custom(e))?;
+    check_before_save(&request).map_err(|e| warp::reject::custom(e))?;
+    let author = id.clone();
+    let (request, (before, revision)) = run_blocking(move || {
+        let saved =
+            storage().save_diagram(&request, &author, &author_name(&author), precondition)?;
+        Ok((request, saved))
+    })
+    .await
+    .map_err(|e| warp::reject::custom(e))?;
+    record_audit(
+        &id,
+        if before.is_some() {
+            "diagram.update"
+        } else {
+            "diagram.create"
+        },
+        &request.diagramId,
+        before.as_ref(),
+        Some(&request),
+    );
+
+    Ok(with_header(
+        json(&SaveDiagramResponse {
+            success: true,
+            message: "".to_string(),
+            diagramId: request.diagramId,
+        }),
+        ETAG,
+        etag(revision.revision),
+    ))
 }
 
+/// Deletes the diagram, a published diagram also takes the PublishDiagram permission since
+/// operators stop seeing it
 // POST
-pub async fn delete_handler(request: Diagram) -> Result<impl Reply> {
-    let _ = fs::remove_file(format!(
-        "{}/{}.json",
-        get_diagram_folder(),
-        request.diagramId
-    ));
+pub async fn delete_handler(id: String, request: Diagram) -> Result<impl Reply> {
+    let (author, diagram_id) = (id.clone(), request.diagramId.clone());
+    let removed = run_blocking(move || {
+        let published = storage()
+            .diagram(&diagram_id)?
+            .map_or(false, |d| d.published_version.is_some());
+        if published && !has_permission(&author, Permission::PublishDiagram) {
+            return Err(Error::NoPermissionError);
+        }
+        storage().delete_diagram(&diagram_id, &author, &author_name(&author))
+    })
+    .await
+    .map_err(|e| warp::reject::custom(e))?;
+    if let Some(removed) = removed {
+        record_audit(
+            &id,
+            "diagram.delete",
+            &request.diagramId,
+            Some(&removed),
+            None,
+        );
+    }
     Ok(json(&Response {
         success: true,
         message: "".to_string(),
@@ -386,124 +627,142 @@ pub async fn delete_handler(request: Diagram) -> Result<impl Reply> {
 }
 
 // GET
-pub async fn list_handler() -> Result<impl Reply> {
-    Ok(json(
-        &read_json(format!("{}", get_diagram_folder())).unwrap(),
-    ))
+/// Metadata of the diagrams matching the query. Editors get every diagram as of its latest
+/// draft, other users only published diagrams.
+pub async fn list_handler(id: String, query: DiagramListQuery) -> Result<impl Reply> {
+    let mut diagrams = run_blocking(move || {
+        storage().diagram_summaries(!has_permission(&id, Permission::EditDiagram))
+    })
+    .await
+    .map_err(|e| warp::reject::custom(e))?;
+    diagrams.retain(|d| query.matches(d));
+    query
+        .sort(&mut diagrams)
+        .map_err(|e| warp::reject::custom(e))?;
+
+    Ok(json(&query.page(diagrams)))
 }
 
 // GET
 pub async fn equipment_handler(_id: String) -> Result<impl Reply> {
-    let equipment_list: Vec<Equipment> = read_equipment_list().unwrap();
+    let equipment_list: Vec<Equipment> = run_blocking(read_equipment_list)
+        .await
+        .map_err(|e| warp::reject::custom(e))?;
     Ok(json(&equipment_list))
 }
 
 // POST
-pub async fn create_equipment_handler(_id: String, eq: Equipment) -> Result<impl Reply> {
-    let mut list = read_equipment_list().unwrap();
-    if let Some(_pos) = list.iter().position(|x| *x.mrid == eq.mrid) {
-        // same user id/username already exists
-        error!(
-            "Equipment with same MRID ({}/{}) already exists",
-            eq.mrid, eq.name
-        );
-
-        return Err(warp::reject::custom(Error::AddDeviceError));
-    } else {
-        list.push(eq);
-        let _ = save_equipment_list(&list);
-    }
+pub async fn create_equipment_handler(id: String, eq: Equipment) -> Result<impl Reply> {
+    let (eq, equipment_list) = run_blocking(move || {
+        storage().insert_equipment(&eq)?;
+        Ok((eq, read_equipment_list()?))
+    })
+    .await
+    .map_err(|e| warp::reject::custom(e))?;
+    record_audit(&id, "equipment.create", &eq.mrid, None, Some(&eq));
 
-    Ok(json(&list))
+    Ok(json(&equipment_list))
 }
 
 // POST
-pub async fn delete_equipment_handler(_id: String, equipment: Equipment) -> Result<impl Reply> {
-    let mut list = read_equipment_list().unwrap();
-
-    if let Some(pos) = list.iter().position(|x| *x.mrid == equipment.mrid) {
-        list.remove(pos);
-
-        let _ = save_equipment_list(&list);
+pub async fn delete_equipment_handler(id: String, equipment: Equipment) -> Result<impl Reply> {
+    let (removed, equipment_list) = run_blocking(move || {
+        let removed = storage().delete_equipment(&equipment.mrid)?;
+        Ok((removed, read_equipment_list()?))
+    })
+    .await
+    .map_err(|e| warp::reject::custom(e))?;
+    if let Some(removed) = removed {
+        record_audit(&id, "equipment.delete", &removed.mrid, Some(&removed), None);
     }
 
-    Ok(json(&list))
+    Ok(json(&equipment_list))
 }
 
 // POST
-pub async fn update_equipment_handler(_id: String, eq: Equipment) -> Result<impl Reply> {
-    let mut list = read_equipment_list().unwrap();
-
-    if let Some(pos) = list.iter().position(|x| *x.mrid == eq.mrid) {
-        let mut e = list.get_mut(pos).unwrap();
-        e.name = eq.name;
-        e.mrid = eq.mrid;
-        e.device_type = eq.device_type;
-
-        let _ = save_equipment_list(&list);
-    }
-
-    Ok(json(&list))
-}
-
-fn get_equipment_file() -> String {
-    let app_dir = std::env::var("APP_DIR_NAME").unwrap_or_else(|_| "".into());
-    if app_dir != "" {
-        return format!("/{}/equipment.json", app_dir);
-    }
-    "equipment.json".to_string()
-}
-
-pub fn read_equipment_list() -> std::io::Result<Vec<Equipment>> {
-    let file_path = &get_equipment_file();
-    let equipment_list: Vec<Equipment> = vec![];
-
-    if let Ok(mut file) = File::open(file_path.clone()) {
-        let mut contents = String::new();
-        if let Ok(_) = file.read_to_string(&mut contents) {
-            match serde_json::from_str(&contents) {
-                Ok(equipment_list) => {
-                    return Ok(equipment_list);
-                }
-                Err(e) => {
-                    error!("Unable to parse equipment file: {} [{}]", file_path, e);
-                }
-            }
-        } else {
-            error!("Unable to read equipment file: {}", file_path);
+pub async fn update_equipment_handler(
+    id: String,
+    if_match: Option<String>,
+    eq: Equipment,
+) -> Result<impl Reply> {
+    let precondition =
+        Precondition::from_if_match(if_match.as_deref()).map_err(|e| warp::reject::custom(e))?;
+    let (eq, updated, equipment_list) = run_blocking(move || {
+        let updated = storage().update_equipment(&eq, precondition)?;
+        Ok((eq, updated, read_equipment_list()?))
+    })
+    .await
+    .map_err(|e| warp::reject::custom(e))?;
+    let (before, version) = match updated {
+        Some(updated) => updated,
+        None => {
+            return Err(warp::reject::custom(Error::NotFoundError(format!(
+                "equipment {}",
+                eq.mrid
+            ))))
         }
-    } else {
-        error!("Unable to open equipment file: {}", file_path);
-    }
+    };
+    record_audit(&id, "equipment.update", &eq.mrid, Some(&before), Some(&eq));
 
-    Ok(equipment_list)
+    Ok(with_header(json(&equipment_list), ETAG, etag(version)))
 }
 
-fn save_equipment_list(equipment_list: &Vec<Equipment>) -> std::io::Result<()> {
-    let json = serde_json::to_string(&equipment_list).unwrap();
-    fs::write(get_equipment_file(), json).expect("Unable to write file");
-
-    Ok(())
+pub fn read_equipment_list() -> std::result::Result<Vec<Equipment>, Error> {
+    storage().equipment()
 }
 
 // GET
-pub async fn diagram_handler(id: DiagramQuery) -> Result<impl Reply> {
-    let list = read_json(format!("{}", get_diagram_folder())).unwrap();
-    let id = id.id;
-    for d in list {
-        if d.diagramId == id {
-            return Ok(json(&d));
+pub async fn diagram_handler(user_id: String, id: DiagramQuery) -> Result<impl Reply> {
+    if id.draft && !has_permission(&user_id, Permission::EditDiagram) {
+        return Err(warp::reject::custom(Error::NoPermissionError));
+    }
+    let (diagram_id, draft) = (id.id.clone(), id.draft);
+    let diagram = run_blocking(move || {
+        let load = |diagram_id: &str| {
+            if draft {
+                storage().diagram(diagram_id)
+            } else {
+                storage().published_diagram(diagram_id)
+            }
+        };
+        let diagram = load(&diagram_id)?;
+        if diagram.is_some() {
+            return Ok(diagram);
+        }
+        // Links made before the diagram got a safe id still open it
+        match storage().renamed_diagram_id(&diagram_id)? {
+            Some(new_id) => load(&new_id),
+            None => Ok(None),
         }
+    })
+    .await
+    .map_err(|e| warp::reject::custom(e))?;
+    match diagram {
+        Some(diagram) => {
+            let tag = etag(diagram.version.unwrap_or_default());
+            Ok(with_header(json(&diagram), ETAG, tag))
+        }
+        None if !id.draft => Err(warp::reject::custom(Error::NotFoundError(format!(
+            "published diagram {}",
+            id.id
+        )))),
+        None => Err(warp::reject::custom(Error::NotFoundError(format!(
+            "diagram {}",
+            id.id
+        )))),
     }
-
-    Err(warp::reject::not_found())
 }
 
-pub async fn connect_handler(ws: warp::ws::Ws, id: String, clients: Clients) -> Result<impl Reply> {
-    return Ok(ws.on_upgrade(move |socket| client_connection(socket, id, clients)));
+pub async fn connect_handler(
+    ws: warp::ws::Ws,
+    id: String,
+    user_id: String,
+    clients: Clients,
+) -> Result<impl Reply> {
+    return Ok(ws.on_upgrade(move |socket| client_connection(socket, id, user_id, clients)));
 }
 
-pub async fn client_connection(ws: WebSocket, id: String, clients: Clients) {
+pub async fn client_connection(ws: WebSocket, id: String, user_id: String, clients: Clients) {
     let mut client = match clients.read().await.get(&id).cloned() {
         Some(c) => c,
         None => Client {
@@ -539,24 +798,34 @@ pub async fn client_connection(ws: WebSocket, id: String, clients: Clients) {
                 break;
             }
         };
-        client_msg(&id, msg, &clients).await;
+        client_msg(&id, &user_id, msg, &clients).await;
     }
 
+    client_disconnected(&id, &clients).await;
     clients.write().await.remove(&id);
     println!("Client id '{}' disconnected", id);
 }
 
-async fn client_msg(id: &str, msg: Message, clients: &Clients) {
+async fn client_msg(id: &str, user_id: &str, msg: Message, clients: &Clients) {
     //println!("Received message from {}: {:?}", id, msg);
     let message = match msg.to_str() {
         Ok(v) => v,
         Err(_) => return,
     };
 
+    // Designers editing a diagram together, see `collaboration`
+    if let Ok(edit_request) = from_str::<EditRequest>(&message) {
+        handle_edit_request(id, user_id, edit_request, clients).await;
+        return;
+    }
+
     let register_request: RegisterRequest = match from_str(&message) {
         Ok(v) => v,
         Err(e) => {
-            println!("Only can handle RegisterRequest at this moment. {:?}", e);
+            println!(
+                "Only can handle RegisterRequest and EditRequest at this moment. {:?}",
+                e
+            );
             return;
         }
     };
@@ -576,23 +845,140 @@ async fn client_msg(id: &str, msg: Message, clients: &Clients) {
     };
 }
 
-fn read_json(file_path: String) -> std::io::Result<Vec<Diagram>> {
-    let mut diagrams: Vec<Diagram> = vec![];
-    for entry in fs::read_dir(&file_path)? {
-        let entry = entry?;
-        let path = entry.path();
-        if !path.is_dir() {
-            let mut file = File::open(path)?;
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            diagrams.push(serde_json::from_str(&contents).expect("JSON was not well-formatted"));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(id: &str, name: &str, location: Option<&str>, updated: i64) -> DiagramSummary {
+        DiagramSummary {
+            diagramId: id.to_string(),
+            name: Some(name.to_string()),
+            description: Some(format!("{} one-line", name)),
+            location: location.map(|l| l.to_string()),
+            createdBy: Some(format!("{}-author", id)),
+            createdDate: Some(format!("2021-01-0{}", updated)),
+            updated: Some(updated),
+            ..DiagramSummary::default()
         }
     }
-    Ok(diagrams)
-}
 
-fn write_json(file_path: String, json: String) -> std::io::Result<()> {
-    fs::write(file_path, json).expect("Unable to write file");
+    fn diagrams() -> Vec<DiagramSummary> {
+        vec![
+            summary("d1", "Feeder B", Some("Substation North"), 3),
+            summary("d2", "feeder a", Some("substation north "), 1),
+            summary("d3", "Battery", Some("Substation South"), 2),
+            summary("d4", "Overview", None, 4),
+        ]
+    }
 
-    Ok(())
+    fn query(value: serde_json::Value) -> DiagramListQuery {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// Ids of the diagrams the query lists, in order
+    fn listed(query: &DiagramListQuery) -> Vec<String> {
+        let mut diagrams = diagrams();
+        diagrams.retain(|d| query.matches(d));
+        query.sort(&mut diagrams).unwrap();
+        diagrams.into_iter().map(|d| d.diagramId).collect()
+    }
+
+    #[test]
+    fn search_matches_part_of_the_text_ignoring_case() {
+        assert_eq!(
+            listed(&query(serde_json::json!({}))),
+            vec!["d3", "d2", "d1", "d4"]
+        );
+        assert_eq!(
+            listed(&query(serde_json::json!({ "search": " FEEDER " }))),
+            vec!["d2", "d1"]
+        );
+        assert_eq!(
+            listed(&query(serde_json::json!({ "search": "south" }))),
+            vec!["d3"]
+        );
+        assert_eq!(
+            listed(&query(serde_json::json!({ "search": "d4-AUTHOR" }))),
+            vec!["d4"]
+        );
+        assert_eq!(
+            listed(&query(serde_json::json!({ "search": "one-line" }))).len(),
+            4
+        );
+        assert!(listed(&query(serde_json::json!({ "search": "missing" }))).is_empty());
+    }
+
+    #[test]
+    fn location_matches_the_whole_location() {
+        assert_eq!(
+            listed(&query(
+                serde_json::json!({ "location": "Substation North" })
+            )),
+            vec!["d2", "d1"]
+        );
+        assert!(listed(&query(serde_json::json!({ "location": "North" }))).is_empty());
+        assert_eq!(
+            listed(&query(
+                serde_json::json!({ "location": "substation north", "search": "feeder b" })
+            )),
+            vec!["d1"]
+        );
+    }
+
+    #[test]
+    fn diagrams_are_sorted_by_the_field() {
+        let sorted = |sort: &str| listed(&query(serde_json::json!({ "sort": sort })));
+        assert_eq!(sorted("name"), vec!["d3", "d2", "d1", "d4"]);
+        assert_eq!(sorted("-name"), vec!["d4", "d1", "d2", "d3"]);
+        // Diagrams without a location come first
+        assert_eq!(sorted("location"), vec!["d4", "d1", "d2", "d3"]);
+        assert_eq!(sorted("createdBy"), vec!["d1", "d2", "d3", "d4"]);
+        assert_eq!(sorted("createdDate"), vec!["d2", "d3", "d1", "d4"]);
+        assert_eq!(sorted("updated"), vec!["d2", "d3", "d1", "d4"]);
+        assert_eq!(sorted("-updated"), vec!["d4", "d1", "d3", "d2"]);
+
+        let mut diagrams = diagrams();
+        assert!(matches!(
+            query(serde_json::json!({ "sort": "diagramId" })).sort(&mut diagrams),
+            Err(Error::InvalidRequestError(_))
+        ));
+    }
+
+    #[test]
+    fn pages_have_a_default_and_a_maximum_size() {
+        let many: Vec<DiagramSummary> = (0..1200)
+            .map(|i| summary(&format!("d{}", i), "Diagram", None, 1))
+            .collect();
+        let page = |value: serde_json::Value| query(value).page(many.clone());
+
+        let first = page(serde_json::json!({}));
+        assert_eq!(
+            (
+                first.total,
+                first.page,
+                first.page_size,
+                first.diagrams.len()
+            ),
+            (1200, 0, DEFAULT_PAGE_SIZE, DEFAULT_PAGE_SIZE)
+        );
+        assert_eq!(first.diagrams[0].diagramId, "d0");
+
+        let second = page(serde_json::json!({ "page": 1, "page_size": 20 }));
+        assert_eq!((second.page_size, second.diagrams.len()), (20, 20));
+        assert_eq!(second.diagrams[0].diagramId, "d20");
+
+        let capped = page(serde_json::json!({ "page_size": 100000 }));
+        assert_eq!(capped.page_size, MAX_PAGE_SIZE);
+        assert_eq!(capped.diagrams.len(), MAX_PAGE_SIZE);
+
+        let last = page(serde_json::json!({ "page": 2, "page_size": 500 }));
+        assert_eq!(last.diagrams.len(), 200);
+        assert!(page(serde_json::json!({ "page": 3, "page_size": 500 }))
+            .diagrams
+            .is_empty());
+        assert_eq!(page(serde_json::json!({ "page_size": 0 })).page_size, 1);
+        assert!(page(serde_json::json!({ "page": usize::MAX }))
+            .diagrams
+            .is_empty());
+    }
 }
@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::control_log::record_control;
 use crate::coordinator::*;
 use crate::messages::*;
 use openfmb_messages_ext::OpenFMBMessage;
@@ -62,6 +63,8 @@ pub struct HmiPublisher {
     pub message_count: u32,
     pub nats_client: Option<nats::Connection>,
     pub cfg: Config,
+    /// Subject and result of the last publish, recorded in the control log
+    last_publish: Option<(String, std::result::Result<(), String>)>,
 }
 
 impl ActorFactoryArgs<Config> for HmiPublisher {
@@ -70,6 +73,7 @@ impl ActorFactoryArgs<Config> for HmiPublisher {
             message_count: 0,
             nats_client: None,
             cfg: config,
+            last_publish: None,
         }
     }
 }
@@ -134,17 +138,9 @@ impl HmiPublisher {
     }
     fn get_common_control_profile(&self, mrid: String) -> Option<String> {
         let t = match self.get_device_type_by_mrid(mrid) {
-            Some(device_type) => match device_type.as_str() {
-                "switch" => Some("SwitchDiscreteControlProfile".to_string()),
-                "breaker" => Some("BreakerDiscreteControlProfile".to_string()),
-                "recloser" => Some("RecloserDiscreteControlProfile".to_string()),
-                "ess" => Some("EssControlProfile".to_string()),
-                "solar" => Some("SolarControlProfile".to_string()),
-                "generator" => Some("GenerationDiscreteControlProfile".to_string()),
-                "regulator" => Some("RegulatorDiscreteControlProfile".to_string()),
-                "load" => Some("LoadControlProfile".to_string()),
-                "resource" => Some("ResourceDiscreteControlProfile".to_string()),
-                _ => {
+            Some(device_type) => match common_control_profile(&device_type) {
+                Some(profile_name) => Some(profile_name.to_string()),
+                None => {
                     info!(
                         "Unable to get common control profile for device type: {}",
                         device_type
@@ -156,19 +152,23 @@ impl HmiPublisher {
         };
         t
     }
-    fn publish(&self, subject: &str, buffer: &mut Vec<u8>) {
-        if let Some(connnection) = &self.nats_client {
+    fn publish(&mut self, subject: &str, buffer: &mut Vec<u8>) {
+        let result = if let Some(connnection) = &self.nats_client {
             match connnection.publish(subject, buffer) {
                 Ok(_) => {
-                    debug!("Message with subject {} published succesfully.", subject)
+                    debug!("Message with subject {} published succesfully.", subject);
+                    Ok(())
                 }
                 Err(e) => {
                     error!("Error publishing message: {:?}", e);
+                    Err(e.to_string())
                 }
             }
         } else {
             error!("NATS connection is not available.");
-        }
+            Err("NATS connection is not available".to_string())
+        };
+        self.last_publish = Some((subject.to_string(), result));
     }
 }
 impl Actor for HmiPublisher {
@@ -216,6 +216,14 @@ impl Receive<MicrogridControl> for HmiPublisher {
         let mut buffer = Vec::<u8>::new();
         msg.message.encode(&mut buffer);
         self.publish(&subject, &mut buffer);
+        record_control(
+            &msg.issuer,
+            "",
+            &format!("{:?}", msg.message),
+            (None, None),
+            None,
+            self.last_publish.take(),
+        );
     }
 }
 
@@ -226,15 +234,174 @@ impl Receive<DeviceControl> for HmiPublisher {
         let subject = "openfmb.microgridui.device_control";
         info!("Sending {:?} to NATS topic {}", msg, subject);
         let mut buffer = Vec::<u8>::new();
+        let control = format!("{:?}", msg.message);
         let device_control_msg = microgrid_protobuf::DeviceControl {
-            mrid: msg.text,
+            mrid: msg.text.clone(),
             msg: msg.message.into(),
         };
         device_control_msg.encode(&mut buffer).unwrap();
         self.publish(&subject, &mut buffer);
+        record_control(
+            &msg.issuer,
+            &msg.text,
+            &control,
+            (None, None),
+            None,
+            self.last_publish.take(),
+        );
     }
 }
 
+/// Profile of the controls sent to devices of the type, when the control doesn't name it
+pub fn common_control_profile(device_type: &str) -> Option<&'static str> {
+    match device_type {
+        "switch" => Some("SwitchDiscreteControlProfile"),
+        "breaker" => Some("BreakerDiscreteControlProfile"),
+        "recloser" => Some("RecloserDiscreteControlProfile"),
+        "ess" => Some("EssControlProfile"),
+        "solar" => Some("SolarControlProfile"),
+        "generator" => Some("GenerationDiscreteControlProfile"),
+        "regulator" => Some("RegulatorDiscreteControlProfile"),
+        "load" => Some("LoadControlProfile"),
+        "resource" => Some("ResourceDiscreteControlProfile"),
+        _ => None,
+    }
+}
+
+/// Whether a control of the type is published for the control profile. The handling of
+/// `GenericControl` below refuses controls not listed here, so a control added there has
+/// to be added here too.
+pub fn supports_control(
+    profile_name: &str,
+    control: &microgrid_protobuf::generic_control::ControlType,
+) -> bool {
+    use microgrid_protobuf::generic_control::ControlType;
+    match profile_name {
+        "BreakerDiscreteControlProfile" => matches!(
+            *control,
+            ControlType::Open | ControlType::Close | ControlType::ResetBreaker
+        ),
+        "RecloserDiscreteControlProfile" => {
+            matches!(*control, ControlType::Open | ControlType::Close)
+        }
+        "SwitchDiscreteControlProfile" => matches!(
+            *control,
+            ControlType::Open
+                | ControlType::Close
+                | ControlType::SetModBlkOn
+                | ControlType::SetModBlkOff
+        ),
+        "ESSControlProfile" | "EssControlProfile" => {
+            is_set_point_control(control)
+                || matches!(
+                    *control,
+                    ControlType::SetModBlkOn
+                        | ControlType::SetModBlkOff
+                        | ControlType::SetValue
+                        | ControlType::ResetEss
+                )
+        }
+        "SolarControlProfile" => {
+            is_set_point_control(control)
+                || matches!(
+                    *control,
+                    ControlType::SetModBlkOn | ControlType::SetModBlkOff | ControlType::ResetSolar
+                )
+        }
+        "LoadControlProfile" => {
+            (is_set_point_control(control) && !matches!(*control, ControlType::SetVarNetMag))
+                || matches!(
+                    *control,
+                    ControlType::StateOn
+                        | ControlType::StateOff
+                        | ControlType::SetValue
+                        | ControlType::ResetLoad
+                )
+        }
+        "ResourceDiscreteControlProfile" => matches!(
+            *control,
+            ControlType::SetValue
+                | ControlType::SetGgioValueAnalog
+                | ControlType::SetGgioValueInteger
+                | ControlType::SetGgioValueBool
+                | ControlType::StartTransaction
+                | ControlType::StopTransaction
+        ),
+        "RegulatorDiscreteControlProfile" => {
+            is_set_point_control(control)
+                || matches!(
+                    *control,
+                    ControlType::TapChangeLowerPhs3
+                        | ControlType::TapChangeRaisePhs3
+                        | ControlType::TapChangeLowerPhsA
+                        | ControlType::TapChangeRaisePhsA
+                        | ControlType::TapChangeLowerPhsB
+                        | ControlType::TapChangeRaisePhsB
+                        | ControlType::TapChangeLowerPhsC
+                        | ControlType::TapChangeRaisePhsC
+                )
+        }
+        "GenerationControlProfile" => {
+            is_set_point_control(control)
+                || matches!(*control, ControlType::SetValue | ControlType::ResetSolar)
+        }
+        "CapBankControlProfile" => is_set_point_control(control),
+        _ => false,
+    }
+}
+
+/// Set points of measured values, accepted by most control profiles
+fn is_set_point_control(control: &microgrid_protobuf::generic_control::ControlType) -> bool {
+    use microgrid_protobuf::generic_control::ControlType;
+    matches!(
+        *control,
+        ControlType::ANetMag
+            | ControlType::ANeutMag
+            | ControlType::APhsAMag
+            | ControlType::APhsBMag
+            | ControlType::APhsCMag
+            | ControlType::HzMag
+            | ControlType::PfNetMag
+            | ControlType::PfNeutMag
+            | ControlType::PfPhsAMag
+            | ControlType::PfPhsBMag
+            | ControlType::PfPhsCMag
+            | ControlType::PhVNetAng
+            | ControlType::PhVNetMag
+            | ControlType::PhVNeutAng
+            | ControlType::PhVNeutMag
+            | ControlType::PhVPhsAAng
+            | ControlType::PhVPhsAMag
+            | ControlType::PhVPhsBAng
+            | ControlType::PhVPhsBMag
+            | ControlType::PhVPhsCAng
+            | ControlType::PhVPhsCMag
+            | ControlType::PpvPhsAbAng
+            | ControlType::PpvPhsAbMag
+            | ControlType::PpvPhsBcAng
+            | ControlType::PpvPhsBcMag
+            | ControlType::PpvPhsCaAng
+            | ControlType::PpvPhsCaMag
+            | ControlType::VaNetMag
+            | ControlType::VaNeutMag
+            | ControlType::VaPhsAMag
+            | ControlType::VaPhsBMag
+            | ControlType::VaPhsCMag
+            | ControlType::VArNetMag
+            | ControlType::SetVarNetMag
+            | ControlType::VArNeutMag
+            | ControlType::VArPhsAMag
+            | ControlType::VArPhsBMag
+            | ControlType::VArPhsCMag
+            | ControlType::WNetMag
+            | ControlType::SetWNetMag
+            | ControlType::WNeutMag
+            | ControlType::WPhsAMag
+            | ControlType::WPhsBMag
+            | ControlType::WPhsCMag
+    )
+}
+
 impl Receive<GenericControl> for HmiPublisher {
     type Msg = HmiPublisherMsg;
 
@@ -249,6 +416,24 @@ impl Receive<GenericControl> for HmiPublisher {
             profile_name = p.clone();
         }
 
+        if !supports_control(&profile_name, &msg.message) {
+            warn!(
+                "Unsupported control {:?} for {} of {}",
+                msg.message, profile_name, msg.mrid
+            );
+            // Recorded as not published, with the control being unsupported as the error
+            record_control(
+                &msg.issuer,
+                &msg.mrid,
+                &format!("{:?}", msg.message),
+                (msg.args, msg.args2),
+                Some(profile_name).filter(|p| !p.is_empty()),
+                None,
+            );
+            return;
+        }
+
+        self.last_publish = None;
         use microgrid_protobuf as microgrid;
         match profile_name.as_str() {
             "BreakerDiscreteControlProfile" => {
@@ -3417,9 +3602,17 @@ impl Receive<GenericControl> for HmiPublisher {
                 }
             }
             _ => {
-                println!("Unsupported generic control for profile {}", profile_name);
+                warn!("Unsupported generic control for profile {}", profile_name);
             }
         }
+        record_control(
+            &msg.issuer,
+            &msg.mrid,
+            &format!("{:?}", msg.message),
+            (msg.args, msg.args2),
+            Some(profile_name).filter(|p| !p.is_empty()),
+            self.last_publish.take(),
+        );
     }
 }
 
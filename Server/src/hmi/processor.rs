@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::handler::*;
+use crate::live_values::update_live_values;
 use crate::messages::*;
 use openfmb_messages_ext::OpenFMBMessage;
 
@@ -217,6 +218,28 @@ macro_rules! extract {
     };
 }
 
+/// Keeps the values of the message for rendering diagrams with live values, see
+/// `diagram_render`
+fn record_live_values(device_mrid: &str, msg: &OpenFMBMessage) {
+    let message_type = msg.message_type().to_string();
+    let mut json = match serde_json::to_value(msg) {
+        Ok(json) => json,
+        Err(_) => {
+            debug!("Unable to save message to json: {:?}", msg);
+            return;
+        }
+    };
+    // Paths start at the profile, not at the variant of the message
+    if let Some(inner) = json.get_mut(&message_type).map(Value::take) {
+        json = inner;
+    }
+    let mut d: BTreeMap<String, DataValue> = BTreeMap::new();
+    let mut root = Node::new("mapping");
+    root.path = format!("{}Profile.mapping", message_type);
+    root.from_json(&json, &mut d);
+    update_live_values(device_mrid, d);
+}
+
 async fn handle_openfmb_message(clients: &Clients, msg: OpenFMBMessage) {
     let device_mrid = match msg.device_mrid() {
         Ok(mrid) => mrid.as_hyphenated().to_string(),
@@ -226,6 +249,7 @@ async fn handle_openfmb_message(clients: &Clients, msg: OpenFMBMessage) {
         error!("Missing device MRID in OpenFMB message.");
         return;
     }
+    record_live_values(&device_mrid, &msg);
 
     let mut update_messages: BTreeMap<String, Vec<UpdateMessage>> = BTreeMap::new();
 
@@ -280,6 +304,7 @@ async fn handle_openfmb_message(clients: &Clients, msg: OpenFMBMessage) {
                             let update_msg = UpdateMessage {
                                 profile: Some(msg.message_type().to_string()),
                                 session_id: Some(client.session_id.clone()),
+                                reason: None,
                                 topic: crate::handler::Topic {
                                     name: key.clone(),
                                     mrid: device_mrid.clone(),
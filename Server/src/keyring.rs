@@ -0,0 +1,308 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::Error;
+use config::{Config, ConfigError};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+lazy_static! {
+    static ref KEYRING: KeyRing = match KeyRing::from_config(&riker::load_config()) {
+        Ok(keyring) => keyring,
+        Err(e) => panic!("Unable to load JWT signing keys: {}", e),
+    };
+}
+
+const DEFAULT_KID: &str = "default";
+
+/// One entry of the `[[auth.keys]]` array in the configuration file
+#[derive(Debug, Deserialize)]
+struct KeyConfig {
+    kid: String,
+    algorithm: Option<String>,
+    secret: Option<String>,
+    secret_file: Option<String>,
+    private_key: Option<String>,
+    public_key: Option<String>,
+}
+
+pub struct JwtKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+}
+
+impl JwtKey {
+    fn from_config(cfg: &KeyConfig) -> Result<JwtKey, Error> {
+        let algorithm = match &cfg.algorithm {
+            Some(alg) => Algorithm::from_str(alg)
+                .map_err(|_| key_error(&cfg.kid, &format!("unsupported algorithm {}", alg)))?,
+            None => Algorithm::HS512,
+        };
+
+        match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = match (&cfg.secret, &cfg.secret_file) {
+                    (Some(s), _) => s.as_bytes().to_vec(),
+                    (None, Some(file)) => {
+                        let mut contents = read_key_file(&cfg.kid, file)?;
+                        // Secret files are commonly written with a trailing newline
+                        while let Some(b'\n') | Some(b'\r') = contents.last() {
+                            contents.pop();
+                        }
+                        contents
+                    }
+                    (None, None) => {
+                        return Err(key_error(&cfg.kid, "missing secret or secret_file"));
+                    }
+                };
+                if secret.is_empty() {
+                    return Err(key_error(&cfg.kid, "secret is empty"));
+                }
+                Ok(JwtKey {
+                    kid: cfg.kid.clone(),
+                    algorithm,
+                    encoding: Some(EncodingKey::from_secret(&secret)),
+                    decoding: DecodingKey::from_secret(&secret),
+                })
+            }
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => {
+                let public_key = match &cfg.public_key {
+                    Some(file) => read_key_file(&cfg.kid, file)?,
+                    None => return Err(key_error(&cfg.kid, "missing public_key")),
+                };
+                let encoding = match &cfg.private_key {
+                    Some(file) => Some(
+                        EncodingKey::from_rsa_pem(&read_key_file(&cfg.kid, file)?)
+                            .map_err(|e| key_error(&cfg.kid, &e.to_string()))?,
+                    ),
+                    None => None,
+                };
+                Ok(JwtKey {
+                    kid: cfg.kid.clone(),
+                    algorithm,
+                    encoding,
+                    decoding: DecodingKey::from_rsa_pem(&public_key)
+                        .map_err(|e| key_error(&cfg.kid, &e.to_string()))?,
+                })
+            }
+            Algorithm::ES256 | Algorithm::ES384 => {
+                let public_key = match &cfg.public_key {
+                    Some(file) => read_key_file(&cfg.kid, file)?,
+                    None => return Err(key_error(&cfg.kid, "missing public_key")),
+                };
+                let encoding = match &cfg.private_key {
+                    Some(file) => Some(
+                        EncodingKey::from_ec_pem(&read_key_file(&cfg.kid, file)?)
+                            .map_err(|e| key_error(&cfg.kid, &e.to_string()))?,
+                    ),
+                    None => None,
+                };
+                Ok(JwtKey {
+                    kid: cfg.kid.clone(),
+                    algorithm,
+                    encoding,
+                    decoding: DecodingKey::from_ec_pem(&public_key)
+                        .map_err(|e| key_error(&cfg.kid, &e.to_string()))?,
+                })
+            }
+            _ => Err(key_error(&cfg.kid, "unsupported algorithm")),
+        }
+    }
+
+    pub fn encoding_key(&self) -> Option<&EncodingKey> {
+        self.encoding.as_ref()
+    }
+
+    pub fn decoding_key(&self) -> &DecodingKey {
+        &self.decoding
+    }
+}
+
+/// Set of keys used to sign and verify JWT tokens.
+///
+/// New tokens are always signed with the key named by `auth.signing_kid`. Every other key
+/// in the ring is still accepted for verification, so a key can be rotated by adding the new
+/// key, pointing `auth.signing_kid` at it and removing the old key once its tokens expired.
+pub struct KeyRing {
+    signing_kid: String,
+    keys: HashMap<String, JwtKey>,
+}
+
+impl KeyRing {
+    pub fn from_config(config: &Config) -> Result<KeyRing, Error> {
+        let key_configs: Vec<KeyConfig> = match config.get("auth.keys") {
+            Ok(key_configs) => key_configs,
+            Err(ConfigError::NotFound(_)) => vec![],
+            Err(e) => {
+                return Err(Error::JWTKeyError(format!(
+                    "auth.keys can't be read: {}",
+                    e
+                )))
+            }
+        };
+
+        if key_configs.is_empty() {
+            warn!("No JWT keys configured in [auth], using the generated local secret");
+            return KeyRing::with_local_secret();
+        }
+
+        let mut keys = HashMap::new();
+        for cfg in key_configs.iter() {
+            let key = JwtKey::from_config(cfg)?;
+            if keys.insert(key.kid.clone(), key).is_some() {
+                return Err(key_error(&cfg.kid, "duplicate kid"));
+            }
+        }
+
+        let signing_kid = match config.get_str("auth.signing_kid") {
+            Ok(kid) => kid,
+            Err(_) if key_configs.len() == 1 => key_configs[0].kid.clone(),
+            Err(_) => {
+                return Err(Error::JWTKeyError(
+                    "auth.signing_kid is required when more than one key is configured"
+                        .to_string(),
+                ))
+            }
+        };
+
+        match keys.get(&signing_kid) {
+            Some(key) if key.encoding.is_some() => {}
+            Some(_) => return Err(key_error(&signing_kid, "signing key has no private key")),
+            None => return Err(key_error(&signing_kid, "signing key is not configured")),
+        }
+
+        info!(
+            "Loaded {} JWT key(s), signing with '{}'",
+            keys.len(),
+            signing_kid
+        );
+
+        Ok(KeyRing { signing_kid, keys })
+    }
+
+    /// Key ring holding a single HS512 secret that is generated on first start and kept
+    /// next to the user file, so tokens survive restarts without shipping a shared secret.
+    fn with_local_secret() -> Result<KeyRing, Error> {
+        let file = get_secret_file();
+
+        if !Path::new(&file).exists() {
+            let secret: String = (0..4)
+                .map(|_| uuid::Uuid::new_v4().simple().to_string())
+                .collect();
+            write_secret_file(&file, &secret)
+                .map_err(|e| Error::JWTKeyError(format!("unable to write {}: {}", file, e)))?;
+            info!("Generated JWT secret {}", file);
+        }
+
+        let cfg = KeyConfig {
+            kid: DEFAULT_KID.to_string(),
+            algorithm: None,
+            secret: None,
+            secret_file: Some(file),
+            private_key: None,
+            public_key: None,
+        };
+
+        let mut keys = HashMap::new();
+        keys.insert(DEFAULT_KID.to_string(), JwtKey::from_config(&cfg)?);
+
+        Ok(KeyRing {
+            signing_kid: DEFAULT_KID.to_string(),
+            keys,
+        })
+    }
+
+    pub fn signing_key(&self) -> &JwtKey {
+        // Presence of the signing key is checked when the ring is loaded
+        &self.keys[&self.signing_kid]
+    }
+
+    pub fn verification_key(&self, kid: &str) -> Option<&JwtKey> {
+        self.keys.get(kid)
+    }
+}
+
+/// Returns the process wide key ring, loading it on first use
+pub fn keyring() -> &'static KeyRing {
+    &KEYRING
+}
+
+/// Loads the key ring eagerly so that a bad key configuration stops the server at startup
+pub fn init_keyring() {
+    lazy_static::initialize(&KEYRING);
+}
+
+fn get_secret_file() -> String {
+    let app_dir = std::env::var("APP_DIR_NAME").unwrap_or_else(|_| "".into());
+    if app_dir != "" {
+        return format!("/{}/jwt.secret", app_dir);
+    }
+    "jwt.secret".to_string()
+}
+
+/// Creates the file readable by its owner only, the secret signs every token
+fn write_secret_file(file: &str, secret: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(file)?.write_all(secret.as_bytes())
+}
+
+fn read_key_file(kid: &str, file: &str) -> Result<Vec<u8>, Error> {
+    fs::read(file).map_err(|e| key_error(kid, &format!("unable to read {}: {}", file, e)))
+}
+
+fn key_error(kid: &str, message: &str) -> Error {
+    Error::JWTKeyError(format!("key '{}': {}", kid, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_keys_are_an_error() {
+        let mut config = Config::default();
+        config.set("auth.keys", "not a list of keys").unwrap();
+        assert!(matches!(
+            KeyRing::from_config(&config),
+            Err(Error::JWTKeyError(message)) if message.starts_with("auth.keys")
+        ));
+    }
+
+    #[test]
+    fn single_key_signs() {
+        let mut config = Config::default();
+        config
+            .set(
+                "auth.keys",
+                vec![[("kid", "k1"), ("secret", "s3cret")]
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<HashMap<String, String>>()],
+            )
+            .unwrap();
+        let keyring = KeyRing::from_config(&config).unwrap();
+        assert_eq!(keyring.signing_key().kid, "k1");
+        assert!(keyring.verification_key("other").is_none());
+    }
+}
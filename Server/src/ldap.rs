@@ -0,0 +1,301 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::auth::{provision_external_user, Role, User};
+use crate::auth_provider::{AuthProvider, AuthResult};
+use crate::error::Error;
+use config::Config;
+use ldap3::{ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use log::{debug, info};
+use std::time::Duration;
+
+const PROVIDER_NAME: &str = "ldap";
+
+/// Authenticates users with a bind against an LDAP or Active Directory server.
+///
+/// The user entry is looked up with `user_filter` under `base_dn`, optionally binding
+/// with a service account first, then the user's own DN is bound with the password.
+/// The groups listed in `group_attribute` of the entry decide the user's role.
+pub struct LdapAuthProvider {
+    url: String,
+    starttls: bool,
+    no_tls_verify: bool,
+    timeout: Duration,
+    bind_dn: Option<String>,
+    bind_password: Option<String>,
+    base_dn: String,
+    user_filter: String,
+    display_name_attribute: String,
+    group_attribute: String,
+    admin_groups: Vec<String>,
+    engineer_groups: Vec<String>,
+    viewer_groups: Vec<String>,
+}
+
+impl LdapAuthProvider {
+    pub fn from_config(config: &Config) -> Result<LdapAuthProvider, Error> {
+        let url = config
+            .get_str("ldap.url")
+            .map_err(|_| Error::AuthProviderError("missing ldap.url".to_string()))?;
+        let base_dn = config
+            .get_str("ldap.base_dn")
+            .map_err(|_| Error::AuthProviderError("missing ldap.base_dn".to_string()))?;
+
+        Ok(LdapAuthProvider {
+            url,
+            starttls: config.get_bool("ldap.starttls").unwrap_or(false),
+            no_tls_verify: config.get_bool("ldap.no_tls_verify").unwrap_or(false),
+            timeout: Duration::from_secs(config.get_int("ldap.timeout").unwrap_or(10) as u64),
+            bind_dn: config.get_str("ldap.bind_dn").ok(),
+            bind_password: config.get_str("ldap.bind_password").ok(),
+            base_dn,
+            user_filter: config
+                .get_str("ldap.user_filter")
+                .unwrap_or("(&(objectClass=person)(uid={username}))".to_string()),
+            display_name_attribute: config
+                .get_str("ldap.display_name_attribute")
+                .unwrap_or("displayName".to_string()),
+            group_attribute: config
+                .get_str("ldap.group_attribute")
+                .unwrap_or("memberOf".to_string()),
+            admin_groups: config.get("ldap.admin_groups").unwrap_or_default(),
+            engineer_groups: config.get("ldap.engineer_groups").unwrap_or_default(),
+            viewer_groups: config.get("ldap.viewer_groups").unwrap_or_default(),
+        })
+    }
+
+    /// Maps the directory groups onto a role, the most privileged match wins.
+    /// When viewer groups are configured, users in none of the groups are refused.
+    fn role_from_groups(&self, groups: &[String]) -> Option<Role> {
+        let is_member = |configured: &Vec<String>| {
+            groups.iter().any(|g| {
+                configured
+                    .iter()
+                    .any(|c| c.eq_ignore_ascii_case(g) || c.eq_ignore_ascii_case(&group_cn(g)))
+            })
+        };
+
+        if is_member(&self.admin_groups) {
+            Some(Role::Admin)
+        } else if is_member(&self.engineer_groups) {
+            Some(Role::Engineer)
+        } else if self.viewer_groups.is_empty() || is_member(&self.viewer_groups) {
+            Some(Role::Viewer)
+        } else {
+            None
+        }
+    }
+
+    async fn bind_user(&self, username: &str, password: &str) -> Result<Option<User>, Error> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(self.timeout)
+            .set_starttls(self.starttls)
+            .set_no_tls_verify(self.no_tls_verify);
+
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url)
+            .await
+            .map_err(ldap_error)?;
+        ldap3::drive!(conn);
+
+        if let (Some(dn), Some(pwd)) = (&self.bind_dn, &self.bind_password) {
+            ldap.simple_bind(dn, pwd)
+                .await
+                .map_err(ldap_error)?
+                .success()
+                .map_err(ldap_error)?;
+        }
+
+        let filter = self
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let (entries, _) = ldap
+            .search(
+                &self.base_dn,
+                Scope::Subtree,
+                &filter,
+                vec![
+                    self.display_name_attribute.as_str(),
+                    self.group_attribute.as_str(),
+                ],
+            )
+            .await
+            .map_err(ldap_error)?
+            .success()
+            .map_err(ldap_error)?;
+
+        if entries.len() != 1 {
+            debug!(
+                "LDAP search for {} returned {} entries",
+                username,
+                entries.len()
+            );
+            let _ = ldap.unbind().await;
+            return Ok(None);
+        }
+        let entry = SearchEntry::construct(entries.into_iter().next().unwrap());
+
+        // A failed bind is a wrong password, not a provider failure
+        let bound = ldap
+            .simple_bind(&entry.dn, password)
+            .await
+            .map_err(ldap_error)?
+            .success()
+            .is_ok();
+        let _ = ldap.unbind().await;
+        if !bound {
+            return Ok(None);
+        }
+
+        let groups = entry
+            .attrs
+            .get(&self.group_attribute)
+            .cloned()
+            .unwrap_or_default();
+        let role = match self.role_from_groups(&groups) {
+            Some(role) => role,
+            None => {
+                info!("LDAP user {} is not in any HMI group", username);
+                return Ok(None);
+            }
+        };
+
+        let displayname = entry
+            .attrs
+            .get(&self.display_name_attribute)
+            .and_then(|v| v.first().cloned())
+            .unwrap_or_else(|| username.to_string());
+
+        Ok(provision_external_user(
+            PROVIDER_NAME,
+            username,
+            &displayname,
+            &role,
+        ))
+    }
+}
+
+impl AuthProvider for LdapAuthProvider {
+    fn name(&self) -> &str {
+        PROVIDER_NAME
+    }
+
+    fn authenticate<'a>(&'a self, username: &'a str, password: &'a str) -> AuthResult<'a> {
+        Box::pin(async move {
+            // An empty password would be an unauthenticated bind, which servers accept
+            if password.is_empty() {
+                return Ok(None);
+            }
+            self.bind_user(username, password).await
+        })
+    }
+}
+
+/// Value of the first RDN of a group DN, `cn=hmi-admins,ou=groups,...` gives `hmi-admins`
+fn group_cn(dn: &str) -> String {
+    let rdn = dn.split(',').next().unwrap_or(dn);
+    match rdn.split_once('=') {
+        Some((_, value)) => value.trim().to_string(),
+        None => rdn.trim().to_string(),
+    }
+}
+
+fn ldap_error(e: ldap3::LdapError) -> Error {
+    Error::AuthProviderError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(viewer_groups: &[&str]) -> LdapAuthProvider {
+        let groups = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let mut config = Config::default();
+        config.set("ldap.url", "ldap://localhost").unwrap();
+        config.set("ldap.base_dn", "dc=example,dc=com").unwrap();
+        config
+            .set(
+                "ldap.admin_groups",
+                groups(&["cn=HMI-Admins,ou=groups,dc=example,dc=com"]),
+            )
+            .unwrap();
+        config
+            .set("ldap.engineer_groups", groups(&["hmi-engineers"]))
+            .unwrap();
+        config
+            .set("ldap.viewer_groups", groups(viewer_groups))
+            .unwrap();
+        LdapAuthProvider::from_config(&config).unwrap()
+    }
+
+    fn role(provider: &LdapAuthProvider, groups: &[&str]) -> Option<Role> {
+        let groups: Vec<String> = groups.iter().map(|g| g.to_string()).collect();
+        provider.role_from_groups(&groups)
+    }
+
+    #[test]
+    fn group_cn_of_dns_and_names() {
+        assert_eq!(
+            group_cn("cn=hmi-admins,ou=groups,dc=example,dc=com"),
+            "hmi-admins"
+        );
+        assert_eq!(group_cn("CN = Operators , OU=groups"), "Operators");
+        assert_eq!(group_cn("hmi-engineers"), "hmi-engineers");
+        assert_eq!(group_cn(""), "");
+    }
+
+    #[test]
+    fn groups_match_by_dn_or_cn() {
+        let provider = provider(&["hmi-viewers"]);
+        assert!(matches!(
+            role(&provider, &["cn=hmi-admins,ou=groups,dc=example,dc=com"]),
+            Some(Role::Admin)
+        ));
+        assert!(matches!(
+            role(&provider, &["CN=HMI-Engineers,OU=Groups,DC=example,DC=com"]),
+            Some(Role::Engineer)
+        ));
+        assert!(matches!(
+            role(&provider, &["hmi-engineers"]),
+            Some(Role::Engineer)
+        ));
+        assert!(matches!(
+            role(&provider, &["cn=hmi-viewers,ou=groups"]),
+            Some(Role::Viewer)
+        ));
+        // A configured DN is only matched by the whole DN
+        assert!(role(&provider, &["hmi-admins"]).is_none());
+        assert!(role(&provider, &["cn=others,ou=groups"]).is_none());
+        assert!(role(&provider, &[]).is_none());
+    }
+
+    #[test]
+    fn most_privileged_group_wins() {
+        let provider = provider(&["hmi-viewers"]);
+        assert!(matches!(
+            role(
+                &provider,
+                &[
+                    "cn=hmi-viewers,ou=groups",
+                    "hmi-engineers",
+                    "cn=hmi-admins,ou=groups,dc=example,dc=com"
+                ]
+            ),
+            Some(Role::Admin)
+        ));
+        assert!(matches!(
+            role(&provider, &["hmi-viewers", "cn=hmi-engineers,ou=groups"]),
+            Some(Role::Engineer)
+        ));
+    }
+
+    #[test]
+    fn without_viewer_groups_everyone_is_a_viewer() {
+        let provider = provider(&[]);
+        assert!(matches!(role(&provider, &[]), Some(Role::Viewer)));
+        assert!(matches!(
+            role(&provider, &["cn=others,ou=groups"]),
+            Some(Role::Viewer)
+        ));
+    }
+}
@@ -2,12 +2,33 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod api_key;
+pub mod audit;
 pub mod auth;
+pub mod auth_provider;
+pub mod bundle;
+pub mod collaboration;
+pub mod control_log;
+pub mod diagram_history;
+pub mod diagram_id;
+pub mod diagram_publish;
+pub mod diagram_render;
+pub mod diagram_template;
+pub mod diagram_validation;
 pub mod error;
 pub mod handler;
 pub mod hmi;
+pub mod keyring;
+pub mod ldap;
+pub mod live_values;
+pub mod lockout;
 pub mod logs;
 pub mod messages;
+pub mod oidc;
+pub mod password_policy;
+pub mod session;
+pub mod storage;
+pub mod two_factor;
 
 pub use hmi::*;
 pub use messages::*;
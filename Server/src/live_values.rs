@@ -0,0 +1,51 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::handler::DataValue;
+use chrono::prelude::*;
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+lazy_static! {
+    /// Latest value of every data point received, by device mRID and path
+    static ref LIVE_VALUES: RwLock<HashMap<String, DeviceValues>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DeviceValues {
+    /// Unix timestamp in seconds of the latest message of the device
+    pub updated: i64,
+    values: BTreeMap<String, DataValue>,
+}
+
+impl DeviceValues {
+    pub fn get(&self, path: &str) -> Option<&DataValue> {
+        self.values.get(&path_key(path))
+    }
+}
+
+/// Paths are compared the way the processor matches topics, without case and underscores
+fn path_key(path: &str) -> String {
+    path.replace('_', "").to_lowercase()
+}
+
+/// Keeps the values of a message of the device, values of other profiles are kept as well
+pub fn update_live_values(mrid: &str, values: BTreeMap<String, DataValue>) {
+    if let Ok(mut live) = LIVE_VALUES.write() {
+        let device = live.entry(mrid.to_lowercase()).or_default();
+        device.updated = Utc::now().timestamp();
+        for (path, value) in values {
+            device.values.insert(path_key(&path), value);
+        }
+    }
+}
+
+/// Values received from the device since the server started
+pub fn live_values(mrid: &str) -> Option<DeviceValues> {
+    LIVE_VALUES
+        .read()
+        .ok()
+        .and_then(|live| live.get(&mrid.to_lowercase()).cloned())
+}
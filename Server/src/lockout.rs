@@ -0,0 +1,342 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use config::Config;
+use lazy_static::lazy_static;
+use log::error;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use warp::{Filter, Rejection};
+
+lazy_static! {
+    static ref SETTINGS: LockoutSettings = LockoutSettings::from_config(&riker::load_config());
+    static ref ATTEMPTS: Mutex<HashMap<String, FailedAttempts>> = Mutex::new(HashMap::new());
+}
+
+const DEFAULT_MAX_FAILED_LOGINS: i64 = 5;
+const DEFAULT_LOGIN_DELAY: i64 = 1; // seconds
+const DEFAULT_MAX_LOGIN_DELAY: i64 = 300; // seconds
+
+struct LockoutSettings {
+    max_failed_logins: u32,
+    login_delay: Duration,
+    max_login_delay: Duration,
+    trusted_proxies: Vec<TrustedProxy>,
+}
+
+impl LockoutSettings {
+    fn from_config(config: &Config) -> LockoutSettings {
+        LockoutSettings {
+            max_failed_logins: config
+                .get_int("auth.max_failed_logins")
+                .unwrap_or(DEFAULT_MAX_FAILED_LOGINS)
+                .max(0) as u32,
+            login_delay: Duration::from_secs(
+                config
+                    .get_int("auth.login_delay")
+                    .unwrap_or(DEFAULT_LOGIN_DELAY)
+                    .max(0) as u64,
+            ),
+            max_login_delay: Duration::from_secs(
+                config
+                    .get_int("auth.max_login_delay")
+                    .unwrap_or(DEFAULT_MAX_LOGIN_DELAY)
+                    .max(0) as u64,
+            ),
+            trusted_proxies: config
+                .get::<Vec<String>>("auth.trusted_proxies")
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|proxy| match TrustedProxy::from_str(proxy) {
+                    Ok(proxy) => Some(proxy),
+                    Err(_) => {
+                        error!("Ignoring invalid auth.trusted_proxies entry {}", proxy);
+                        None
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Address or network of a reverse proxy, e.g. `10.0.0.5` or `172.16.0.0/12`, that is
+/// trusted to name the client in `X-Forwarded-For`
+#[derive(Debug, PartialEq)]
+struct TrustedProxy {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl FromStr for TrustedProxy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s.trim(), None),
+        };
+        let network = IpAddr::from_str(address).map_err(|_| ())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().map_err(|_| ())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(());
+        }
+        Ok(TrustedProxy {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl TrustedProxy {
+    fn contains(&self, address: &IpAddr) -> bool {
+        let mask = |bits: u32| match bits.checked_sub(self.prefix_len) {
+            Some(host_bits) if host_bits < bits => u128::MAX << host_bits,
+            _ => 0,
+        };
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = mask(32) as u32;
+                u32::from(network) & mask == u32::from(*address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = mask(128);
+                u128::from(network) & mask == u128::from(*address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Address of the client, the peer unless the peer is a trusted proxy.
+///
+/// Proxies append the address they received the request from to `X-Forwarded-For`, so the
+/// list is read from the end and the first address that isn't a trusted proxy is the
+/// client. Entries before it could have been sent by the client itself.
+fn client_address(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[TrustedProxy],
+) -> Option<IpAddr> {
+    let is_trusted = |address: &IpAddr| trusted_proxies.iter().any(|p| p.contains(address));
+    let mut client = peer?;
+    if let Some(forwarded_for) = forwarded_for {
+        for hop in forwarded_for.rsplit(',') {
+            if !is_trusted(&client) {
+                break;
+            }
+            match IpAddr::from_str(hop.trim()) {
+                Ok(address) => client = address,
+                Err(_) => break,
+            }
+        }
+    }
+    Some(client)
+}
+
+/// Address of the client the request came from, as used to throttle logins per client.
+/// `X-Forwarded-For` is only followed for the proxies listed in `auth.trusted_proxies`.
+pub fn with_client_address() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone
+{
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(|peer: Option<SocketAddr>, forwarded_for: Option<String>| {
+            client_address(
+                peer.map(|a| a.ip()),
+                forwarded_for.as_deref(),
+                &SETTINGS.trusted_proxies,
+            )
+            .map(|address| address.to_string())
+        })
+}
+
+/// Consecutive failed logins for a username or a client address
+struct FailedAttempts {
+    count: u32,
+    last: Instant,
+}
+
+impl FailedAttempts {
+    /// Delay doubles with every consecutive failure, up to the configured maximum
+    fn delay(&self, settings: &LockoutSettings) -> Duration {
+        let factor = 2u32.saturating_pow(self.count.saturating_sub(1));
+        std::cmp::min(
+            settings.login_delay.saturating_mul(factor),
+            settings.max_login_delay,
+        )
+    }
+
+    fn retry_after(&self, settings: &LockoutSettings) -> Option<Duration> {
+        let next = self.last + self.delay(settings);
+        let now = Instant::now();
+        if next > now {
+            Some(next - now)
+        } else {
+            None
+        }
+    }
+}
+
+fn username_key(username: &str) -> String {
+    format!("user:{}", username.to_lowercase())
+}
+
+fn address_key(address: &str) -> String {
+    format!("addr:{}", address)
+}
+
+/// Time left before another login attempt is accepted for the username or address
+pub fn login_retry_after(username: &str, address: Option<&str>) -> Option<Duration> {
+    let attempts = ATTEMPTS.lock().unwrap();
+
+    let mut keys = vec![username_key(username)];
+    if let Some(address) = address {
+        keys.push(address_key(address));
+    }
+
+    keys.iter()
+        .filter_map(|k| attempts.get(k).and_then(|a| a.retry_after(&SETTINGS)))
+        .max()
+}
+
+/// Records a failed login, returns true when the account has to be locked
+pub fn record_failed_login(username: &str, address: Option<&str>) -> bool {
+    let mut attempts = ATTEMPTS.lock().unwrap();
+
+    // Forget clients that stopped trying long ago
+    let idle = SETTINGS.max_login_delay * 2;
+    attempts.retain(|_, a| a.last.elapsed() < idle);
+
+    let mut keys = vec![username_key(username)];
+    if let Some(address) = address {
+        keys.push(address_key(address));
+    }
+
+    let mut user_failures = 0;
+    for key in keys.iter() {
+        let entry = attempts.entry(key.clone()).or_insert(FailedAttempts {
+            count: 0,
+            last: Instant::now(),
+        });
+        entry.count += 1;
+        entry.last = Instant::now();
+        if key == &keys[0] {
+            user_failures = entry.count;
+        }
+    }
+
+    SETTINGS.max_failed_logins > 0 && user_failures >= SETTINGS.max_failed_logins
+}
+
+/// Clears the failures of the username and address after a successful login
+pub fn record_successful_login(username: &str, address: Option<&str>) {
+    let mut attempts = ATTEMPTS.lock().unwrap();
+    attempts.remove(&username_key(username));
+    if let Some(address) = address {
+        attempts.remove(&address_key(address));
+    }
+}
+
+/// Clears the failures of the username, used when an administrator unlocks the account
+pub fn reset_failed_logins(username: &str) {
+    ATTEMPTS.lock().unwrap().remove(&username_key(username));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(login_delay: u64, max_login_delay: u64) -> LockoutSettings {
+        LockoutSettings {
+            max_failed_logins: 5,
+            login_delay: Duration::from_secs(login_delay),
+            max_login_delay: Duration::from_secs(max_login_delay),
+            trusted_proxies: vec![],
+        }
+    }
+
+    fn attempts(count: u32) -> FailedAttempts {
+        FailedAttempts {
+            count,
+            last: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_maximum() {
+        let settings = settings(1, 300);
+        let delays: Vec<u64> = (1..=10)
+            .map(|count| attempts(count).delay(&settings).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 64, 128, 256, 300]);
+        assert_eq!(attempts(1000).delay(&settings).as_secs(), 300);
+    }
+
+    #[test]
+    fn no_delay_when_disabled() {
+        let settings = settings(0, 300);
+        assert_eq!(attempts(3).delay(&settings), Duration::from_secs(0));
+        assert!(attempts(3).retry_after(&settings).is_none());
+    }
+
+    #[test]
+    fn retry_after_counts_from_the_last_failure() {
+        let settings = settings(10, 300);
+        let wait = attempts(1).retry_after(&settings).unwrap();
+        assert!(wait <= Duration::from_secs(10) && wait > Duration::from_secs(9));
+    }
+
+    fn proxies(list: &[&str]) -> Vec<TrustedProxy> {
+        list.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn trusted_proxy_networks() {
+        let proxies = proxies(&["10.0.0.5", "172.16.0.0/12", "fd00::/8", "0.0.0.0/0"]);
+        assert!(proxies[0].contains(&ip("10.0.0.5")));
+        assert!(!proxies[0].contains(&ip("10.0.0.6")));
+        assert!(proxies[1].contains(&ip("172.31.255.1")));
+        assert!(!proxies[1].contains(&ip("172.32.0.1")));
+        assert!(proxies[2].contains(&ip("fd12::1")));
+        assert!(!proxies[2].contains(&ip("10.0.0.5")));
+        assert!(proxies[3].contains(&ip("192.168.1.1")));
+
+        assert!("10.0.0.0/33".parse::<TrustedProxy>().is_err());
+        assert!("proxy.local".parse::<TrustedProxy>().is_err());
+    }
+
+    #[test]
+    fn forwarded_for_is_only_followed_from_trusted_proxies() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+        let client = |peer: &str, forwarded_for: Option<&str>| {
+            client_address(Some(ip(peer)), forwarded_for, &trusted).unwrap()
+        };
+
+        // Direct connection, a header sent by the client is ignored
+        assert_eq!(client("192.0.2.1", Some("198.51.100.7")), ip("192.0.2.1"));
+        assert_eq!(client("10.0.0.5", None), ip("10.0.0.5"));
+        assert_eq!(client("10.0.0.5", Some("198.51.100.7")), ip("198.51.100.7"));
+        // Chained proxies, an address the client put in front is not taken
+        assert_eq!(
+            client("10.0.0.5", Some("203.0.113.9, 198.51.100.7, 10.0.0.9")),
+            ip("198.51.100.7")
+        );
+        // Garbage stops at the last trusted hop
+        assert_eq!(
+            client("10.0.0.5", Some("unknown, 10.0.0.9")),
+            ip("10.0.0.9")
+        );
+        assert_eq!(client_address(None, Some("198.51.100.7"), &trusted), None);
+    }
+}
@@ -0,0 +1,499 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::auth::{issue_tokens, provision_external_user, Role};
+use crate::error::Error;
+use config::Config;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use openidconnect::core::{
+    CoreAuthDisplay, CoreAuthPrompt, CoreAuthenticationFlow, CoreErrorResponseType,
+    CoreGenderClaim, CoreJsonWebKey, CoreJsonWebKeyType, CoreJsonWebKeyUse,
+    CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm, CoreProviderMetadata,
+    CoreRevocableToken, CoreRevocationErrorResponse, CoreTokenIntrospectionResponse, CoreTokenType,
+};
+use openidconnect::reqwest::async_http_client;
+use openidconnect::{
+    AdditionalClaims, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken,
+    EmptyExtraTokenFields, IdTokenClaims, IdTokenFields, IssuerUrl, Nonce, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, Scope, StandardErrorResponse, StandardTokenResponse,
+    TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+use warp::{http::Uri, reject, reply::json, Rejection, Reply};
+
+lazy_static! {
+    static ref SETTINGS: Option<OidcSettings> = OidcSettings::from_config(&riker::load_config());
+    static ref PENDING: Mutex<HashMap<String, PendingLogin>> = Mutex::new(HashMap::new());
+}
+
+static CLIENT: OnceCell<OidcClient> = OnceCell::const_new();
+
+pub(crate) const PROVIDER_NAME: &str = "oidc";
+
+/// Time allowed between starting a login and the identity provider calling back
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Page of the client application that receives the outcome of a login
+const SIGNIN_PAGE: &str = "/sessions/signin";
+
+/// Claims of the ID token not covered by the standard claims, the role claim is one of them
+#[derive(Debug, Deserialize, Serialize)]
+struct ExtraClaims {
+    #[serde(flatten)]
+    claims: HashMap<String, Value>,
+}
+
+impl AdditionalClaims for ExtraClaims {}
+
+type OidcIdTokenClaims = IdTokenClaims<ExtraClaims, CoreGenderClaim>;
+
+type OidcTokenResponse = StandardTokenResponse<
+    IdTokenFields<
+        ExtraClaims,
+        EmptyExtraTokenFields,
+        CoreGenderClaim,
+        CoreJweContentEncryptionAlgorithm,
+        CoreJwsSigningAlgorithm,
+        CoreJsonWebKeyType,
+    >,
+    CoreTokenType,
+>;
+
+type OidcClient = Client<
+    ExtraClaims,
+    CoreAuthDisplay,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+    CoreJsonWebKeyUse,
+    CoreJsonWebKey,
+    CoreAuthPrompt,
+    StandardErrorResponse<CoreErrorResponseType>,
+    OidcTokenResponse,
+    CoreTokenType,
+    CoreTokenIntrospectionResponse,
+    CoreRevocableToken,
+    CoreRevocationErrorResponse,
+>;
+
+struct OidcSettings {
+    display_name: String,
+    issuer_url: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_url: String,
+    scopes: Vec<String>,
+    username_claim: String,
+    role_claim: String,
+    admin_values: Vec<String>,
+    engineer_values: Vec<String>,
+    viewer_values: Vec<String>,
+}
+
+impl OidcSettings {
+    fn from_config(config: &Config) -> Option<OidcSettings> {
+        let issuer_url = config.get_str("oidc.issuer_url").ok()?;
+        let client_id = config.get_str("oidc.client_id").ok()?;
+        let redirect_url = match config.get_str("oidc.redirect_url") {
+            Ok(url) => url,
+            Err(_) => {
+                error!("OpenID Connect is disabled, oidc.redirect_url is missing");
+                return None;
+            }
+        };
+
+        Some(OidcSettings {
+            display_name: config
+                .get_str("oidc.display_name")
+                .unwrap_or("Single Sign-On".to_string()),
+            issuer_url,
+            client_id,
+            client_secret: config.get_str("oidc.client_secret").ok(),
+            redirect_url,
+            scopes: config
+                .get("oidc.scopes")
+                .unwrap_or_else(|_| vec!["profile".to_string(), "email".to_string()]),
+            username_claim: config
+                .get_str("oidc.username_claim")
+                .unwrap_or("preferred_username".to_string()),
+            role_claim: config
+                .get_str("oidc.role_claim")
+                .unwrap_or("groups".to_string()),
+            admin_values: config.get("oidc.admin_values").unwrap_or_default(),
+            engineer_values: config.get("oidc.engineer_values").unwrap_or_default(),
+            viewer_values: config.get("oidc.viewer_values").unwrap_or_default(),
+        })
+    }
+
+    /// Maps the values of the role claim onto a role, the most privileged match wins.
+    /// When viewer values are configured, users matching none of the values are refused.
+    fn role_from_claim(&self, values: &[String]) -> Option<Role> {
+        let matches = |configured: &Vec<String>| values.iter().any(|v| configured.contains(v));
+
+        if matches(&self.admin_values) {
+            Some(Role::Admin)
+        } else if matches(&self.engineer_values) {
+            Some(Role::Engineer)
+        } else if self.viewer_values.is_empty() || matches(&self.viewer_values) {
+            Some(Role::Viewer)
+        } else {
+            None
+        }
+    }
+}
+
+struct PendingLogin {
+    nonce: Nonce,
+    pkce_verifier: PkceCodeVerifier,
+    started: Instant,
+}
+
+#[derive(Serialize)]
+pub struct OidcConfigResponse {
+    pub enabled: bool,
+    pub display_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+async fn get_client(settings: &OidcSettings) -> Result<&'static OidcClient, Error> {
+    CLIENT
+        .get_or_try_init(|| async {
+            let issuer_url = IssuerUrl::new(settings.issuer_url.clone())
+                .map_err(|e| Error::AuthProviderError(e.to_string()))?;
+            let metadata = CoreProviderMetadata::discover_async(issuer_url, async_http_client)
+                .await
+                .map_err(|e| Error::AuthProviderError(e.to_string()))?;
+            let redirect_url = RedirectUrl::new(settings.redirect_url.clone())
+                .map_err(|e| Error::AuthProviderError(e.to_string()))?;
+
+            info!("Discovered OpenID Connect provider {}", settings.issuer_url);
+
+            Ok(OidcClient::from_provider_metadata(
+                metadata,
+                ClientId::new(settings.client_id.clone()),
+                settings.client_secret.clone().map(ClientSecret::new),
+            )
+            .set_redirect_uri(redirect_url))
+        })
+        .await
+}
+
+// GET
+pub async fn oidc_config_handler() -> Result<impl Reply, Rejection> {
+    Ok(json(&OidcConfigResponse {
+        enabled: SETTINGS.is_some(),
+        display_name: SETTINGS.as_ref().map(|s| s.display_name.clone()),
+    }))
+}
+
+// GET
+pub async fn oidc_login_handler() -> Result<impl Reply, Rejection> {
+    let settings = SETTINGS.as_ref().ok_or_else(reject::not_found)?;
+    let client = get_client(settings).await.map_err(|e| {
+        error!("Unable to start OpenID Connect login: {}", e);
+        reject::custom(e)
+    })?;
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let mut request = client
+        .authorize_url(
+            CoreAuthenticationFlow::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        )
+        .set_pkce_challenge(pkce_challenge);
+    for scope in settings.scopes.iter() {
+        request = request.add_scope(Scope::new(scope.clone()));
+    }
+    let (auth_url, csrf_token, nonce) = request.url();
+
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|_, p| p.started.elapsed() < LOGIN_TIMEOUT);
+    pending.insert(
+        csrf_token.secret().clone(),
+        PendingLogin {
+            nonce,
+            pkce_verifier,
+            started: Instant::now(),
+        },
+    );
+
+    let uri: Uri = auth_url
+        .as_str()
+        .parse()
+        .map_err(|_| reject::custom(Error::AuthProviderError(auth_url.to_string())))?;
+    Ok(warp::redirect::found(uri))
+}
+
+// GET
+pub async fn oidc_callback_handler(query: OidcCallbackQuery) -> Result<impl Reply, Rejection> {
+    // The browser is sent back to the client application either way, the tokens or the
+    // error travel in the URL fragment so they never reach server logs
+    let fragment = match complete_login(query).await {
+        Ok((token, refresh_token)) => format!("token={}&refresh_token={}", token, refresh_token),
+        Err(e) => {
+            error!("OpenID Connect login failed: {}", e);
+            "error=sso_failed".to_string()
+        }
+    };
+
+    let uri: Uri = format!("{}#{}", SIGNIN_PAGE, fragment)
+        .parse()
+        .map_err(|_| reject::not_found())?;
+    Ok(warp::redirect::found(uri))
+}
+
+async fn complete_login(query: OidcCallbackQuery) -> Result<(String, String), Error> {
+    let settings = SETTINGS.as_ref().ok_or(Error::WrongCredentialsError)?;
+
+    if let Some(e) = query.error {
+        return Err(Error::AuthProviderError(e));
+    }
+    let (code, state) = match (query.code, query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return Err(Error::WrongCredentialsError),
+    };
+
+    let pending = PENDING
+        .lock()
+        .unwrap()
+        .remove(&state)
+        .filter(|p| p.started.elapsed() < LOGIN_TIMEOUT)
+        .ok_or(Error::WrongCredentialsError)?;
+
+    let client = get_client(settings).await?;
+    let token_response = client
+        .exchange_code(AuthorizationCode::new(code))
+        .set_pkce_verifier(pending.pkce_verifier)
+        .request_async(async_http_client)
+        .await
+        .map_err(|e| Error::AuthProviderError(e.to_string()))?;
+
+    let id_token = token_response
+        .id_token()
+        .ok_or_else(|| Error::AuthProviderError("no ID token in response".to_string()))?;
+    let claims = id_token
+        .claims(&client.id_token_verifier(), &pending.nonce)
+        .map_err(|e| Error::AuthProviderError(e.to_string()))?;
+
+    let username = claim_username(claims, &settings.username_claim)
+        .ok_or_else(|| Error::AuthProviderError("no username claim".to_string()))?;
+    let role_values = claim_values(claims, &settings.role_claim);
+    if role_values.is_empty() {
+        // Keycloak e.g. leaves the realm roles out of the ID token unless a mapper adds them
+        warn!(
+            "ID token of {} has no {} claim, check that the provider adds it to the ID token",
+            username, settings.role_claim
+        );
+    }
+    let role = settings
+        .role_from_claim(&role_values)
+        .ok_or(Error::NoPermissionError)?;
+    let displayname = claims
+        .name()
+        .and_then(|n| n.get(None))
+        .map(|n| n.to_string())
+        .unwrap_or_else(|| username.clone());
+
+    let usr = provision_external_user(PROVIDER_NAME, &username, &displayname, &role)
+        .ok_or(Error::WrongCredentialsError)?;
+
+    info!("User {} authenticated by {}", username, PROVIDER_NAME);
+
+    issue_tokens(&usr)
+}
+
+fn claim_username(claims: &OidcIdTokenClaims, claim: &str) -> Option<String> {
+    match claim {
+        "preferred_username" => claims.preferred_username().map(|u| u.to_string()),
+        "email" => claims.email().map(|e| e.to_string()),
+        "sub" => Some(claims.subject().to_string()),
+        _ => claim_values(claims, claim).into_iter().next(),
+    }
+}
+
+/// Values of a claim given as a dotted path, e.g. `realm_access.roles` for Keycloak
+fn claim_values(claims: &OidcIdTokenClaims, path: &str) -> Vec<String> {
+    let mut parts = path.split('.');
+    let mut value = match parts
+        .next()
+        .and_then(|p| claims.additional_claims().claims.get(p))
+    {
+        Some(v) => v,
+        None => return vec![],
+    };
+    for part in parts {
+        value = match value.get(part) {
+            Some(v) => v,
+            None => return vec![],
+        };
+    }
+
+    match value {
+        Value::String(s) => vec![s.clone()],
+        Value::Array(a) => a
+            .iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn claims(extra: Value) -> OidcIdTokenClaims {
+        let mut claims = json!({
+            "iss": "https://idp.example.com",
+            "aud": "hmi",
+            "exp": 1_900_000_000,
+            "iat": 1_600_000_000,
+            "sub": "248289761001",
+            "preferred_username": "jdoe",
+            "email": "jdoe@example.com",
+        });
+        for (key, value) in extra.as_object().unwrap() {
+            claims[key] = value.clone();
+        }
+        serde_json::from_value(claims).unwrap()
+    }
+
+    fn settings(viewer_values: &[&str]) -> OidcSettings {
+        let values = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let mut config = Config::default();
+        config
+            .set("oidc.issuer_url", "https://idp.example.com")
+            .unwrap();
+        config.set("oidc.client_id", "hmi").unwrap();
+        config
+            .set("oidc.redirect_url", "https://hmi.example.com/oidc/callback")
+            .unwrap();
+        config
+            .set("oidc.admin_values", values(&["hmi-admin"]))
+            .unwrap();
+        config
+            .set("oidc.engineer_values", values(&["hmi-engineer"]))
+            .unwrap();
+        config
+            .set("oidc.viewer_values", values(viewer_values))
+            .unwrap();
+        OidcSettings::from_config(&config).unwrap()
+    }
+
+    fn role(settings: &OidcSettings, values: &[&str]) -> Option<Role> {
+        let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        settings.role_from_claim(&values)
+    }
+
+    #[test]
+    fn usernames_from_standard_and_custom_claims() {
+        let claims = claims(json!({
+            "upn": "jdoe@corp.example.com",
+            "account": { "login": "JD" },
+            "count": 3,
+        }));
+        assert_eq!(
+            claim_username(&claims, "preferred_username").as_deref(),
+            Some("jdoe")
+        );
+        assert_eq!(
+            claim_username(&claims, "email").as_deref(),
+            Some("jdoe@example.com")
+        );
+        assert_eq!(
+            claim_username(&claims, "sub").as_deref(),
+            Some("248289761001")
+        );
+        assert_eq!(
+            claim_username(&claims, "upn").as_deref(),
+            Some("jdoe@corp.example.com")
+        );
+        assert_eq!(
+            claim_username(&claims, "account.login").as_deref(),
+            Some("JD")
+        );
+        assert_eq!(claim_username(&claims, "count"), None);
+        assert_eq!(claim_username(&claims, "missing"), None);
+    }
+
+    #[test]
+    fn claim_values_of_strings_arrays_and_nested_paths() {
+        let claims = claims(json!({
+            "groups": ["hmi-viewer", "staff", 7],
+            "role": "hmi-engineer",
+            "realm_access": { "roles": ["hmi-admin"], "level": { "name": "top" } },
+            "flag": true,
+        }));
+        assert_eq!(claim_values(&claims, "groups"), vec!["hmi-viewer", "staff"]);
+        assert_eq!(claim_values(&claims, "role"), vec!["hmi-engineer"]);
+        assert_eq!(
+            claim_values(&claims, "realm_access.roles"),
+            vec!["hmi-admin"]
+        );
+        assert_eq!(
+            claim_values(&claims, "realm_access.level.name"),
+            vec!["top"]
+        );
+        assert!(claim_values(&claims, "realm_access").is_empty());
+        assert!(claim_values(&claims, "realm_access.missing").is_empty());
+        assert!(claim_values(&claims, "role.name").is_empty());
+        assert!(claim_values(&claims, "flag").is_empty());
+        assert!(claim_values(&claims, "missing").is_empty());
+    }
+
+    #[test]
+    fn most_privileged_claim_value_wins() {
+        let settings = settings(&["hmi-viewer"]);
+        assert!(matches!(
+            role(&settings, &["hmi-viewer", "hmi-engineer", "hmi-admin"]),
+            Some(Role::Admin)
+        ));
+        assert!(matches!(
+            role(&settings, &["hmi-viewer", "hmi-engineer"]),
+            Some(Role::Engineer)
+        ));
+        assert!(matches!(
+            role(&settings, &["staff", "hmi-viewer"]),
+            Some(Role::Viewer)
+        ));
+        // Values are matched exactly
+        assert!(role(&settings, &["HMI-Admin"]).is_none());
+        assert!(role(&settings, &["staff"]).is_none());
+        assert!(role(&settings, &[]).is_none());
+    }
+
+    #[test]
+    fn without_viewer_values_everyone_is_a_viewer() {
+        let settings = settings(&[]);
+        assert!(matches!(role(&settings, &[]), Some(Role::Viewer)));
+        assert!(matches!(role(&settings, &["staff"]), Some(Role::Viewer)));
+        assert!(matches!(role(&settings, &["hmi-admin"]), Some(Role::Admin)));
+    }
+
+    #[test]
+    fn role_claim_of_nested_claims() {
+        let settings = settings(&["hmi-viewer"]);
+        let claims = claims(json!({ "realm_access": { "roles": ["offline", "hmi-engineer"] } }));
+        let values = claim_values(&claims, "realm_access.roles");
+        assert!(matches!(
+            settings.role_from_claim(&values),
+            Some(Role::Engineer)
+        ));
+    }
+}
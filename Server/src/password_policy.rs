@@ -0,0 +1,211 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::Error;
+use config::Config;
+use lazy_static::lazy_static;
+use pwhash::bcrypt;
+
+lazy_static! {
+    static ref POLICY: PasswordPolicy = PasswordPolicy::from_config(&riker::load_config());
+}
+
+const DEFAULT_MIN_LENGTH: i64 = 8;
+const DEFAULT_HISTORY: i64 = 5;
+
+/// Rules a new password has to follow, from the `[password_policy]` configuration
+struct PasswordPolicy {
+    min_length: usize,
+    require_uppercase: bool,
+    require_lowercase: bool,
+    require_digit: bool,
+    require_symbol: bool,
+    history: usize,
+}
+
+impl PasswordPolicy {
+    fn from_config(config: &Config) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: config
+                .get_int("password_policy.min_length")
+                .unwrap_or(DEFAULT_MIN_LENGTH)
+                .max(1) as usize,
+            require_uppercase: config
+                .get_bool("password_policy.require_uppercase")
+                .unwrap_or(true),
+            require_lowercase: config
+                .get_bool("password_policy.require_lowercase")
+                .unwrap_or(true),
+            require_digit: config
+                .get_bool("password_policy.require_digit")
+                .unwrap_or(true),
+            require_symbol: config
+                .get_bool("password_policy.require_symbol")
+                .unwrap_or(false),
+            history: config
+                .get_int("password_policy.history")
+                .unwrap_or(DEFAULT_HISTORY)
+                .max(0) as usize,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    fn check(&self, password: &str, previous: &[String]) -> Result<(), Error> {
+        if password.chars().count() < self.min_length {
+            return Err(Error::PasswordPolicyError(format!(
+                "password must be at least {} characters long",
+                self.min_length
+            )));
+        }
+        if self.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+            return Err(Error::PasswordPolicyError(
+                "password must contain an uppercase letter".to_string(),
+            ));
+        }
+        if self.require_lowercase && !password.chars().any(|c| c.is_lowercase()) {
+            return Err(Error::PasswordPolicyError(
+                "password must contain a lowercase letter".to_string(),
+            ));
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            return Err(Error::PasswordPolicyError(
+                "password must contain a digit".to_string(),
+            ));
+        }
+        if self.require_symbol && password.chars().all(|c| c.is_alphanumeric()) {
+            return Err(Error::PasswordPolicyError(
+                "password must contain a symbol".to_string(),
+            ));
+        }
+
+        let reused = previous
+            .iter()
+            .take(self.history + 1)
+            .any(|hash| !hash.is_empty() && bcrypt::verify(password, hash));
+        if reused {
+            return Err(Error::PasswordPolicyError(
+                "password was used recently".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn remember(&self, history: &mut Vec<String>, replaced_hash: String) {
+        if !replaced_hash.is_empty() {
+            history.insert(0, replaced_hash);
+        }
+        history.truncate(self.history);
+    }
+}
+
+/// Checks a new password against the policy.
+///
+/// `previous` holds the hashes of the current and earlier passwords of the user, the new
+/// password must not match any of them.
+pub fn check_password(password: &str, previous: &[String]) -> Result<(), Error> {
+    POLICY.check(password, previous)
+}
+
+/// Adds the replaced password hash to the front of the history, keeping as many entries
+/// as the policy remembers
+pub fn remember_password(history: &mut Vec<String>, replaced_hash: String) {
+    POLICY.remember(history, replaced_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pwhash::bcrypt::{BcryptSetup, BcryptVariant};
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy::from_config(&Config::default())
+    }
+
+    /// Lowest cost, the tests only need valid hashes
+    fn hash(password: &str) -> String {
+        bcrypt::hash_with(
+            BcryptSetup {
+                cost: Some(4),
+                variant: Some(BcryptVariant::V2b),
+                salt: None,
+            },
+            password,
+        )
+        .unwrap()
+    }
+
+    fn message(result: Result<(), Error>) -> String {
+        match result {
+            Err(Error::PasswordPolicyError(message)) => message,
+            other => panic!("unexpected {:?}", other.map_err(|e| e.to_string())),
+        }
+    }
+
+    #[test]
+    fn default_policy() {
+        let policy = policy();
+        assert!(policy.check("Passw0rd", &[]).is_ok());
+        assert!(message(policy.check("Pass0rd", &[])).contains("8 characters"));
+        assert!(message(policy.check("password0", &[])).contains("uppercase"));
+        assert!(message(policy.check("PASSWORD0", &[])).contains("lowercase"));
+        assert!(message(policy.check("Password", &[])).contains("digit"));
+    }
+
+    #[test]
+    fn length_counts_characters_not_bytes() {
+        let policy = PasswordPolicy {
+            require_digit: false,
+            ..policy()
+        };
+        assert!(policy.check("Ääääääää", &[]).is_ok());
+        assert!(policy.check("Äääääää", &[]).is_err());
+    }
+
+    #[test]
+    fn symbol_when_required() {
+        let policy = PasswordPolicy {
+            require_symbol: true,
+            ..policy()
+        };
+        assert!(message(policy.check("Passw0rd", &[])).contains("symbol"));
+        assert!(policy.check("Passw0rd!", &[]).is_ok());
+    }
+
+    #[test]
+    fn recent_passwords_are_refused() {
+        let policy = PasswordPolicy {
+            history: 1,
+            ..policy()
+        };
+        let previous = vec![hash("Current1"), hash("Earlier1"), hash("Oldest11")];
+        assert!(message(policy.check("Current1", &previous)).contains("used recently"));
+        assert!(message(policy.check("Earlier1", &previous)).contains("used recently"));
+        // Beyond the remembered history
+        assert!(policy.check("Oldest11", &previous).is_ok());
+        // External users and old entries without a hash
+        assert!(policy.check("Current1", &[String::new()]).is_ok());
+    }
+
+    #[test]
+    fn history_keeps_the_newest_hashes() {
+        let policy = PasswordPolicy {
+            history: 2,
+            ..policy()
+        };
+        let mut history = vec![];
+        for hash in ["a", "b", "", "c"] {
+            policy.remember(&mut history, hash.to_string());
+        }
+        assert_eq!(history, vec!["c", "b"]);
+
+        let policy = PasswordPolicy {
+            history: 0,
+            ..policy
+        };
+        policy.remember(&mut history, "d".to_string());
+        assert!(history.is_empty());
+    }
+}
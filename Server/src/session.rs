@@ -0,0 +1,254 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::Error;
+use crate::storage::storage;
+use chrono::prelude::*;
+use config::Config;
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use subtle::ConstantTimeEq;
+
+lazy_static! {
+    static ref SETTINGS: SessionSettings = SessionSettings::from_config(&riker::load_config());
+    /// Sessions of the database, every change is written through to it outside the lock
+    static ref SESSIONS: RwLock<HashMap<String, Session>> = RwLock::new(load_sessions());
+}
+
+const DEFAULT_ACCESS_TOKEN_TTL: i64 = 15; // minutes
+const DEFAULT_REFRESH_TOKEN_TTL: i64 = 7 * 24 * 60; // minutes
+
+struct SessionSettings {
+    access_token_ttl: chrono::Duration,
+    refresh_token_ttl: chrono::Duration,
+}
+
+impl SessionSettings {
+    fn from_config(config: &Config) -> SessionSettings {
+        SessionSettings {
+            access_token_ttl: chrono::Duration::minutes(
+                config
+                    .get_int("auth.access_token_ttl")
+                    .unwrap_or(DEFAULT_ACCESS_TOKEN_TTL),
+            ),
+            refresh_token_ttl: chrono::Duration::minutes(
+                config
+                    .get_int("auth.refresh_token_ttl")
+                    .unwrap_or(DEFAULT_REFRESH_TOKEN_TTL),
+            ),
+        }
+    }
+}
+
+/// A login session.
+///
+/// Access tokens carry the id of the session they were issued for and are only accepted
+/// while that session exists, so ending a session revokes every token issued for it.
+/// The refresh token is handed out as `<session id>.<secret>`, only a SHA-256 hash of the
+/// secret is kept on the server.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    refresh_hash: String,
+    pub created: i64,
+    pub expires: i64,
+}
+
+impl Session {
+    fn is_expired(&self) -> bool {
+        self.expires <= Utc::now().timestamp()
+    }
+}
+
+/// Lifetime of an access token issued for a session
+pub fn access_token_ttl() -> chrono::Duration {
+    SETTINGS.access_token_ttl
+}
+
+/// Starts a new session for the user, returns the session and its refresh token
+pub fn create_session(user_id: &str) -> Result<(Session, String), Error> {
+    let now = Utc::now();
+    let secret = new_secret();
+
+    let session = Session {
+        id: uuid::Uuid::new_v4().to_string(),
+        user_id: user_id.to_string(),
+        refresh_hash: hash_secret(&secret),
+        created: now.timestamp(),
+        expires: (now + SETTINGS.refresh_token_ttl).timestamp(),
+    };
+
+    let refresh_token = format!("{}.{}", session.id, secret);
+
+    storage().save_session(&session)?;
+    let expired: Vec<String> = {
+        let mut sessions = SESSIONS.write().unwrap();
+        let expired = sessions
+            .values()
+            .filter(|s| s.is_expired())
+            .map(|s| s.id.clone())
+            .collect();
+        sessions.retain(|_, s| !s.is_expired());
+        sessions.insert(session.id.clone(), session.clone());
+        expired
+    };
+    delete_sessions(&expired);
+
+    Ok((session, refresh_token))
+}
+
+/// Exchanges a refresh token for a new one, extending the session.
+///
+/// The presented refresh token can't be used again afterwards.
+pub fn refresh_session(refresh_token: &str) -> Result<(Session, String), Error> {
+    let (session_id, secret) = split_refresh_token(refresh_token)?;
+    let presented_hash = hash_secret(secret);
+    let secret = new_secret();
+    let refresh_hash = hash_secret(&secret);
+
+    // The token is checked and replaced under the lock, so it can only be used once
+    let session = {
+        let mut sessions = SESSIONS.write().unwrap();
+        let session = match sessions.get_mut(session_id) {
+            Some(s) if !s.is_expired() && hash_matches(&presented_hash, &s.refresh_hash) => s,
+            _ => return Err(Error::InvalidRefreshTokenError),
+        };
+        session.refresh_hash = refresh_hash;
+        session.expires = (Utc::now() + SETTINGS.refresh_token_ttl).timestamp();
+        session.clone()
+    };
+    // The session has changed already, a failed write only loses it on a restart
+    if let Err(e) = storage().save_session(&session) {
+        error!("Unable to save session {}: {}", session.id, e);
+    }
+
+    let refresh_token = format!("{}.{}", session.id, secret);
+    Ok((session, refresh_token))
+}
+
+/// Ends the session the refresh token belongs to
+pub fn end_session(refresh_token: &str) -> Result<(), Error> {
+    let (session_id, secret) = split_refresh_token(refresh_token)?;
+    let presented_hash = hash_secret(secret);
+
+    {
+        let mut sessions = SESSIONS.write().unwrap();
+        match sessions.get(session_id) {
+            Some(s) if hash_matches(&presented_hash, &s.refresh_hash) => {
+                info!("Session {} of user {} ended", s.id, s.user_id);
+                sessions.remove(session_id);
+            }
+            _ => return Err(Error::InvalidRefreshTokenError),
+        }
+    }
+    delete_sessions(&[session_id.to_string()]);
+    Ok(())
+}
+
+/// Ends every session of the user, revoking all of the user's tokens
+pub fn end_user_sessions(user_id: &str) {
+    let ended: Vec<String> = {
+        let mut sessions = SESSIONS.write().unwrap();
+        let ended = sessions
+            .values()
+            .filter(|s| s.user_id == user_id)
+            .map(|s| s.id.clone())
+            .collect();
+        sessions.retain(|_, s| s.user_id != user_id);
+        ended
+    };
+    if !ended.is_empty() {
+        info!("Revoked {} session(s) of user {}", ended.len(), user_id);
+        delete_sessions(&ended);
+    }
+}
+
+pub fn is_session_active(session_id: &str) -> bool {
+    match SESSIONS.read().unwrap().get(session_id) {
+        Some(s) => !s.is_expired(),
+        None => false,
+    }
+}
+
+fn split_refresh_token(refresh_token: &str) -> Result<(&str, &str), Error> {
+    let mut token = refresh_token.splitn(2, '.');
+    match (token.next(), token.next()) {
+        (Some(id), Some(secret)) if !id.is_empty() && !secret.is_empty() => Ok((id, secret)),
+        _ => Err(Error::InvalidRefreshTokenError),
+    }
+}
+
+fn new_secret() -> String {
+    (0..2)
+        .map(|_| uuid::Uuid::new_v4().simple().to_string())
+        .collect()
+}
+
+/// The secret is random, a fast hash is enough to keep it from being read from the file
+fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// Compares in constant time, so the time taken doesn't tell how much of the hash matched
+fn hash_matches(presented: &str, stored: &str) -> bool {
+    presented.as_bytes().ct_eq(stored.as_bytes()).into()
+}
+
+fn load_sessions() -> HashMap<String, Session> {
+    match storage().sessions() {
+        Ok(sessions) => sessions
+            .into_iter()
+            .filter(|s| !s.is_expired())
+            .map(|s| (s.id.clone(), s))
+            .collect(),
+        Err(e) => {
+            error!("Unable to read sessions: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Removes the sessions from the database, they are already gone from memory. A session
+/// left behind by a failed write is removed once it has expired.
+fn delete_sessions(ids: &[String]) {
+    if ids.is_empty() {
+        return;
+    }
+    if let Err(e) = storage().delete_sessions(ids) {
+        error!("Unable to remove sessions: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_token_needs_id_and_secret() {
+        assert_eq!(split_refresh_token("id.secret").unwrap(), ("id", "secret"));
+        assert_eq!(split_refresh_token("id.se.cret").unwrap(), ("id", "se.cret"));
+        assert!(split_refresh_token("id").is_err());
+        assert!(split_refresh_token(".secret").is_err());
+        assert!(split_refresh_token("id.").is_err());
+    }
+
+    #[test]
+    fn hash_matches_only_the_same_secret() {
+        let hash = hash_secret("secret");
+        assert!(hash_matches(&hash_secret("secret"), &hash));
+        assert!(!hash_matches(&hash_secret("Secret"), &hash));
+    }
+
+    #[test]
+    fn a_different_hash_does_not_match() {
+        let hash = hash_secret("secret");
+        assert!(!hash_matches(&hash, "$2y$10$abcdefghijklmnopqrstuv"));
+        assert!(!hash_matches(&hash, ""));
+    }
+}
@@ -0,0 +1,397 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::sqlite::next_revision;
+use super::SqliteStorage;
+use crate::api_key::ApiKey;
+use crate::audit::AuditEntry;
+use crate::auth::User;
+use crate::diagram_id::{is_safe_diagram_id, new_diagram_id};
+use crate::error::Error;
+use crate::handler::{Diagram, Equipment};
+use crate::session::Session;
+use chrono::prelude::*;
+use log::{error, info};
+use rusqlite::{params, OptionalExtension};
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::Path;
+
+/// Setting recording when the JSON files were imported
+const IMPORTED_SETTING: &str = "json_import";
+
+/// Setting recording when `sessions.json` was imported
+const SESSIONS_IMPORTED_SETTING: &str = "json_import_sessions";
+
+/// Setting recording when `api_keys.json` was imported
+const API_KEYS_IMPORTED_SETTING: &str = "json_import_api_keys";
+
+/// Setting recording when `audit.log` was imported
+const AUDIT_IMPORTED_SETTING: &str = "json_import_audit_log";
+
+/// Copies `users.json`, `equipment.json` and the files of the `diagrams` folder written by
+/// earlier versions of the server into the database.
+///
+/// The import runs once, in a single transaction, and leaves the files in place.
+/// Records already in the database are kept.
+pub(super) fn import_json_files(storage: &SqliteStorage) -> Result<(), Error> {
+    storage.transaction(|tx| {
+        let imported: Option<String> = tx
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                [IMPORTED_SETTING],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if imported.is_some() {
+            return Ok(());
+        }
+
+        let users: Vec<User> = read_file(&get_user_file())?.unwrap_or_default();
+        for user in users.iter() {
+            tx.execute(
+                "INSERT OR IGNORE INTO users (id, username, data) VALUES (?1, ?2, ?3)",
+                params![user.id, user.username, serde_json::to_string(user)?],
+            )?;
+        }
+
+        let equipment: Vec<Equipment> = read_file(&get_equipment_file())?.unwrap_or_default();
+        for eq in equipment.iter() {
+            tx.execute(
+                "INSERT OR IGNORE INTO equipment (mrid, data) VALUES (?1, ?2)",
+                params![eq.mrid, serde_json::to_string(eq)?],
+            )?;
+        }
+
+        let mut diagrams = read_diagrams(&get_diagram_folder())?;
+        for diagram in diagrams.iter_mut() {
+            // Files named after ids that aren't safe are imported with a new id, links to
+            // the old id still open the diagram
+            if !is_safe_diagram_id(&diagram.diagramId) {
+                let new_id = new_diagram_id();
+                info!(
+                    "Diagram {:?} imported as {} for its id not being safe",
+                    diagram.diagramId, new_id
+                );
+                tx.execute(
+                    "INSERT OR IGNORE INTO diagram_id_renames (old_id, new_id) VALUES (?1, ?2)",
+                    params![diagram.diagramId, new_id],
+                )?;
+                diagram.diagramId = new_id;
+            }
+            let data = serde_json::to_string(diagram)?;
+            let inserted = tx.execute(
+                "INSERT OR IGNORE INTO diagrams (id, name, data) VALUES (?1, ?2, ?3)",
+                params![diagram.diagramId, diagram.name, data],
+            )?;
+            // The imported file is the first revision of the diagram
+            if inserted > 0 {
+                tx.execute(
+                    "INSERT INTO diagram_revisions
+                        (diagram_id, revision, author, author_name, timestamp, data)
+                     VALUES (?1, ?2, '', '', ?3, ?4)",
+                    params![
+                        diagram.diagramId,
+                        next_revision(tx, &diagram.diagramId)?,
+                        Utc::now().timestamp(),
+                        data
+                    ],
+                )?;
+            }
+        }
+
+        tx.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)",
+            params![IMPORTED_SETTING, Utc::now().to_rfc3339()],
+        )?;
+        info!(
+            "Imported {} users, {} equipment and {} diagrams from JSON files",
+            users.len(),
+            equipment.len(),
+            diagrams.len()
+        );
+        Ok(())
+    })
+}
+
+/// Copies the sessions of `sessions.json`, so that users stay logged in when the server
+/// switches to the database. Runs once, a file that can't be read is left out.
+pub(super) fn import_session_file(storage: &SqliteStorage) -> Result<(), Error> {
+    storage.transaction(|tx| {
+        let imported: Option<String> = tx
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                [SESSIONS_IMPORTED_SETTING],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if imported.is_some() {
+            return Ok(());
+        }
+
+        let sessions: Vec<Session> = read_file(&get_session_file()).unwrap_or_else(|e| {
+            error!("Sessions not imported: {}", e);
+            None
+        })
+        .unwrap_or_default();
+        let now = Utc::now().timestamp();
+        let mut count = 0;
+        for session in sessions.iter().filter(|s| s.expires > now) {
+            count += tx.execute(
+                "INSERT OR IGNORE INTO sessions (id, user_id, expires, data) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    session.id,
+                    session.user_id,
+                    session.expires,
+                    serde_json::to_string(session)?
+                ],
+            )?;
+        }
+
+        tx.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)",
+            params![SESSIONS_IMPORTED_SETTING, Utc::now().to_rfc3339()],
+        )?;
+        if count > 0 {
+            info!("Imported {} sessions from {}", count, get_session_file());
+        }
+        Ok(())
+    })
+}
+
+/// Copies the keys of `api_keys.json`, so that scripts keep working when the server
+/// switches to the database. Runs once and leaves the file in place.
+pub(super) fn import_api_key_file(storage: &SqliteStorage) -> Result<(), Error> {
+    storage.transaction(|tx| {
+        let imported: Option<String> = tx
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                [API_KEYS_IMPORTED_SETTING],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if imported.is_some() {
+            return Ok(());
+        }
+
+        let keys: Vec<ApiKey> = read_file(&get_api_key_file())?.unwrap_or_default();
+        let mut count = 0;
+        for key in keys.iter() {
+            count += tx.execute(
+                "INSERT OR IGNORE INTO api_keys (id, created, data) VALUES (?1, ?2, ?3)",
+                params![key.id, key.created, serde_json::to_string(key)?],
+            )?;
+        }
+
+        tx.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)",
+            params![API_KEYS_IMPORTED_SETTING, Utc::now().to_rfc3339()],
+        )?;
+        if count > 0 {
+            info!("Imported {} API keys from {}", count, get_api_key_file());
+        }
+        Ok(())
+    })
+}
+
+/// Copies the entries of `audit.log`, the journal kept before the database. Runs once and
+/// leaves the file in place, lines that can't be read are logged and left out.
+pub(super) fn import_audit_file(storage: &SqliteStorage) -> Result<(), Error> {
+    storage.transaction(|tx| {
+        let imported: Option<String> = tx
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                [AUDIT_IMPORTED_SETTING],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if imported.is_some() {
+            return Ok(());
+        }
+
+        let file_path = get_audit_file();
+        let contents = match fs::read_to_string(&file_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                error!("Audit log not imported: {} [{}]", file_path, e);
+                String::new()
+            }
+        };
+        let mut count = 0;
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let entry = match serde_json::from_str::<AuditEntry>(line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    error!("Unable to import audit entry of {}: {}", file_path, e);
+                    continue;
+                }
+            };
+            count += tx.execute(
+                "INSERT OR IGNORE INTO audit_log (id, timestamp, actor, action, target, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    entry.id,
+                    entry.timestamp,
+                    entry.actor,
+                    entry.action,
+                    entry.target,
+                    serde_json::to_string(&entry)?
+                ],
+            )?;
+        }
+
+        tx.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)",
+            params![AUDIT_IMPORTED_SETTING, Utc::now().to_rfc3339()],
+        )?;
+        if count > 0 {
+            info!("Imported {} audit entries from {}", count, file_path);
+        }
+        Ok(())
+    })
+}
+
+/// The parsed file, `None` when there is none. A file that can't be parsed stops the
+/// import, rather than starting the server without its records.
+fn read_file<T: DeserializeOwned>(file_path: &str) -> Result<Option<T>, Error> {
+    if !Path::new(file_path).exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(file_path)?;
+    let value = serde_json::from_str(&contents).map_err(|e| {
+        error!(
+            "Unable to import {}, fix or remove the file: {}",
+            file_path, e
+        );
+        Error::from(e)
+    })?;
+    Ok(Some(value))
+}
+
+/// Diagrams of the folder, files that can't be parsed are left out as they were before.
+/// Only files that are in the folder itself are read, links leading out of it are skipped.
+fn read_diagrams(folder: &str) -> Result<Vec<Diagram>, Error> {
+    let mut diagrams: Vec<Diagram> = vec![];
+    if !Path::new(folder).is_dir() {
+        return Ok(diagrams);
+    }
+    let folder = fs::canonicalize(folder)?;
+    // A file that can't be read is left out, it must not keep the server from starting
+    for entry in fs::read_dir(&folder)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                error!("Unable to list diagram files of {:?}: {}", folder, e);
+                continue;
+            }
+        };
+        let path = match fs::canonicalize(entry.path()) {
+            Ok(path) => path,
+            Err(e) => {
+                error!("Unable to import diagram file: {:?} [{}]", entry.path(), e);
+                continue;
+            }
+        };
+        if path.parent() != Some(folder.as_path()) {
+            error!(
+                "Diagram file outside of {:?} not imported: {:?}",
+                folder, path
+            );
+            continue;
+        }
+        if path.is_dir() {
+            continue;
+        }
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                error!("Unable to import diagram file: {:?} [{}]", path, e);
+                continue;
+            }
+        };
+        match serde_json::from_str::<Diagram>(&contents) {
+            Ok(diagram) if !diagram.diagramId.trim().is_empty() => diagrams.push(diagram),
+            Ok(_) => error!("Diagram file without diagram id not imported: {:?}", path),
+            Err(e) => error!("Unable to import diagram file: {:?} [{}]", path, e),
+        }
+    }
+    Ok(diagrams)
+}
+
+fn get_session_file() -> String {
+    let app_dir = std::env::var("APP_DIR_NAME").unwrap_or_else(|_| "".into());
+    if app_dir != "" {
+        return format!("/{}/sessions.json", app_dir);
+    }
+    "sessions.json".to_string()
+}
+
+fn get_api_key_file() -> String {
+    let app_dir = std::env::var("APP_DIR_NAME").unwrap_or_else(|_| "".into());
+    if app_dir != "" {
+        return format!("/{}/api_keys.json", app_dir);
+    }
+    "api_keys.json".to_string()
+}
+
+fn get_audit_file() -> String {
+    let app_dir = std::env::var("APP_DIR_NAME").unwrap_or_else(|_| "".into());
+    if app_dir != "" {
+        return format!("/{}/audit.log", app_dir);
+    }
+    "audit.log".to_string()
+}
+
+fn get_user_file() -> String {
+    let app_dir = std::env::var("APP_DIR_NAME").unwrap_or_else(|_| "".into());
+    if app_dir != "" {
+        return format!("/{}/users.json", app_dir);
+    }
+    "users.json".to_string()
+}
+
+fn get_equipment_file() -> String {
+    let app_dir = std::env::var("APP_DIR_NAME").unwrap_or_else(|_| "".into());
+    if app_dir != "" {
+        return format!("/{}/equipment.json", app_dir);
+    }
+    "equipment.json".to_string()
+}
+
+fn get_diagram_folder() -> String {
+    let app_dir = std::env::var("APP_DIR_NAME").unwrap_or_else(|_| "".into());
+    if app_dir != "" {
+        return format!("/{}/diagrams", app_dir);
+    }
+    "diagrams".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreadable_diagram_files_are_skipped() {
+        let folder = std::env::temp_dir().join(format!("hmi-diagrams-{}", new_diagram_id()));
+        fs::create_dir(&folder).unwrap();
+        fs::write(
+            folder.join("feeder.json"),
+            r#"{"diagramId": "feeder", "name": "Feeder"}"#,
+        )
+        .unwrap();
+        fs::write(folder.join("binary.json"), [0xff, 0xfe, 0x00]).unwrap();
+        fs::write(folder.join("broken.json"), "{").unwrap();
+        fs::write(folder.join("no-id.json"), r#"{"diagramId": " "}"#).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(folder.join("missing.json"), folder.join("dangling.json"))
+            .unwrap();
+
+        let diagrams = read_diagrams(folder.to_str().unwrap());
+        fs::remove_dir_all(&folder).unwrap();
+        let diagrams = diagrams.unwrap();
+        assert_eq!(diagrams.len(), 1);
+        assert_eq!(diagrams[0].diagramId, "feeder");
+    }
+}
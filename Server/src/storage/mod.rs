@@ -0,0 +1,304 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+mod import;
+mod sqlite;
+
+use crate::api_key::ApiKey;
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::auth::User;
+use crate::diagram_history::DiagramRevision;
+use crate::diagram_template::DiagramTemplate;
+use crate::error::Error;
+use crate::handler::{Diagram, DiagramSummary, Equipment};
+use crate::session::Session;
+use lazy_static::lazy_static;
+use log::info;
+use serde::Serialize;
+
+pub use sqlite::SqliteStorage;
+
+lazy_static! {
+    static ref STORAGE: Box<dyn Storage> = match open_storage() {
+        Ok(storage) => storage,
+        Err(e) => panic!("Unable to open the database: {}", e),
+    };
+}
+
+/// Users, equipment and diagrams of the HMI.
+///
+/// Every call is a transaction of its own, so concurrent requests changing different
+/// records don't overwrite each other.
+pub trait Storage: Send + Sync {
+    fn users(&self) -> Result<Vec<User>, Error>;
+
+    fn user(&self, id: &str) -> Result<Option<User>, Error>;
+
+    /// Usernames are compared ignoring case
+    fn user_by_username(&self, username: &str) -> Result<Option<User>, Error>;
+
+    /// Fails with `AddUserError` when the id or the username is taken
+    fn insert_user(&self, user: &User) -> Result<(), Error>;
+
+    /// Reads, changes and writes the user in one transaction, nothing is written when
+    /// `change` fails. Resolves to the changed user, `None` when there is no user with the id.
+    fn update_user(
+        &self,
+        id: &str,
+        change: &mut dyn FnMut(&mut User) -> Result<(), Error>,
+    ) -> Result<Option<User>, Error>;
+
+    /// Resolves to the removed user
+    fn delete_user(&self, id: &str) -> Result<Option<User>, Error>;
+
+    /// Equipment in the order it was added, with its current version
+    fn equipment(&self) -> Result<Vec<Equipment>, Error>;
+
+    /// Fails with `AddDeviceError` when the MRID is taken
+    fn insert_equipment(&self, equipment: &Equipment) -> Result<(), Error>;
+
+    /// Replaces the equipment with the same MRID when `precondition` holds. Resolves to the
+    /// replaced equipment and the new version, `None` when there is no equipment with the MRID.
+    fn update_equipment(
+        &self,
+        equipment: &Equipment,
+        precondition: Precondition,
+    ) -> Result<Option<(Equipment, i64)>, Error>;
+
+    /// Resolves to the removed equipment
+    fn delete_equipment(&self, mrid: &str) -> Result<Option<Equipment>, Error>;
+
+    /// Metadata of the diagrams by name, as of their latest revision or, with
+    /// `published_only`, of their published revision. Diagrams that can't be read are
+    /// listed with the error instead of failing the whole list.
+    fn diagram_summaries(&self, published_only: bool) -> Result<Vec<DiagramSummary>, Error>;
+
+    fn diagram(&self, id: &str) -> Result<Option<Diagram>, Error>;
+
+    /// Creates or replaces the diagram when `precondition` holds and keeps it as a new
+    /// revision. Resolves to the replaced diagram and the new revision, which is also the
+    /// new version of the diagram.
+    fn save_diagram(
+        &self,
+        diagram: &Diagram,
+        author: &str,
+        author_name: &str,
+        precondition: Precondition,
+    ) -> Result<(Option<Diagram>, DiagramRevision), Error>;
+
+    /// Resolves to the removed diagram. Its revisions are kept, with a new revision by the
+    /// author recording the deletion.
+    fn delete_diagram(
+        &self,
+        id: &str,
+        author: &str,
+        author_name: &str,
+    ) -> Result<Option<Diagram>, Error>;
+
+    /// Revisions of the diagram, newest first
+    fn diagram_revisions(&self, id: &str) -> Result<Vec<DiagramRevision>, Error>;
+
+    /// The diagram as it was saved in the revision
+    fn diagram_revision(
+        &self,
+        id: &str,
+        revision: i64,
+    ) -> Result<Option<(DiagramRevision, Diagram)>, Error>;
+
+    /// Makes the revision the one the HMI shows. Resolves to the revision published before.
+    fn publish_diagram(&self, id: &str, revision: i64) -> Result<Option<i64>, Error>;
+
+    fn published_diagram(&self, id: &str) -> Result<Option<Diagram>, Error>;
+
+    /// Templates by name
+    fn diagram_templates(&self) -> Result<Vec<DiagramTemplate>, Error>;
+
+    fn diagram_template(&self, id: &str) -> Result<Option<DiagramTemplate>, Error>;
+
+    /// Creates or replaces the template, resolves to the replaced template
+    fn save_diagram_template(
+        &self,
+        template: &DiagramTemplate,
+    ) -> Result<Option<DiagramTemplate>, Error>;
+
+    /// Resolves to the removed template
+    fn delete_diagram_template(&self, id: &str) -> Result<Option<DiagramTemplate>, Error>;
+
+    /// Id a diagram got when its id was replaced for not being safe
+    fn renamed_diagram_id(&self, old_id: &str) -> Result<Option<String>, Error>;
+
+    /// Login sessions, including expired ones that haven't been removed yet
+    fn sessions(&self) -> Result<Vec<Session>, Error>;
+
+    /// Creates or replaces the session
+    fn save_session(&self, session: &Session) -> Result<(), Error>;
+
+    fn delete_sessions(&self, ids: &[String]) -> Result<(), Error>;
+
+    /// API keys, oldest first
+    fn api_keys(&self) -> Result<Vec<ApiKey>, Error>;
+
+    /// Fails with `AddApiKeyError` when the id is taken
+    fn insert_api_key(&self, key: &ApiKey) -> Result<(), Error>;
+
+    /// Records when the key was last used, without rewriting the rest of the key
+    fn set_api_key_last_used(&self, id: &str, last_used: i64) -> Result<(), Error>;
+
+    /// Resolves to the removed key
+    fn delete_api_key(&self, id: &str) -> Result<Option<ApiKey>, Error>;
+
+    fn insert_audit_entry(&self, entry: &AuditEntry) -> Result<(), Error>;
+
+    /// Audit entries matching the query, newest first, with the number of matching entries.
+    /// `page` is the page number and page size, all matching entries are returned without it.
+    fn audit_entries(
+        &self,
+        query: &AuditQuery,
+        page: Option<(usize, usize)>,
+    ) -> Result<(usize, Vec<AuditEntry>), Error>;
+
+    /// Values the server keeps for itself, e.g. which data has been imported
+    fn setting(&self, key: &str) -> Result<Option<String>, Error>;
+
+    fn set_setting(&self, key: &str, value: &str) -> Result<(), Error>;
+}
+
+/// Condition a write of a versioned record has to meet, taken from the `If-Match` header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Precondition {
+    /// `If-Match: *`, the record is written whatever its version
+    Any,
+    /// No `If-Match` header, only records that don't exist yet are written
+    New,
+    /// The record is written when it still has the version
+    Version(i64),
+}
+
+impl Precondition {
+    /// Parses an `If-Match` header value, `"3"`, `W/"3"`, `3` and `*` are accepted
+    pub fn from_if_match(if_match: Option<&str>) -> Result<Precondition, Error> {
+        let value = match if_match {
+            Some(value) => value.trim(),
+            None => return Ok(Precondition::New),
+        };
+        if value == "*" {
+            return Ok(Precondition::Any);
+        }
+        let tag = value.strip_prefix("W/").unwrap_or(value).trim_matches('"');
+        tag.parse::<i64>()
+            .map(Precondition::Version)
+            .map_err(|_| Error::InvalidRequestError(format!("invalid If-Match header: {}", value)))
+    }
+
+    /// Fails with `PreconditionRequiredError` or `VersionConflictError` when a record with
+    /// the `current` version must not be written
+    pub fn check<T: Serialize>(&self, current: Option<(&T, i64)>) -> Result<(), Error> {
+        match (self, current) {
+            (Precondition::Any, _) | (Precondition::New, None) => Ok(()),
+            (Precondition::New, Some(_)) => Err(Error::PreconditionRequiredError),
+            (Precondition::Version(version), Some((_, current_version)))
+                if *version == current_version =>
+            {
+                Ok(())
+            }
+            (Precondition::Version(_), current) => Err(Error::VersionConflictError {
+                version: current.map(|(_, version)| version),
+                current: match current {
+                    Some((record, _)) => Some(serde_json::to_value(record)?),
+                    None => None,
+                },
+            }),
+        }
+    }
+}
+
+/// Entity tag of a record version, for the `ETag` header
+pub fn etag(version: i64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// The storage opened at startup
+pub fn storage() -> &'static dyn Storage {
+    STORAGE.as_ref()
+}
+
+/// Opens the database eagerly, so that a database that can't be opened, migrated or
+/// imported into stops the server at startup
+pub fn init_storage() {
+    lazy_static::initialize(&STORAGE);
+}
+
+fn open_storage() -> Result<Box<dyn Storage>, Error> {
+    let file_path = get_database_file();
+    info!("Opening database {}", file_path);
+    let storage = SqliteStorage::open(&file_path)?;
+    import::import_json_files(&storage)?;
+    import::import_session_file(&storage)?;
+    import::import_api_key_file(&storage)?;
+    import::import_audit_file(&storage)?;
+    Ok(Box::new(storage))
+}
+
+fn get_database_file() -> String {
+    let app_dir = std::env::var("APP_DIR_NAME").unwrap_or_else(|_| "".into());
+    if app_dir != "" {
+        return format!("/{}/hmi.db", app_dir);
+    }
+    "hmi.db".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn if_match_header_values() {
+        assert_eq!(
+            Precondition::from_if_match(None).unwrap(),
+            Precondition::New
+        );
+        assert_eq!(
+            Precondition::from_if_match(Some(" * ")).unwrap(),
+            Precondition::Any
+        );
+        for value in &["\"3\"", "W/\"3\"", "3"] {
+            assert_eq!(
+                Precondition::from_if_match(Some(value)).unwrap(),
+                Precondition::Version(3)
+            );
+        }
+        assert!(matches!(
+            Precondition::from_if_match(Some("\"abc\"")),
+            Err(Error::InvalidRequestError(_))
+        ));
+    }
+
+    #[test]
+    fn check_against_the_current_version() {
+        let record = "record";
+        assert!(Precondition::Any.check(Some((&record, 2))).is_ok());
+        assert!(Precondition::Any.check::<&str>(None).is_ok());
+        assert!(Precondition::New.check::<&str>(None).is_ok());
+        assert!(matches!(
+            Precondition::New.check(Some((&record, 2))),
+            Err(Error::PreconditionRequiredError)
+        ));
+        assert!(Precondition::Version(2).check(Some((&record, 2))).is_ok());
+
+        match Precondition::Version(1).check(Some((&record, 2))) {
+            Err(Error::VersionConflictError { version, current }) => {
+                assert_eq!(version, Some(2));
+                assert_eq!(current, Some(serde_json::json!("record")));
+            }
+            _ => panic!("stale version was accepted"),
+        }
+        assert!(matches!(
+            Precondition::Version(1).check::<&str>(None),
+            Err(Error::VersionConflictError {
+                version: None,
+                current: None
+            })
+        ));
+    }
+}
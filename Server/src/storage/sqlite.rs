@@ -0,0 +1,1394 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Precondition, Storage};
+use crate::api_key::ApiKey;
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::auth::User;
+use crate::diagram_history::DiagramRevision;
+use crate::diagram_template::DiagramTemplate;
+use crate::error::Error;
+use crate::handler::{Diagram, DiagramSummary, Equipment};
+use crate::session::Session;
+use chrono::prelude::*;
+use log::{error, info};
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row, Transaction};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Schema changes, applied in order. `PRAGMA user_version` holds the number of applied
+/// migrations; new migrations are only ever appended.
+const MIGRATIONS: &[&str] = &[
+    // 1: records are kept as JSON documents next to the columns they are looked up by
+    "CREATE TABLE users (
+        id TEXT PRIMARY KEY NOT NULL,
+        username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE equipment (
+        mrid TEXT PRIMARY KEY NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE diagrams (
+        id TEXT PRIMARY KEY NOT NULL,
+        name TEXT,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE settings (
+        key TEXT PRIMARY KEY NOT NULL,
+        value TEXT NOT NULL
+    );",
+    // 2: every save of a diagram is kept, existing diagrams become their first revision
+    "CREATE TABLE diagram_revisions (
+        diagram_id TEXT NOT NULL,
+        revision INTEGER NOT NULL,
+        author TEXT NOT NULL,
+        author_name TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (diagram_id, revision)
+    );
+    INSERT INTO diagram_revisions (diagram_id, revision, author, author_name, timestamp, data)
+        SELECT id, 1, '', '', CAST(strftime('%s', 'now') AS INTEGER), data FROM diagrams;",
+    // 3: equipment is versioned so that stale updates can be refused, diagrams use their
+    // latest revision as version
+    "ALTER TABLE equipment ADD COLUMN version INTEGER NOT NULL DEFAULT 1;",
+    // 4: the HMI shows the published revision of a diagram, diagrams shown so far are
+    // published as they are
+    "ALTER TABLE diagrams ADD COLUMN published_revision INTEGER;
+    UPDATE diagrams SET published_revision =
+        (SELECT MAX(revision) FROM diagram_revisions WHERE diagram_id = diagrams.id);",
+    // 5: diagram ids are limited to letters, digits, '-' and '_', diagrams with other ids
+    // get a new one. The old ids are kept so that links to them still open the diagram.
+    "CREATE TABLE diagram_id_renames (
+        old_id TEXT PRIMARY KEY NOT NULL,
+        new_id TEXT NOT NULL
+    );
+    INSERT INTO diagram_id_renames (old_id, new_id)
+        SELECT id, lower(hex(randomblob(16))) FROM diagrams
+        WHERE id = '' OR length(id) > 64 OR id GLOB '*[^A-Za-z0-9_-]*';
+    UPDATE diagram_revisions SET
+        diagram_id = (SELECT new_id FROM diagram_id_renames WHERE old_id = diagram_id),
+        data = CASE WHEN json_valid(data) THEN json_set(data, '$.diagramId',
+            (SELECT new_id FROM diagram_id_renames WHERE old_id = diagram_id)) ELSE data END
+        WHERE diagram_id IN (SELECT old_id FROM diagram_id_renames);
+    UPDATE diagrams SET
+        id = (SELECT new_id FROM diagram_id_renames WHERE old_id = id),
+        data = CASE WHEN json_valid(data) THEN json_set(data, '$.diagramId',
+            (SELECT new_id FROM diagram_id_renames WHERE old_id = id)) ELSE data END
+        WHERE id IN (SELECT old_id FROM diagram_id_renames);",
+    // 6: diagrams saved as templates, with placeholders in place of their mRIDs
+    "CREATE TABLE diagram_templates (
+        id TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL,
+        data TEXT NOT NULL
+    );",
+    // 7: deleting a diagram keeps its revisions and adds one recording the deletion
+    "ALTER TABLE diagram_revisions ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;",
+    // 8: login sessions, kept so that a restart doesn't log everyone out
+    "CREATE TABLE sessions (
+        id TEXT PRIMARY KEY NOT NULL,
+        user_id TEXT NOT NULL,
+        expires INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );",
+    // 9: API keys, with the hash of their secret
+    "CREATE TABLE api_keys (
+        id TEXT PRIMARY KEY NOT NULL,
+        created INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );",
+    // 10: the audit log, looked up by time, actor and target
+    "CREATE TABLE audit_log (
+        id TEXT PRIMARY KEY NOT NULL,
+        timestamp INTEGER NOT NULL,
+        actor TEXT NOT NULL,
+        action TEXT NOT NULL,
+        target TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX audit_log_timestamp ON audit_log (timestamp);
+    CREATE INDEX audit_log_actor ON audit_log (actor, timestamp);
+    CREATE INDEX audit_log_target ON audit_log (target, timestamp);",
+];
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::StorageError(e.to_string())
+    }
+}
+
+/// Storage in an SQLite database file
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /// Opens or creates the database and brings its schema up to date
+    pub fn open(file_path: &str) -> Result<SqliteStorage, Error> {
+        let mut connection = Connection::open(file_path)?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        // A crash can't leave a half written database. Requests share the one connection,
+        // reads wait for a write in progress; handlers reach it from the blocking pool.
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut connection)?;
+
+        Ok(SqliteStorage {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Runs `f` in a transaction, which is committed when `f` succeeds
+    pub(crate) fn transaction<T>(
+        &self,
+        f: impl FnOnce(&Transaction) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|_| Error::StorageError("database connection poisoned".to_string()))?;
+        let tx = connection.transaction()?;
+        let result = f(&tx)?;
+        tx.commit()?;
+        Ok(result)
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), Error> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(Error::StorageError(format!(
+            "database schema version {} is newer than this server",
+            version
+        )));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = connection.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        info!("Database migrated to schema version {}", i + 1);
+    }
+    Ok(())
+}
+
+fn to_data<T: Serialize>(value: &T) -> Result<String, Error> {
+    Ok(serde_json::to_string(value)?)
+}
+
+fn from_data<T: DeserializeOwned>(table: &str, data: String) -> Result<T, Error> {
+    serde_json::from_str(&data).map_err(|e| {
+        error!("Unable to parse record of {}: {}", table, e);
+        Error::from(e)
+    })
+}
+
+fn query_all<T: DeserializeOwned>(
+    tx: &Transaction,
+    table: &str,
+    sql: &str,
+) -> Result<Vec<T>, Error> {
+    let mut statement = tx.prepare(sql)?;
+    let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+    let mut records = vec![];
+    for data in rows {
+        records.push(from_data(table, data?)?);
+    }
+    Ok(records)
+}
+
+fn query_one<T: DeserializeOwned>(
+    tx: &Transaction,
+    table: &str,
+    sql: &str,
+    key: &str,
+) -> Result<Option<T>, Error> {
+    let data: Option<String> = tx.query_row(sql, [key], |row| row.get(0)).optional()?;
+    data.map(|data| from_data(table, data)).transpose()
+}
+
+/// Records read with `query_versioned` carry the version they were read at, the version
+/// is kept in its own column rather than in the stored data
+trait Versioned: Clone + Serialize + DeserializeOwned {
+    fn version(&self) -> Option<i64>;
+
+    fn set_version(&mut self, version: Option<i64>);
+
+    /// Records that can be published also carry their published version
+    fn set_published_version(&mut self, _version: Option<i64>) {}
+}
+
+impl Versioned for Equipment {
+    fn version(&self) -> Option<i64> {
+        self.version
+    }
+
+    fn set_version(&mut self, version: Option<i64>) {
+        self.version = version;
+    }
+}
+
+impl Versioned for Diagram {
+    fn version(&self) -> Option<i64> {
+        self.version
+    }
+
+    fn set_version(&mut self, version: Option<i64>) {
+        self.version = version;
+    }
+
+    fn set_published_version(&mut self, version: Option<i64>) {
+        self.published_version = version;
+    }
+}
+
+/// Records of a query selecting the data, the version and optionally the published version
+/// of each record
+fn query_versioned<T: Versioned>(
+    tx: &Transaction,
+    table: &str,
+    sql: &str,
+    key: Option<&str>,
+) -> Result<Vec<T>, Error> {
+    let mut statement = tx.prepare(sql)?;
+    let to_row = |row: &Row| {
+        let published = if row.as_ref().column_count() > 2 {
+            row.get::<_, Option<i64>>(2)?
+        } else {
+            None
+        };
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<i64>>(1)?,
+            published,
+        ))
+    };
+    let rows = match key {
+        Some(key) => statement
+            .query_map([key], to_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?,
+        None => statement
+            .query_map([], to_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?,
+    };
+    let mut records = vec![];
+    for (data, version, published) in rows {
+        let mut record: T = from_data(table, data)?;
+        record.set_version(version);
+        record.set_published_version(published);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Data of the record to store, without the version it was read at
+fn to_versioned_data<T: Versioned>(value: &T) -> Result<String, Error> {
+    let mut value = value.clone();
+    value.set_version(None);
+    value.set_published_version(None);
+    to_data(&value)
+}
+
+/// The record with the version it was read at, for `Precondition::check`
+fn with_version<T: Versioned>(record: &Option<T>) -> Option<(&T, i64)> {
+    record
+        .as_ref()
+        .map(|record| (record, record.version().unwrap_or_default()))
+}
+
+fn find_user(tx: &Transaction, id: &str) -> Result<Option<User>, Error> {
+    query_one(tx, "users", "SELECT data FROM users WHERE id = ?1", id)
+}
+
+fn find_equipment(tx: &Transaction, mrid: &str) -> Result<Option<Equipment>, Error> {
+    Ok(query_versioned(
+        tx,
+        "equipment",
+        "SELECT data, version FROM equipment WHERE mrid = ?1",
+        Some(mrid),
+    )?
+    .pop())
+}
+
+/// Selects the data of diagrams with their latest revision as version
+const SELECT_DIAGRAMS: &str = "SELECT data, (SELECT MAX(revision) FROM diagram_revisions
+    WHERE diagram_id = diagrams.id), published_revision FROM diagrams";
+
+/// Selects the published revisions of diagrams
+const SELECT_PUBLISHED_DIAGRAMS: &str = "SELECT r.data, r.revision, r.revision
+    FROM diagrams d JOIN diagram_revisions r
+        ON r.diagram_id = d.id AND r.revision = d.published_revision";
+
+/// Selects id, name, the fields the diagram list shows as a small JSON object, version,
+/// published revision and time of the latest revision of diagrams. Only the listed fields
+/// are extracted, the drawing isn't read out of the database.
+const SELECT_DIAGRAM_SUMMARIES: &str = "SELECT d.id, d.name,
+        CASE WHEN json_valid(d.data) THEN json_object(
+            'name', json_extract(d.data, '$.name'),
+            'description', json_extract(d.data, '$.description'),
+            'location', json_extract(d.data, '$.location'),
+            'createdDate', json_extract(d.data, '$.createdDate'),
+            'createdBy', json_extract(d.data, '$.createdBy')) END,
+        MAX(r.revision), d.published_revision, MAX(r.timestamp)
+    FROM diagrams d LEFT JOIN diagram_revisions r ON r.diagram_id = d.id
+    GROUP BY d.id ORDER BY d.name COLLATE NOCASE, d.id";
+
+/// Selects the same for the published revisions of diagrams
+const SELECT_PUBLISHED_SUMMARIES: &str = "SELECT d.id, d.name,
+        CASE WHEN json_valid(r.data) THEN json_object(
+            'name', json_extract(r.data, '$.name'),
+            'description', json_extract(r.data, '$.description'),
+            'location', json_extract(r.data, '$.location'),
+            'createdDate', json_extract(r.data, '$.createdDate'),
+            'createdBy', json_extract(r.data, '$.createdBy')) END,
+        r.revision, r.revision, r.timestamp
+    FROM diagrams d JOIN diagram_revisions r
+        ON r.diagram_id = d.id AND r.revision = d.published_revision
+    ORDER BY d.name COLLATE NOCASE, d.id";
+
+/// Fields of a stored diagram the diagram list shows
+#[allow(non_snake_case)]
+#[derive(Deserialize)]
+struct DiagramMetadata {
+    name: Option<String>,
+    description: Option<String>,
+    location: Option<String>,
+    createdDate: Option<String>,
+    createdBy: Option<String>,
+}
+
+fn to_summary(row: &Row) -> rusqlite::Result<DiagramSummary> {
+    let id: String = row.get(0)?;
+    let metadata = match row.get::<_, Option<String>>(2)? {
+        Some(metadata) => serde_json::from_str::<DiagramMetadata>(&metadata)
+            .map_err(|e| format!("the diagram can't be read: {}", e)),
+        None => Err("the diagram can't be read: its data is not JSON".to_string()),
+    };
+    let mut summary = match metadata {
+        Ok(metadata) => DiagramSummary {
+            diagramId: id,
+            name: metadata.name,
+            description: metadata.description,
+            location: metadata.location,
+            createdDate: metadata.createdDate,
+            createdBy: metadata.createdBy,
+            ..Default::default()
+        },
+        Err(e) => {
+            error!("Unable to parse diagram {}: {}", id, e);
+            DiagramSummary {
+                diagramId: id,
+                name: row.get(1)?,
+                error: Some(e),
+                ..Default::default()
+            }
+        }
+    };
+    summary.version = row.get(3)?;
+    summary.published_version = row.get(4)?;
+    summary.updated = row.get(5)?;
+    Ok(summary)
+}
+
+fn find_diagram(tx: &Transaction, id: &str) -> Result<Option<Diagram>, Error> {
+    Ok(query_versioned(
+        tx,
+        "diagrams",
+        &format!("{} WHERE id = ?1", SELECT_DIAGRAMS),
+        Some(id),
+    )?
+    .pop())
+}
+
+fn find_template(tx: &Transaction, id: &str) -> Result<Option<DiagramTemplate>, Error> {
+    query_one(
+        tx,
+        "diagram_templates",
+        "SELECT data FROM diagram_templates WHERE id = ?1",
+        id,
+    )
+}
+
+/// Number the next revision of the diagram gets
+pub(super) fn next_revision(tx: &Transaction, id: &str) -> Result<i64, Error> {
+    Ok(tx.query_row(
+        "SELECT COALESCE(MAX(revision), 0) + 1 FROM diagram_revisions WHERE diagram_id = ?1",
+        [id],
+        |row| row.get(0),
+    )?)
+}
+
+fn to_revision(row: &Row) -> rusqlite::Result<DiagramRevision> {
+    Ok(DiagramRevision {
+        diagram_id: row.get(0)?,
+        revision: row.get(1)?,
+        author: row.get(2)?,
+        author_name: row.get(3)?,
+        timestamp: row.get(4)?,
+        deleted: row.get(5)?,
+    })
+}
+
+impl Storage for SqliteStorage {
+    fn users(&self) -> Result<Vec<User>, Error> {
+        self.transaction(|tx| query_all(tx, "users", "SELECT data FROM users ORDER BY rowid"))
+    }
+
+    fn user(&self, id: &str) -> Result<Option<User>, Error> {
+        self.transaction(|tx| find_user(tx, id))
+    }
+
+    fn user_by_username(&self, username: &str) -> Result<Option<User>, Error> {
+        self.transaction(|tx| {
+            query_one(
+                tx,
+                "users",
+                "SELECT data FROM users WHERE username = ?1",
+                username,
+            )
+        })
+    }
+
+    fn insert_user(&self, user: &User) -> Result<(), Error> {
+        let data = to_data(user)?;
+        self.transaction(|tx| {
+            let taken: bool = tx.query_row(
+                "SELECT EXISTS(SELECT 1 FROM users WHERE id = ?1 COLLATE NOCASE OR username = ?2)",
+                params![user.id, user.username],
+                |row| row.get(0),
+            )?;
+            if taken {
+                error!(
+                    "User with same id/username ({}/{}) already exists",
+                    user.id, user.username
+                );
+                return Err(Error::AddUserError);
+            }
+            tx.execute(
+                "INSERT INTO users (id, username, data) VALUES (?1, ?2, ?3)",
+                params![user.id, user.username, data],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn update_user(
+        &self,
+        id: &str,
+        change: &mut dyn FnMut(&mut User) -> Result<(), Error>,
+    ) -> Result<Option<User>, Error> {
+        self.transaction(|tx| {
+            let mut user = match find_user(tx, id)? {
+                Some(user) => user,
+                None => return Ok(None),
+            };
+            change(&mut user)?;
+            // The id is the key of the record and can't be changed
+            user.id = id.to_string();
+            tx.execute(
+                "UPDATE users SET username = ?2, data = ?3 WHERE id = ?1",
+                params![id, user.username, to_data(&user)?],
+            )?;
+            Ok(Some(user))
+        })
+    }
+
+    fn delete_user(&self, id: &str) -> Result<Option<User>, Error> {
+        self.transaction(|tx| {
+            let user = find_user(tx, id)?;
+            tx.execute("DELETE FROM users WHERE id = ?1", [id])?;
+            Ok(user)
+        })
+    }
+
+    fn equipment(&self) -> Result<Vec<Equipment>, Error> {
+        self.transaction(|tx| {
+            query_versioned(
+                tx,
+                "equipment",
+                "SELECT data, version FROM equipment ORDER BY rowid",
+                None,
+            )
+        })
+    }
+
+    fn insert_equipment(&self, equipment: &Equipment) -> Result<(), Error> {
+        let data = to_versioned_data(equipment)?;
+        self.transaction(|tx| {
+            if find_equipment(tx, &equipment.mrid)?.is_some() {
+                error!(
+                    "Equipment with same MRID ({}/{}) already exists",
+                    equipment.mrid, equipment.name
+                );
+                return Err(Error::AddDeviceError);
+            }
+            tx.execute(
+                "INSERT INTO equipment (mrid, data) VALUES (?1, ?2)",
+                params![equipment.mrid, data],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn update_equipment(
+        &self,
+        equipment: &Equipment,
+        precondition: Precondition,
+    ) -> Result<Option<(Equipment, i64)>, Error> {
+        let data = to_versioned_data(equipment)?;
+        self.transaction(|tx| {
+            let before = find_equipment(tx, &equipment.mrid)?;
+            precondition.check(with_version(&before))?;
+            match before {
+                Some(before) => {
+                    let version = before.version.unwrap_or_default() + 1;
+                    tx.execute(
+                        "UPDATE equipment SET data = ?2, version = ?3 WHERE mrid = ?1",
+                        params![equipment.mrid, data, version],
+                    )?;
+                    Ok(Some((before, version)))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn delete_equipment(&self, mrid: &str) -> Result<Option<Equipment>, Error> {
+        self.transaction(|tx| {
+            let equipment = find_equipment(tx, mrid)?;
+            tx.execute("DELETE FROM equipment WHERE mrid = ?1", [mrid])?;
+            Ok(equipment)
+        })
+    }
+
+    fn diagram_summaries(&self, published_only: bool) -> Result<Vec<DiagramSummary>, Error> {
+        let sql = if published_only {
+            SELECT_PUBLISHED_SUMMARIES
+        } else {
+            SELECT_DIAGRAM_SUMMARIES
+        };
+        self.transaction(|tx| {
+            Ok(tx
+                .prepare(sql)?
+                .query_map([], to_summary)?
+                .collect::<rusqlite::Result<Vec<_>>>()?)
+        })
+    }
+
+    fn diagram(&self, id: &str) -> Result<Option<Diagram>, Error> {
+        self.transaction(|tx| find_diagram(tx, id))
+    }
+
+    fn save_diagram(
+        &self,
+        diagram: &Diagram,
+        author: &str,
+        author_name: &str,
+        precondition: Precondition,
+    ) -> Result<(Option<Diagram>, DiagramRevision), Error> {
+        let data = to_versioned_data(diagram)?;
+        self.transaction(|tx| {
+            let before = find_diagram(tx, &diagram.diagramId)?;
+            precondition.check(with_version(&before))?;
+            tx.execute(
+                "INSERT INTO diagrams (id, name, data) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(id) DO UPDATE SET name = excluded.name, data = excluded.data",
+                params![diagram.diagramId, diagram.name, data],
+            )?;
+
+            let revision = DiagramRevision {
+                diagram_id: diagram.diagramId.clone(),
+                revision: next_revision(tx, &diagram.diagramId)?,
+                author: author.to_string(),
+                author_name: author_name.to_string(),
+                timestamp: Utc::now().timestamp(),
+                deleted: false,
+            };
+            tx.execute(
+                "INSERT INTO diagram_revisions
+                    (diagram_id, revision, author, author_name, timestamp, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    revision.diagram_id,
+                    revision.revision,
+                    revision.author,
+                    revision.author_name,
+                    revision.timestamp,
+                    data
+                ],
+            )?;
+            Ok((before, revision))
+        })
+    }
+
+    fn delete_diagram(
+        &self,
+        id: &str,
+        author: &str,
+        author_name: &str,
+    ) -> Result<Option<Diagram>, Error> {
+        self.transaction(|tx| {
+            let diagram = match find_diagram(tx, id)? {
+                Some(diagram) => diagram,
+                None => return Ok(None),
+            };
+            tx.execute("DELETE FROM diagrams WHERE id = ?1", [id])?;
+            // The deletion revision holds the diagram as it was, restoring any revision
+            // brings the diagram back
+            tx.execute(
+                "INSERT INTO diagram_revisions
+                    (diagram_id, revision, author, author_name, timestamp, data, deleted)
+                 SELECT diagram_id, revision + 1, ?2, ?3, ?4, data, 1 FROM diagram_revisions
+                 WHERE diagram_id = ?1 ORDER BY revision DESC LIMIT 1",
+                params![id, author, author_name, Utc::now().timestamp()],
+            )?;
+            Ok(Some(diagram))
+        })
+    }
+
+    fn diagram_revisions(&self, id: &str) -> Result<Vec<DiagramRevision>, Error> {
+        self.transaction(|tx| {
+            let mut statement = tx.prepare(
+                "SELECT diagram_id, revision, author, author_name, timestamp, deleted
+                 FROM diagram_revisions WHERE diagram_id = ?1 ORDER BY revision DESC",
+            )?;
+            let revisions = statement
+                .query_map([id], to_revision)?
+                .collect::<rusqlite::Result<Vec<DiagramRevision>>>()?;
+            Ok(revisions)
+        })
+    }
+
+    fn diagram_revision(
+        &self,
+        id: &str,
+        revision: i64,
+    ) -> Result<Option<(DiagramRevision, Diagram)>, Error> {
+        self.transaction(|tx| {
+            let row = tx
+                .query_row(
+                    "SELECT diagram_id, revision, author, author_name, timestamp, deleted, data
+                     FROM diagram_revisions WHERE diagram_id = ?1 AND revision = ?2",
+                    params![id, revision],
+                    |row| Ok((to_revision(row)?, row.get::<_, String>(6)?)),
+                )
+                .optional()?;
+            match row {
+                Some((revision, data)) => {
+                    Ok(Some((revision, from_data("diagram_revisions", data)?)))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn publish_diagram(&self, id: &str, revision: i64) -> Result<Option<i64>, Error> {
+        self.transaction(|tx| {
+            let published = tx
+                .query_row(
+                    "SELECT published_revision FROM diagrams WHERE id = ?1",
+                    [id],
+                    |row| row.get::<_, Option<i64>>(0),
+                )
+                .optional()?
+                .ok_or_else(|| Error::NotFoundError(format!("diagram {}", id)))?;
+            let exists = tx
+                .query_row(
+                    "SELECT 1 FROM diagram_revisions WHERE diagram_id = ?1 AND revision = ?2",
+                    params![id, revision],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            if exists.is_none() {
+                return Err(Error::NotFoundError(format!(
+                    "revision {} of diagram {}",
+                    revision, id
+                )));
+            }
+            tx.execute(
+                "UPDATE diagrams SET published_revision = ?2 WHERE id = ?1",
+                params![id, revision],
+            )?;
+            Ok(published)
+        })
+    }
+
+    fn published_diagram(&self, id: &str) -> Result<Option<Diagram>, Error> {
+        self.transaction(|tx| {
+            Ok(query_versioned(
+                tx,
+                "diagram_revisions",
+                &format!("{} WHERE d.id = ?1", SELECT_PUBLISHED_DIAGRAMS),
+                Some(id),
+            )?
+            .pop())
+        })
+    }
+
+    fn diagram_templates(&self) -> Result<Vec<DiagramTemplate>, Error> {
+        self.transaction(|tx| {
+            query_all(
+                tx,
+                "diagram_templates",
+                "SELECT data FROM diagram_templates ORDER BY name COLLATE NOCASE, id",
+            )
+        })
+    }
+
+    fn diagram_template(&self, id: &str) -> Result<Option<DiagramTemplate>, Error> {
+        self.transaction(|tx| find_template(tx, id))
+    }
+
+    fn save_diagram_template(
+        &self,
+        template: &DiagramTemplate,
+    ) -> Result<Option<DiagramTemplate>, Error> {
+        let data = to_data(template)?;
+        self.transaction(|tx| {
+            let before = find_template(tx, &template.id)?;
+            tx.execute(
+                "INSERT INTO diagram_templates (id, name, data) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(id) DO UPDATE SET name = excluded.name, data = excluded.data",
+                params![template.id, template.name, data],
+            )?;
+            Ok(before)
+        })
+    }
+
+    fn delete_diagram_template(&self, id: &str) -> Result<Option<DiagramTemplate>, Error> {
+        self.transaction(|tx| {
+            let template = find_template(tx, id)?;
+            tx.execute("DELETE FROM diagram_templates WHERE id = ?1", [id])?;
+            Ok(template)
+        })
+    }
+
+    fn renamed_diagram_id(&self, old_id: &str) -> Result<Option<String>, Error> {
+        self.transaction(|tx| {
+            Ok(tx
+                .query_row(
+                    "SELECT new_id FROM diagram_id_renames WHERE old_id = ?1",
+                    [old_id],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+    }
+
+    fn sessions(&self) -> Result<Vec<Session>, Error> {
+        self.transaction(|tx| query_all(tx, "sessions", "SELECT data FROM sessions ORDER BY rowid"))
+    }
+
+    fn save_session(&self, session: &Session) -> Result<(), Error> {
+        let data = to_data(session)?;
+        self.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO sessions (id, user_id, expires, data) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(id) DO UPDATE SET expires = excluded.expires, data = excluded.data",
+                params![session.id, session.user_id, session.expires, data],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn delete_sessions(&self, ids: &[String]) -> Result<(), Error> {
+        self.transaction(|tx| {
+            let mut statement = tx.prepare("DELETE FROM sessions WHERE id = ?1")?;
+            for id in ids {
+                statement.execute([id])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn api_keys(&self) -> Result<Vec<ApiKey>, Error> {
+        self.transaction(|tx| {
+            query_all(
+                tx,
+                "api_keys",
+                "SELECT data FROM api_keys ORDER BY created, rowid",
+            )
+        })
+    }
+
+    fn insert_api_key(&self, key: &ApiKey) -> Result<(), Error> {
+        let data = to_data(key)?;
+        self.transaction(|tx| {
+            let inserted = tx.execute(
+                "INSERT OR IGNORE INTO api_keys (id, created, data) VALUES (?1, ?2, ?3)",
+                params![key.id, key.created, data],
+            )?;
+            if inserted == 0 {
+                error!("API key with same id ({}) already exists", key.id);
+                return Err(Error::AddApiKeyError);
+            }
+            Ok(())
+        })
+    }
+
+    fn set_api_key_last_used(&self, id: &str, last_used: i64) -> Result<(), Error> {
+        self.transaction(|tx| {
+            tx.execute(
+                "UPDATE api_keys SET data = json_set(data, '$.last_used', ?2) WHERE id = ?1",
+                params![id, last_used],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn delete_api_key(&self, id: &str) -> Result<Option<ApiKey>, Error> {
+        self.transaction(|tx| {
+            let key = query_one(
+                tx,
+                "api_keys",
+                "SELECT data FROM api_keys WHERE id = ?1",
+                id,
+            )?;
+            tx.execute("DELETE FROM api_keys WHERE id = ?1", [id])?;
+            Ok(key)
+        })
+    }
+
+    fn insert_audit_entry(&self, entry: &AuditEntry) -> Result<(), Error> {
+        let data = to_data(entry)?;
+        self.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO audit_log (id, timestamp, actor, action, target, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    entry.id,
+                    entry.timestamp,
+                    entry.actor,
+                    entry.action,
+                    entry.target,
+                    data
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn audit_entries(
+        &self,
+        query: &AuditQuery,
+        page: Option<(usize, usize)>,
+    ) -> Result<(usize, Vec<AuditEntry>), Error> {
+        let mut conditions = vec![];
+        let mut values = vec![];
+        if let Some(actor) = &query.actor {
+            values.push(SqlValue::Text(actor.clone()));
+            conditions.push(format!("actor = ?{}", values.len()));
+        }
+        match &query.action {
+            Some(prefix) if prefix.ends_with('.') => {
+                values.push(SqlValue::Text(prefix.clone()));
+                conditions.push(format!(
+                    "substr(action, 1, length(?{0})) = ?{0}",
+                    values.len()
+                ));
+            }
+            Some(action) => {
+                values.push(SqlValue::Text(action.clone()));
+                conditions.push(format!("action = ?{}", values.len()));
+            }
+            None => {}
+        }
+        if let Some(target) = &query.target {
+            values.push(SqlValue::Text(target.clone()));
+            conditions.push(format!("target = ?{}", values.len()));
+        }
+        if let Some(from) = query.from {
+            values.push(SqlValue::Integer(from));
+            conditions.push(format!("timestamp >= ?{}", values.len()));
+        }
+        if let Some(to) = query.to {
+            values.push(SqlValue::Integer(to));
+            conditions.push(format!("timestamp <= ?{}", values.len()));
+        }
+        let filter = match conditions.is_empty() {
+            true => String::new(),
+            false => format!(" WHERE {}", conditions.join(" AND ")),
+        };
+        let limit = match page {
+            Some((page, page_size)) => format!(
+                " LIMIT {} OFFSET {}",
+                page_size,
+                page.saturating_mul(page_size)
+            ),
+            None => String::new(),
+        };
+
+        self.transaction(|tx| {
+            let total: i64 = tx.query_row(
+                &format!("SELECT COUNT(*) FROM audit_log{}", filter),
+                params_from_iter(values.iter()),
+                |row| row.get(0),
+            )?;
+            let mut statement = tx.prepare(&format!(
+                "SELECT data FROM audit_log{} ORDER BY timestamp DESC, rowid DESC{}",
+                filter, limit
+            ))?;
+            let rows = statement.query_map(params_from_iter(values.iter()), |row| {
+                row.get::<_, String>(0)
+            })?;
+            let mut entries = vec![];
+            for data in rows {
+                entries.push(from_data("audit_log", data?)?);
+            }
+            Ok((total as usize, entries))
+        })
+    }
+
+    fn setting(&self, key: &str) -> Result<Option<String>, Error> {
+        self.transaction(|tx| {
+            Ok(tx
+                .query_row("SELECT value FROM settings WHERE key = ?1", [key], |row| {
+                    row.get(0)
+                })
+                .optional()?)
+        })
+    }
+
+    fn set_setting(&self, key: &str, value: &str) -> Result<(), Error> {
+        self.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO settings (key, value) VALUES (?1, ?2)
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                params![key, value],
+            )?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema_version(connection: &Connection) -> usize {
+        connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    /// Database with the first `count` migrations applied, as an older server left it
+    fn database_at(count: usize) -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..count] {
+            connection.execute_batch(migration).unwrap();
+        }
+        connection
+            .pragma_update(None, "user_version", count)
+            .unwrap();
+        connection
+    }
+
+    fn storage(mut connection: Connection) -> SqliteStorage {
+        migrate(&mut connection).unwrap();
+        SqliteStorage {
+            connection: Mutex::new(connection),
+        }
+    }
+
+    fn diagram_data(id: &str, name: &str) -> String {
+        serde_json::json!({
+            "diagramId": id,
+            "name": name,
+            "description": null,
+            "location": "Plant",
+            "data": "<mxGraphModel/>",
+            "createdDate": null,
+            "createdBy": null,
+            "backgroundColor": null,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn empty_database_gets_every_migration() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        assert_eq!(schema_version(&connection), MIGRATIONS.len());
+
+        let tables: Vec<String> = connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        for table in &[
+            "api_keys",
+            "audit_log",
+            "diagram_id_renames",
+            "diagram_revisions",
+            "diagram_templates",
+            "diagrams",
+            "equipment",
+            "sessions",
+            "settings",
+            "users",
+        ] {
+            assert!(tables.iter().any(|t| t == table), "{} is missing", table);
+        }
+
+        // Migrating again changes nothing
+        migrate(&mut connection).unwrap();
+        assert_eq!(schema_version(&connection), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+        let mut connection = database_at(MIGRATIONS.len());
+        connection
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(matches!(
+            migrate(&mut connection),
+            Err(Error::StorageError(_))
+        ));
+    }
+
+    #[test]
+    fn diagrams_of_the_first_schema_become_published_revisions() {
+        let connection = database_at(1);
+        connection
+            .execute(
+                "INSERT INTO diagrams (id, name, data) VALUES ('feeder-1', 'Feeder', ?1)",
+                [diagram_data("feeder-1", "Feeder")],
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO equipment (mrid, data) VALUES ('m1', ?1)",
+                [r#"{"mrid":"m1","name":"Breaker","deviceType":"breaker"}"#],
+            )
+            .unwrap();
+        let storage = storage(connection);
+
+        let revisions = storage.diagram_revisions("feeder-1").unwrap();
+        assert_eq!(revisions.len(), 1);
+        assert_eq!(revisions[0].revision, 1);
+
+        let diagram = storage.diagram("feeder-1").unwrap().unwrap();
+        assert_eq!(diagram.name.as_deref(), Some("Feeder"));
+        assert_eq!(diagram.version, Some(1));
+        assert_eq!(diagram.published_version, Some(1));
+        assert!(storage.published_diagram("feeder-1").unwrap().is_some());
+
+        let equipment = storage.equipment().unwrap();
+        assert_eq!(equipment[0].version, Some(1));
+    }
+
+    #[test]
+    fn unsafe_diagram_ids_are_renamed() {
+        let connection = database_at(4);
+        for id in &["feeder 1", "feeder-2"] {
+            connection
+                .execute(
+                    "INSERT INTO diagrams (id, name, data) VALUES (?1, ?1, ?2)",
+                    params![id, diagram_data(id, id)],
+                )
+                .unwrap();
+            connection
+                .execute(
+                    "INSERT INTO diagram_revisions
+                        (diagram_id, revision, author, author_name, timestamp, data)
+                     VALUES (?1, 1, '', '', 0, ?2)",
+                    params![id, diagram_data(id, id)],
+                )
+                .unwrap();
+        }
+        let storage = storage(connection);
+
+        let new_id = storage.renamed_diagram_id("feeder 1").unwrap().unwrap();
+        assert_eq!(new_id.len(), 32);
+        assert!(storage.diagram("feeder 1").unwrap().is_none());
+        assert_eq!(storage.diagram(&new_id).unwrap().unwrap().diagramId, new_id);
+        let (revision, diagram) = storage.diagram_revision(&new_id, 1).unwrap().unwrap();
+        assert_eq!(revision.diagram_id, new_id);
+        assert_eq!(diagram.diagramId, new_id);
+
+        // Safe ids are kept
+        assert!(storage.renamed_diagram_id("feeder-2").unwrap().is_none());
+        assert!(storage.diagram("feeder-2").unwrap().is_some());
+    }
+
+    #[test]
+    fn revisions_are_numbered_per_diagram_and_kept_on_delete() {
+        let storage = storage(Connection::open_in_memory().unwrap());
+        let diagram = |id: &str, name: &str| -> Diagram {
+            serde_json::from_str(&diagram_data(id, name)).unwrap()
+        };
+
+        let (before, revision) = storage
+            .save_diagram(&diagram("a", "A"), "u1", "one", Precondition::New)
+            .unwrap();
+        assert!(before.is_none());
+        assert_eq!(revision.revision, 1);
+        let (_, revision) = storage
+            .save_diagram(&diagram("a", "A2"), "u2", "two", Precondition::Version(1))
+            .unwrap();
+        assert_eq!(revision.revision, 2);
+        let (_, revision) = storage
+            .save_diagram(&diagram("b", "B"), "u1", "one", Precondition::New)
+            .unwrap();
+        assert_eq!(revision.revision, 1);
+        assert!(matches!(
+            storage.save_diagram(&diagram("a", "A3"), "u1", "one", Precondition::Version(1)),
+            Err(Error::VersionConflictError {
+                version: Some(2),
+                ..
+            })
+        ));
+
+        let removed = storage.delete_diagram("a", "u3", "three").unwrap().unwrap();
+        assert_eq!(removed.name.as_deref(), Some("A2"));
+        assert!(storage.diagram("a").unwrap().is_none());
+        assert!(storage
+            .delete_diagram("a", "u3", "three")
+            .unwrap()
+            .is_none());
+
+        let revisions = storage.diagram_revisions("a").unwrap();
+        let numbers: Vec<i64> = revisions.iter().map(|r| r.revision).collect();
+        assert_eq!(numbers, vec![3, 2, 1]);
+        assert!(revisions[0].deleted && !revisions[1].deleted);
+        assert_eq!(revisions[0].author_name, "three");
+        let (_, deleted) = storage.diagram_revision("a", 3).unwrap().unwrap();
+        assert_eq!(deleted.name.as_deref(), Some("A2"));
+
+        // Saving the diagram again continues its history
+        let (before, revision) = storage
+            .save_diagram(&deleted, "u1", "one", Precondition::New)
+            .unwrap();
+        assert!(before.is_none());
+        assert_eq!(revision.revision, 4);
+        assert_eq!(storage.diagram("a").unwrap().unwrap().version, Some(4));
+    }
+
+    #[test]
+    fn summaries_carry_the_listed_fields_of_the_diagrams() {
+        let storage = storage(Connection::open_in_memory().unwrap());
+        let diagram: Diagram = serde_json::from_str(&diagram_data("a", "A")).unwrap();
+        storage
+            .save_diagram(&diagram, "u1", "one", Precondition::New)
+            .unwrap();
+        storage.publish_diagram("a", 1).unwrap();
+        let mut renamed = diagram.clone();
+        renamed.name = Some("A2".to_string());
+        storage
+            .save_diagram(&renamed, "u1", "one", Precondition::Version(1))
+            .unwrap();
+        storage
+            .transaction(|tx| {
+                tx.execute(
+                    "INSERT INTO diagrams (id, name, data) VALUES ('b', 'B', 'not json')",
+                    [],
+                )?;
+                tx.execute(
+                    "INSERT INTO diagrams (id, name, data) VALUES ('c', 'C', '{\"name\": 3}')",
+                    [],
+                )?;
+                Ok(())
+            })
+            .unwrap();
+
+        let summaries = storage.diagram_summaries(false).unwrap();
+        let ids: Vec<&str> = summaries.iter().map(|d| d.diagramId.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b", "c"]);
+        assert_eq!(summaries[0].name.as_deref(), Some("A2"));
+        assert_eq!(summaries[0].location.as_deref(), Some("Plant"));
+        assert_eq!(summaries[0].version, Some(2));
+        assert_eq!(summaries[0].published_version, Some(1));
+        assert!(summaries[0].updated.is_some() && summaries[0].error.is_none());
+        // Diagrams that can't be read are listed with their id and name
+        assert_eq!(summaries[1].name.as_deref(), Some("B"));
+        assert!(summaries[1].error.is_some());
+        assert_eq!(summaries[2].name.as_deref(), Some("C"));
+        assert!(summaries[2].error.is_some());
+
+        let published = storage.diagram_summaries(true).unwrap();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].name.as_deref(), Some("A"));
+        assert_eq!(published[0].version, Some(1));
+    }
+
+    #[test]
+    fn sessions_are_saved_replaced_and_deleted() {
+        let storage = storage(Connection::open_in_memory().unwrap());
+        let session = |id: &str, expires: i64| -> Session {
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "user_id": "u1",
+                "refresh_hash": "hash",
+                "created": 0,
+                "expires": expires,
+            }))
+            .unwrap()
+        };
+        storage.save_session(&session("s1", 10)).unwrap();
+        storage.save_session(&session("s2", 20)).unwrap();
+        storage.save_session(&session("s1", 30)).unwrap();
+
+        let expires: Vec<(String, i64)> = storage
+            .sessions()
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.id, s.expires))
+            .collect();
+        assert_eq!(
+            expires,
+            vec![("s1".to_string(), 30), ("s2".to_string(), 20)]
+        );
+
+        storage
+            .delete_sessions(&["s1".to_string(), "s3".to_string()])
+            .unwrap();
+        let ids: Vec<String> = storage
+            .sessions()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.id)
+            .collect();
+        assert_eq!(ids, vec!["s2"]);
+    }
+
+    #[test]
+    fn api_keys_are_inserted_used_and_deleted() {
+        let storage = storage(Connection::open_in_memory().unwrap());
+        let key = |id: &str, created: i64| -> ApiKey {
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "name": format!("key {}", id),
+                "key_hash": "hash",
+                "permissions": ["ViewData"],
+                "created": created,
+                "created_by": "u1",
+            }))
+            .unwrap()
+        };
+        storage.insert_api_key(&key("k2", 20)).unwrap();
+        storage.insert_api_key(&key("k1", 10)).unwrap();
+        assert!(matches!(
+            storage.insert_api_key(&key("k1", 30)),
+            Err(Error::AddApiKeyError)
+        ));
+
+        storage.set_api_key_last_used("k2", 25).unwrap();
+        let keys: Vec<(String, Option<i64>)> = storage
+            .api_keys()
+            .unwrap()
+            .into_iter()
+            .map(|k| (k.id, k.last_used))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![("k1".to_string(), None), ("k2".to_string(), Some(25))]
+        );
+
+        let deleted = storage.delete_api_key("k1").unwrap().unwrap();
+        assert_eq!(deleted.id, "k1");
+        assert!(storage.delete_api_key("k1").unwrap().is_none());
+        assert_eq!(storage.api_keys().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn audit_entries_are_filtered_and_paged_newest_first() {
+        let storage = storage(Connection::open_in_memory().unwrap());
+        let entries = [
+            ("e1", 100, "u1", "user.create", "u2"),
+            ("e2", 200, "u1", "user.update", "u2"),
+            ("e3", 300, "api-key:k1", "equipment.create", "m1"),
+            ("e4", 400, "u2", "user.delete", "u3"),
+            ("e5", 500, "u1", "userx.other", "u2"),
+        ];
+        for (id, timestamp, actor, action, target) in entries.iter() {
+            storage
+                .insert_audit_entry(&AuditEntry {
+                    id: id.to_string(),
+                    timestamp: *timestamp,
+                    actor: actor.to_string(),
+                    action: action.to_string(),
+                    target: target.to_string(),
+                    before: None,
+                    after: Some(serde_json::json!({ "id": target })),
+                })
+                .unwrap();
+        }
+        let find = |query: serde_json::Value, page: Option<(usize, usize)>| {
+            let query: AuditQuery = serde_json::from_value(query).unwrap();
+            let (total, entries) = storage.audit_entries(&query, page).unwrap();
+            let ids: Vec<String> = entries.into_iter().map(|e| e.id).collect();
+            (total, ids)
+        };
+
+        assert_eq!(
+            find(serde_json::json!({}), None),
+            (5, ids(&["e5", "e4", "e3", "e2", "e1"]))
+        );
+        assert_eq!(
+            find(serde_json::json!({ "actor": "u1" }), None),
+            (3, ids(&["e5", "e2", "e1"]))
+        );
+        assert_eq!(
+            find(serde_json::json!({ "target": "u3" }), None),
+            (1, ids(&["e4"]))
+        );
+        assert_eq!(
+            find(serde_json::json!({ "action": "user.update" }), None),
+            (1, ids(&["e2"]))
+        );
+        // A prefix ends with a dot, so that `userx.` actions aren't included
+        assert_eq!(
+            find(serde_json::json!({ "action": "user." }), None),
+            (3, ids(&["e4", "e2", "e1"]))
+        );
+        assert_eq!(
+            find(serde_json::json!({ "action": "user" }), None),
+            (0, ids(&[]))
+        );
+        assert_eq!(
+            find(serde_json::json!({ "from": 200, "to": 400 }), None),
+            (3, ids(&["e4", "e3", "e2"]))
+        );
+        assert_eq!(
+            find(
+                serde_json::json!({ "actor": "u1", "action": "user.", "from": 150 }),
+                None
+            ),
+            (1, ids(&["e2"]))
+        );
+
+        assert_eq!(
+            find(serde_json::json!({}), Some((0, 2))),
+            (5, ids(&["e5", "e4"]))
+        );
+        assert_eq!(find(serde_json::json!({}), Some((2, 2))), (5, ids(&["e1"])));
+        assert_eq!(find(serde_json::json!({}), Some((3, 2))), (5, ids(&[])));
+        assert_eq!(
+            find(serde_json::json!({ "actor": "u1" }), Some((1, 2))),
+            (3, ids(&["e1"]))
+        );
+
+        let (_, entries) = storage
+            .audit_entries(
+                &serde_json::from_value(serde_json::json!({ "target": "m1" })).unwrap(),
+                None,
+            )
+            .unwrap();
+        assert_eq!(entries[0].actor, "api-key:k1");
+        assert_eq!(entries[0].after, Some(serde_json::json!({ "id": "m1" })));
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+}
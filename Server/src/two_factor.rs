@@ -0,0 +1,313 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::Error;
+use config::Config;
+use lazy_static::lazy_static;
+use pwhash::bcrypt;
+use qrcode::{render::svg, QrCode};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+lazy_static! {
+    static ref SETTINGS: TwoFactorSettings = TwoFactorSettings::from_config(&riker::load_config());
+    static ref PENDING_LOGINS: Mutex<HashMap<String, PendingLogin>> = Mutex::new(HashMap::new());
+    static ref PENDING_ENROLLMENTS: Mutex<HashMap<String, PendingEnrollment>> =
+        Mutex::new(HashMap::new());
+    static ref LAST_USED_STEP: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+}
+
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP: u64 = 30; // seconds
+const TOTP_SKEW: u64 = 1; // steps accepted before and after the current one
+const RECOVERY_CODES: usize = 10;
+const MAX_CODE_ATTEMPTS: u32 = 5;
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const ENROLLMENT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+struct TwoFactorSettings {
+    issuer: String,
+    required_roles: Vec<String>,
+}
+
+impl TwoFactorSettings {
+    fn from_config(config: &Config) -> TwoFactorSettings {
+        TwoFactorSettings {
+            issuer: config
+                .get_str("auth.two_factor_issuer")
+                .unwrap_or("OpenFMB HMI".to_string()),
+            required_roles: config
+                .get("auth.two_factor_roles")
+                .unwrap_or_else(|_| vec!["Admin".to_string(), "Engineer".to_string()]),
+        }
+    }
+}
+
+/// A login whose password was verified and that waits for the second factor
+#[derive(Clone)]
+pub struct PendingLogin {
+    pub user_id: String,
+    pub username: String,
+    created: Instant,
+    attempts: u32,
+}
+
+struct PendingEnrollment {
+    secret: String,
+    created: Instant,
+}
+
+/// A new TOTP secret to be added to an authenticator app
+#[derive(Serialize)]
+pub struct TotpEnrollment {
+    /// Base32 secret for manual entry
+    pub secret: String,
+    /// `otpauth://` provisioning URI
+    pub uri: String,
+    /// The provisioning URI as an SVG QR code
+    pub qr_svg: String,
+}
+
+/// True when users with the role have to log in with a second factor
+pub fn is_two_factor_required(role: &str) -> bool {
+    SETTINGS.required_roles.iter().any(|r| r == role)
+}
+
+fn totp(secret: &str, username: &str) -> Result<TOTP, Error> {
+    let bytes = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|_| Error::TwoFactorError)?;
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        TOTP_SKEW as u8,
+        TOTP_STEP,
+        bytes,
+        Some(SETTINGS.issuer.replace(':', "")),
+        username.replace(':', ""),
+    )
+    .map_err(|_| Error::TwoFactorError)
+}
+
+/// Generates a secret for the user, which is kept until `confirm_enrollment` is called
+/// with a code from the authenticator
+pub fn start_enrollment(user_id: &str, username: &str) -> Result<TotpEnrollment, Error> {
+    let secret = match Secret::generate_secret().to_encoded() {
+        Secret::Encoded(s) => s,
+        Secret::Raw(_) => return Err(Error::TwoFactorError),
+    };
+    let uri = totp(&secret, username)?.get_url();
+    let qr_svg = QrCode::new(uri.as_bytes())
+        .map_err(|_| Error::TwoFactorError)?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+
+    let mut pending = PENDING_ENROLLMENTS.lock().unwrap();
+    pending.retain(|_, e| e.created.elapsed() < ENROLLMENT_TIMEOUT);
+    pending.insert(
+        user_id.to_string(),
+        PendingEnrollment {
+            secret: secret.clone(),
+            created: Instant::now(),
+        },
+    );
+
+    Ok(TotpEnrollment {
+        secret,
+        uri,
+        qr_svg,
+    })
+}
+
+/// Returns the enrolled secret when the code matches the secret of `start_enrollment`
+pub fn confirm_enrollment(user_id: &str, username: &str, code: &str) -> Result<String, Error> {
+    let mut pending = PENDING_ENROLLMENTS.lock().unwrap();
+    let secret = match pending.get(user_id) {
+        Some(e) if e.created.elapsed() < ENROLLMENT_TIMEOUT => e.secret.clone(),
+        _ => return Err(Error::TwoFactorError),
+    };
+    if !verify_totp(user_id, username, &secret, code)? {
+        return Err(Error::InvalidTwoFactorCodeError);
+    }
+    pending.remove(user_id);
+    Ok(secret)
+}
+
+/// Checks a code of the authenticator, each code is accepted only once
+pub fn verify_totp(user_id: &str, username: &str, secret: &str, code: &str) -> Result<bool, Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::TwoFactorError)?
+        .as_secs();
+    verify_totp_at(user_id, username, secret, code, now)
+}
+
+/// Checks the code at the time given in seconds since the epoch
+fn verify_totp_at(
+    user_id: &str,
+    username: &str,
+    secret: &str,
+    code: &str,
+    now: u64,
+) -> Result<bool, Error> {
+    let totp = totp(secret, username)?;
+    let current = now / TOTP_STEP;
+
+    let mut last_used = LAST_USED_STEP.lock().unwrap();
+    let matched = (current.saturating_sub(TOTP_SKEW)..=current + TOTP_SKEW)
+        .filter(|step| last_used.get(user_id).map_or(true, |last| step > last))
+        .find(|step| totp.generate(step * TOTP_STEP) == code.trim());
+
+    match matched {
+        Some(step) => {
+            last_used.insert(user_id.to_string(), step);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Creates one-time recovery codes, returns the codes to show and the hashes to keep
+pub fn generate_recovery_codes() -> Result<(Vec<String>, Vec<String>), Error> {
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let s = uuid::Uuid::new_v4().simple().to_string();
+            format!("{}-{}", &s[0..5], &s[5..10])
+        })
+        .collect();
+    let hashes = codes
+        .iter()
+        .map(|c| bcrypt::hash(c).map_err(|_| Error::TwoFactorError))
+        .collect::<Result<Vec<String>, Error>>()?;
+    Ok((codes, hashes))
+}
+
+/// Hash of the recovery code matching the given code, the caller removes it to use it up
+pub fn matching_recovery_code(hashes: &[String], code: &str) -> Option<String> {
+    let code = code.trim().to_lowercase();
+    hashes.iter().find(|h| bcrypt::verify(&code, h)).cloned()
+}
+
+/// Starts the second step of a login, returns the token identifying it
+pub fn start_login(user_id: &str, username: &str) -> String {
+    let token = (0..2)
+        .map(|_| uuid::Uuid::new_v4().simple().to_string())
+        .collect::<String>();
+
+    let mut pending = PENDING_LOGINS.lock().unwrap();
+    pending.retain(|_, l| l.created.elapsed() < LOGIN_TIMEOUT);
+    pending.insert(
+        token.clone(),
+        PendingLogin {
+            user_id: user_id.to_string(),
+            username: username.to_string(),
+            created: Instant::now(),
+            attempts: 0,
+        },
+    );
+    token
+}
+
+/// Looks up the pending login and counts the attempt, the login is dropped after too
+/// many wrong codes
+pub fn pending_login(token: &str) -> Option<PendingLogin> {
+    let mut pending = PENDING_LOGINS.lock().unwrap();
+    let login = match pending.get_mut(token) {
+        Some(l) if l.created.elapsed() < LOGIN_TIMEOUT && l.attempts < MAX_CODE_ATTEMPTS => l,
+        _ => {
+            pending.remove(token);
+            return None;
+        }
+    };
+    login.attempts += 1;
+    Some(login.clone())
+}
+
+/// Ends the pending login after the second factor was verified
+pub fn finish_login(token: &str) {
+    PENDING_LOGINS.lock().unwrap().remove(token);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+    const NOW: u64 = 1_700_000_000;
+
+    fn code_at(time: u64) -> String {
+        totp(SECRET, "tester").unwrap().generate(time)
+    }
+
+    #[test]
+    fn current_code_is_accepted_once() {
+        let user = "totp-once";
+        assert!(verify_totp_at(user, "tester", SECRET, &code_at(NOW), NOW).unwrap());
+        assert!(!verify_totp_at(user, "tester", SECRET, &code_at(NOW), NOW).unwrap());
+    }
+
+    #[test]
+    fn codes_of_adjacent_steps_are_accepted() {
+        let code = format!(" {} ", code_at(NOW - TOTP_STEP));
+        assert!(verify_totp_at("totp-skew-before", "tester", SECRET, &code, NOW).unwrap());
+        let code = code_at(NOW + TOTP_STEP);
+        assert!(verify_totp_at("totp-skew-after", "tester", SECRET, &code, NOW).unwrap());
+        let code = code_at(NOW - 2 * TOTP_STEP);
+        assert!(!verify_totp_at("totp-skew-old", "tester", SECRET, &code, NOW).unwrap());
+    }
+
+    #[test]
+    fn codes_older_than_the_last_used_are_refused() {
+        let user = "totp-older";
+        assert!(verify_totp_at(user, "tester", SECRET, &code_at(NOW), NOW).unwrap());
+        let earlier = code_at(NOW - TOTP_STEP);
+        assert!(!verify_totp_at(user, "tester", SECRET, &earlier, NOW).unwrap());
+        let later = code_at(NOW + TOTP_STEP);
+        assert!(verify_totp_at(user, "tester", SECRET, &later, NOW + TOTP_STEP).unwrap());
+    }
+
+    #[test]
+    fn invalid_secret_is_an_error() {
+        assert!(matches!(
+            verify_totp_at("totp-invalid", "tester", "not base32!", "123456", NOW),
+            Err(Error::TwoFactorError)
+        ));
+    }
+
+    #[test]
+    fn recovery_codes_match_once_generated() {
+        let (codes, hashes) = generate_recovery_codes().unwrap();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert_eq!(hashes.len(), RECOVERY_CODES);
+        assert!(codes
+            .iter()
+            .all(|c| c.len() == 11 && c.as_bytes()[5] == b'-'));
+
+        // Codes are typed in, case and surrounding space don't matter
+        let code = format!(" {} ", codes[3].to_uppercase());
+        assert_eq!(
+            matching_recovery_code(&hashes, &code),
+            Some(hashes[3].clone())
+        );
+        assert_eq!(matching_recovery_code(&hashes, "00000-00000"), None);
+        assert_eq!(matching_recovery_code(&hashes[4..], &codes[3]), None);
+    }
+
+    #[test]
+    fn pending_login_allows_a_few_attempts() {
+        let token = start_login("user-id", "tester");
+        for _ in 0..MAX_CODE_ATTEMPTS {
+            assert_eq!(pending_login(&token).unwrap().user_id, "user-id");
+        }
+        assert!(pending_login(&token).is_none());
+
+        let token = start_login("user-id", "tester");
+        finish_login(&token);
+        assert!(pending_login(&token).is_none());
+    }
+}
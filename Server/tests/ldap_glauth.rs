@@ -0,0 +1,88 @@
+// SPDX-FileCopyrightText: 2021 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Logs in against the sample glauth server of `config/ldap`, see README.md. The test is
+//! ignored by default, start the server and run
+//! `cargo test --test ldap_glauth -- --ignored`, `HMI_LDAP_TEST_URL` overrides the
+//! default `ldap://localhost:3893`.
+
+use config::Config;
+use hmi_server::auth::Role;
+use hmi_server::auth_provider::AuthProvider;
+use hmi_server::ldap::LdapAuthProvider;
+
+fn sample_config() -> Config {
+    let url = std::env::var("HMI_LDAP_TEST_URL").unwrap_or("ldap://localhost:3893".to_string());
+    let mut config = Config::default();
+    for (key, value) in [
+        ("ldap.url", url.as_str()),
+        (
+            "ldap.bind_dn",
+            "cn=hmiservice,ou=svcaccts,ou=users,dc=openfmb,dc=local",
+        ),
+        ("ldap.bind_password", "svcpass"),
+        ("ldap.base_dn", "dc=openfmb,dc=local"),
+        (
+            "ldap.user_filter",
+            "(&(objectClass=posixAccount)(cn={username}))",
+        ),
+    ] {
+        config.set(key, value).unwrap();
+    }
+    config.set("ldap.admin_groups", vec!["hmi-admins"]).unwrap();
+    config
+        .set("ldap.engineer_groups", vec!["hmi-engineers"])
+        .unwrap();
+    config.set("ldap.viewer_groups", vec!["hmi-viewers"]).unwrap();
+    config
+}
+
+#[tokio::test]
+#[ignore]
+async fn glauth_login() {
+    // Users logging in through LDAP are added to the database, keep it out of the tree
+    let dir = std::env::temp_dir().join(format!("hmi-ldap-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_var(
+        "APP_DIR_NAME",
+        dir.to_str().unwrap().trim_start_matches('/'),
+    );
+
+    let provider = LdapAuthProvider::from_config(&sample_config()).unwrap();
+
+    for (username, password, role) in [
+        ("hmiadmin", "adminpass", Role::Admin),
+        ("hmiengineer", "engineerpass", Role::Engineer),
+        ("hmiviewer", "viewerpass", Role::Viewer),
+    ] {
+        let user = provider
+            .authenticate(username, password)
+            .await
+            .unwrap()
+            .unwrap_or_else(|| panic!("{} is refused", username));
+        assert_eq!(user.username, username);
+        assert_eq!(user.role, role.to_string());
+        assert_eq!(user.provider.as_deref(), Some("ldap"));
+    }
+
+    // Wrong password, unknown user, empty password and a user in none of the HMI groups
+    for (username, password) in [
+        ("hmiadmin", "viewerpass"),
+        ("nobody", "adminpass"),
+        ("hmiadmin", ""),
+        ("hmiservice", "svcpass"),
+    ] {
+        assert!(
+            provider
+                .authenticate(username, password)
+                .await
+                .unwrap()
+                .is_none(),
+            "{} is accepted",
+            username
+        );
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}